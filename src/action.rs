@@ -0,0 +1,139 @@
+use crate::dedup::Interner;
+use crate::output::expand_template;
+use crate::GropError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+
+/// Hooks run at given points of the run.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Actions {
+    pub on_complete: Option<Vec<CompleteAction>>,
+}
+
+/// A summary of the run rendered from the template once it completes, written to the file or
+/// posted to the webhook.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompleteAction {
+    pub template: String,
+    pub top_field: Option<String>,
+    pub top: Option<usize>,
+    pub path: Option<String>,
+    pub webhook: Option<String>,
+    // Post the summary as the "text" of a JSON object rather than as plain text.
+    #[serde(default)]
+    pub json: bool,
+}
+
+const DEFAULT_TOP: usize = 10;
+
+/// The complete actions, along with the number of the records for each value of their top field.
+pub(crate) struct Summaries {
+    actions: Vec<(CompleteAction, HashMap<Rc<str>, u64>)>,
+    // The values counted by several actions are held once.
+    values: Interner,
+}
+
+impl Summaries {
+    pub(crate) fn new(actions: &[CompleteAction]) -> Result<Summaries, GropError> {
+        for action in actions.iter() {
+            if action.path.is_some() == action.webhook.is_some() {
+                return Err(GropError::InvalidArg(String::from(
+                    "each on_complete action needs either a path or a webhook",
+                )));
+            }
+        }
+        Ok(Summaries {
+            actions: actions
+                .iter()
+                .map(|action| (action.clone(), HashMap::new()))
+                .collect(),
+            values: Interner::default(),
+        })
+    }
+
+    /// Count the record written out.
+    pub(crate) fn observe(&mut self, m: &HashMap<String, String>) {
+        for (action, counts) in self.actions.iter_mut() {
+            if let Some(value) = action.top_field.as_ref().and_then(|f| m.get(f)) {
+                *counts.entry(self.values.intern(value)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Render the summary of each action, with the figures of the run along with the `top` most
+    /// frequent values of its top field (one "count value" per line), and deliver it.
+    pub(crate) fn complete(&self, figures: &HashMap<String, String>) -> Result<(), GropError> {
+        for (action, counts) in self.actions.iter() {
+            let mut top = counts.iter().collect::<Vec<(&Rc<str>, &u64)>>();
+            top.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            let mut figures = figures.clone();
+            figures.insert(
+                String::from("top"),
+                top.iter()
+                    .take(action.top.unwrap_or(DEFAULT_TOP))
+                    .map(|(value, count)| format!("{} {}", count, value))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            );
+            let summary = expand_template(&action.template, &figures)?;
+            if let Some(path) = &action.path {
+                let path = expand_template(path, &figures)?;
+                log::info!("on_complete: write the summary to {}", path);
+                fs::write(&path, summary)?;
+            } else if let Some(url) = &action.webhook {
+                log::info!("on_complete: post the summary to {}", url);
+                let (content_type, body) = match action.json {
+                    true => (
+                        "application/json",
+                        serde_json::json!({ "text": summary }).to_string(),
+                    ),
+                    false => ("text/plain; charset=utf-8", summary),
+                };
+                ureq::post(url)
+                    .set("Content-Type", content_type)
+                    .send_string(&body)
+                    .map_err(|err| {
+                        io::Error::other(format!("failed to post the summary to {}: {}", url, err))
+                    })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_summaries() {
+        let path = std::env::temp_dir().join(format!("grop-summary-{}", std::process::id()));
+        let action: CompleteAction = toml::from_str(&format!(
+            "template = \"{{records}} records\\n{{top}}\"\ntop_field = 'level'\ntop = 2\npath = '{}'",
+            path.display()
+        ))
+        .unwrap();
+        let mut summaries = Summaries::new(&[action]).unwrap();
+        for level in ["WARN", "ERROR", "INFO", "ERROR"].iter() {
+            let mut m = HashMap::new();
+            m.insert(String::from("level"), String::from(*level));
+            summaries.observe(&m);
+        }
+        summaries.observe(&HashMap::new());
+        let mut figures = HashMap::new();
+        figures.insert(String::from("records"), String::from("5"));
+        summaries.complete(&figures).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "5 records\n2 ERROR\n1 INFO"
+        );
+        fs::remove_file(&path).unwrap();
+
+        let action: CompleteAction = toml::from_str("template = 'x'").unwrap();
+        assert!(Summaries::new(&[action]).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::untyped_groups;
+use fgrok::{Error, Grok, Pattern};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+/// The grok instance along with the patterns it compiled, by their expression, so that each
+/// expression (match expressions, filters, merge expressions, conditions...) is compiled once
+/// however many times it's given, e.g. for each input or test case. The definitions are to be
+/// added through the grok instance before compiling any pattern depending on them.
+#[derive(Default)]
+pub(crate) struct PatternCache {
+    grok: Grok,
+    patterns: HashMap<(String, bool), Rc<Pattern>>,
+    hits: u64,
+}
+
+impl PatternCache {
+    pub(crate) fn new(grok: Grok) -> PatternCache {
+        PatternCache {
+            grok,
+            patterns: HashMap::new(),
+            hits: 0,
+        }
+    }
+
+    /// Compile the expression, unless it was already. The fields of its inline groups may be given
+    /// a type, like the ones of its references.
+    pub(crate) fn compile(
+        &mut self,
+        expression: &str,
+        with_alias_only: bool,
+    ) -> Result<Rc<Pattern>, Error> {
+        let key = (String::from(expression), with_alias_only);
+        if let Some(pattern) = self.patterns.get(&key) {
+            self.hits += 1;
+            return Ok(pattern.clone());
+        }
+        let untyped = untyped_groups(expression);
+        let pattern = Rc::new(self.grok.compile(&untyped, with_alias_only)?);
+        self.patterns.insert(key, pattern.clone());
+        Ok(pattern)
+    }
+
+    /// Log the number of the patterns compiled and of the times they were reused.
+    pub(crate) fn report(&self) {
+        log::info!(
+            "pattern cache: {} patterns compiled, {} hits",
+            self.patterns.len(),
+            self.hits
+        );
+    }
+}
+
+impl Deref for PatternCache {
+    type Target = Grok;
+
+    fn deref(&self) -> &Grok {
+        &self.grok
+    }
+}
+
+impl DerefMut for PatternCache {
+    fn deref_mut(&mut self) -> &mut Grok {
+        &mut self.grok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_pattern_cache() {
+        let mut cache = PatternCache::default();
+        let first = cache.compile("%{INT:n}", false).unwrap();
+        let second = cache.compile("%{INT:n}", false).unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+        assert!(!Rc::ptr_eq(
+            &first,
+            &cache.compile("%{INT:n}", true).unwrap()
+        ));
+        assert_eq!((cache.patterns.len(), cache.hits), (2, 1));
+        // The failures are not cached.
+        assert!(cache.compile("%{NOPE}", false).is_err());
+        assert_eq!(cache.patterns.len(), 2);
+    }
+}
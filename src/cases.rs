@@ -0,0 +1,189 @@
+use crate::cache::PatternCache;
+use crate::source::Source;
+use crate::stats::PatternStats;
+use crate::{build_emitter, load_patterns, process_input, Config, GropError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+struct CaseFile {
+    #[serde(default)]
+    cases: Vec<Case>,
+}
+
+/// Input lines along with the records expected to be extracted from them.
+#[derive(Deserialize)]
+struct Case {
+    name: Option<String>,
+    input: String,
+    // Only the listed fields of each record are compared, but the number of records must match.
+    #[serde(default)]
+    expected: Vec<HashMap<String, String>>,
+}
+
+/// Describe the first difference between the expected and the actual records, if any.
+fn compare(
+    expected: &[HashMap<String, String>],
+    actual: &[HashMap<String, String>],
+) -> Option<String> {
+    if expected.len() != actual.len() {
+        return Some(format!(
+            "expected {} records, got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+    for (i, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        let mut fields = e.iter().collect::<Vec<(&String, &String)>>();
+        fields.sort();
+        for (k, v) in fields {
+            match a.get(k) {
+                Some(value) if value == v => {}
+                Some(value) => {
+                    return Some(format!(
+                        "record {}: field {} is {:?}, expected {:?}",
+                        i, k, value, v
+                    ))
+                }
+                None => {
+                    return Some(format!(
+                        "record {}: field {} is missing, expected {:?}",
+                        i, k, v
+                    ))
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Run the input of the case through the pipeline of the config, returning the description of the
+/// mismatch if the case fails.
+fn check_case(
+    config: &Config,
+    case: &Case,
+    grok: &mut PatternCache,
+) -> Result<Option<String>, GropError> {
+    let mut emitter = build_emitter(config, grok)?;
+    // Test runs must not leave any trace.
+    emitter.dedup = None;
+    emitter.partitions = None;
+    emitter.stats = PatternStats::default();
+    emitter.records = Some(Vec::new());
+    let input = Source::Reader(Box::new(Cursor::new(case.input.clone().into_bytes())));
+    process_input(input, &mut io::sink(), config, &mut emitter, grok)?;
+    Ok(compare(
+        &case.expected,
+        &emitter.records.take().unwrap_or_default(),
+    ))
+}
+
+/// The case files: the file itself, or the `.toml` files of the directory.
+fn case_files(path: &Path) -> Result<Vec<PathBuf>, GropError> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.extension().map(|ext| ext == "toml").unwrap_or(false) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Check the config against the test cases, reporting the result of each case to stdout. Returns
+/// the number of failed cases.
+pub fn run_test_config(config: Config, cases: &Path) -> Result<usize, GropError> {
+    let (grok, _) = load_patterns(&config.custom_patterns, config.pattern_library.as_deref())?;
+    let mut grok = PatternCache::new(grok);
+    let (mut passed, mut failed) = (0, 0);
+    for file in case_files(cases)? {
+        let content = fs::read_to_string(&file)?;
+        let case_file: CaseFile = toml::from_str(&content).map_err(|err| {
+            GropError::InvalidArg(format!("invalid case file {}: {}", file.display(), err))
+        })?;
+        for (i, case) in case_file.cases.iter().enumerate() {
+            let label = match &case.name {
+                Some(name) => format!("{}: {}", file.display(), name),
+                None => format!("{}: #{}", file.display(), i),
+            };
+            match check_case(&config, case, &mut grok)? {
+                None => {
+                    passed += 1;
+                    println!("ok    {}", label);
+                }
+                Some(reason) => {
+                    failed += 1;
+                    println!("FAIL  {}: {}", label, reason);
+                }
+            }
+        }
+    }
+    grok.report();
+    println!("\n{} passed, {} failed", passed, failed);
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn case(input: &str, expected: &str) -> Case {
+        let expected = expected
+            .split(';')
+            .filter(|r| !r.is_empty())
+            .map(|r| {
+                r.split(',')
+                    .map(|kv| {
+                        let kv = kv.splitn(2, '=').collect::<Vec<&str>>();
+                        (String::from(kv[0]), String::from(kv[1]))
+                    })
+                    .collect()
+            })
+            .collect();
+        Case {
+            name: None,
+            input: String::from(input),
+            expected,
+        }
+    }
+
+    #[test]
+    fn test_check_case() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '^%{IP:ip} %{LOGLEVEL:lvl}'
+            filters = ["-lvl DEBUG"]
+            "#,
+        )
+        .unwrap();
+        let mut grok = PatternCache::default();
+
+        let c = case(
+            "10.0.0.1 ERROR\n10.0.0.2 DEBUG\ngarbage\n10.0.0.3 INFO\n",
+            "ip=10.0.0.1,lvl=ERROR;ip=10.0.0.3",
+        );
+        assert_eq!(check_case(&config, &c, &mut grok).unwrap(), None);
+
+        let c = case("10.0.0.1 ERROR\n", "ip=10.0.0.1,lvl=INFO");
+        assert_eq!(
+            check_case(&config, &c, &mut grok).unwrap(),
+            Some(String::from(
+                r#"record 0: field lvl is "ERROR", expected "INFO""#
+            ))
+        );
+
+        let c = case("10.0.0.1 ERROR\n", "");
+        assert_eq!(
+            check_case(&config, &c, &mut grok).unwrap(),
+            Some(String::from("expected 0 records, got 1"))
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use crate::GropError;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Progress persisted in the checkpoint file.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct Progress {
+    // Number of input lines (of all the inputs) whose records are all written out.
+    line: u64,
+    // Number of records emitted by then, so that the offset and limit carry over.
+    records: u64,
+}
+
+/// Periodically records how far the input was processed, once everything emitted by then is
+/// flushed, so that a run killed partway through can be resumed from there.
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    interval: Duration,
+    saved: Instant,
+    resumed: Progress,
+}
+
+impl Checkpoint {
+    pub(crate) fn load(path: PathBuf, interval: Duration) -> Result<Checkpoint, GropError> {
+        let resumed = match fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).map_err(|err| {
+                GropError::InvalidArg(format!(
+                    "invalid checkpoint file {}: {}",
+                    path.display(),
+                    err
+                ))
+            })?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Progress::default(),
+            Err(err) => return Err(err.into()),
+        };
+        if resumed.line > 0 {
+            log::info!(
+                "checkpoint: resuming after line {} from {}",
+                resumed.line,
+                path.display()
+            );
+        }
+        Ok(Checkpoint {
+            path,
+            interval,
+            saved: Instant::now(),
+            resumed,
+        })
+    }
+
+    /// Number of input lines processed by the run being resumed, 0 if starting afresh.
+    pub(crate) fn resumed_line(&self) -> u64 {
+        self.resumed.line
+    }
+
+    /// Number of records emitted by the run being resumed.
+    pub(crate) fn resumed_records(&self) -> u64 {
+        self.resumed.records
+    }
+
+    pub(crate) fn due(&self) -> bool {
+        self.saved.elapsed() >= self.interval
+    }
+
+    /// Save the progress, replacing the previous one atomically.
+    pub(crate) fn save(&mut self, line: u64, records: u64) -> Result<(), GropError> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, format!("line = {}\nrecords = {}\n", line, records))?;
+        fs::rename(&temp, &self.path)?;
+        self.saved = Instant::now();
+        Ok(())
+    }
+
+    /// Drop the checkpoint once the run completed, so that the next one starts afresh.
+    pub(crate) fn finish(&self) -> Result<(), GropError> {
+        match fs::remove_file(&self.path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("grop-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("progress");
+
+        let mut checkpoint = Checkpoint::load(path.clone(), Duration::from_secs(0)).unwrap();
+        assert_eq!(checkpoint.resumed_line(), 0);
+        assert!(checkpoint.due());
+        checkpoint.save(120, 80).unwrap();
+
+        let checkpoint = Checkpoint::load(path.clone(), Duration::from_secs(60)).unwrap();
+        assert_eq!(checkpoint.resumed_line(), 120);
+        assert_eq!(checkpoint.resumed_records(), 80);
+        assert!(!checkpoint.due());
+        checkpoint.finish().unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, "line = 'x'").unwrap();
+        assert!(Checkpoint::load(path, Duration::from_secs(0)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
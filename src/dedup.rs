@@ -0,0 +1,411 @@
+use crate::transform::parse_millis;
+use crate::{GropError, INGESTED_AT_FIELD, LINE_NUMBER_FIELD, OFFSET_FIELD};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*, BufWriter};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher`, its output is stable across Rust releases, which
+/// matters because the hashes are persisted between runs.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Fields telling where the line of a record is, which differ even between repeated records.
+const PER_LINE_FIELDS: [&str; 3] = [LINE_NUMBER_FIELD, OFFSET_FIELD, INGESTED_AT_FIELD];
+
+/// Drops output records that were already emitted, either earlier in this run or in a previous
+/// run sharing the same state file. The keys are never expired: the state file is only appended
+/// to, by the keys not in it yet.
+pub(crate) struct Deduplicator {
+    state: PathBuf,
+    seen: HashSet<u64>,
+    added: Vec<u64>,
+}
+
+impl Deduplicator {
+    pub(crate) fn load(state: PathBuf) -> Result<Deduplicator, GropError> {
+        let mut seen = HashSet::new();
+        match fs::read_to_string(&state) {
+            Ok(content) => {
+                for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                    let hash = u64::from_str_radix(line.trim(), 16).map_err(|_| {
+                        GropError::InvalidArg(format!(
+                            "invalid entry {} in dedup state file {}",
+                            line,
+                            state.display()
+                        ))
+                    })?;
+                    seen.insert(hash);
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        log::info!("dedup: loaded {} keys from {}", seen.len(), state.display());
+        Ok(Deduplicator {
+            state,
+            seen,
+            added: Vec::new(),
+        })
+    }
+
+    /// Returns whether the record is seen for the first time, apart from the fields telling where
+    /// its line is.
+    pub(crate) fn check(&mut self, m: &HashMap<String, String>) -> bool {
+        // Hash the fields in a canonical order, the map iteration order differs between runs.
+        let mut fields = m
+            .iter()
+            .filter(|(k, _)| !PER_LINE_FIELDS.contains(&k.as_str()))
+            .collect::<Vec<(&String, &String)>>();
+        fields.sort();
+        let mut key = Vec::new();
+        for (k, v) in fields {
+            key.extend_from_slice(k.as_bytes());
+            key.push(0);
+            key.extend_from_slice(v.as_bytes());
+            key.push(0);
+        }
+        let hash = fnv1a(&key);
+        if self.seen.insert(hash) {
+            self.added.push(hash);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Persist the keys seen in this run.
+    pub(crate) fn finish(&mut self) -> Result<(), GropError> {
+        if self.added.is_empty() {
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.state)?;
+        let mut writer = BufWriter::new(file);
+        for hash in self.added.drain(..) {
+            writeln!(writer, "{:016x}", hash)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Collapses the runs of consecutive identical records into their first record, along with the
+/// number of records in the run. The records are compared on the compared fields (all of them if
+/// not given), apart from the ones telling where their line is.
+pub(crate) struct Squasher {
+    compared: Option<Vec<String>>,
+    run: Option<(HashMap<String, String>, String, u64)>,
+}
+
+impl Squasher {
+    pub(crate) fn new(compared: Option<Vec<String>>) -> Squasher {
+        let compared = compared.map(|fields| {
+            fields
+                .into_iter()
+                .filter(|f| !PER_LINE_FIELDS.contains(&f.as_str()))
+                .collect()
+        });
+        Squasher {
+            compared,
+            run: None,
+        }
+    }
+
+    fn same(&self, a: &HashMap<String, String>, b: &HashMap<String, String>) -> bool {
+        match &self.compared {
+            Some(fields) => fields.iter().all(|f| a.get(f) == b.get(f)),
+            None => {
+                let fields = |m: &HashMap<String, String>| {
+                    m.keys()
+                        .filter(|k| !PER_LINE_FIELDS.contains(&k.as_str()))
+                        .count()
+                };
+                fields(a) == fields(b)
+                    && a.iter()
+                        .filter(|(k, _)| !PER_LINE_FIELDS.contains(&k.as_str()))
+                        .all(|(k, v)| b.get(k) == Some(v))
+            }
+        }
+    }
+
+    /// Add the record (along with its output line) to the current run. Returns the run it ended,
+    /// if it starts a new one.
+    pub(crate) fn push(
+        &mut self,
+        m: HashMap<String, String>,
+        output_line: String,
+    ) -> Option<(HashMap<String, String>, String, u64)> {
+        let repeated = match &self.run {
+            Some((first, _, _)) => self.same(first, &m),
+            None => false,
+        };
+        if repeated {
+            if let Some((_, _, count)) = &mut self.run {
+                *count += 1;
+            }
+            return None;
+        }
+        self.run.replace((m, output_line, 1))
+    }
+
+    /// Take the current run, once there are no more records.
+    pub(crate) fn take(&mut self) -> Option<(HashMap<String, String>, String, u64)> {
+        self.run.take()
+    }
+}
+
+/// Keeps a single copy of each of the field values held across records (e.g. the levels, hosts or
+/// status codes counted), shared by all the places holding it.
+#[derive(Default)]
+pub(crate) struct Interner {
+    values: HashSet<Rc<str>>,
+}
+
+impl Interner {
+    /// The shared copy of the value, stored the first time it comes.
+    pub(crate) fn intern(&mut self, value: &str) -> Rc<str> {
+        if let Some(value) = self.values.get(value) {
+            return Rc::clone(value);
+        }
+        let value = Rc::<str>::from(value);
+        self.values.insert(Rc::clone(&value));
+        value
+    }
+
+    /// Let go of the value, its copy being dropped once it's held nowhere else.
+    pub(crate) fn release(&mut self, value: Rc<str>) {
+        // Held by the interner and by the value released only.
+        if Rc::strong_count(&value) <= 2 {
+            self.values.remove(&value);
+        }
+    }
+}
+
+/// Limits the number of records written out for each value of the field within a time window,
+/// counting the ones suppressed once the limit is reached.
+pub(crate) struct Throttler {
+    field: String,
+    limit: u64,
+    window: Duration,
+    // Number of the records written out and suppressed in the current window of each value.
+    windows: HashMap<Rc<str>, (u64, u64)>,
+    // End of the current windows, in order.
+    ends: VecDeque<(Instant, Rc<str>)>,
+    values: Interner,
+}
+
+impl Throttler {
+    /// Parse a throttle in format `field N/period`, the period being a duration (e.g. `30s`) or a
+    /// unit: `s`, `sec`, `min`, `h` or `hour`.
+    pub(crate) fn parse(s: &str) -> Result<Throttler, GropError> {
+        let invalid = || {
+            GropError::InvalidArg(format!(
+                r#"Invalid throttle {} (should be "field N/period", e.g. "client 10/min")"#,
+                s
+            ))
+        };
+        let (field, rate) = match s.split_whitespace().collect::<Vec<&str>>()[..] {
+            [field, rate] => (field, rate),
+            _ => return Err(invalid()),
+        };
+        let (limit, period) = rate.split_once('/').ok_or_else(invalid)?;
+        let limit = limit.parse::<u64>().map_err(|_| invalid())?;
+        let millis = match period {
+            "s" | "sec" | "second" => 1e3,
+            "min" | "minute" => 60e3,
+            "h" | "hour" => 3600e3,
+            period => parse_millis(period).ok_or_else(invalid)?,
+        };
+        if millis <= 0.0 {
+            return Err(invalid());
+        }
+        Ok(Throttler {
+            field: String::from(field),
+            limit,
+            window: Duration::from_millis(millis as u64),
+            windows: HashMap::new(),
+            ends: VecDeque::new(),
+            values: Interner::default(),
+        })
+    }
+
+    pub(crate) fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Returns whether the record may be written out at the time, along with the values whose
+    /// window ended by then and the number of their records which were suppressed. The records
+    /// without the field are not limited.
+    pub(crate) fn check(
+        &mut self,
+        m: &HashMap<String, String>,
+        now: Instant,
+    ) -> (bool, Vec<(String, u64)>) {
+        let mut ended = Vec::new();
+        while let Some((end, _)) = self.ends.front() {
+            if *end > now {
+                break;
+            }
+            if let Some((_, value)) = self.ends.pop_front() {
+                if let Some((_, suppressed)) = self.windows.remove(&value) {
+                    if suppressed > 0 {
+                        ended.push((value.to_string(), suppressed));
+                    }
+                }
+                self.values.release(value);
+            }
+        }
+        let value = match m.get(&self.field) {
+            Some(value) => value,
+            None => return (true, ended),
+        };
+        let value = self.values.intern(value);
+        if !self.windows.contains_key(&value) {
+            self.ends.push_back((now + self.window, Rc::clone(&value)));
+        }
+        let (written, suppressed) = self.windows.entry(value).or_insert((0, 0));
+        if *written < self.limit {
+            *written += 1;
+            (true, ended)
+        } else {
+            *suppressed += 1;
+            (false, ended)
+        }
+    }
+
+    /// The values whose records were suppressed in their current window, once there are no more
+    /// records.
+    pub(crate) fn finish(&mut self) -> Vec<(String, u64)> {
+        let mut ended = Vec::new();
+        while let Some((_, value)) = self.ends.pop_front() {
+            if let Some((_, suppressed)) = self.windows.remove(&value) {
+                if suppressed > 0 {
+                    ended.push((value.to_string(), suppressed));
+                }
+            }
+            self.values.release(value);
+        }
+        ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn record(data: &str) -> HashMap<String, String> {
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("lvl"), String::from("INFO"));
+        m.insert(String::from("data"), String::from(data));
+        m
+    }
+
+    #[test]
+    fn test_dedup_across_runs() {
+        let state = std::env::temp_dir().join(format!("grop-dedup-{}", std::process::id()));
+        let _ = fs::remove_file(&state);
+
+        let mut d = Deduplicator::load(state.clone()).unwrap();
+        assert!(d.check(&record("foo")));
+        assert!(d.check(&record("bar")));
+        assert!(!d.check(&record("foo")));
+        d.finish().unwrap();
+
+        let mut d = Deduplicator::load(state.clone()).unwrap();
+        assert!(!d.check(&record("bar")));
+        assert!(d.check(&record("baz")));
+        d.finish().unwrap();
+
+        assert_eq!(fs::read_to_string(&state).unwrap().lines().count(), 3);
+        fs::remove_file(&state).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_per_line_fields() {
+        let state = std::env::temp_dir().join(format!("grop-dedup-line-{}", std::process::id()));
+        let _ = fs::remove_file(&state);
+        let mut d = Deduplicator::load(state.clone()).unwrap();
+        let located = |line: &str, offset: &str, at: &str| {
+            let mut m = record("foo");
+            m.insert(String::from(LINE_NUMBER_FIELD), String::from(line));
+            m.insert(String::from(OFFSET_FIELD), String::from(offset));
+            m.insert(String::from(INGESTED_AT_FIELD), String::from(at));
+            m
+        };
+        // The records of lines told apart only by where they are are duplicates.
+        assert!(d.check(&located("1", "0", "2024-01-01T00:00:00Z")));
+        assert!(!d.check(&located("7", "42", "2024-01-01T00:00:01Z")));
+        assert!(!d.check(&record("foo")));
+        assert!(d.check(&record("bar")));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut t = Throttler::parse("lvl 2/min").unwrap();
+        let start = Instant::now();
+        let allowed = (0..4)
+            .map(|_| t.check(&record("x"), start).0)
+            .collect::<Vec<bool>>();
+        assert_eq!(allowed, vec![true, true, false, false]);
+        assert!(t.check(&HashMap::new(), start).0);
+
+        // The suppressed records are reported once the window ends.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            t.check(&record("x"), later),
+            (true, vec![(String::from("INFO"), 2)])
+        );
+        assert!(t.check(&record("x"), later).0);
+        assert!(!t.check(&record("x"), later).0);
+        assert_eq!(t.finish(), vec![(String::from("INFO"), 1)]);
+
+        assert_eq!(Throttler::parse("lvl 5/30s").unwrap().window.as_secs(), 30);
+        assert!(Throttler::parse("lvl 5").is_err());
+        assert!(Throttler::parse("lvl x/min").is_err());
+    }
+
+    #[test]
+    fn test_interner() {
+        let mut values = Interner::default();
+        let error = values.intern("ERROR");
+        let again = values.intern(&String::from("ERROR"));
+        assert!(Rc::ptr_eq(&error, &again));
+        assert_eq!(values.values.len(), 1);
+
+        values.release(again);
+        assert_eq!(values.values.len(), 1);
+        values.release(error);
+        assert!(values.values.is_empty());
+    }
+
+    #[test]
+    fn test_squash() {
+        let mut s = Squasher::new(None);
+        assert!(s.push(record("foo"), String::from("1")).is_none());
+        let mut repeated = record("foo");
+        repeated.insert(String::from(LINE_NUMBER_FIELD), String::from("2"));
+        assert!(s.push(repeated, String::from("2")).is_none());
+        let (m, line, count) = s.push(record("bar"), String::from("3")).unwrap();
+        assert_eq!((m["data"].as_str(), line.as_str(), count), ("foo", "1", 2));
+        assert_eq!(s.take().unwrap().2, 1);
+        assert!(s.take().is_none());
+
+        // Only the compared fields tell the records apart.
+        let mut s = Squasher::new(Some(vec![String::from("lvl")]));
+        assert!(s.push(record("foo"), String::from("1")).is_none());
+        assert!(s.push(record("bar"), String::from("2")).is_none());
+        assert_eq!(s.take().unwrap().2, 2);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::library::read_definitions;
+use crate::{check_config_keys, load_patterns, split_pattern_definition, Config, GropError};
+use crate::{History, PatternLibrary, UPSTREAM_URL};
+use fgrok::patterns;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Outcome of a check of the environment.
+#[derive(Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+type Check = (Status, String);
+
+/// Check the config file: that it can be read and parsed, and that it has no unknown key.
+fn check_config(path: &Path) -> (Vec<Check>, Option<Config>) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            let check = (
+                Status::Fail,
+                format!("config {}: can't be read ({})", path.display(), err),
+            );
+            return (vec![check], None);
+        }
+    };
+    let config = match toml::from_str::<Config>(&content) {
+        Ok(config) => config,
+        Err(err) => {
+            let check = (
+                Status::Fail,
+                format!("config {}: can't be parsed ({})", path.display(), err),
+            );
+            return (vec![check], None);
+        }
+    };
+    let check = match check_config_keys(&content) {
+        Ok(()) => (Status::Ok, format!("config {}", path.display())),
+        Err(err) => (Status::Warn, format!("config {}: {}", path.display(), err)),
+    };
+    (vec![check], Some(config))
+}
+
+/// Check that files can be created in the directory of the path, or in its closest existing
+/// ancestor if the directory doesn't exist yet, without creating any directory.
+fn check_writable(label: &str, path: &Path) -> Check {
+    let mut dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    while !dir.is_dir() {
+        match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => dir = parent.to_path_buf(),
+            _ => dir = PathBuf::from("."),
+        }
+    }
+    let probe = dir.join(format!(".grop-doctor-{}", std::process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            (Status::Ok, format!("{} {}", label, path.display()))
+        }
+        Err(err) => (
+            Status::Fail,
+            format!(
+                "{} {}: {} is not writable ({})",
+                label,
+                path.display(),
+                dir.display(),
+                err
+            ),
+        ),
+    }
+}
+
+/// Check the patterns: the bundled ones, the user library along with the pattern files of the
+/// config, and that all the definitions compile.
+fn check_patterns(config: Option<&Config>) -> Vec<Check> {
+    let mut checks = vec![(
+        Status::Ok,
+        format!("built-in patterns: {} definitions", patterns().len()),
+    )];
+    let version = UPSTREAM_URL
+        .split('/')
+        .find(|s| s.starts_with('v'))
+        .unwrap_or("unknown");
+    let dir = match PatternLibrary::default_dir() {
+        Some(dir) => dir,
+        None => {
+            checks.push((
+                Status::Warn,
+                String::from("pattern library: can't be located, HOME is not set"),
+            ));
+            return checks;
+        }
+    };
+    let library = PatternLibrary::open(dir.clone());
+    checks.push(match library.upstream_installed() {
+        true => (
+            Status::Ok,
+            format!("upstream patterns: logstash-patterns-core {}", version),
+        ),
+        false => (
+            Status::Warn,
+            format!(
+                "upstream patterns: logstash-patterns-core {} is not installed (see `grop patterns update`)",
+                version
+            ),
+        ),
+    });
+    let mut definitions = match library.definitions() {
+        Ok(definitions) => {
+            checks.push((
+                Status::Ok,
+                format!(
+                    "pattern library {}: {} definitions",
+                    dir.display(),
+                    definitions.len()
+                ),
+            ));
+            definitions
+        }
+        Err(err) => {
+            checks.push((
+                Status::Fail,
+                format!("pattern library {}: {}", dir.display(), err),
+            ));
+            return checks;
+        }
+    };
+    if let Some(config) = config {
+        let paths = config
+            .pattern_files
+            .iter()
+            .chain(config.pattern_dirs.iter());
+        for path in paths.flatten() {
+            match read_definitions(path) {
+                Ok(file_definitions) => {
+                    checks.push((
+                        Status::Ok,
+                        format!(
+                            "pattern file {}: {} definitions",
+                            path.display(),
+                            file_definitions.len()
+                        ),
+                    ));
+                    definitions.extend(file_definitions);
+                }
+                Err(err) => checks.push((Status::Fail, format!("pattern file: {}", err))),
+            }
+        }
+        definitions.extend(config.custom_patterns.clone().unwrap_or_default());
+    }
+    // The definitions of the library are among the ones above.
+    let mut grok = match load_patterns(&None, None) {
+        Ok((grok, _)) => grok,
+        Err(err) => {
+            checks.push((Status::Fail, format!("patterns: {}", err)));
+            return checks;
+        }
+    };
+    let mut names = Vec::new();
+    for definition in definitions.iter() {
+        match split_pattern_definition(definition) {
+            Ok((name, pattern)) => {
+                grok.insert_definition(String::from(name), String::from(pattern));
+                names.push(name);
+            }
+            Err(err) => checks.push((Status::Fail, format!("pattern {}: {}", definition, err))),
+        }
+    }
+    for name in names {
+        if let Err(err) = grok.compile(&format!("%{{{}}}", name), false) {
+            checks.push((Status::Fail, format!("pattern {}: {}", name, err)));
+        }
+    }
+    checks
+}
+
+/// Check that the hosts to tail over SSH can be logged in to.
+fn check_ssh(hosts: &[String]) -> Vec<Check> {
+    hosts
+        .iter()
+        .map(|host| {
+            let status = Command::new("ssh")
+                .args([
+                    "-o",
+                    "BatchMode=yes",
+                    "-o",
+                    "ConnectTimeout=5",
+                    host,
+                    "true",
+                ])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            match status {
+                Ok(status) if status.success() => (Status::Ok, format!("ssh host {}", host)),
+                Ok(status) => (
+                    Status::Fail,
+                    format!("ssh host {}: can't be logged in to ({})", host, status),
+                ),
+                Err(err) => (
+                    Status::Fail,
+                    format!("ssh host {}: failed to run ssh ({})", host, err),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Check the environment grop runs in, reporting the result of each check to stdout. Returns the
+/// number of failed checks.
+pub fn run_doctor(config_file: Option<&Path>) -> Result<usize, GropError> {
+    let mut checks = vec![(Status::Ok, format!("grop {}", env!("CARGO_PKG_VERSION")))];
+    let config = match config_file {
+        Some(path) => {
+            let (config_checks, config) = check_config(path);
+            checks.extend(config_checks);
+            config
+        }
+        None => None,
+    };
+    checks.extend(check_patterns(config.as_ref()));
+    if let Some(path) = History::default_path() {
+        checks.push(check_writable("history", &path));
+    }
+    if let Some(config) = &config {
+        if let Some(path) = &config.dedup_state {
+            checks.push(check_writable("dedup state", path));
+        }
+        if let Some(path) = &config.checkpoint {
+            checks.push(check_writable("checkpoint", path));
+        }
+        if let Some(path) = &config.output_path {
+            // Only the directories before the first placeholder are known.
+            let fixed = match path.find('{') {
+                Some(i) => &path[..path[..i].rfind('/').map(|j| j + 1).unwrap_or(0)],
+                None => path.as_str(),
+            };
+            checks.push(check_writable(
+                "output",
+                Path::new(fixed).join("x").as_path(),
+            ));
+        }
+        if let Some(hosts) = &config.ssh {
+            checks.extend(check_ssh(hosts));
+        }
+    }
+
+    let (mut warned, mut failed) = (0, 0);
+    for (status, message) in checks.iter() {
+        let label = match status {
+            Status::Ok => "ok",
+            Status::Warn => {
+                warned += 1;
+                "warn"
+            }
+            Status::Fail => {
+                failed += 1;
+                "FAIL"
+            }
+        };
+        println!("{:<5} {}", label, message);
+    }
+    println!("\n{} warnings, {} failed", warned, failed);
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_check_config() {
+        let dir = std::env::temp_dir().join(format!("grop-doctor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("grop.toml");
+
+        fs::write(&path, "match_expression = '%{INT:n}'\n").unwrap();
+        let (checks, config) = check_config(&path);
+        assert_eq!(checks[0].0, Status::Ok);
+        assert!(config.is_some());
+
+        fs::write(&path, "match_expresion = '%{INT:n}'\n").unwrap();
+        assert_eq!(check_config(&path).0[0].0, Status::Warn);
+
+        fs::write(&path, "match_expression = [\n").unwrap();
+        let (checks, config) = check_config(&path);
+        assert_eq!(checks[0].0, Status::Fail);
+        assert!(config.is_none());
+        assert_eq!(check_config(&dir.join("missing")).0[0].0, Status::Fail);
+
+        // The missing directories are judged by their closest existing ancestor.
+        assert_eq!(
+            check_writable("state", &dir.join("a").join("b").join("state")).0,
+            Status::Ok
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use crate::cache::PatternCache;
+use crate::transform::{Condition, Operator};
+use crate::{split_field_pattern, GropError};
+use fgrok::Pattern;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A filter on a field of the records, in format `field_name pattern` to keep the records whose
+/// field matches the pattern, or `-field_name pattern` to drop them. The last filter matching a
+/// record decides.
+pub(crate) struct FieldFilter {
+    negative: bool,
+    field: String,
+    pattern: Rc<Pattern>,
+}
+
+impl FieldFilter {
+    pub(crate) fn parse(s: &str, grok: &mut PatternCache) -> Result<FieldFilter, GropError> {
+        let (negative, filter) = match s.strip_prefix('-') {
+            Some(filter) => (true, filter),
+            None => (false, s),
+        };
+        let (field, pattern) = split_field_pattern(filter)?;
+        Ok(FieldFilter {
+            negative,
+            field: String::from(field),
+            pattern: grok.compile(pattern, false)?,
+        })
+    }
+
+    /// Whether to keep the record, failing if it lacks the field of a filter.
+    pub(crate) fn keep(
+        filters: &[FieldFilter],
+        m: &HashMap<String, String>,
+    ) -> Result<bool, GropError> {
+        let mut to_keep = true;
+        for filter in filters.iter() {
+            let field_payload = m
+                .get(&filter.field)
+                .ok_or_else(|| GropError::InvalidArg(format!("unknown field: {}", filter.field)))?;
+            if filter.pattern.match_against(field_payload).is_some() {
+                to_keep = !filter.negative;
+            }
+            log::info!(
+                "filter: name: {}, to_keep: {}, content: {}",
+                filter.field,
+                to_keep,
+                field_payload,
+            );
+        }
+        Ok(to_keep)
+    }
+}
+
+/// A boolean expression of conditions on the record fields, e.g.
+/// `(level =~ "ERROR|WARN") && !(msg =~ "healthcheck")`.
+pub(crate) enum Expr {
+    Condition(Condition),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Operator(String),
+    // A field name or a value, quoted or not.
+    Word(String),
+}
+
+/// Split the expression into its tokens. A quoted value may contain `\"` for a quote, the other
+/// backslashes are kept as they are for the patterns.
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(operator) = Operator::ALL.iter().find(|o| rest.starts_with(*o)) {
+            tokens.push(Token::Operator(String::from(*operator)));
+            rest = &rest[operator.len()..];
+        } else if rest.starts_with("&&") {
+            tokens.push(Token::And);
+            rest = &rest[2..];
+        } else if rest.starts_with("||") {
+            tokens.push(Token::Or);
+            rest = &rest[2..];
+        } else if c == '!' {
+            tokens.push(Token::Not);
+            rest = &rest[1..];
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            rest = &rest[1..];
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            rest = &rest[1..];
+        } else if c == '"' {
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) if chars.as_str().starts_with('"') => {
+                        value.push('"');
+                        chars.next();
+                    }
+                    Some((_, c)) => value.push(c),
+                    None => return Err(String::from("unterminated quoted value")),
+                }
+            };
+            tokens.push(Token::Word(value));
+            rest = &rest[end..];
+        } else {
+            let end = rest
+                .char_indices()
+                .find(|(i, c)| {
+                    c.is_whitespace()
+                        || *c == '('
+                        || *c == ')'
+                        || ["&&", "||"]
+                            .iter()
+                            .chain(Operator::ALL.iter())
+                            .any(|o| rest[*i..].starts_with(o))
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(String::from(&rest[..end])));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser of the tokens, `!` binding tighter than `&&`, which binds tighter than
+/// `||`.
+struct Parser<'a> {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+    grok: &'a mut PatternCache,
+}
+
+impl<'a> Parser<'a> {
+    fn or(&mut self) -> Result<Expr, GropError> {
+        let mut expr = self.and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, GropError> {
+        let mut expr = self.unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, GropError> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(invalid("missing closing parenthesis")),
+                }
+            }
+            Some(Token::Word(field)) => {
+                let operator = match self.tokens.next() {
+                    Some(Token::Operator(operator)) => Operator::parse(&operator),
+                    _ => None,
+                }
+                .ok_or_else(|| invalid(&format!("missing operator after {}", field)))?;
+                match self.tokens.next() {
+                    Some(Token::Word(value)) => Ok(Expr::Condition(Condition::new(
+                        &field, operator, &value, self.grok,
+                    )?)),
+                    _ => Err(invalid(&format!("missing value to compare {} to", field))),
+                }
+            }
+            Some(token) => Err(invalid(&format!("unexpected {:?}", token))),
+            None => Err(invalid("unexpected end")),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> GropError {
+    GropError::InvalidArg(format!("invalid where expression: {}", msg))
+}
+
+impl Expr {
+    /// Parse the expression, of conditions in format `field_name (==|!=|=~|!~|<|<=|>|>=) value`
+    /// combined with `&&`, `||`, `!` and parentheses.
+    pub(crate) fn parse(s: &str, grok: &mut PatternCache) -> Result<Expr, GropError> {
+        let tokens = tokenize(s).map_err(|msg| invalid(&msg))?;
+        let mut parser = Parser {
+            tokens: tokens.into_iter().peekable(),
+            grok,
+        };
+        let expr = parser.or()?;
+        match parser.tokens.next() {
+            Some(token) => Err(invalid(&format!("unexpected {:?}", token))),
+            None => Ok(expr),
+        }
+    }
+
+    pub(crate) fn eval(&self, m: &HashMap<String, String>) -> bool {
+        match self {
+            Expr::Condition(condition) => condition.eval(m),
+            Expr::Not(expr) => !expr.eval(m),
+            Expr::And(left, right) => left.eval(m) && right.eval(m),
+            Expr::Or(left, right) => left.eval(m) || right.eval(m),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_where() {
+        let mut grok = PatternCache::default();
+        let record = |level: &str, msg: &str| {
+            let mut m = HashMap::new();
+            m.insert(String::from("level"), String::from(level));
+            m.insert(String::from("msg"), String::from(msg));
+            m
+        };
+        let expr = Expr::parse(
+            r#"(level =~ "ERROR|WARN") && !(msg =~ "healthcheck")"#,
+            &mut grok,
+        )
+        .unwrap();
+        assert!(expr.eval(&record("ERROR", "disk full")));
+        assert!(!expr.eval(&record("ERROR", "GET /healthcheck")));
+        assert!(!expr.eval(&record("INFO", "disk full")));
+
+        // && binds tighter than ||, and the values may be bare.
+        let expr = Expr::parse("level==INFO || level == WARN && msg != x", &mut grok).unwrap();
+        assert!(expr.eval(&record("INFO", "x")));
+        assert!(expr.eval(&record("WARN", "y")));
+        assert!(!expr.eval(&record("WARN", "x")));
+
+        let expr = Expr::parse(r#"msg == "say \"hi\" (now)""#, &mut grok).unwrap();
+        assert!(expr.eval(&record("INFO", r#"say "hi" (now)"#)));
+        // A missing field is never equal.
+        assert!(!Expr::parse("host == a", &mut grok)
+            .unwrap()
+            .eval(&record("INFO", "x")));
+
+        // The values are ordered as numbers, or else as severities.
+        let expr = Expr::parse("level >= warn && msg < 10", &mut grok).unwrap();
+        assert!(expr.eval(&record("ERROR", "9.5")));
+        assert!(expr.eval(&record("Warning", "-1")));
+        assert!(!expr.eval(&record("INFO", "9")));
+        assert!(!expr.eval(&record("ERROR", "10")));
+        assert!(!expr.eval(&record("ERROR", "ten")));
+        assert!(!expr.eval(&record("OOPS", "1")));
+        let expr = Expr::parse("msg<=10||msg>100", &mut grok).unwrap();
+        assert!(expr.eval(&record("INFO", "10")));
+        assert!(expr.eval(&record("INFO", "1e3")));
+        assert!(!expr.eval(&record("INFO", "50")));
+        assert!(!Expr::parse("host > 1", &mut grok)
+            .unwrap()
+            .eval(&record("INFO", "x")));
+
+        for invalid in [
+            "",
+            "level",
+            "level ==",
+            "(level == a",
+            "level == a)",
+            "level == a &&",
+            r#"msg == "open"#,
+            r#"n > "abc""#,
+            "n <= x",
+        ] {
+            assert!(Expr::parse(invalid, &mut grok).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,746 @@
+use crate::cache::PatternCache;
+use crate::timestamp::parse_time_bound;
+use crate::{capture_field, load_patterns, Config, GropError};
+use chrono::{DateTime, Duration, Utc};
+use fgrok::Pattern;
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of attempts at generating a line matching its expression before giving up.
+const ATTEMPTS: usize = 100;
+
+/// Depth of the patterns referring to each other beyond which they are taken as recursive.
+const MAX_DEPTH: usize = 32;
+
+/// Most repetitions of `*`, `+` and open ranges beyond their minimum.
+const MAX_REPEAT: u32 = 8;
+
+const ALNUM: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+// Faker-style values, the frequent ones repeated to weigh them.
+const LEVELS: &[&str] = &[
+    "INFO", "INFO", "INFO", "INFO", "DEBUG", "DEBUG", "WARN", "ERROR",
+];
+const STATUSES: &[&str] = &[
+    "200", "200", "200", "200", "200", "200", "201", "204", "301", "304", "400", "401", "403",
+    "404", "404", "500", "502", "503",
+];
+const METHODS: &[&str] = &[
+    "GET", "GET", "GET", "GET", "POST", "POST", "PUT", "DELETE", "HEAD",
+];
+const PATHS: &[&str] = &[
+    "/",
+    "/index.html",
+    "/health",
+    "/login",
+    "/static/app.js",
+    "/static/style.css",
+    "/api/users/{n}",
+    "/api/orders/{n}",
+    "/api/search?q={word}",
+];
+const HTTP_VERSIONS: &[&str] = &["1.1", "1.1", "1.1", "2.0", "1.0"];
+const AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_2) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+    "curl/8.4.0",
+    "Go-http-client/1.1",
+    "kube-probe/1.28",
+];
+const REFERRERS: &[&str] = &[
+    "-",
+    "-",
+    "https://www.example.com/",
+    "https://www.google.com/",
+];
+const USERS: &[&str] = &["-", "-", "-", "alice", "bob", "carol"];
+const HOSTS: &[&str] = &["web-1", "web-2", "api-1", "db-1", "cache-1"];
+const PROGRAMS: &[&str] = &["sshd", "cron", "systemd", "kernel", "nginx", "dockerd"];
+const THREADS: &[&str] = &[
+    "main",
+    "worker-{n}",
+    "http-nio-8080-exec-{n}",
+    "scheduler-{n}",
+];
+const LOGGERS: &[&str] = &[
+    "com.example.api.UserController",
+    "com.example.service.OrderService",
+    "com.example.db.ConnectionPool",
+    "org.springframework.web.servlet.DispatcherServlet",
+];
+const WORDS: &[&str] = &[
+    "alpha", "beta", "cache", "disk", "user", "order", "session", "token", "queue", "job",
+];
+const MESSAGES: &[&str] = &[
+    "user {n} logged in",
+    "request completed in {n}ms",
+    "cache miss for key {word}",
+    "connection reset by peer",
+    "retrying job {n} after timeout",
+    "order {n} created",
+    "disk usage at {n}%",
+    "session {word} expired",
+];
+
+/// Pseudo random numbers (xorshift64*), the same ones for the same seed.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        // The state must not be zero.
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+}
+
+/// An element of a grok expression, as far as generating a text matching it goes.
+enum Node {
+    Literal(char),
+    // The characters a class (or an escape like `\d`) may be rendered as.
+    Class(Vec<char>),
+    Reference {
+        name: String,
+        field: Option<String>,
+        definition: Option<String>,
+    },
+    // Anchors and lookarounds are rendered as an empty alternation.
+    Alternation(Vec<Vec<Node>>),
+    Repeat(Box<Node>, u32, u32),
+}
+
+fn empty() -> Node {
+    Node::Alternation(vec![Vec::new()])
+}
+
+fn class(chars: &str) -> Node {
+    Node::Class(chars.chars().collect())
+}
+
+/// The characters of the class escape (e.g. `d` for `\d`), if it is one.
+fn escape_class(c: char) -> Option<&'static str> {
+    match c {
+        'd' => Some("0123456789"),
+        'w' => Some("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_"),
+        's' => Some(" "),
+        'S' | 'W' => Some(ALNUM),
+        'D' => Some("abcdefghijklmnopqrstuvwxyz"),
+        'h' => Some("0123456789abcdef"),
+        _ => None,
+    }
+}
+
+/// Parser of the grok expressions, i.e. of the regexes with `%{NAME:field}` references.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn parse(s: &str) -> Result<Vec<Vec<Node>>, String> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        let alternation = parser.alternation()?;
+        match parser.peek() {
+            Some(c) => Err(format!("unexpected {} at {}", c, parser.pos)),
+            None => Ok(alternation),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        let matches = s
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if matches {
+            self.pos += s.chars().count();
+        }
+        matches
+    }
+
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut branches = vec![self.sequence()?];
+        while self.eat("|") {
+            branches.push(self.sequence()?);
+        }
+        Ok(branches)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom));
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        if self.eat("%{") {
+            let end = self.chars[self.pos..]
+                .iter()
+                .position(|c| *c == '}')
+                .ok_or("unterminated pattern reference")?;
+            let reference = self.chars[self.pos..self.pos + end]
+                .iter()
+                .collect::<String>();
+            self.pos += end + 1;
+            let (spec, definition) = match reference.split_once('=') {
+                Some((spec, definition)) => (spec, Some(String::from(definition))),
+                None => (reference.as_str(), None),
+            };
+            let mut parts = spec.splitn(3, ':');
+            return Ok(Node::Reference {
+                name: String::from(parts.next().unwrap_or_default()),
+                field: parts.next().map(String::from),
+                definition,
+            });
+        }
+        match self.next() {
+            Some('(') => self.group(),
+            Some('[') => self.class(),
+            Some('\\') => Ok(self.escape()),
+            Some('.') => Ok(class(ALNUM)),
+            Some('^') | Some('$') => Ok(empty()),
+            Some(c) => Ok(Node::Literal(c)),
+            None => Err(String::from("unexpected end")),
+        }
+    }
+
+    fn group(&mut self) -> Result<Node, String> {
+        let mut lookaround = false;
+        if self.eat("?#") {
+            while self.next().ok_or("unterminated comment")? != ')' {}
+            return Ok(empty());
+        } else if self.eat("?=") || self.eat("?!") || self.eat("?<=") || self.eat("?<!") {
+            lookaround = true;
+        } else if self.eat("?<") || self.eat("?P<") || self.eat("?'") {
+            // Rendered like a reference to its regex, so that its field gets a realistic value.
+            let mut name = String::new();
+            loop {
+                match self.next().ok_or("unterminated group name")? {
+                    '>' | '\'' => break,
+                    c => name.push(c),
+                }
+            }
+            let start = self.pos;
+            self.alternation()?;
+            if !self.eat(")") {
+                return Err(String::from("missing closing parenthesis"));
+            }
+            let field = String::from(capture_field(&name));
+            return Ok(Node::Reference {
+                name: field.clone(),
+                field: Some(field),
+                definition: Some(self.chars[start..self.pos - 1].iter().collect()),
+            });
+        } else if self.eat("?") {
+            // Non-capturing and atomic groups, and the flags, alone or for the group.
+            while let Some(c) = self.next() {
+                match c {
+                    ':' | '>' => break,
+                    ')' => return Ok(empty()),
+                    _ => (),
+                }
+            }
+        }
+        let alternation = self.alternation()?;
+        if !self.eat(")") {
+            return Err(String::from("missing closing parenthesis"));
+        }
+        match lookaround {
+            true => Ok(empty()),
+            false => Ok(Node::Alternation(alternation)),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat("^");
+        let mut chars = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or("unterminated character class")?;
+            let c = match c {
+                ']' if !first => break,
+                '[' if self.eat(":") => {
+                    let end = self.chars[self.pos..]
+                        .iter()
+                        .position(|c| *c == ':')
+                        .ok_or("unterminated POSIX class")?;
+                    let name = self.chars[self.pos..self.pos + end]
+                        .iter()
+                        .collect::<String>();
+                    self.pos += end;
+                    self.eat(":]");
+                    chars.extend(
+                        match name.as_str() {
+                            "digit" => "0123456789",
+                            "space" | "blank" => " ",
+                            "upper" => "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+                            "lower" | "alpha" => "abcdefghijklmnopqrstuvwxyz",
+                            "xdigit" => "0123456789abcdef",
+                            _ => ALNUM,
+                        }
+                        .chars(),
+                    );
+                    first = false;
+                    continue;
+                }
+                '\\' => {
+                    let escaped = self.next().ok_or("unterminated escape")?;
+                    if let Some(class) = escape_class(escaped) {
+                        chars.extend(class.chars());
+                        first = false;
+                        continue;
+                    }
+                    unescape(escaped)
+                }
+                c => c,
+            };
+            first = false;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let end = match self.next().ok_or("unterminated character class")? {
+                    '\\' => unescape(self.next().ok_or("unterminated escape")?),
+                    end => end,
+                };
+                // Only the ASCII part of the wide ranges.
+                chars.extend((c..=end.min('~').max(c)).take(128));
+            } else {
+                chars.push(c);
+            }
+        }
+        if negated {
+            chars = format!("{}.-_/", ALNUM)
+                .chars()
+                .filter(|c| !chars.contains(c))
+                .collect();
+        }
+        if chars.is_empty() {
+            chars.push('x');
+        }
+        Ok(Node::Class(chars))
+    }
+
+    fn escape(&mut self) -> Node {
+        match self.next() {
+            Some(c) if escape_class(c).is_some() => class(escape_class(c).unwrap_or_default()),
+            Some('b') | Some('B') | Some('A') | Some('z') | Some('Z') | Some('G') => empty(),
+            Some('x') => {
+                let hex = self.chars[self.pos..]
+                    .iter()
+                    .take(2)
+                    .take_while(|c| c.is_ascii_hexdigit())
+                    .collect::<String>();
+                self.pos += hex.len();
+                match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    Some(c) => Node::Literal(c),
+                    None => Node::Literal('x'),
+                }
+            }
+            Some(c) => Node::Literal(unescape(c)),
+            None => Node::Literal('\\'),
+        }
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Node {
+        let (min, max) = if self.eat("?") {
+            (0, 1)
+        } else if self.eat("*") {
+            (0, u32::MAX)
+        } else if self.eat("+") {
+            (1, u32::MAX)
+        } else if self.peek() == Some('{') {
+            let end = match self.chars[self.pos..].iter().position(|c| *c == '}') {
+                Some(end) => end,
+                None => return atom,
+            };
+            let bounds = self.chars[self.pos + 1..self.pos + end]
+                .iter()
+                .collect::<String>();
+            let (min, max) = match bounds.split_once(',') {
+                Some((min, "")) => (min.parse::<u32>().ok(), Some(u32::MAX)),
+                Some((min, max)) => (min.parse::<u32>().ok(), max.parse::<u32>().ok()),
+                None => (bounds.parse::<u32>().ok(), bounds.parse::<u32>().ok()),
+            };
+            match (min, max) {
+                (Some(min), Some(max)) => {
+                    self.pos += end + 1;
+                    (min, max)
+                }
+                // A literal brace.
+                _ => return atom,
+            }
+        } else {
+            return atom;
+        };
+        // Lazy and possessive quantifiers.
+        if !self.eat("?") {
+            self.eat("+");
+        }
+        Node::Repeat(Box::new(atom), min, max)
+    }
+}
+
+fn unescape(c: char) -> char {
+    match c {
+        't' => '\t',
+        'n' => '\n',
+        'r' => '\r',
+        c => c,
+    }
+}
+
+/// A match expression along with its compiled pattern, which the lines generated from it are
+/// checked against.
+struct Expression {
+    nodes: Rc<Vec<Vec<Node>>>,
+    pattern: Rc<Pattern>,
+}
+
+/// Generator of lines matching the expressions, with faker-style values for the well-known
+/// patterns and fields, and the other parts rendered from the definitions of the patterns.
+struct Generator {
+    grok: PatternCache,
+    pattern_map: HashMap<String, String>,
+    rng: Rng,
+    // Time of the line being generated, which goes forward from line to line.
+    time: DateTime<Utc>,
+    parsed: HashMap<String, Rc<Vec<Vec<Node>>>>,
+}
+
+impl Generator {
+    fn parse(&mut self, name: &str, definition: &str) -> Result<Rc<Vec<Vec<Node>>>, GropError> {
+        if let Some(nodes) = self.parsed.get(definition) {
+            return Ok(nodes.clone());
+        }
+        let nodes = Rc::new(Parser::parse(definition).map_err(|err| {
+            GropError::InvalidArg(format!("can't generate lines of {}: {}", name, err))
+        })?);
+        self.parsed.insert(String::from(definition), nodes.clone());
+        Ok(nodes)
+    }
+
+    fn line(&mut self, expression: &Expression) -> Result<Option<String>, GropError> {
+        for _ in 0..ATTEMPTS {
+            let mut line = String::new();
+            self.render_alternation(&expression.nodes, 0, &mut line)?;
+            if expression.pattern.match_against(&line).is_some() {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+
+    fn render_alternation(
+        &mut self,
+        alternation: &[Vec<Node>],
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), GropError> {
+        // The first branch, usually the most common form.
+        for node in alternation[0].iter() {
+            self.render(node, depth, out)?;
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, node: &Node, depth: usize, out: &mut String) -> Result<(), GropError> {
+        match node {
+            Node::Literal(c) => out.push(*c),
+            Node::Class(chars) => out.push(*self.rng.pick(chars)),
+            Node::Alternation(alternation) => self.render_alternation(alternation, depth, out)?,
+            Node::Repeat(node, min, max) => {
+                let count = match node.as_ref() {
+                    // A single space for the runs of whitespaces.
+                    Node::Class(chars) if chars.iter().all(|c| *c == ' ') => {
+                        (*min).max(1).min(*max)
+                    }
+                    _ => min + self.rng.below((max - min).min(MAX_REPEAT) as u64 + 1) as u32,
+                };
+                for _ in 0..count {
+                    self.render(node, depth, out)?;
+                }
+            }
+            Node::Reference {
+                name,
+                field,
+                definition,
+            } => {
+                self.render_reference(name, field.as_deref(), definition.as_deref(), depth, out)?
+            }
+        }
+        Ok(())
+    }
+
+    fn render_reference(
+        &mut self,
+        name: &str,
+        field: Option<&str>,
+        definition: Option<&str>,
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), GropError> {
+        if depth > MAX_DEPTH {
+            return Err(GropError::InvalidArg(format!(
+                "can't generate lines of {}: the patterns refer to each other",
+                name
+            )));
+        }
+        let definition = match definition {
+            Some(definition) => String::from(definition),
+            None => self
+                .pattern_map
+                .get(name)
+                .cloned()
+                .ok_or_else(|| GropError::InvalidArg(format!("unknown pattern {}", name)))?,
+        };
+        if let Some(value) = self.fake(name, field) {
+            // The value must match the reference as a whole.
+            let pattern = self.grok.compile(&format!("^(?:{})$", definition), false);
+            if pattern.is_ok_and(|p| p.match_against(&value).is_some()) {
+                out.push_str(&value);
+                return Ok(());
+            }
+        }
+        let nodes = self.parse(name, &definition)?;
+        self.render_alternation(&nodes, depth + 1, out)
+    }
+
+    /// Expand the `{n}` and `{word}` placeholders of the value.
+    fn expand(&mut self, value: &str) -> String {
+        let value = value.replace("{n}", &(1 + self.rng.below(9999)).to_string());
+        let word = *self.rng.pick(WORDS);
+        value.replace("{word}", word)
+    }
+
+    /// A faker-style value of the field or of the pattern, if it's a well-known one.
+    fn fake(&mut self, name: &str, field: Option<&str>) -> Option<String> {
+        let value = match name {
+            "HTTPDATE" => return Some(self.time.format("%d/%b/%Y:%H:%M:%S +0000").to_string()),
+            "TIMESTAMP_ISO8601" => {
+                return Some(self.time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+            }
+            "SYSLOGTIMESTAMP" => return Some(self.time.format("%b %e %H:%M:%S").to_string()),
+            "QS" | "QUOTEDSTRING" => {
+                let value = match self.fake("", field) {
+                    Some(value) => value,
+                    None => {
+                        let message = *self.rng.pick(MESSAGES);
+                        self.expand(message)
+                    }
+                };
+                return Some(format!("\"{}\"", value));
+            }
+            _ => match field {
+                Some("status") | Some("status_code") | Some("response") => STATUSES,
+                Some("method") | Some("verb") => METHODS,
+                Some("path") | Some("uri") | Some("url") | Some("request_path") => PATHS,
+                Some("http_version") | Some("httpversion") => HTTP_VERSIONS,
+                Some("agent") | Some("user_agent") | Some("useragent") => AGENTS,
+                Some("referrer") | Some("referer") => REFERRERS,
+                Some("user") | Some("ident") | Some("username") => USERS,
+                Some("host") | Some("hostname") => HOSTS,
+                Some("program") | Some("prog") => PROGRAMS,
+                Some("thread") => THREADS,
+                Some("logger") | Some("class") => LOGGERS,
+                Some("msg") | Some("message") => MESSAGES,
+                Some("bytes") | Some("size") | Some("body_bytes_sent") => {
+                    return Some(self.rng.below(50000).to_string())
+                }
+                _ => match name {
+                    "IP" | "IPV4" | "IPORHOST" => {
+                        return Some(format!(
+                            "{}.{}.{}.{}",
+                            1 + self.rng.below(223),
+                            self.rng.below(256),
+                            self.rng.below(256),
+                            1 + self.rng.below(254)
+                        ))
+                    }
+                    "NUMBER" | "INT" | "BASE10NUM" | "POSINT" | "NONNEGINT" => {
+                        return Some((1 + self.rng.below(9999)).to_string())
+                    }
+                    "UUID" => {
+                        let hex = (0..32)
+                            .map(|_| format!("{:x}", self.rng.below(16)))
+                            .collect::<String>();
+                        return Some(format!(
+                            "{}-{}-{}-{}-{}",
+                            &hex[..8],
+                            &hex[8..12],
+                            &hex[12..16],
+                            &hex[16..20],
+                            &hex[20..]
+                        ));
+                    }
+                    "LOGLEVEL" => LEVELS,
+                    "HOSTNAME" | "SYSLOGHOST" | "HOST" => HOSTS,
+                    "PROG" => PROGRAMS,
+                    "USERNAME" | "USER" | "HTTPDUSER" => USERS,
+                    "URIPATH" | "URIPATHPARAM" => PATHS,
+                    "WORD" => WORDS,
+                    "DATA" | "GREEDYDATA" => MESSAGES,
+                    _ => return None,
+                },
+            },
+        };
+        let value = *self.rng.pick(value);
+        Some(self.expand(value))
+    }
+}
+
+/// Write the lines generated from the expressions of the config (one of them picked at random for
+/// each line), their timestamps going forward from the start by up to 2 seconds per line.
+pub(crate) fn generate(
+    config: Config,
+    lines: u64,
+    seed: u64,
+    start: DateTime<Utc>,
+    output: &mut dyn Write,
+) -> Result<(), GropError> {
+    let config = match config.preset {
+        Some(preset) => preset.apply(config),
+        None => config,
+    };
+    let (grok, pattern_map) =
+        load_patterns(&config.custom_patterns, config.pattern_library.as_deref())?;
+    let mut generator = Generator {
+        grok: PatternCache::new(grok),
+        pattern_map,
+        rng: Rng::new(seed),
+        time: start,
+        parsed: HashMap::new(),
+    };
+    let mut expressions = Vec::new();
+    for text in config.expressions().iter() {
+        expressions.push(Expression {
+            nodes: generator.parse("the expression", text)?,
+            pattern: generator.grok.compile(text, false)?,
+        });
+    }
+    for _ in 0..lines {
+        let expression = &expressions[generator.rng.below(expressions.len() as u64) as usize];
+        generator.time += Duration::milliseconds(generator.rng.below(2000) as i64);
+        let line = generator.line(expression)?.ok_or_else(|| {
+            GropError::InvalidArg(String::from(
+                "failed to generate a line matching the expression",
+            ))
+        })?;
+        writeln!(output, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Print the synthetic lines, e.g. to benchmark a pipeline or to write tests without real logs.
+/// The start is a timestamp or an age like `1d` back from now, a second per line back from now by
+/// default.
+pub fn run_generate(
+    config: Config,
+    lines: u64,
+    seed: Option<u64>,
+    start: Option<&str>,
+) -> Result<(), GropError> {
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+    });
+    let start = match start {
+        Some(start) => parse_time_bound(start)?,
+        None => Utc::now() - Duration::seconds(lines as i64),
+    };
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+    generate(config, lines, seed, start, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Preset;
+
+    #[test]
+    fn test_generate() {
+        let start = parse_time_bound("2024-01-01").unwrap();
+        let generated = |config: Config, seed: u64| {
+            let mut output = Vec::new();
+            generate(config, 50, seed, start, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        for preset in [
+            Preset::Nginx,
+            Preset::ApacheCombined,
+            Preset::Syslog,
+            Preset::Java,
+        ] {
+            let config = || Config {
+                preset: Some(preset),
+                ..toml::from_str("").unwrap()
+            };
+            let lines = generated(config(), 1);
+            assert_eq!(lines.lines().count(), 50, "{:?}", preset);
+            // The same lines for the same seed.
+            assert_eq!(generated(config(), 1), lines);
+            assert_ne!(generated(config(), 2), lines);
+        }
+
+        let config: Config = toml::from_str(
+            r#"
+            custom_patterns = ['CODE [A-Z]{3}-\d{2,4}']
+            match_expressions = ['^%{CODE:code} (?:ok|failed)(?<!x) \[%{INT:n}\]$', '%{UUID:id}']
+            "#,
+        )
+        .unwrap();
+        let lines = generated(config, 3);
+        let (mut grok, _) =
+            load_patterns(&Some(vec![String::from("CODE [A-Z]{3}-\\d{2,4}")]), None).unwrap();
+        let code = grok
+            .compile("^[A-Z]{3}-[0-9]{2,4} ok \\[[0-9]+\\]$|^%{UUID}$", false)
+            .unwrap();
+        for line in lines.lines() {
+            assert!(code.match_against(line).is_some(), "{}", line);
+        }
+
+        let config: Config = toml::from_str("match_expression = '%{NOPE:x}'").unwrap();
+        assert!(generate(config, 1, 1, start, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Config, GropError};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Number of queries kept in the history file.
+const MAX_ENTRIES: usize = 100;
+
+/// A previously run query.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub match_expression: Option<String>,
+    pub filters: Option<Vec<String>>,
+    pub output_format: Option<String>,
+}
+
+impl From<&Config> for HistoryEntry {
+    fn from(config: &Config) -> HistoryEntry {
+        HistoryEntry {
+            match_expression: config.match_expression.clone(),
+            filters: config.filters.clone(),
+            output_format: config.output_format.clone(),
+        }
+    }
+}
+
+impl HistoryEntry {
+    /// Fill in the settings not explicitly set in `config` from this entry.
+    pub fn apply(self, config: Config) -> Config {
+        Config {
+            match_expression: config.match_expression.or(self.match_expression),
+            filters: config.filters.or(self.filters),
+            output_format: config.output_format.or(self.output_format),
+            ..config
+        }
+    }
+}
+
+/// Quote `s` for the shell, so that the listed entries can be copied to the command line.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut args = Vec::new();
+        if let Some(expression) = &self.match_expression {
+            args.push(format!("-e {}", quote(expression)));
+        }
+        if let Some(filters) = &self.filters {
+            for filter in filters.iter() {
+                args.push(format!("--filter {}", quote(filter)));
+            }
+        }
+        if let Some(output_format) = &self.output_format {
+            args.push(format!("-o {}", quote(output_format)));
+        }
+        write!(f, "{}", args.join(" "))
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct HistoryFile {
+    #[serde(default)]
+    entries: Vec<HistoryEntry>,
+}
+
+/// The recently run queries, persisted in a small state file.
+pub struct History {
+    path: PathBuf,
+    // Oldest first.
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /// The default history file: `$XDG_STATE_HOME/grop/history.toml`, falling back to
+    /// `~/.local/state/grop/history.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        match env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")),
+        }
+        .map(|dir| dir.join("grop").join("history.toml"))
+    }
+
+    pub fn load(path: PathBuf) -> Result<History, GropError> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => {
+                toml::from_str::<HistoryFile>(&content)
+                    .map_err(|err| {
+                        GropError::InvalidArg(format!(
+                            "invalid history file {}: {}",
+                            path.display(),
+                            err
+                        ))
+                    })?
+                    .entries
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(History { path, entries })
+    }
+
+    /// Entries along with their index, the most recent one first.
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &HistoryEntry)> {
+        self.entries
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, e)| (i + 1, e))
+    }
+
+    /// Get the entry by its index, 1 being the most recent one.
+    pub fn get(&self, index: usize) -> Result<&HistoryEntry, GropError> {
+        match index {
+            0 => None,
+            _ => self.entries.iter().rev().nth(index - 1),
+        }
+        .ok_or_else(|| GropError::InvalidArg(format!("no history entry {}", index)))
+    }
+
+    /// Remember the entry as the most recent one and persist the history.
+    pub fn record(&mut self, entry: HistoryEntry) -> Result<(), GropError> {
+        self.entries.retain(|e| *e != entry);
+        self.entries.push(entry);
+        if self.entries.len() > MAX_ENTRIES {
+            let n = self.entries.len() - MAX_ENTRIES;
+            self.entries.drain(..n);
+        }
+
+        let content = toml::to_string(&HistoryFile {
+            entries: self.entries.clone(),
+        })
+        .map_err(|err| GropError::InvalidArg(err.to_string()))?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn entry(expression: &str) -> HistoryEntry {
+        HistoryEntry {
+            match_expression: Some(String::from(expression)),
+            filters: Some(vec![String::from("lvl ERROR")]),
+            output_format: None,
+        }
+    }
+
+    #[test]
+    fn test_history_record() {
+        let path = env::temp_dir().join(format!("grop-history-{}.toml", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut h = History::load(path.clone()).unwrap();
+        h.record(entry("%{WORD:a}")).unwrap();
+        h.record(entry("%{INT:b}")).unwrap();
+        h.record(entry("%{WORD:a}")).unwrap();
+
+        let h = History::load(path.clone()).unwrap();
+        assert_eq!(h.entries().count(), 2);
+        assert_eq!(h.get(1).unwrap(), &entry("%{WORD:a}"));
+        assert_eq!(h.get(2).unwrap(), &entry("%{INT:b}"));
+        assert!(h.get(0).is_err());
+        assert!(h.get(3).is_err());
+        assert_eq!(
+            h.get(1).unwrap().to_string(),
+            "-e '%{WORD:a}' --filter 'lvl ERROR'"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
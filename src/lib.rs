@@ -1,69 +1,706 @@
+mod action;
+mod cache;
+mod cases;
+mod checkpoint;
+mod dedup;
+mod doctor;
+mod filter;
+mod generate;
+mod history;
+mod library;
+mod lint;
+mod output;
+mod preset;
+mod source;
+mod stats;
+mod strict;
+mod timestamp;
+mod transform;
+mod wizard;
+
+use action::Summaries;
+pub use action::{Actions, CompleteAction};
+use cache::PatternCache;
+pub use cases::run_test_config;
+use checkpoint::Checkpoint;
+use chrono::{DateTime, Utc};
+use dedup::{Deduplicator, Squasher, Throttler};
+pub use doctor::run_doctor;
 use fgrok::{patterns, Grok, Matches, Pattern};
-use log;
+use filter::{Expr, FieldFilter};
+pub use generate::run_generate;
+pub use history::{History, HistoryEntry};
+use library::read_definitions;
+pub use library::{PatternLibrary, UPSTREAM_SHA256, UPSTREAM_URL};
+pub use lint::{lint, run_lint, Finding, LintFormat};
+use output::{
+    assert_output, encode_binary, highlight_block, paint, ColorMap, Context, FieldType,
+    PartitionedWriter,
+};
+pub use output::{ColorMode, NumberLocale, OutputMode, Unmatched};
+pub use preset::Preset;
 use serde::Deserialize;
+use source::{
+    decompress, map_file, open_at, open_input, read_lines, transcode_utf16, Line, MappedLines,
+    Reverse, Source,
+};
+pub use source::{Compression, SOURCES};
+use stats::{Classes, Examples, PatternStats, Warnings};
+use std::borrow::Cow;
 use std::char;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error;
 use std::error::Error;
 use std::fmt;
-use std::fs::File;
-use std::io::{self, prelude::*, BufReader};
-use std::path::PathBuf;
+use std::io::{self, prelude::*, IsTerminal};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+pub use strict::check_config_keys;
+use timestamp::{format_timestamp, parse_time_bound, record_time, DisplayZone};
+use transform::{CarryForward, Condition, Delta, MovingAverage, Transform};
+pub use wizard::run_wizard;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(skip)]
-    pub input: Option<PathBuf>,
+    pub input: Vec<PathBuf>,
+    pub preset: Option<Preset>,
+    pub ssh: Option<Vec<String>>,
+    pub remote_path: Option<String>,
+    #[serde(default)]
+    pub retry_open: bool,
+    #[serde(default)]
+    pub mmap: bool,
+    #[serde(default)]
+    pub reverse: bool,
+    #[serde(default)]
+    pub with_filename: bool,
+    #[serde(default)]
+    pub line_number: bool,
+    #[serde(default)]
+    pub follow: bool,
+    pub tail: Option<usize>,
+    pub skip: Option<u64>,
+    pub take: Option<u64>,
+    pub from_byte: Option<u64>,
+    pub to_byte: Option<u64>,
+    pub compression: Option<Compression>,
     pub custom_patterns: Option<Vec<String>>,
+    pub pattern_files: Option<Vec<PathBuf>>,
+    pub pattern_dirs: Option<Vec<PathBuf>>,
+    /// Directory of the user's pattern library, see `PatternLibrary::default_dir`.
+    #[serde(skip)]
+    pub pattern_library: Option<PathBuf>,
     #[serde(skip)]
     pub list_pattern: Option<Option<String>>,
+    #[serde(skip)]
+    pub check_expression: bool,
     pub match_expression: Option<String>,
+    pub match_expressions: Option<Vec<String>>,
+    pub expression_names: Option<Vec<String>>,
+    pub expression_file: Option<PathBuf>,
     pub filters: Option<Vec<String>>,
+    pub filter_file: Option<PathBuf>,
+    #[serde(rename = "where")]
+    pub where_expression: Option<String>,
+    #[serde(default)]
+    pub invert_match: bool,
+    pub on_unmatched: Option<Unmatched>,
     pub output_format: Option<String>,
+    pub display_tz: Option<String>,
+    pub time_field: Option<String>,
+    pub time_format: Option<String>,
+    pub fresher_than: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub fields: Option<Vec<String>>,
+    pub output_path: Option<String>,
+    pub tee: Option<Vec<Tee>>,
+    pub output_mode: Option<OutputMode>,
+    pub color: Option<ColorMode>,
+    pub color_by: Option<String>,
+    pub colors: Option<HashMap<String, String>>,
+    pub aliases: Option<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    pub output_atomic: bool,
+    pub dedup_state: Option<PathBuf>,
+    #[serde(default)]
+    pub squash_repeats: bool,
+    pub squash_key: Option<String>,
+    pub throttle: Option<String>,
+    pub before_context: Option<usize>,
+    pub after_context: Option<usize>,
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_interval: Option<String>,
+    pub assert_output: Option<PathBuf>,
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub pattern_stats: bool,
+    pub examples: Option<usize>,
+    #[serde(default)]
+    pub summary_only: bool,
+    #[serde(default)]
+    pub classify: bool,
+    #[serde(default)]
+    pub count: bool,
+    #[serde(default)]
+    pub base64_binary: bool,
+    #[serde(default)]
+    pub provenance: bool,
+    pub exit_on: Option<String>,
+    pub exit_code: Option<i32>,
+    pub timeout: Option<String>,
+    pub heartbeat: Option<String>,
+    #[serde(default)]
+    pub lag: bool,
+    #[serde(default)]
+    pub hardened: bool,
+    pub max_line_length: Option<usize>,
+    pub max_merge_lines: Option<usize>,
+    pub jobs: Option<usize>,
+    pub batch_lines: Option<usize>,
+    #[serde(default)]
+    pub auto_tune: bool,
+    #[serde(default)]
+    pub group_by_file: bool,
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+    pub page: Option<String>,
+    pub fail_on_warn: Option<u64>,
+    pub min_match_rate: Option<f64>,
+    pub match_rate_lines: Option<u64>,
+    #[serde(default)]
+    pub match_rate_warn: bool,
+    pub conditional_tags: Option<Vec<ConditionalTag>>,
+    pub set: Option<Vec<String>>,
+    pub unset: Option<Vec<String>>,
+    pub transforms: Option<Vec<String>>,
+    pub carry_forward: Option<Vec<String>>,
+    pub deltas: Option<Vec<String>>,
+    pub moving_averages: Option<Vec<String>>,
+    pub number_locale: Option<NumberLocale>,
     pub merge_config: Option<MergeConfig>,
+    pub merge_configs: Option<HashMap<String, MergeConfig>>,
+    pub actions: Option<Actions>,
 }
 
 impl Config {
     pub fn merge(self, config: Config) -> Config {
+        // The expressions, given inline or in a file, override each other.
+        let has_expression = config.match_expression.is_some()
+            || config.match_expressions.is_some()
+            || config.expression_file.is_some();
+        // So do the output format and the fields, which are a shorthand of one.
+        let has_output = config.output_format.is_some() || config.fields.is_some();
         Config {
-            input: match config.input {
+            input: if config.input.is_empty() {
+                self.input
+            } else {
+                config.input
+            },
+            preset: match config.preset {
+                Some(v) => Some(v),
+                None => self.preset,
+            },
+            ssh: match config.ssh {
+                Some(v) => Some(v),
+                None => self.ssh,
+            },
+            remote_path: match config.remote_path {
+                Some(v) => Some(v),
+                None => self.remote_path,
+            },
+            retry_open: self.retry_open || config.retry_open,
+            mmap: self.mmap || config.mmap,
+            reverse: self.reverse || config.reverse,
+            with_filename: self.with_filename || config.with_filename,
+            line_number: self.line_number || config.line_number,
+            follow: self.follow || config.follow,
+            tail: match config.tail {
+                Some(v) => Some(v),
+                None => self.tail,
+            },
+            compression: match config.compression {
                 Some(v) => Some(v),
-                None => self.input,
+                None => self.compression,
             },
             custom_patterns: match config.custom_patterns {
                 Some(v) => Some(v),
                 None => self.custom_patterns,
             },
+            pattern_files: match config.pattern_files {
+                Some(v) => Some(v),
+                None => self.pattern_files,
+            },
+            pattern_dirs: match config.pattern_dirs {
+                Some(v) => Some(v),
+                None => self.pattern_dirs,
+            },
+            pattern_library: match config.pattern_library {
+                Some(v) => Some(v),
+                None => self.pattern_library,
+            },
             list_pattern: match config.list_pattern {
                 Some(v) => Some(v),
                 None => self.list_pattern,
             },
-            match_expression: match config.match_expression {
-                Some(v) => Some(v),
-                None => self.match_expression,
+            check_expression: self.check_expression || config.check_expression,
+            match_expression: match has_expression {
+                true => config.match_expression,
+                false => self.match_expression,
+            },
+            match_expressions: match has_expression {
+                true => config.match_expressions,
+                false => self.match_expressions,
+            },
+            expression_names: match (has_expression, config.expression_names) {
+                (_, Some(v)) => Some(v),
+                (true, None) => None,
+                (false, None) => self.expression_names,
+            },
+            expression_file: match has_expression {
+                true => config.expression_file,
+                false => self.expression_file,
             },
             filters: match config.filters {
                 Some(v) => Some(v),
                 None => self.filters,
             },
-            output_format: match config.output_format {
+            filter_file: match config.filter_file {
+                Some(v) => Some(v),
+                None => self.filter_file,
+            },
+            where_expression: match config.where_expression {
+                Some(v) => Some(v),
+                None => self.where_expression,
+            },
+            invert_match: self.invert_match || config.invert_match,
+            on_unmatched: match config.on_unmatched {
+                Some(v) => Some(v),
+                None => self.on_unmatched,
+            },
+            output_format: match has_output {
+                true => config.output_format,
+                false => self.output_format,
+            },
+            display_tz: match config.display_tz {
+                Some(v) => Some(v),
+                None => self.display_tz,
+            },
+            time_field: match config.time_field {
+                Some(v) => Some(v),
+                None => self.time_field,
+            },
+            time_format: match config.time_format {
+                Some(v) => Some(v),
+                None => self.time_format,
+            },
+            fresher_than: match config.fresher_than {
+                Some(v) => Some(v),
+                None => self.fresher_than,
+            },
+            since: match config.since {
+                Some(v) => Some(v),
+                None => self.since,
+            },
+            until: match config.until {
+                Some(v) => Some(v),
+                None => self.until,
+            },
+            fields: match has_output {
+                true => config.fields,
+                false => self.fields,
+            },
+            output_path: match config.output_path {
+                Some(v) => Some(v),
+                None => self.output_path,
+            },
+            tee: match config.tee {
+                Some(v) => Some(v),
+                None => self.tee,
+            },
+            output_mode: match config.output_mode {
+                Some(v) => Some(v),
+                None => self.output_mode,
+            },
+            color: match config.color {
+                Some(v) => Some(v),
+                None => self.color,
+            },
+            color_by: match config.color_by {
+                Some(v) => Some(v),
+                None => self.color_by,
+            },
+            colors: match config.colors {
+                Some(v) => Some(v),
+                None => self.colors,
+            },
+            aliases: match config.aliases {
+                Some(v) => Some(v),
+                None => self.aliases,
+            },
+            output_atomic: self.output_atomic || config.output_atomic,
+            dedup_state: match config.dedup_state {
+                Some(v) => Some(v),
+                None => self.dedup_state,
+            },
+            squash_repeats: self.squash_repeats || config.squash_repeats,
+            squash_key: match config.squash_key {
+                Some(v) => Some(v),
+                None => self.squash_key,
+            },
+            throttle: match config.throttle {
+                Some(v) => Some(v),
+                None => self.throttle,
+            },
+            before_context: match config.before_context {
+                Some(v) => Some(v),
+                None => self.before_context,
+            },
+            after_context: match config.after_context {
+                Some(v) => Some(v),
+                None => self.after_context,
+            },
+            checkpoint: match config.checkpoint {
+                Some(v) => Some(v),
+                None => self.checkpoint,
+            },
+            checkpoint_interval: match config.checkpoint_interval {
+                Some(v) => Some(v),
+                None => self.checkpoint_interval,
+            },
+            assert_output: match config.assert_output {
+                Some(v) => Some(v),
+                None => self.assert_output,
+            },
+            tags: match config.tags {
+                Some(v) => Some(v),
+                None => self.tags,
+            },
+            pattern_stats: self.pattern_stats || config.pattern_stats,
+            examples: match config.examples {
+                Some(v) => Some(v),
+                None => self.examples,
+            },
+            summary_only: self.summary_only || config.summary_only,
+            classify: self.classify || config.classify,
+            count: self.count || config.count,
+            base64_binary: self.base64_binary || config.base64_binary,
+            provenance: self.provenance || config.provenance,
+            exit_on: match config.exit_on {
+                Some(v) => Some(v),
+                None => self.exit_on,
+            },
+            exit_code: match config.exit_code {
+                Some(v) => Some(v),
+                None => self.exit_code,
+            },
+            timeout: match config.timeout {
+                Some(v) => Some(v),
+                None => self.timeout,
+            },
+            heartbeat: match config.heartbeat {
+                Some(v) => Some(v),
+                None => self.heartbeat,
+            },
+            lag: self.lag || config.lag,
+            hardened: self.hardened || config.hardened,
+            max_line_length: match config.max_line_length {
+                Some(v) => Some(v),
+                None => self.max_line_length,
+            },
+            max_merge_lines: match config.max_merge_lines {
+                Some(v) => Some(v),
+                None => self.max_merge_lines,
+            },
+            jobs: match config.jobs {
+                Some(v) => Some(v),
+                None => self.jobs,
+            },
+            batch_lines: match config.batch_lines {
+                Some(v) => Some(v),
+                None => self.batch_lines,
+            },
+            auto_tune: self.auto_tune || config.auto_tune,
+            group_by_file: self.group_by_file || config.group_by_file,
+            offset: match config.offset {
+                Some(v) => Some(v),
+                None => self.offset,
+            },
+            limit: match config.limit {
+                Some(v) => Some(v),
+                None => self.limit,
+            },
+            skip: match config.skip {
+                Some(v) => Some(v),
+                None => self.skip,
+            },
+            take: match config.take {
+                Some(v) => Some(v),
+                None => self.take,
+            },
+            from_byte: match config.from_byte {
+                Some(v) => Some(v),
+                None => self.from_byte,
+            },
+            to_byte: match config.to_byte {
+                Some(v) => Some(v),
+                None => self.to_byte,
+            },
+            page: match config.page {
+                Some(v) => Some(v),
+                None => self.page,
+            },
+            fail_on_warn: match config.fail_on_warn {
+                Some(v) => Some(v),
+                None => self.fail_on_warn,
+            },
+            min_match_rate: match config.min_match_rate {
+                Some(v) => Some(v),
+                None => self.min_match_rate,
+            },
+            match_rate_lines: match config.match_rate_lines {
+                Some(v) => Some(v),
+                None => self.match_rate_lines,
+            },
+            match_rate_warn: self.match_rate_warn || config.match_rate_warn,
+            conditional_tags: match config.conditional_tags {
+                Some(v) => Some(v),
+                None => self.conditional_tags,
+            },
+            set: match config.set {
+                Some(v) => Some(v),
+                None => self.set,
+            },
+            unset: match config.unset {
+                Some(v) => Some(v),
+                None => self.unset,
+            },
+            transforms: match config.transforms {
+                Some(v) => Some(v),
+                None => self.transforms,
+            },
+            carry_forward: match config.carry_forward {
+                Some(v) => Some(v),
+                None => self.carry_forward,
+            },
+            deltas: match config.deltas {
+                Some(v) => Some(v),
+                None => self.deltas,
+            },
+            moving_averages: match config.moving_averages {
+                Some(v) => Some(v),
+                None => self.moving_averages,
+            },
+            number_locale: match config.number_locale {
                 Some(v) => Some(v),
-                None => self.output_format,
+                None => self.number_locale,
             },
             merge_config: match config.merge_config {
                 Some(v) => Some(v),
                 None => self.merge_config,
             },
+            merge_configs: match config.merge_configs {
+                Some(v) => Some(v),
+                None => self.merge_configs,
+            },
+            actions: match config.actions {
+                Some(v) => Some(v),
+                None => self.actions,
+            },
+        }
+    }
+
+    /// Read the expression file, if any, into the match expression and the filters of the filter
+    /// file ahead of the other filters, and fold the multi-line expressions and filters into
+    /// single lines.
+    pub fn load_expressions(mut self) -> Result<Config, GropError> {
+        let read = |path: &PathBuf, kind: &str| {
+            std::fs::read_to_string(path).map_err(|err| {
+                GropError::InvalidArg(format!(
+                    "failed to read {} file {}: {}",
+                    kind,
+                    path.display(),
+                    err
+                ))
+            })
+        };
+        if let Some(path) = self.expression_file.take() {
+            self.match_expression = Some(read(&path, "expression")?);
+        }
+        if let Some(path) = self.filter_file.take() {
+            // One filter per line, along with blank lines and `#` comments.
+            let mut filters = read(&path, "filter")?
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from)
+                .collect::<Vec<String>>();
+            filters.extend(self.filters.take().unwrap_or_default());
+            self.filters = Some(filters);
+        }
+        let unfold = |e: Option<String>| e.map(|e| unfold_expression(&e));
+        self.match_expression = unfold(self.match_expression);
+        self.where_expression = unfold(self.where_expression);
+        self.match_expressions = self
+            .match_expressions
+            .map(|expressions| expressions.iter().map(|e| unfold_expression(e)).collect());
+        self.filters = self
+            .filters
+            .map(|filters| filters.iter().map(|f| unfold_expression(f)).collect());
+        for merge_config in self
+            .merge_config
+            .iter_mut()
+            .chain(self.merge_configs.iter_mut().flat_map(|m| m.values_mut()))
+        {
+            merge_config.merge_exp_start = unfold(merge_config.merge_exp_start.take());
+            merge_config.merge_exp_end = unfold(merge_config.merge_exp_end.take());
+            merge_config.merge_continuation = unfold(merge_config.merge_continuation.take());
+        }
+        Ok(self)
+    }
+
+    /// The match expressions to try in order, the default one if none is given.
+    pub fn expressions(&self) -> Vec<String> {
+        match (&self.match_expressions, &self.match_expression) {
+            (Some(expressions), _) => expressions.clone(),
+            (None, Some(expression)) => vec![expression.clone()],
+            (None, None) => vec![String::from(DEFAULT_EXPRESSION)],
+        }
+    }
+
+    /// The names of the match expressions in order, their number by default.
+    fn expression_names(&self) -> Result<Vec<String>, GropError> {
+        let count = self.expressions().len();
+        match &self.expression_names {
+            Some(names) if names.len() != count => Err(GropError::InvalidArg(format!(
+                "{} expression names for {} expressions",
+                names.len(),
+                count
+            ))),
+            Some(names) => Ok(names.clone()),
+            // Numbered like the `__pattern` field.
+            None => Ok((1..=count).map(|i| i.to_string()).collect()),
+        }
+    }
+
+    /// The number of the lines or input files to match at the same time.
+    fn jobs(&self) -> Result<usize, GropError> {
+        match self.jobs {
+            Some(0) => Err(GropError::InvalidArg(String::from(
+                "the number of jobs should be at least 1",
+            ))),
+            Some(jobs) => Ok(jobs),
+            None => Ok(1),
+        }
+    }
+
+    /// How the lines of an input are matched by the pool of worker threads. The auto-tune mode
+    /// picks up to the number of jobs, or of CPUs by default.
+    fn pipeline(&self) -> Result<Pipeline, GropError> {
+        let jobs = match (self.jobs, self.auto_tune) {
+            (None, true) => thread::available_parallelism().map_or(1, |n| n.get()),
+            _ => self.jobs()?,
+        };
+        let batch_lines = match self.batch_lines {
+            Some(0) => {
+                return Err(GropError::InvalidArg(String::from(
+                    "the number of lines a batch should be at least 1",
+                )))
+            }
+            Some(batch_lines) => batch_lines,
+            None => BATCH_LINES,
+        };
+        Ok(Pipeline {
+            jobs,
+            batch_lines,
+            auto_tune: self.auto_tune,
+        })
+    }
+
+    /// The merge config of each match expression in order: its own one if any, or else the common
+    /// one.
+    fn expression_merge_configs(&self) -> Result<Vec<Option<&MergeConfig>>, GropError> {
+        let names = self.expression_names()?;
+        let merge_configs = self.merge_configs.as_ref();
+        if let Some(name) = merge_configs
+            .into_iter()
+            .flat_map(|m| m.keys())
+            .find(|name| !names.contains(name))
+        {
+            return Err(GropError::InvalidArg(format!(
+                "merge config of unknown expression {} (should be one of {})",
+                name,
+                names.join(", ")
+            )));
+        }
+        Ok(names
+            .iter()
+            .map(|name| {
+                merge_configs
+                    .and_then(|m| m.get(name))
+                    .or(self.merge_config.as_ref())
+            })
+            .collect())
+    }
+
+    /// Read the definitions of the pattern files and directories into the custom patterns, ahead
+    /// of the ones given one by one so that those may override them.
+    pub fn load_pattern_files(mut self) -> Result<Config, GropError> {
+        let paths = self
+            .pattern_files
+            .take()
+            .into_iter()
+            .chain(self.pattern_dirs.take())
+            .flatten()
+            .collect::<Vec<PathBuf>>();
+        if paths.is_empty() {
+            return Ok(self);
         }
+        let mut patterns = Vec::new();
+        for path in paths.iter() {
+            patterns.extend(read_definitions(path)?);
+        }
+        patterns.extend(self.custom_patterns.take().unwrap_or_default());
+        self.custom_patterns = Some(patterns);
+        Ok(self)
     }
 }
 
+/// A tag (`name=value`) which is only injected into records satisfying the condition
+/// (`field_name operator value`, e.g. `level == "ERROR"`).
+#[derive(Debug, Deserialize)]
+pub struct ConditionalTag {
+    pub condition: String,
+    pub tag: String,
+}
+
+/// An output written along with the main one, in its own format, e.g. to archive the records as
+/// JSON while reading them on stdout.
+#[derive(Debug, Deserialize)]
+pub struct Tee {
+    pub path: String,
+    pub format: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MergeConfig {
     pub merge_fields: Option<Vec<String>>,
     pub merge_exp_start: Option<String>,
     pub merge_exp_end: Option<String>,
+    #[serde(default)]
     pub merge_scope_exclusive: bool,
+    pub merge_key_field: Option<String>,
+    pub merge_key_ttl: Option<String>,
+    pub merge_continuation: Option<String>,
+    #[serde(default)]
+    pub merge_flush_eof: bool,
 }
 
 impl MergeConfig {
@@ -82,6 +719,19 @@ impl MergeConfig {
                 None => self.merge_exp_end,
             },
             merge_scope_exclusive: config.merge_scope_exclusive,
+            merge_key_field: match config.merge_key_field {
+                Some(v) => Some(v),
+                None => self.merge_key_field,
+            },
+            merge_key_ttl: match config.merge_key_ttl {
+                Some(v) => Some(v),
+                None => self.merge_key_ttl,
+            },
+            merge_continuation: match config.merge_continuation {
+                Some(v) => Some(v),
+                None => self.merge_continuation,
+            },
+            merge_flush_eof: self.merge_flush_eof || config.merge_flush_eof,
         }
     }
 }
@@ -91,23 +741,67 @@ pub enum GropError {
     Io(io::Error),
     Compile(fgrok::Error),
     InvalidArg(String),
+    Assertion(String),
+    Timeout(String),
+    Warnings(String),
+    MatchRate(String),
+    Unmatched(String),
+    /// An error processing the line of the input (file).
+    Input {
+        file: Option<String>,
+        line: usize,
+        err: Box<GropError>,
+    },
+}
+
+impl GropError {
+    /// Stable identifier of the kind of error, for the machine-readable error output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            GropError::Io(_) => "io",
+            GropError::Compile(_) => "compile",
+            GropError::InvalidArg(_) => "invalid-argument",
+            GropError::Assertion(_) => "assertion",
+            GropError::Timeout(_) => "timeout",
+            GropError::Warnings(_) => "warnings",
+            GropError::MatchRate(_) => "match-rate",
+            GropError::Unmatched(_) => "unmatched",
+            GropError::Input { err, .. } => err.code(),
+        }
+    }
 }
 
 impl fmt::Display for GropError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
+        match self {
             GropError::Io(err) => err.fmt(f),
             GropError::Compile(err) => err.fmt(f),
             GropError::InvalidArg(msg) => write!(f, "Invalid argument {}", msg),
+            GropError::Assertion(msg) => write!(f, "Assertion failed: {}", msg),
+            GropError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            GropError::Warnings(msg) => write!(f, "Too many warnings: {}", msg),
+            GropError::MatchRate(msg) => write!(f, "Match rate too low: {}", msg),
+            GropError::Unmatched(line) => write!(f, "Line not matching the expression: {}", line),
+            GropError::Input {
+                file: Some(file),
+                line,
+                err,
+            } => write!(f, "{} line {}: {}", file, line, err),
+            GropError::Input {
+                file: None,
+                line,
+                err,
+            } => write!(f, "line {}: {}", line, err),
         }
     }
 }
 
 impl error::Error for GropError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match &*self {
+        match self {
             GropError::Io(err) => Some(err),
             GropError::Compile(err) => Some(err),
+            GropError::Input { err, .. } => Some(err.as_ref()),
             _ => None,
         }
     }
@@ -132,469 +826,4118 @@ impl<'a> From<Matches<'a>> for MatchWrapper<'a> {
     }
 }
 
-impl<'a> Into<HashMap<String, String>> for MatchWrapper<'a> {
-    fn into(self) -> HashMap<String, String> {
-        self.0
-            .iter()
-            .map(|(k, v)| (String::from(k), String::from(v)))
-            .collect::<HashMap<String, String>>()
+/// The field of the capture, without the type it may be given (e.g. `bytes:int`).
+pub(crate) fn capture_field(name: &str) -> &str {
+    match name.rsplit_once(':') {
+        Some((field, type_name)) if FieldType::parse(type_name).is_some() => field,
+        _ => name,
     }
 }
 
-pub fn run(config: Config) -> Result<(), GropError> {
-    let mut grok = Grok::default();
-
-    let mut pattern_map: HashMap<String, String> = patterns()
-        .to_vec()
-        .iter()
-        .map(|(x, y)| (String::from(*x), String::from(*y)))
-        .collect();
+/// The name of the group the text (following `(?<`) starts with, if it's a named group rather
+/// than a lookbehind, along with the type the field may be given, e.g. `bytes:int`.
+fn group_name(text: &str) -> Option<&str> {
+    let name = &text[..text.find('>')?];
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    match name.split_once(':') {
+        Some((field, type_name)) if field.chars().all(word) && type_name.chars().all(word) => {
+            Some(name)
+        }
+        None if !name.is_empty() && name.chars().all(word) => Some(name),
+        _ => None,
+    }
+}
 
-    // Read customized patterns (if any)
-    if let Some(custom_patterns) = config.custom_patterns {
-        for p in custom_patterns.iter() {
-            add_pattern(&mut grok, &mut pattern_map, p)?;
+/// The expression without the types given to the fields of its inline `(?<field:type>regex)`
+/// groups, which the regex engine doesn't take in the group names.
+pub(crate) fn untyped_groups(expression: &str) -> Cow<'_, str> {
+    if !expression.contains("(?<") {
+        return Cow::Borrowed(expression);
+    }
+    let mut untyped = String::new();
+    let mut rest = expression;
+    while let Some(start) = rest.find("(?<") {
+        untyped.push_str(&rest[..start + 3]);
+        rest = &rest[start + 3..];
+        if let Some(name) = group_name(rest) {
+            untyped.push_str(capture_field(name));
+            rest = &rest[name.len()..];
         }
     }
+    untyped.push_str(rest);
+    Cow::Owned(untyped)
+}
 
-    // List pattern
-    if let Some(target) = config.list_pattern {
-        println!("{}", list_pattern(&pattern_map, target)?);
-        return Ok(());
+impl<'a> From<MatchWrapper<'a>> for HashMap<String, String> {
+    fn from(m: MatchWrapper<'a>) -> HashMap<String, String> {
+        m.0.iter()
+            .map(|(k, v)| (String::from(capture_field(k)), String::from(v)))
+            .collect::<HashMap<String, String>>()
     }
+}
 
-    let input: Box<dyn Read> = match config.input {
-        Some(file) => Box::new(File::open(file)?),
-        None => Box::new(io::stdin()),
-    };
-    let mut output = io::stdout();
+/// A constant field injected into records, optionally guarded by a condition on another field.
+struct Tag {
+    name: String,
+    value: String,
+    condition: Option<Condition>,
+}
 
-    match config.merge_config {
-        None => process(
-            input,
-            &mut output,
-            &config.match_expression,
-            &config.output_format,
-            &config.filters,
-            &mut grok,
-        ),
-        Some(MergeConfig {
-            merge_fields: Some(merge_fields),
-            merge_exp_start: Some(merge_exp_start),
-            merge_exp_end: Some(merge_exp_end),
-            merge_scope_exclusive,
-        }) => process_merge(
-            input,
-            &mut output,
-            &config.match_expression,
-            &config.output_format,
-            &merge_fields,
-            &merge_exp_start,
-            &merge_exp_end,
-            merge_scope_exclusive,
-            &config.filters,
-            &mut grok,
-        ),
-        _ => Err(GropError::InvalidArg(format!(
-            "invalid merge option combinations"
-        ))),
+impl Tag {
+    fn apply(&self, m: &mut HashMap<String, String>) {
+        if self.condition.as_ref().is_some_and(|c| !c.eval(m)) {
+            return;
+        }
+        m.insert(self.name.clone(), self.value.clone());
     }
 }
 
-fn process(
-    input: Box<dyn Read>,
-    output: &mut dyn Write,
-    exp: &Option<String>,
-    oformat: &Option<String>,
-    filter: &Option<Vec<String>>,
-    mut grok: &mut Grok,
-) -> Result<(), GropError> {
-    let p: Pattern;
-    match exp {
-        Some(expression) => p = grok.compile(&expression, false)?,
-        None => p = grok.compile("%{GREEDYDATA:all}", false)?,
+/// Number of lines the match rate is checked after, by default.
+const DEFAULT_MATCH_RATE_LINES: u64 = 100;
+
+/// How often the progress is saved to the checkpoint file, by default.
+const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Output format writing each record as a JSON object per line.
+const JSON_FORMAT: &str = "json";
+
+/// Pseudo field holding the raw line, only visible to the transforms.
+const LINE_FIELD: &str = "_line";
+
+/// Field holding the host the line was read from, when tailing over SSH.
+const HOST_FIELD: &str = "_host";
+
+/// Fields holding the input file and the number of the line in it, like `grep -H -n`.
+const FILE_FIELD: &str = "__file";
+const LINE_NUMBER_FIELD: &str = "__line";
+
+/// Field holding which of the match expressions (starting at 1) matched, when there are several.
+const PATTERN_FIELD: &str = "__pattern";
+
+/// Provenance fields: where the record was read from, and how and when it was parsed.
+const SOURCE_FIELD: &str = "_source";
+const OFFSET_FIELD: &str = "_offset";
+const INGESTED_AT_FIELD: &str = "_ingested_at";
+const EXPRESSION_FIELD: &str = "_expression";
+
+/// Field of the squashed records holding the number of records they stand for.
+const REPEAT_COUNT_FIELD: &str = "_repeat_count";
+
+/// Field of the records made of the lines not matching the expression, holding the line.
+const UNMATCHED_FIELD: &str = "__unmatched";
+
+/// Field of the records reporting the number of records suppressed by the throttle.
+const SUPPRESSED_COUNT_FIELD: &str = "_suppressed_count";
+
+/// Field holding how late the record is read, in milliseconds since its timestamp.
+const LAG_FIELD: &str = "_lag";
+
+/// Field of the heartbeat records, holding when they were written.
+const HEARTBEAT_FIELD: &str = "_heartbeat";
+
+/// Expression matching the whole line, used when none is configured.
+const DEFAULT_EXPRESSION: &str = "%{GREEDYDATA:all}";
+
+/// Limits of the hardened mode, unless given.
+const HARDENED_MAX_LINE_LENGTH: usize = 64 * 1024;
+const HARDENED_MAX_MERGE_LINES: usize = 1000;
+
+/// Record-level settings shared by `process` and `process_merge`, applied to each record right
+/// before it is written out.
+struct Emitter {
+    output_format: Option<String>,
+    // Types of the fields, as given in the expressions.
+    types: HashMap<String, FieldType>,
+    filters: Vec<FieldFilter>,
+    where_expression: Option<Expr>,
+    tags: Vec<Tag>,
+    transforms: Vec<Transform>,
+    carried: Vec<CarryForward>,
+    deltas: Vec<Delta>,
+    moving_averages: Vec<MovingAverage>,
+    // Locale the numbers of the typed fields are written in.
+    number_locale: NumberLocale,
+    display_zone: Option<DisplayZone>,
+    // Records whose timestamp (in the time field) is older than the age, or out of the time range,
+    // are dropped.
+    time_field: Option<String>,
+    time_format: Option<String>,
+    max_age: Option<chrono::Duration>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    // Each field of the output is written in its own color, or the whole record in the color of
+    // the value of the `color_by` field.
+    color: bool,
+    color_by: Option<(String, ColorMap)>,
+    fields: Option<Vec<String>>,
+    dedup: Option<Deduplicator>,
+    // Runs of consecutive identical records are written out as their first record.
+    squasher: Option<Squasher>,
+    context: Option<Context>,
+    // Whether the context is made of the raw lines read rather than the records, when merging.
+    raw_context: bool,
+    throttler: Option<Throttler>,
+    partitions: Option<PartitionedWriter>,
+    // Outputs written along with the main one, with their format.
+    tees: Vec<(PartitionedWriter, Option<String>)>,
+    // Summaries of the run delivered once it completes.
+    summaries: Option<Summaries>,
+    stats: PatternStats,
+    warnings: Warnings,
+    // The minimum rate of lines matching the expression, checked once after the number of lines.
+    // Falling below it fails the run, unless only warned about.
+    min_match_rate: Option<(f64, u64, bool)>,
+    match_rate_checked: bool,
+    base64_binary: bool,
+    // The expression the records are added as a provenance field, along with their input, the
+    // offset of their line and the time they were parsed at.
+    provenance: bool,
+    // Limits on untrusted input: the lines are cut to the length, the merged records are written
+    // out once they hold the number of lines, and a panic processing a record only loses it.
+    max_line_length: Option<usize>,
+    max_merge_lines: Option<usize>,
+    hardened: bool,
+    // Only the number of records is kept, the records themselves are not written out.
+    summary_only: bool,
+    // The lines are written out along with the class of the expression matching them instead of
+    // the records.
+    classes: Option<Classes>,
+    // Names of the match expressions, written out as the `__pattern` field (their number if
+    // missing).
+    expression_names: Vec<String>,
+    // A sample of the lines of each class, reported along with the counts.
+    examples: Option<Examples>,
+    // Only the number of records is written out.
+    count: bool,
+    // The raw lines not matching the expression, or whose record is filtered out, are written
+    // out instead of the records.
+    invert_match: bool,
+    on_unmatched: Unmatched,
+    emitted: u64,
+    // The field and pattern of the records stopping the run, along with the exit code to stop
+    // with.
+    exit_on: Option<(String, Rc<Pattern>, i32)>,
+    // Set once a record matched `exit_on`, the input is not read any further.
+    exit_code: Option<i32>,
+    // Reading the input fails with `TimedOut` past the deadline.
+    deadline: Option<Instant>,
+    // Interval of the heartbeats written while no record is, along with when the last record (or
+    // heartbeat) was written.
+    heartbeat: Option<Duration>,
+    last_written: Instant,
+    // Whether the records get how late they are read.
+    lag: bool,
+    // Set while a merged record is emitted, to whether it ended on an end line, to highlight it.
+    block: Option<bool>,
+    // Input file being processed, and the number of the line being processed in it, reported
+    // along with the errors.
+    file: Option<String>,
+    line_number: usize,
+    // Number of lines read from all the inputs.
+    lines: u64,
+    // Whether the records get the file and line number fields.
+    with_filename: bool,
+    with_line_number: bool,
+    // Window of the records written out, counted after the filters.
+    offset: u64,
+    limit: Option<u64>,
+    // Range of the lines of each input to process, by line number and by byte offset, along with
+    // the offset the input is read from.
+    skip: u64,
+    take: Option<u64>,
+    from_byte: u64,
+    to_byte: Option<u64>,
+    base_offset: u64,
+    // Collects the records instead of writing them out, used to check them against test cases.
+    records: Option<Vec<HashMap<String, String>>>,
+    checkpoint: Option<Checkpoint>,
+}
+
+impl Emitter {
+    fn new(output_format: Option<String>, filters: Vec<FieldFilter>) -> Emitter {
+        Emitter {
+            output_format,
+            types: HashMap::new(),
+            filters,
+            where_expression: None,
+            tags: Vec::new(),
+            transforms: Vec::new(),
+            carried: Vec::new(),
+            deltas: Vec::new(),
+            moving_averages: Vec::new(),
+            number_locale: NumberLocale::C,
+            display_zone: None,
+            time_field: None,
+            time_format: None,
+            max_age: None,
+            since: None,
+            until: None,
+            color: false,
+            color_by: None,
+            fields: None,
+            dedup: None,
+            squasher: None,
+            context: None,
+            raw_context: false,
+            throttler: None,
+            partitions: None,
+            tees: Vec::new(),
+            summaries: None,
+            stats: PatternStats::default(),
+            warnings: Warnings::default(),
+            min_match_rate: None,
+            match_rate_checked: false,
+            base64_binary: false,
+            provenance: false,
+            max_line_length: None,
+            max_merge_lines: None,
+            hardened: false,
+            summary_only: false,
+            classes: None,
+            expression_names: Vec::new(),
+            examples: None,
+            count: false,
+            invert_match: false,
+            on_unmatched: Unmatched::Drop,
+            emitted: 0,
+            exit_on: None,
+            exit_code: None,
+            deadline: None,
+            heartbeat: None,
+            last_written: Instant::now(),
+            lag: false,
+            block: None,
+            file: None,
+            line_number: 0,
+            lines: 0,
+            with_filename: false,
+            with_line_number: false,
+            offset: 0,
+            skip: 0,
+            take: None,
+            from_byte: 0,
+            to_byte: None,
+            base_offset: 0,
+            limit: None,
+            records: None,
+            checkpoint: None,
+        }
     }
 
-    for line in BufReader::new(input).lines() {
-        let line = line?;
-        if let Some(m) = p.match_against(&line) {
-            if let Some(output_line) =
-                format_output(&MatchWrapper::from(m).into(), &oformat, &filter, &mut grok)?
-            {
-                output.write(format!("{}\n", output_line,).as_bytes())?;
-            }
+    /// Keep track of the line being processed.
+    fn start_line(&mut self, line: &Line) {
+        self.line_number = line.number;
+        self.lines += 1;
+        // The lines of a followed directory come from each of its files.
+        if let Some(file) = &line.file {
+            self.file = Some(file.clone());
         }
     }
-    Ok(())
-}
 
-fn process_merge(
-    input: Box<dyn Read>,
-    output: &mut dyn Write,
-    exp: &Option<String>,
-    oformat: &Option<String>,
-    merge_field: &Vec<String>,
-    merge_exp_start: &str,
-    merge_exp_end: &str,
-    merge_scope_exclusive: bool,
-    filter: &Option<Vec<String>>,
-    mut grok: &mut Grok,
-) -> Result<(), GropError> {
-    let mut in_scope = false;
-
-    let p: Pattern;
-    match exp {
-        Some(expression) => p = grok.compile(&expression, false)?,
-        None => p = grok.compile("%{GREEDYDATA:all}", false)?,
-    }
-    let p_start = grok.compile(merge_exp_start, false)?;
-    let p_end = grok.compile(merge_exp_end, false)?;
-    let mut buf = HashMap::<String, String>::new();
-    for line in BufReader::new(input).lines() {
-        let line = line?;
-        if let Some(m) = p.match_against(&line) {
-            match (
-                in_scope,
-                p_start.match_against(&line),
-                p_end.match_against(&line),
-            ) {
-                (false, None, _) => {
-                    log::info!("process merge: regular line: {}", line);
+    /// Where the line stands in the range of the lines of the input to process.
+    fn position(&self, line: &Line) -> Position {
+        let number = line.number as u64;
+        let offset = self.base_offset + line.offset;
+        let past_take = self.take.is_some_and(|take| number > self.skip + take);
+        if past_take || self.to_byte.is_some_and(|to_byte| offset >= to_byte) {
+            Position::After
+        } else if number <= self.skip || offset < self.from_byte {
+            Position::Before
+        } else {
+            Position::Within
+        }
+    }
 
-                    if let Some(output_line) =
-                        format_output(&MatchWrapper::from(m).into(), &oformat, &filter, &mut grok)?
-                    {
-                        output.write(format!("{}\n", output_line,).as_bytes())?;
-                    }
-                }
-                (false, Some(_), _) => {
-                    log::info!("process merge: entering merge scope: {}", line);
-                    in_scope = true;
-                    buf = MatchWrapper::from(m).into();
-                }
-                (true, _, None) => {
-                    log::info!("process merge: in scope: {}", line);
-                    merge_match_to_buf(&merge_field, &m, &mut buf)?;
-                }
-                (true, match_start, Some(_)) => {
-                    if merge_scope_exclusive {
-                        log::info!("process merge: leaving merge scope (exclusive): {}", line);
-
-                        if let Some(output_line) =
-                            format_output(&buf, &oformat, &filter, &mut grok)?
-                        {
-                            output.write(format!("{}\n", output_line,).as_bytes())?;
-                        }
-                        buf.clear();
-
-                        // In case the end expression is exclusive, we need further check if it
-                        // match the start expression. If so, we will launch a new merge section
-                        // right away.
-                        if let Some(_) = match_start {
-                            buf = MatchWrapper::from(m).into();
-                            log::info!("process merge: still in merge scope as ending line match start pattern");
-                            in_scope = true;
-                        } else {
-                            // Not match start expression, just output current line and clear
-                            // buffer and state.
-                            if let Some(output_line) = format_output(
-                                &MatchWrapper::from(m).into(),
-                                &oformat,
-                                &filter,
-                                &mut grok,
-                            )? {
-                                output.write(format!("{}\n", output_line,).as_bytes())?;
-                            }
-                            in_scope = false;
-                        }
-                    } else {
-                        log::info!("process merge: leaving merge scope (inclusive): {}", line);
-                        merge_match_to_buf(&merge_field, &m, &mut buf)?;
-
-                        if let Some(output_line) =
-                            format_output(&buf, &oformat, &filter, &mut grok)?
-                        {
-                            output.write(format!("{}\n", output_line,).as_bytes())?;
-                        }
-                        buf.clear();
-                        in_scope = false;
-                    }
-                }
-            }
+    /// Whether the line being processed was already processed by the run being resumed.
+    fn resumed(&self) -> bool {
+        match &self.checkpoint {
+            Some(checkpoint) => self.lines <= checkpoint.resumed_line(),
+            None => false,
         }
     }
-    Ok(())
-}
 
-fn merge_match_to_buf(
-    merge_field: &Vec<String>,
-    m: &Matches,
-    buf: &mut HashMap<String, String>,
-) -> Result<(), GropError> {
-    for field in merge_field.iter() {
-        buf.insert(
-            String::from(field),
-            match buf.get(field) {
-                Some(o) => format!(
-                    "{}\n{}",
-                    o,
-                    m.get(field)
-                        .ok_or(GropError::InvalidArg(format!(
-                            "merge_field {} not exists in pattern",
-                            field
-                        )))
-                        .unwrap(),
-                ),
-                None => String::from(
-                    m.get(field)
-                        .ok_or(GropError::InvalidArg(format!(
-                            "merge_field {} not exists in pattern",
-                            field
-                        )))
-                        .unwrap(),
-                ),
-            },
+    /// Save the progress to the checkpoint file once due, after flushing everything emitted so
+    /// far. Must only be called between records.
+    fn save_checkpoint(&mut self, output: &mut dyn Write) -> Result<(), GropError> {
+        let checkpoint = match &mut self.checkpoint {
+            Some(checkpoint) if checkpoint.due() => checkpoint,
+            _ => return Ok(()),
+        };
+        output.flush()?;
+        if let Some(partitions) = &mut self.partitions {
+            partitions.flush()?;
+        }
+        for (tee, _) in self.tees.iter_mut() {
+            tee.flush()?;
+        }
+        if let Some(dedup) = &mut self.dedup {
+            dedup.finish()?;
+        }
+        checkpoint.save(self.lines, self.emitted)
+    }
+
+    /// Remember the records written out before the run failed, so that the next run doesn't
+    /// write them again. The partitions are not published on failure, their records are not.
+    fn abort(&mut self, output: &mut dyn Write) -> Result<(), GropError> {
+        output.flush()?;
+        match (&mut self.dedup, &self.partitions) {
+            (Some(dedup), None) => dedup.finish(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the input should not be read any further.
+    fn stopped(&self) -> bool {
+        match self.limit {
+            Some(limit) if !self.summary_only && self.emitted >= self.offset + limit => true,
+            _ => self.exit_code.is_some(),
+        }
+    }
+
+    /// Count the warnings about the line itself.
+    fn check_line(&mut self, line: &Line, matched: bool) -> Result<(), GropError> {
+        if !matched {
+            self.warnings.unmatched += 1;
+        }
+        if line.invalid_utf8 {
+            self.warnings.invalid_utf8 += 1;
+        }
+        if line.truncated {
+            self.warnings.long_lines += 1;
+        }
+        match self.min_match_rate {
+            Some((_, lines, _)) if self.lines == lines => self.check_match_rate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the rate of the lines read so far matching the expression, only once.
+    fn check_match_rate(&mut self) -> Result<(), GropError> {
+        let (min_rate, _, warn) = match self.min_match_rate {
+            Some(min_match_rate) if !self.match_rate_checked && self.lines > 0 => min_match_rate,
+            _ => return Ok(()),
+        };
+        self.match_rate_checked = true;
+        let lines = self.lines;
+        let rate = (lines - self.warnings.unmatched) as f64 / lines as f64;
+        if rate >= min_rate {
+            return Ok(());
+        }
+        let msg = format!(
+            "{:.1}% of the first {} lines matched the expression, expected at least {:.1}%",
+            rate * 100.0,
+            lines,
+            min_rate * 100.0
         );
+        if warn {
+            log::warn!("{}", msg);
+            return Ok(());
+        }
+        Err(GropError::MatchRate(msg))
     }
-    Ok(())
-}
 
-fn add_pattern(grok: &mut Grok, m: &mut HashMap<String, String>, p: &str) -> Result<(), GropError> {
-    let pt = p
-        .splitn(2, char::is_whitespace)
-        .map(str::trim)
-        .collect::<Vec<&str>>();
-    if pt.len() != 2 {
-        return Err(GropError::InvalidArg(String::from(
-            r#"Invalid pattern (should be "pattern_name pattern")"#,
-        )));
+    /// The record of the line matched by the expression (at the index), with the extra fields.
+    fn record(
+        &self,
+        m: Matches,
+        line: &Line,
+        expressions: &[Expression],
+        index: usize,
+    ) -> HashMap<String, String> {
+        self.record_of(MatchWrapper::from(m).into(), line, expressions, index)
     }
-    m.insert(String::from(pt[0]), String::from(pt[1]));
-    grok.insert_definition(String::from(pt[0]), String::from(pt[1]));
-    Ok(())
-}
 
-fn list_pattern(
-    pattern_map: &HashMap<String, String>,
-    target_pattern: Option<String>,
-) -> Result<String, GropError> {
-    match target_pattern {
-        Some(target) => match pattern_map.get(&target) {
-            Some(v) => {
-                return Ok(String::from(v));
+    /// The record of the fields captured on the line by the expression of the index.
+    fn record_of(
+        &self,
+        mut record: HashMap<String, String>,
+        line: &Line,
+        expressions: &[Expression],
+        index: usize,
+    ) -> HashMap<String, String> {
+        if expressions.len() > 1 {
+            let name = match self.expression_names.get(index) {
+                Some(name) => name.clone(),
+                None => (index + 1).to_string(),
+            };
+            record.insert(String::from(PATTERN_FIELD), name);
+        }
+        if !self.transforms.is_empty() || self.invert_match || self.raw_context {
+            record.insert(String::from(LINE_FIELD), line.text.clone());
+        }
+        if let Some(host) = &line.host {
+            record.insert(String::from(HOST_FIELD), host.clone());
+        }
+        if self.with_filename {
+            let file = self.file.as_deref().unwrap_or("-");
+            record.insert(String::from(FILE_FIELD), String::from(file));
+        }
+        if self.with_line_number {
+            record.insert(
+                String::from(LINE_NUMBER_FIELD),
+                self.line_number.to_string(),
+            );
+        }
+        if self.provenance {
+            let source = match (&line.host, &self.file) {
+                (Some(host), Some(file)) => format!("{}:{}", host, file),
+                (_, Some(file)) => file.clone(),
+                _ => String::from("-"),
+            };
+            record.insert(String::from(SOURCE_FIELD), source);
+            record.insert(
+                String::from(OFFSET_FIELD),
+                (self.base_offset + line.offset).to_string(),
+            );
+            record.insert(
+                String::from(INGESTED_AT_FIELD),
+                format_timestamp(&Utc::now()),
+            );
+            record.insert(
+                String::from(EXPRESSION_FIELD),
+                expressions[index].text.clone(),
+            );
+        }
+        record
+    }
+
+    fn emit(
+        &mut self,
+        m: HashMap<String, String>,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        let result = match self.hardened {
+            // A bug hit by a record of untrusted input only loses the record.
+            true => match panic::catch_unwind(AssertUnwindSafe(|| self.emit_record(m, output))) {
+                Ok(result) => result,
+                Err(_) => {
+                    log::error!("failed to process the record of line {}", self.line_number);
+                    self.warnings.failed_records += 1;
+                    Ok(())
+                }
+            },
+            false => self.emit_record(m, output),
+        };
+        match result {
+            Err(err) if self.line_number > 0 => Err(GropError::Input {
+                file: self.file.clone(),
+                line: self.line_number,
+                err: Box::new(err),
+            }),
+            result => result,
+        }
+    }
+
+    /// Emit the record merged from several lines, `ended` by an end line or not.
+    fn emit_merged(
+        &mut self,
+        m: HashMap<String, String>,
+        ended: bool,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        self.block = Some(ended);
+        let result = self.emit(m, output);
+        self.block = None;
+        result
+    }
+
+    /// Whether the timestamp is recent enough and within the time range, its end excluded.
+    fn in_time_range(&self, time: DateTime<Utc>) -> bool {
+        match self.max_age {
+            Some(max_age) if Utc::now() - time > max_age => return false,
+            _ => (),
+        }
+        self.since.map(|since| time >= since).unwrap_or(true)
+            && self.until.map(|until| time < until).unwrap_or(true)
+    }
+
+    /// Render the record in the output format, or `None` if it's excluded by the filters or the
+    /// where expression.
+    fn filter_output(&self, m: &HashMap<String, String>) -> Result<Option<String>, GropError> {
+        match &self.where_expression {
+            Some(expression) if !expression.eval(m) => Ok(None),
+            _ => format_output(
+                m,
+                &self.output_format,
+                &self.filters,
+                &self.types,
+                self.number_locale,
+            ),
+        }
+    }
+
+    fn emit_record(
+        &mut self,
+        mut m: HashMap<String, String>,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        for tag in self.tags.iter() {
+            tag.apply(&mut m);
+        }
+        for carry in self.carried.iter_mut() {
+            carry.apply(&mut m);
+        }
+        for transform in self.transforms.iter() {
+            if let Some(field) = transform.missing_field(&m) {
+                log::debug!("emit: field {} of the transform is missing", field);
+                self.warnings.missing_fields += 1;
             }
-            None => {
-                return Err(GropError::InvalidArg(format!(
-                    "Unknown target pattern {}",
-                    &target
-                )));
+            transform.apply(&mut m)?;
+        }
+        for delta in self.deltas.iter_mut() {
+            delta.apply(&mut m);
+        }
+        for average in self.moving_averages.iter_mut() {
+            average.apply(&mut m);
+        }
+        let line = m.remove(LINE_FIELD);
+        if self.base64_binary {
+            encode_binary(&mut m);
+        }
+        if let Some((field, pattern, code)) = &self.exit_on {
+            if m.get(field)
+                .map(|v| pattern.match_against(v).is_some())
+                .unwrap_or(false)
+            {
+                self.exit_code = Some(*code);
             }
-        },
-        None => {
-            let mut spatterns: Vec<(&str, &str)> = Vec::new();
-            for item in pattern_map.iter() {
-                spatterns.push((item.0, item.1));
+        }
+        if self.invert_match {
+            let output_line = self.filter_output(&m)?;
+            return match (output_line, line) {
+                (None, Some(line)) => self.pass_through(&line, output),
+                _ => Ok(()),
+            };
+        }
+        if self.squasher.is_some() {
+            m.insert(String::from(REPEAT_COUNT_FIELD), String::from("1"));
+        }
+        if self.lag || self.max_age.is_some() || self.since.is_some() || self.until.is_some() {
+            match record_time(&m, self.time_field.as_deref(), self.time_format.as_deref()) {
+                Some(time) if !self.in_time_range(time) => return Ok(()),
+                Some(time) if self.lag => {
+                    let lag = Utc::now().signed_duration_since(time).num_milliseconds();
+                    m.insert(String::from(LAG_FIELD), lag.to_string());
+                }
+                Some(_) => (),
+                None => log::debug!("emit: the record has no timestamp to place it in time"),
             }
-            spatterns.sort_by(|a, b| a.0.cmp(b.0));
-            Ok(spatterns
-                .iter()
-                .map(|(k, _)| String::from(*k))
-                .collect::<Vec<String>>()
-                .join("\n"))
+        }
+        if let Some(context) = &mut self.context {
+            context.next();
+        }
+        let output_line = match self.filter_output(&m)? {
+            Some(output_line) => output_line,
+            None => return self.write_context(m, line, output),
+        };
+        if let Some(throttler) = &mut self.throttler {
+            let (allowed, ended) = throttler.check(&m, Instant::now());
+            self.write_suppressed(ended, output)?;
+            if !allowed {
+                return Ok(());
+            }
+        }
+        match &mut self.squasher {
+            Some(squasher) => match squasher.push(m, output_line) {
+                Some((m, output_line, count)) => self.write_run(m, output_line, count, output),
+                None => Ok(()),
+            },
+            None => self.write_record(m, output_line, output),
         }
     }
-}
 
-fn format_output(
-    m: &HashMap<String, String>,
-    format: &Option<String>,
-    filter: &Option<Vec<String>>,
-    grok: &mut Grok,
-) -> Result<Option<String>, GropError> {
-    if let Some(filters) = filter {
-        let mut to_keep = true;
-        // Iterate each filter in order to evaluate whether to keep or skip this match.
-        for filter in filters.iter() {
-            let (is_negative, filter) = if filter.starts_with("-") {
-                (true, String::from(&filter[1..]))
-            } else {
-                (false, String::from(&filter[..]))
+    /// Write out the first record of a run of repeated records, with the number of records in the
+    /// run.
+    fn write_run(
+        &mut self,
+        mut m: HashMap<String, String>,
+        output_line: String,
+        count: u64,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        if count == 1 {
+            return self.write_record(m, output_line, output);
+        }
+        m.insert(String::from(REPEAT_COUNT_FIELD), count.to_string());
+        let output_line = render(
+            &m,
+            &self.output_format,
+            false,
+            &self.types,
+            self.number_locale,
+        )?;
+        self.write_record(m, output_line, output)
+    }
+
+    fn write_record(
+        &mut self,
+        mut m: HashMap<String, String>,
+        output_line: String,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        // The run ended by the last record may come past the limit.
+        match self.limit {
+            Some(limit) if !self.summary_only && self.emitted >= self.offset + limit => {
+                return Ok(())
+            }
+            _ => (),
+        }
+        let output_line = self.display(&m, output_line)?;
+        // The tees are rendered from the whole record, before the fields are dropped.
+        let tee_lines = self
+            .tees
+            .iter()
+            .map(|(_, format)| match format {
+                Some(_) => render(&m, format, false, &self.types, self.number_locale),
+                None => render(
+                    &m,
+                    &self.output_format,
+                    false,
+                    &self.types,
+                    self.number_locale,
+                ),
+            })
+            .collect::<Result<Vec<String>, GropError>>()?;
+        if let Some(fields) = &self.fields {
+            m.retain(|k, _| fields.contains(k));
+        }
+        // The records skipped by the offset are never written out, so they are not remembered as
+        // seen either.
+        let skipped = self.emitted < self.offset;
+        if let (false, Some(dedup)) = (skipped, &mut self.dedup) {
+            if !dedup.check(&m) {
+                return Ok(());
+            }
+        }
+        self.emitted += 1;
+        if let Some(summaries) = &mut self.summaries {
+            summaries.observe(&m);
+        }
+        if skipped {
+            return Ok(());
+        }
+        self.last_written = Instant::now();
+        for ((tee, _), line) in self.tees.iter_mut().zip(tee_lines) {
+            tee.write(&m, &line)?;
+        }
+        if self.summary_only || self.count {
+            return Ok(());
+        }
+        match (&mut self.records, &mut self.partitions) {
+            (Some(records), _) => records.push(m),
+            (None, Some(partitions)) => partitions.write(&m, &output_line)?,
+            (None, None) => match &mut self.context {
+                Some(context) => context.matched(&output_line, output)?,
+                None => writeln!(output, "{}", output_line)?,
+            },
+        }
+        Ok(())
+    }
+
+    /// Report the number of records the throttle suppressed for each of the values.
+    fn write_suppressed(
+        &mut self,
+        ended: Vec<(String, u64)>,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        let field = match &self.throttler {
+            Some(throttler) if !self.summary_only && !self.count && self.records.is_none() => {
+                String::from(throttler.field())
+            }
+            _ => return Ok(()),
+        };
+        for (value, suppressed) in ended {
+            let line = match self.output_format.as_deref() {
+                Some(JSON_FORMAT) => {
+                    let mut m = HashMap::new();
+                    m.insert(field.clone(), value);
+                    m.insert(String::from(SUPPRESSED_COUNT_FIELD), suppressed.to_string());
+                    render(
+                        &m,
+                        &self.output_format,
+                        false,
+                        &self.types,
+                        self.number_locale,
+                    )?
+                }
+                _ => format!("{}={}: {} records suppressed", field, value, suppressed),
             };
-            let ft = filter
-                .splitn(2, char::is_whitespace)
-                .map(str::trim)
-                .collect::<Vec<&str>>();
-            if ft.len() != 2 {
-                return Err(GropError::InvalidArg(String::from(
-                    r#"Invalid pattern (should be "field_name pattern")"#,
-                )));
-            }
-            let (name, pattern) = (ft[0], ft[1]);
-            let field_payload = m.get(name).expect(&format!("unknown field: {}", name));
-            if is_negative {
-                if grok
-                    .compile(pattern, false)?
-                    .match_against(field_payload)
-                    .is_some()
-                {
-                    to_keep = false;
+            writeln!(output, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Write a heartbeat if no record was written for the heartbeat interval, telling the
+    /// downstream monitors that the input is still being read.
+    fn beat(&mut self, output: &mut dyn Write) -> Result<(), GropError> {
+        match self.heartbeat {
+            Some(interval) if self.last_written.elapsed() >= interval => (),
+            _ => return Ok(()),
+        }
+        self.last_written = Instant::now();
+        if self.summary_only || self.count || self.records.is_some() {
+            return Ok(());
+        }
+        let now = format_timestamp(&chrono::Utc::now());
+        let line = match self.output_format.as_deref() {
+            Some(JSON_FORMAT) => {
+                let mut m = HashMap::new();
+                m.insert(String::from(HEARTBEAT_FIELD), now);
+                m.insert(String::from("records"), self.emitted.to_string());
+                m.insert(String::from("lines"), self.lines.to_string());
+                render(
+                    &m,
+                    &self.output_format,
+                    false,
+                    &self.types,
+                    self.number_locale,
+                )?
+            }
+            _ => format!(
+                "heartbeat {}: {} records, {} lines",
+                now, self.emitted, self.lines
+            ),
+        };
+        writeln!(output, "{}", line)?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Render the output line of the record the way it's displayed.
+    fn display(
+        &self,
+        m: &HashMap<String, String>,
+        output_line: String,
+    ) -> Result<String, GropError> {
+        // Only the output is rendered in the display zone, the filters work on the original (UTC)
+        // timestamps.
+        let color_fields = self.color && self.color_by.is_none();
+        let output_line = match &self.display_zone {
+            Some(zone) => render(
+                &zone.localize(m),
+                &self.output_format,
+                color_fields,
+                &self.types,
+                self.number_locale,
+            )?,
+            None if color_fields => render(
+                m,
+                &self.output_format,
+                true,
+                &self.types,
+                self.number_locale,
+            )?,
+            None => output_line,
+        };
+        let output_line = match &self.color_by {
+            Some((field, colors)) if self.color => match m.get(field) {
+                Some(value) => colors.tint(value, output_line),
+                None => output_line,
+            },
+            _ => output_line,
+        };
+        Ok(match self.block {
+            Some(ended) if self.color => highlight_block(&output_line, ended),
+            _ => output_line,
+        })
+    }
+
+    /// Show the record filtered out as context, if it's around a record written out.
+    fn write_context(
+        &mut self,
+        m: HashMap<String, String>,
+        line: Option<String>,
+        output: &mut dyn Write,
+    ) -> Result<(), GropError> {
+        if self.context.is_none() {
+            return Ok(());
+        }
+        // The records filtered out may lack the fields of the output format.
+        let output_line = match line {
+            Some(line) if self.raw_context => line,
+            _ => match render(
+                &m,
+                &self.output_format,
+                false,
+                &self.types,
+                self.number_locale,
+            ) {
+                Ok(output_line) => self.display(&m, output_line)?,
+                Err(err) => {
+                    log::debug!("emit: the context record can't be rendered: {}", err);
+                    return Ok(());
                 }
-            } else {
-                if grok
-                    .compile(pattern, false)?
-                    .match_against(field_payload)
-                    .is_some()
-                {
-                    to_keep = true;
+            },
+        };
+        if let Some(context) = &mut self.context {
+            context.filtered(output_line, output)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the line not matching the expression as configured.
+    fn unmatched(&mut self, line: &Line, output: &mut dyn Write) -> Result<(), GropError> {
+        let result = match self.on_unmatched {
+            // The lines around the merged records are shown as they are.
+            Unmatched::Drop if self.raw_context => match &mut self.context {
+                Some(context) => {
+                    context.next();
+                    context
+                        .filtered(line.text.clone(), output)
+                        .map_err(GropError::from)
                 }
+                None => Ok(()),
+            },
+            Unmatched::Drop => Ok(()),
+            Unmatched::Print if self.summary_only || self.count => Ok(()),
+            Unmatched::Print => writeln!(output, "{}", line.text).map_err(GropError::from),
+            Unmatched::Stderr => writeln!(io::stderr(), "{}", line.text).map_err(GropError::from),
+            Unmatched::Fail => Err(GropError::Unmatched(line.text.clone())),
+            Unmatched::Tag => {
+                let mut m = HashMap::new();
+                m.insert(String::from(UNMATCHED_FIELD), line.text.clone());
+                if self.with_filename {
+                    let file = self.file.as_deref().unwrap_or("-");
+                    m.insert(String::from(FILE_FIELD), String::from(file));
+                }
+                if self.with_line_number {
+                    m.insert(
+                        String::from(LINE_NUMBER_FIELD),
+                        self.line_number.to_string(),
+                    );
+                }
+                // Neither filtered nor in the output format, which their fields are not.
+                let output_line = match self.output_format.as_deref() {
+                    Some(JSON_FORMAT) => render(
+                        &m,
+                        &self.output_format,
+                        false,
+                        &self.types,
+                        self.number_locale,
+                    )?,
+                    _ => line.text.clone(),
+                };
+                self.write_record(m, output_line, output)
             }
-            log::info!(
-                "format_output: name: {}, pattern: {}, to_keep: {}, content: {}",
-                name,
-                pattern,
-                to_keep,
-                field_payload,
-            );
+        };
+        match result {
+            Err(err) if self.line_number > 0 => Err(GropError::Input {
+                file: self.file.clone(),
+                line: self.line_number,
+                err: Box::new(err),
+            }),
+            result => result,
         }
-        if !to_keep {
-            return Ok(None);
+    }
+
+    /// Write out the raw text of a line (or merged lines) as it is, in the window of the output.
+    fn pass_through(&mut self, text: &str, output: &mut dyn Write) -> Result<(), GropError> {
+        self.emitted += 1;
+        if self.summary_only || self.count || self.emitted <= self.offset {
+            return Ok(());
         }
+        writeln!(output, "{}", text)?;
+        Ok(())
     }
-    match format {
-        Some(format) => Ok(Some(
-            format
-                .split(",")
-                .map(|k| {
-                    String::from(
-                        m.get(k)
-                            .expect(&format!("unknown field in format string: {}", k)),
-                    )
-                })
-                .collect::<Vec<String>>()
-                .join(" "),
-        )),
-        None => Ok(Some(
-            m.iter()
-                .map(|x| String::from(x.1))
-                .collect::<Vec<String>>()
-                .join(" "),
-        )),
+
+    fn finish(&mut self, output: &mut dyn Write) -> Result<(), GropError> {
+        if let Some((m, output_line, count)) = self.squasher.as_mut().and_then(Squasher::take) {
+            self.write_run(m, output_line, count, output)?;
+        }
+        if let Some(throttler) = &mut self.throttler {
+            let ended = throttler.finish();
+            self.write_suppressed(ended, output)?;
+        }
+        for (tee, _) in self.tees.iter_mut() {
+            tee.finish()?;
+        }
+        if let Some(partitions) = &mut self.partitions {
+            partitions.finish()?;
+        }
+        // Only remember the records once they are durably written (or counted, in summary mode).
+        if let Some(dedup) = &mut self.dedup {
+            dedup.finish()?;
+        }
+        // In summary mode the reports are the output, nothing else is written.
+        if self.summary_only {
+            writeln!(output, "{} records", self.emitted)?;
+            if let Some(classes) = &self.classes {
+                classes.report(output)?;
+            }
+            if let Some(examples) = &self.examples {
+                examples.report(output)?;
+            }
+            return self.stats.report(output);
+        }
+        if self.count {
+            writeln!(output, "{}", self.emitted)?;
+        }
+        if let Some(classes) = &self.classes {
+            classes.report(&mut io::stderr())?;
+        }
+        if let Some(examples) = &self.examples {
+            examples.report(&mut io::stderr())?;
+        }
+        self.stats.report(&mut io::stderr())
+    }
+
+    /// Deliver the summaries of the run, with its figures: the number of the "records" written
+    /// out (or counted) and of the "lines" read, the "warnings" along with each of their counts,
+    /// and the "stats" report.
+    fn complete(&self) -> Result<(), GropError> {
+        let summaries = match &self.summaries {
+            Some(summaries) => summaries,
+            None => return Ok(()),
+        };
+        let mut stats = Vec::new();
+        self.stats.report(&mut stats)?;
+        let figures = [
+            ("records", self.emitted.to_string()),
+            ("lines", self.lines.to_string()),
+            ("warnings", self.warnings.to_string()),
+            ("unmatched", self.warnings.unmatched.to_string()),
+            ("invalid_utf8", self.warnings.invalid_utf8.to_string()),
+            ("missing_fields", self.warnings.missing_fields.to_string()),
+            ("long_lines", self.warnings.long_lines.to_string()),
+            ("failed_records", self.warnings.failed_records.to_string()),
+            (
+                "stats",
+                String::from_utf8_lossy(&stats).trim_end().to_string(),
+            ),
+        ];
+        summaries.complete(
+            &figures
+                .iter()
+                .map(|(k, v)| (String::from(*k), v.clone()))
+                .collect(),
+        )
     }
 }
 
-#[cfg(test)]
-mod tests {
+/// Create the grok instance with the default patterns plus the ones of the library (if any) and the
+/// custom ones, together with a map of all the known pattern definitions.
+fn load_patterns(
+    custom_patterns: &Option<Vec<String>>,
+    library: Option<&Path>,
+) -> Result<(Grok, HashMap<String, String>), GropError> {
+    let mut grok = Grok::default();
 
-    use super::*;
-    use std::io::Cursor;
+    let mut pattern_map: HashMap<String, String> = patterns()
+        .iter()
+        .map(|(x, y)| (String::from(*x), String::from(*y)))
+        .collect();
 
-    #[test]
-    fn test_add_valid_pattern() {
-        let mut grok = Grok::default();
-        let mut pattern_map = HashMap::<String, String>::new();
-        add_pattern(&mut grok, &mut pattern_map, "FOO foo").expect("failed to add pattern");
-        assert_eq!(pattern_map.get("FOO").unwrap(), "foo");
-        let p = grok
-            .compile("%{FOO:foo}", true)
-            .expect("failed to compile pattern");
-        let m = p.match_against("foo").expect("failed to match pattern");
-        assert_eq!(m.get("foo").unwrap(), "foo");
+    // Read the patterns of the user library, then the customized patterns (if any)
+    if let Some(dir) = library {
+        for (path, definitions) in PatternLibrary::open(dir.to_path_buf()).file_definitions() {
+            // A broken library file is left out rather than failing every query.
+            match definitions {
+                Ok(definitions) => {
+                    for p in definitions.iter() {
+                        add_pattern(&mut grok, &mut pattern_map, p)?;
+                    }
+                }
+                Err(err) => log::warn!("patterns: skipping {}: {}", path.display(), err),
+            }
+        }
+    }
+    if let Some(custom_patterns) = custom_patterns {
+        for p in custom_patterns.iter() {
+            add_pattern(&mut grok, &mut pattern_map, p)?;
+        }
+    }
+    Ok((grok, pattern_map))
+}
+
+/// Run the query of the config, returning the exit code of the process: the one of `exit_on` if a
+/// record matched it, else 0 if any record was written out (or counted) and 1 otherwise.
+pub fn run(config: Config) -> Result<i32, GropError> {
+    let config = match config.preset {
+        Some(preset) => preset.apply(config),
+        None => config,
+    };
+    let (mut grok, pattern_map) =
+        load_patterns(&config.custom_patterns, config.pattern_library.as_deref())?;
+
+    // List pattern
+    if let Some(target) = config.list_pattern {
+        println!("{}", list_pattern(&pattern_map, target)?);
+        return Ok(0);
+    }
+    if config.check_expression {
+        let mut checks = Vec::new();
+        for expression in config.expressions().iter() {
+            checks.push(check_expression(&mut grok, &pattern_map, expression)?);
+        }
+        println!("{}", checks.join("\n\n"));
+        return Ok(0);
+    }
+
+    let mut grok = PatternCache::new(grok);
+    let mut emitter = build_emitter(&config, &mut grok)?;
+
+    // The output is held back to be compared against the expected one, if asked to.
+    let mut stdout = io::stdout();
+    let mut captured = Vec::new();
+    let output: &mut dyn Write = match config.assert_output {
+        Some(_) => &mut captured,
+        None => &mut stdout,
+    };
+
+    match process_inputs(output, &config, &mut emitter, &mut grok) {
+        Err(GropError::Io(err)) if err.kind() == io::ErrorKind::TimedOut => {
+            emitter.finish(output)?;
+            return Err(GropError::Timeout(format!(
+                "no record matched {} within {}",
+                config.exit_on.as_deref().unwrap_or_default(),
+                config.timeout.as_deref().unwrap_or_default()
+            )));
+        }
+        Err(err) => {
+            emitter.abort(output)?;
+            return Err(err);
+        }
+        Ok(()) => (),
+    }
+    // Inputs shorter than the number of lines to check the match rate after are checked in full.
+    if let Err(err) = emitter.check_match_rate() {
+        emitter.abort(output)?;
+        return Err(err);
+    }
+    emitter.finish(output)?;
+    if let Some(checkpoint) = &emitter.checkpoint {
+        checkpoint.finish()?;
+    }
+    emitter.complete()?;
+    grok.report();
+    if let Some(expected) = &config.assert_output {
+        assert_output(expected, &captured)?;
+    }
+    let warnings = emitter.warnings.total();
+    if warnings > 0 {
+        log::warn!("{}", emitter.warnings);
+    }
+    if let Some(threshold) = config.fail_on_warn {
+        if warnings > threshold {
+            return Err(GropError::Warnings(format!(
+                "{} ({} allowed)",
+                emitter.warnings, threshold
+            )));
+        }
+    }
+    // Like grep, the runs which didn't write out any record exit with 1.
+    let written = emitter.emitted > emitter.offset;
+    Ok(emitter.exit_code.unwrap_or(if written { 0 } else { 1 }))
+}
+
+/// Open the input file, or stdin if there is none, unless tailing over SSH. Returns the byte offset
+/// the input is read from along with it.
+fn open_source(config: &Config, file: Option<&PathBuf>) -> Result<(Source, u64), GropError> {
+    let compression = config
+        .compression
+        .unwrap_or(Compression::Auto)
+        .of(file.map(PathBuf::as_path));
+    // The plain files are read from the byte offset, rather than skipping what comes before it.
+    if let (None, Some(file), Some(from_byte)) = (&config.ssh, file, config.from_byte) {
+        if compression == Compression::None && !config.follow && !config.reverse {
+            let (reader, start) = open_at(file, from_byte)?;
+            return Ok((Source::Reader(reader), start));
+        }
+    }
+    // The plain regular files read in full may be mapped in memory rather than read line by line,
+    // though not in the hardened mode, a file truncated meanwhile crashing the mapping reader.
+    if let (None, Some(file)) = (&config.ssh, file) {
+        let whole = !config.follow && !config.reverse && !config.retry_open;
+        let mmap = config.mmap && !config.hardened;
+        if mmap && whole && compression == Compression::None && config.tail.is_none() {
+            if let Some(map) = map_file(file)? {
+                return Ok((Source::Mapped(map), 0));
+            }
+        }
+    }
+    let input = match (&config.ssh, &config.remote_path, file) {
+        (Some(hosts), Some(path), _) => Source::Ssh {
+            hosts: hosts.clone(),
+            path: path.clone(),
+        },
+        (Some(_), None, _) => {
+            return Err(GropError::InvalidArg(String::from(
+                "the remote path to tail over ssh is missing",
+            )))
+        }
+        (None, _, Some(_)) if config.reverse && config.follow => {
+            return Err(GropError::InvalidArg(String::from(
+                "an input read in reverse can't be followed",
+            )))
+        }
+        (None, _, Some(file)) if config.follow && file.is_dir() => {
+            Source::Directory(file.clone(), config.tail)
+        }
+        (None, _, Some(_))
+            if config.follow && config.tail.is_some() && compression != Compression::None =>
+        {
+            return Err(GropError::InvalidArg(String::from(
+                "a compressed input can't be followed from its last lines",
+            )))
+        }
+        (None, _, Some(_)) if config.reverse && compression != Compression::None => {
+            return Err(GropError::InvalidArg(String::from(
+                "a compressed input can't be read in reverse",
+            )))
+        }
+        (None, _, Some(file)) if config.reverse => Source::Reader(Box::new(Reverse::open(file)?)),
+        (None, _, Some(file)) => Source::Reader(open_input(
+            file,
+            config.retry_open,
+            config.follow,
+            config.tail,
+            compression,
+        )?),
+        (None, _, None) if config.follow => {
+            return Err(GropError::InvalidArg(String::from(
+                "only an input file can be followed",
+            )))
+        }
+        (None, _, None) if config.reverse => {
+            return Err(GropError::InvalidArg(String::from(
+                "only an input file can be read in reverse",
+            )))
+        }
+        (None, _, None) => Source::Reader(transcode_utf16(decompress(
+            Box::new(io::stdin()),
+            compression,
+        )?)?),
+    };
+    Ok((input, 0))
+}
+
+/// Process the input files one after the other, each opened once the previous one is done.
+fn process_inputs(
+    output: &mut dyn Write,
+    config: &Config,
+    emitter: &mut Emitter,
+    grok: &mut PatternCache,
+) -> Result<(), GropError> {
+    // Following a file never finishes it, so that the next one would never be read.
+    if config.follow && config.input.len() > 1 {
+        return Err(GropError::InvalidArg(String::from(
+            "only a single input file can be followed",
+        )));
+    }
+    let jobs = config.jobs()?;
+    let merging = config
+        .expression_merge_configs()?
+        .iter()
+        .any(Option::is_some);
+    if jobs > 1 && config.input.len() > 1 {
+        // The lines of the files read at the same time can't be resumed in order.
+        if merging
+            || config.ssh.is_some()
+            || emitter.deadline.is_some()
+            || emitter.checkpoint.is_some()
+        {
+            log::info!("process: reading the input files one after the other");
+        } else {
+            let files = config.input.iter().collect::<Vec<&PathBuf>>();
+            return process_files(&files, output, config, jobs, emitter, grok);
+        }
+    }
+    let files = if config.input.is_empty() {
+        vec![None]
+    } else {
+        config.input.iter().map(Some).collect()
+    };
+    for file in files {
+        // The file tailed over SSH stands for the input file.
+        emitter.file = match file {
+            Some(file) => Some(file.display().to_string()),
+            None if config.ssh.is_some() => config.remote_path.clone(),
+            None => None,
+        };
+        let (source, base_offset) = open_source(config, file)?;
+        emitter.base_offset = base_offset;
+        process_input(source, output, config, emitter, grok)?;
+        if emitter.stopped() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Build the emitter applying the record-level settings of the config.
+fn build_emitter(config: &Config, grok: &mut PatternCache) -> Result<Emitter, GropError> {
+    // The filters are compiled once and for all, rather than for each record.
+    let filters = config
+        .filters
+        .iter()
+        .flatten()
+        .map(|f| FieldFilter::parse(f, grok))
+        .collect::<Result<Vec<FieldFilter>, GropError>>()?;
+    let mut emitter = match &config.fields {
+        // `fields` is a shorthand of an output format which drops all the other fields.
+        Some(fields) => {
+            let mut emitter = Emitter::new(Some(fields.join(",")), filters);
+            emitter.fields = Some(fields.clone());
+            emitter
+        }
+        None => Emitter::new(config.output_format.clone(), filters),
+    };
+    if let Some(tags) = &config.tags {
+        for t in tags.iter() {
+            emitter.tags.push(parse_tag(t, None)?);
+        }
+    }
+    if let Some(conditional_tags) = &config.conditional_tags {
+        for t in conditional_tags.iter() {
+            let condition = Condition::parse(&t.condition, grok)?;
+            emitter.tags.push(parse_tag(&t.tag, Some(condition))?);
+        }
+    }
+    // The fields are set first, so that the unset ones are dropped even if they were just set.
+    if let Some(set) = &config.set {
+        for s in set.iter() {
+            emitter.transforms.push(Transform::parse_set(s)?);
+        }
+    }
+    if let Some(unset) = &config.unset {
+        for field in unset.iter() {
+            emitter.transforms.push(Transform::Unset {
+                field: field.clone(),
+            });
+        }
+    }
+    if let Some(transforms) = &config.transforms {
+        for t in transforms.iter() {
+            emitter.transforms.push(Transform::parse(t, grok)?);
+        }
+    }
+    if let Some(expression) = &config.where_expression {
+        emitter.where_expression = Some(Expr::parse(expression, grok)?);
+    }
+    if let Some(fields) = &config.carry_forward {
+        emitter.carried = fields.iter().map(|f| CarryForward::new(f)).collect();
+    }
+    emitter.number_locale = config.number_locale.unwrap_or_default();
+    if let Some(deltas) = &config.deltas {
+        for d in deltas.iter() {
+            emitter.deltas.push(Delta::parse(d, emitter.number_locale)?);
+        }
+    }
+    if let Some(moving_averages) = &config.moving_averages {
+        for a in moving_averages.iter() {
+            let average = MovingAverage::parse(a, emitter.number_locale)?;
+            emitter.moving_averages.push(average);
+        }
+    }
+    if let Some(display_tz) = &config.display_tz {
+        emitter.display_zone = Some(display_tz.parse()?);
+    }
+    if let Some(age) = &config.fresher_than {
+        let millis = transform::parse_millis(age).ok_or_else(|| {
+            GropError::InvalidArg(format!(
+                "invalid age {} (should be a duration like 15m or 2h)",
+                age
+            ))
+        })?;
+        emitter.max_age = Some(chrono::Duration::milliseconds(millis as i64));
+    }
+    emitter.since = config.since.as_deref().map(parse_time_bound).transpose()?;
+    emitter.until = config.until.as_deref().map(parse_time_bound).transpose()?;
+    emitter.time_field = config.time_field.clone();
+    emitter.time_format = config.time_format.clone();
+    emitter.color = match config.color.unwrap_or(ColorMode::Auto) {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        // Only for a terminal, see https://no-color.org for NO_COLOR.
+        ColorMode::Auto => {
+            config.output_path.is_none()
+                && config.assert_output.is_none()
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                && io::stdout().is_terminal()
+        }
+    };
+    if let Some(field) = &config.color_by {
+        emitter.color_by = Some((field.clone(), ColorMap::new(config.colors.as_ref())?));
+    }
+    if let Some(actions) = config.actions.as_ref().and_then(|a| a.on_complete.as_ref()) {
+        emitter.summaries = Some(Summaries::new(actions)?);
+    }
+    if let Some(dedup_state) = &config.dedup_state {
+        emitter.dedup = Some(Deduplicator::load(dedup_state.clone())?);
+    }
+    if config.squash_repeats {
+        if config.checkpoint.is_some() {
+            return Err(GropError::InvalidArg(String::from(
+                "squashed records can not be resumed from a checkpoint",
+            )));
+        }
+        // The records are told apart by the key, or else by the fields written out.
+        let compared = match (&config.squash_key, &emitter.output_format) {
+            (Some(key), _) => Some(vec![key.clone()]),
+            (None, Some(format)) if format != JSON_FORMAT => {
+                Some(format.split(',').map(String::from).collect())
+            }
+            _ => None,
+        };
+        emitter.squasher = Some(Squasher::new(compared));
+    }
+    if let Some(throttle) = &config.throttle {
+        if config.output_path.is_some() {
+            return Err(GropError::InvalidArg(String::from(
+                "the records suppressed by the throttle can't be written by field",
+            )));
+        }
+        emitter.throttler = Some(Throttler::parse(throttle)?);
+    }
+    if config.before_context.is_some() || config.after_context.is_some() {
+        let conflicts = [
+            (config.output_path.is_some(), "output_path"),
+            (config.squash_repeats, "squash_repeats"),
+            (config.invert_match, "invert_match"),
+            (config.summary_only, "summary_only"),
+            (config.count, "count"),
+        ];
+        if let Some((_, option)) = conflicts.iter().find(|(set, _)| *set) {
+            return Err(GropError::InvalidArg(format!(
+                "context records can't be shown along with {}",
+                option
+            )));
+        }
+        // The JSON records are separated by newlines only.
+        let separator = emitter.output_format.as_deref() != Some(JSON_FORMAT);
+        emitter.raw_context = config
+            .expression_merge_configs()?
+            .iter()
+            .any(Option::is_some);
+        emitter.context = Some(Context::new(
+            config.before_context.unwrap_or(0),
+            config.after_context.unwrap_or(0),
+            separator,
+        ));
+    }
+    emitter.stats = PatternStats::new(config.pattern_stats);
+    let expressions = config.expressions();
+    emitter.types = field_types(&expressions)?;
+    let names = config.expression_names()?;
+    emitter.expression_names = names.clone();
+    if config.classify {
+        let conflicts = [
+            (config.merge_config.is_some(), "merge_config"),
+            (config.merge_configs.is_some(), "merge_configs"),
+            (config.output_path.is_some(), "output_path"),
+            (config.invert_match, "invert_match"),
+        ];
+        if let Some((_, option)) = conflicts.iter().find(|(set, _)| *set) {
+            return Err(GropError::InvalidArg(format!(
+                "the lines can't be classified along with {}",
+                option
+            )));
+        }
+        emitter.classes = Some(Classes::new(names.clone()));
+    }
+    if let Some(size) = config.examples {
+        if config.merge_config.is_some() || config.merge_configs.is_some() {
+            return Err(GropError::InvalidArg(String::from(
+                "the examples can't be sampled from merged lines",
+            )));
+        }
+        emitter.examples = Some(Examples::new(names, size));
+    }
+    if let Some(min_rate) = config.min_match_rate {
+        if !(0.0..=1.0).contains(&min_rate) {
+            return Err(GropError::InvalidArg(format!(
+                "invalid minimum match rate {} (should be between 0 and 1)",
+                min_rate
+            )));
+        }
+        emitter.min_match_rate = Some((
+            min_rate,
+            config.match_rate_lines.unwrap_or(DEFAULT_MATCH_RATE_LINES),
+            config.match_rate_warn,
+        ));
+    }
+    emitter.base64_binary = config.base64_binary;
+    emitter.with_filename = config.with_filename;
+    emitter.with_line_number = config.line_number;
+    let tailed = config.follow && config.tail.is_some();
+    if config.line_number && tailed {
+        return Err(GropError::InvalidArg(String::from(
+            "the line numbers of an input followed from its last lines are unknown",
+        )));
+    }
+    let byte_range = config.from_byte.is_some() || config.to_byte.is_some();
+    if byte_range {
+        if config.reverse {
+            return Err(GropError::InvalidArg(String::from(
+                "the offsets of an input read in reverse can't be tracked",
+            )));
+        }
+        if tailed {
+            return Err(GropError::InvalidArg(String::from(
+                "the offsets of an input followed from its last lines can't be tracked",
+            )));
+        }
+    }
+    if config.line_number && config.from_byte.is_some() {
+        return Err(GropError::InvalidArg(String::from(
+            "the line numbers of an input read from a byte offset are unknown",
+        )));
+    }
+    if let (Some(from_byte), Some(to_byte)) = (config.from_byte, config.to_byte) {
+        if from_byte > to_byte {
+            return Err(GropError::InvalidArg(format!(
+                "invalid byte range {}..{} (the start is past the end)",
+                from_byte, to_byte
+            )));
+        }
+    }
+    emitter.skip = config.skip.unwrap_or(0);
+    emitter.take = config.take;
+    emitter.from_byte = config.from_byte.unwrap_or(0);
+    emitter.to_byte = config.to_byte;
+    if config.provenance {
+        if config.reverse {
+            return Err(GropError::InvalidArg(String::from(
+                "the offsets of an input read in reverse can't be tracked",
+            )));
+        }
+        if tailed {
+            return Err(GropError::InvalidArg(String::from(
+                "the offsets of an input followed from its last lines can't be tracked",
+            )));
+        }
+        emitter.provenance = true;
+    }
+    emitter.summary_only = config.summary_only;
+    emitter.count = config.count;
+    if config.invert_match {
+        if config.output_path.is_some() {
+            return Err(GropError::InvalidArg(String::from(
+                "the lines not matching the expression can't be written by field",
+            )));
+        }
+        emitter.invert_match = true;
+    }
+    if let Some(on_unmatched) = config.on_unmatched {
+        if config.invert_match && on_unmatched != Unmatched::Drop {
+            return Err(GropError::InvalidArg(String::from(
+                "the unmatched lines are already written out with invert_match",
+            )));
+        }
+        emitter.on_unmatched = on_unmatched;
+    }
+    if let Some(exit_on) = &config.exit_on {
+        let (field, pattern) = split_field_pattern(exit_on)?;
+        emitter.exit_on = Some((
+            String::from(field),
+            grok.compile(pattern, false)?,
+            config.exit_code.unwrap_or(0),
+        ));
+    }
+    if let Some(timeout) = &config.timeout {
+        let millis = transform::parse_millis(timeout).ok_or_else(|| {
+            GropError::InvalidArg(format!(
+                "invalid timeout {} (should be a duration like 60s or 1m30s)",
+                timeout
+            ))
+        })?;
+        emitter.deadline = Some(Instant::now() + Duration::from_millis(millis as u64));
+    }
+    if let Some(heartbeat) = &config.heartbeat {
+        let millis = transform::parse_millis(heartbeat)
+            .filter(|millis| *millis > 0.0)
+            .ok_or_else(|| {
+                GropError::InvalidArg(format!(
+                    "invalid heartbeat {} (should be a duration like 30s or 1m)",
+                    heartbeat
+                ))
+            })?;
+        emitter.heartbeat = Some(Duration::from_millis(millis as u64));
+    }
+    emitter.lag = config.lag;
+    emitter.hardened = config.hardened;
+    let hardened = |default| Some(default).filter(|_| config.hardened);
+    emitter.max_line_length = config
+        .max_line_length
+        .or_else(|| hardened(HARDENED_MAX_LINE_LENGTH));
+    emitter.max_merge_lines = config
+        .max_merge_lines
+        .or_else(|| hardened(HARDENED_MAX_MERGE_LINES));
+    emitter.offset = config.offset.unwrap_or(0);
+    emitter.limit = config.limit;
+    if let Some(page) = &config.page {
+        let (offset, limit) = parse_page(page)?;
+        emitter.offset = offset;
+        emitter.limit = Some(limit);
+    }
+    if let Some(path) = &config.checkpoint {
+        if config.output_atomic {
+            return Err(GropError::InvalidArg(String::from(
+                "atomic output can not be resumed from a checkpoint",
+            )));
+        }
+        let interval = match &config.checkpoint_interval {
+            Some(interval) => {
+                Duration::from_millis(transform::parse_millis(interval).ok_or_else(|| {
+                    GropError::InvalidArg(format!(
+                        "invalid checkpoint interval {} (should be a duration like 10s or 1m)",
+                        interval
+                    ))
+                })? as u64)
+            }
+            None => DEFAULT_CHECKPOINT_INTERVAL,
+        };
+        let checkpoint = Checkpoint::load(path.clone(), interval)?;
+        emitter.emitted = checkpoint.resumed_records();
+        emitter.checkpoint = Some(checkpoint);
+    }
+    if let Some(output_path) = &config.output_path {
+        // The output files of the run being resumed are appended to.
+        let mode = match &emitter.checkpoint {
+            Some(checkpoint) if checkpoint.resumed_line() > 0 => OutputMode::Append,
+            _ => config.output_mode.unwrap_or(OutputMode::Truncate),
+        };
+        emitter.partitions = Some(PartitionedWriter::new(
+            output_path,
+            mode,
+            config.output_atomic,
+        )?);
+    }
+    if let Some(tees) = &config.tee {
+        let mode = match &emitter.checkpoint {
+            Some(checkpoint) if checkpoint.resumed_line() > 0 => OutputMode::Append,
+            _ => config.output_mode.unwrap_or(OutputMode::Truncate),
+        };
+        for tee in tees.iter() {
+            let writer = PartitionedWriter::new(&tee.path, mode, config.output_atomic)?;
+            emitter.tees.push((writer, tee.format.clone()));
+        }
+    }
+    Ok(emitter)
+}
+
+/// Match the input lines against the expressions of the config, merging them if configured.
+fn process_input(
+    input: Source,
+    output: &mut dyn Write,
+    config: &Config,
+    emitter: &mut Emitter,
+    grok: &mut PatternCache,
+) -> Result<(), GropError> {
+    let merge_configs = config.expression_merge_configs()?;
+    let merging = merge_configs.iter().any(Option::is_some);
+    let pipeline = config.pipeline()?;
+    let parallel = pipeline.jobs > 1 || pipeline.auto_tune;
+    // The lines coming in over time aren't held back until a batch of them is read.
+    let trickling = config.follow || config.ssh.is_some() || emitter.deadline.is_some();
+    if parallel && (merging || trickling) {
+        log::info!("process: matching the lines sequentially");
+    }
+    match merging {
+        false if parallel && !trickling => process_parallel(
+            input,
+            output,
+            &config.expressions(),
+            &pipeline,
+            emitter,
+            grok,
+        ),
+        false => process(input, output, &config.expressions(), emitter, grok),
+        true => process_merge(
+            input,
+            output,
+            &config.expressions(),
+            &merge_configs,
+            emitter,
+            grok,
+        ),
+    }
+}
+
+/// A match expression, compiled.
+struct Expression {
+    text: String,
+    pattern: Rc<Pattern>,
+    // Id of the expression in the pattern stats.
+    id: usize,
+}
+
+/// The types given to the fields in the expressions, e.g. `%{NUMBER:bytes:int}`.
+fn field_types(expressions: &[String]) -> Result<HashMap<String, FieldType>, GropError> {
+    let mut types = HashMap::new();
+    for expression in expressions.iter() {
+        let mut typed = Vec::new();
+        let mut rest = expression.as_str();
+        while let Some(start) = rest.find("%{") {
+            rest = &rest[start + 2..];
+            let end = rest.find('}').unwrap_or(rest.len());
+            // Without the inline definition of the pattern, if any.
+            let name = rest[..end].split('=').next().unwrap_or_default();
+            if let [_, field, type_name] = name.splitn(3, ':').collect::<Vec<&str>>()[..] {
+                typed.push((field, type_name));
+            }
+            rest = &rest[end..];
+        }
+        let mut rest = expression.as_str();
+        while let Some(start) = rest.find("(?<") {
+            rest = &rest[start + 3..];
+            if let Some(typed_group) = group_name(rest).and_then(|name| name.split_once(':')) {
+                typed.push(typed_group);
+            }
+        }
+        for (field, type_name) in typed {
+            let field_type = FieldType::parse(type_name).ok_or_else(|| {
+                GropError::InvalidArg(format!(
+                    "unknown type {} of field {} (should be int or float)",
+                    type_name, field
+                ))
+            })?;
+            types.insert(String::from(field), field_type);
+        }
+    }
+    Ok(types)
+}
+
+fn compile_expressions(
+    expressions: &[String],
+    grok: &mut PatternCache,
+    stats: &mut PatternStats,
+) -> Result<Vec<Expression>, GropError> {
+    let mut compiled = Vec::new();
+    for text in expressions.iter() {
+        compiled.push(Expression {
+            text: text.clone(),
+            pattern: grok.compile(text, false)?,
+            id: stats.register("expression", text),
+        });
+    }
+    Ok(compiled)
+}
+
+/// Match the text against the expressions in order, returning the index of the first one matching
+/// it along with its matches.
+fn match_first<'a>(
+    expressions: &'a [Expression],
+    text: &'a str,
+    stats: &mut PatternStats,
+) -> Option<(usize, Matches<'a>)> {
+    for (index, expression) in expressions.iter().enumerate() {
+        let m = expression.pattern.match_against(text);
+        stats.hit(expression.id, m.is_some());
+        if let Some(m) = m {
+            return Some((index, m));
+        }
+    }
+    None
+}
+
+fn process(
+    input: Source,
+    output: &mut dyn Write,
+    expressions: &[String],
+    emitter: &mut Emitter,
+    grok: &mut PatternCache,
+) -> Result<(), GropError> {
+    let expressions = compile_expressions(expressions, grok, &mut emitter.stats)?;
+
+    match input {
+        // The lines of a mapped file are read one after the other into the same buffer.
+        Source::Mapped(map) => {
+            let mut lines = MappedLines::new(map, emitter.max_line_length);
+            let mut line = Line::default();
+            while lines.next_into(&mut line) {
+                if !process_next(&line, &expressions, emitter, output)? {
+                    break;
+                }
+            }
+        }
+        input => {
+            let lines = read_lines(
+                input,
+                emitter.deadline,
+                emitter.heartbeat,
+                emitter.max_line_length,
+            )?;
+            for line in lines {
+                emitter.beat(output)?;
+                let line = match line {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    line => line?,
+                };
+                if !process_next(&line, &expressions, emitter, output)? {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Match the line and write it out, unless it's out of the range to process. Returns whether to go
+/// on with the next lines.
+fn process_next(
+    line: &Line,
+    expressions: &[Expression],
+    emitter: &mut Emitter,
+    output: &mut dyn Write,
+) -> Result<bool, GropError> {
+    emitter.start_line(line);
+    if emitter.resumed() {
+        return Ok(true);
+    }
+    match emitter.position(line) {
+        Position::Before => return Ok(true),
+        Position::After => return Ok(false),
+        Position::Within => (),
+    }
+    let m = match_first(expressions, &line.text, &mut emitter.stats)
+        .map(|(index, m)| (index, MatchWrapper::from(m).into()));
+    process_line(line, m, expressions, emitter, output)
+}
+
+/// Write out the line matched by the expression of the index, with the fields it captured, or not
+/// matched. Returns whether to go on with the next lines.
+fn process_line(
+    line: &Line,
+    m: Option<(usize, HashMap<String, String>)>,
+    expressions: &[Expression],
+    emitter: &mut Emitter,
+    output: &mut dyn Write,
+) -> Result<bool, GropError> {
+    emitter.check_line(line, m.is_some())?;
+    if let Some(examples) = &mut emitter.examples {
+        examples.offer(m.as_ref().map(|(index, _)| *index), &line.text);
+    }
+    if let Some(classes) = &mut emitter.classes {
+        let class = classes.classify(m.map(|(index, _)| index));
+        let text = format!("{}\t{}", class, line.text);
+        emitter.pass_through(&text, output)?;
+    } else {
+        match m {
+            Some((index, captures)) => {
+                let record = emitter.record_of(captures, line, expressions, index);
+                emitter.emit(record, output)?;
+            }
+            None if emitter.invert_match => emitter.pass_through(&line.text, output)?,
+            None => emitter.unmatched(line, output)?,
+        }
+    }
+    emitter.save_checkpoint(output)?;
+    Ok(!emitter.stopped())
+}
+
+/// Number of the lines handed to a worker at once, by default.
+const BATCH_LINES: usize = 1024;
+
+/// The numbers of lines a batch benchmarked by the auto-tune mode.
+const TUNED_BATCH_LINES: &[usize] = &[256, 1024, 4096];
+
+/// The auto-tune mode benchmarks the settings on the lines read first, up to the number of them
+/// or for up to the duration.
+const TUNING_LINES: usize = 50_000;
+const TUNING_TIME: Duration = Duration::from_secs(2);
+
+/// How the lines of an input are matched by the pool of worker threads.
+struct Pipeline {
+    jobs: usize,
+    batch_lines: usize,
+    // Whether the number of jobs (up to `jobs`) and of lines a batch are rather picked by
+    // benchmarking a few of them on the first lines of the input.
+    auto_tune: bool,
+}
+
+/// A batch of lines, each with the index of the expression matching it and the fields it captured.
+type MatchedBatch = Vec<(Line, Option<(usize, HashMap<String, String>)>)>;
+
+/// Like `process`, with the lines matched by a pool of worker threads, each with its own compiled
+/// expressions. The lines are handed to the workers in batches, and the batches matched are
+/// written out in input order.
+fn process_parallel(
+    input: Source,
+    output: &mut dyn Write,
+    expressions: &[String],
+    pipeline: &Pipeline,
+    emitter: &mut Emitter,
+    grok: &mut PatternCache,
+) -> Result<(), GropError> {
+    let expressions = compile_expressions(expressions, grok, &mut emitter.stats)?;
+    let mut lines = read_lines(input, emitter.deadline, None, emitter.max_line_length)?;
+    let (jobs, batch_lines) = match pipeline.auto_tune {
+        true => {
+            let started = Instant::now();
+            let mut sample = Vec::new();
+            let mut read_error = None;
+            while sample.len() < TUNING_LINES && started.elapsed() < TUNING_TIME {
+                match lines.next() {
+                    Some(Ok(line)) => sample.push(line),
+                    Some(Err(err)) => {
+                        read_error = Some(err);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            let tuned = tune_pipeline(&sample, &expressions, pipeline.jobs, grok)?;
+            log::info!(
+                "process: matching with {} jobs, {} lines a batch",
+                tuned.0,
+                tuned.1
+            );
+            // The lines benchmarked on are processed like the others.
+            lines = Box::new(
+                sample
+                    .into_iter()
+                    .map(Ok)
+                    .chain(read_error.map(Err))
+                    .chain(lines),
+            );
+            tuned
+        }
+        false => (pipeline.jobs, pipeline.batch_lines),
+    };
+    let (batch_tx, batch_rx) = mpsc::channel::<(u64, Vec<Line>)>();
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    let (matched_tx, matched_rx) = mpsc::channel::<(u64, Option<MatchedBatch>)>();
+    let mut workers = Vec::new();
+    for _ in 0..jobs {
+        let patterns = worker_patterns(&expressions, grok)?;
+        let batch_rx = Arc::clone(&batch_rx);
+        let matched_tx = matched_tx.clone();
+        workers.push(thread::spawn(move || loop {
+            let received = match batch_rx.lock() {
+                Ok(batch_rx) => batch_rx.recv(),
+                Err(_) => break,
+            };
+            let (seq, lines) = match received {
+                Ok(batch) => batch,
+                Err(_) => break,
+            };
+            if matched_tx
+                .send((seq, match_batch(&patterns, lines)))
+                .is_err()
+            {
+                break;
+            }
+        }));
+    }
+    drop(matched_tx);
+
+    // The batches matched ahead of their turn.
+    let mut matched = HashMap::new();
+    let (mut sent, mut next) = (0u64, 0u64);
+    let mut read_error = None;
+    let mut exhausted = false;
+    loop {
+        while !exhausted && sent - next < 2 * jobs as u64 {
+            let mut batch = Vec::with_capacity(batch_lines);
+            while batch.len() < batch_lines {
+                match lines.next() {
+                    Some(Ok(line)) => batch.push(line),
+                    // Reported once the lines read before are written out.
+                    Some(Err(err)) => {
+                        read_error = Some(err);
+                        exhausted = true;
+                        break;
+                    }
+                    None => {
+                        exhausted = true;
+                        break;
+                    }
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            batch_tx.send((sent, batch)).map_err(|_| worker_failed())?;
+            sent += 1;
+        }
+        if next == sent {
+            break;
+        }
+        let batch = loop {
+            if let Some(batch) = matched.remove(&next) {
+                break batch;
+            }
+            match matched_rx.recv() {
+                Ok((seq, Some(batch))) => matched.insert(seq, batch),
+                _ => return Err(worker_failed()),
+            };
+        };
+        next += 1;
+        if !process_matched(batch, &expressions, emitter, output)? {
+            break;
+        }
+    }
+    // The workers still matching batches give up on sending them back.
+    drop(batch_tx);
+    drop(matched_rx);
+    for worker in workers {
+        worker.join().map_err(|_| worker_failed())?;
+    }
+    match read_error {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+/// The expressions compiled for a worker thread, apart from the cache whose patterns stay on this
+/// thread.
+fn worker_patterns(
+    expressions: &[Expression],
+    grok: &mut PatternCache,
+) -> Result<Vec<Pattern>, GropError> {
+    let mut patterns = Vec::new();
+    for expression in expressions.iter() {
+        let text = untyped_groups(&expression.text);
+        patterns.push(Grok::compile(grok, &text, false)?);
+    }
+    Ok(patterns)
+}
+
+/// The number of jobs, up to `max_jobs`, and of lines a batch matching the sample lines the
+/// fastest: powers of two of jobs along with `max_jobs`, each with the numbers of lines a batch
+/// of `TUNED_BATCH_LINES`.
+fn tune_pipeline(
+    sample: &[Line],
+    expressions: &[Expression],
+    max_jobs: usize,
+    grok: &mut PatternCache,
+) -> Result<(usize, usize), GropError> {
+    let mut candidates = Vec::new();
+    let mut jobs = 1;
+    while jobs < max_jobs {
+        candidates.push(jobs);
+        jobs *= 2;
+    }
+    candidates.push(max_jobs);
+    let mut fastest: Option<(Duration, usize, usize)> = None;
+    for jobs in candidates {
+        let mut workers = Vec::new();
+        for _ in 0..jobs {
+            workers.push(worker_patterns(expressions, grok)?);
+        }
+        for batch_lines in TUNED_BATCH_LINES.iter().copied() {
+            let batches = sample.chunks(batch_lines).collect::<Vec<&[Line]>>();
+            let next = AtomicUsize::new(0);
+            let started = Instant::now();
+            thread::scope(|scope| {
+                for patterns in workers.iter_mut() {
+                    let (batches, next) = (&batches, &next);
+                    scope.spawn(move || {
+                        // A line failing to be matched fails the same when processed.
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                            while let Some(batch) =
+                                batches.get(next.fetch_add(1, Ordering::Relaxed))
+                            {
+                                for line in batch.iter() {
+                                    match_line(patterns, &line.text);
+                                }
+                            }
+                        }));
+                    });
+                }
+            });
+            let elapsed = started.elapsed();
+            log::debug!(
+                "process: matched {} lines with {} jobs, {} lines a batch in {:?}",
+                sample.len(),
+                jobs,
+                batch_lines,
+                elapsed
+            );
+            if fastest.is_none_or(|(fastest, _, _)| elapsed < fastest) {
+                fastest = Some((elapsed, jobs, batch_lines));
+            }
+        }
+    }
+    Ok(
+        fastest.map_or((max_jobs, BATCH_LINES), |(_, jobs, batch_lines)| {
+            (jobs, batch_lines)
+        }),
+    )
+}
+
+/// The index of the first pattern matching the text, along with the fields it captured.
+fn match_line(patterns: &[Pattern], text: &str) -> Option<(usize, HashMap<String, String>)> {
+    patterns.iter().enumerate().find_map(|(index, pattern)| {
+        let m = pattern.match_against(text)?;
+        Some((index, MatchWrapper::from(m).into()))
+    })
+}
+
+/// Match the lines against the patterns in order, on a worker thread. A panic matching a line is
+/// told as a missing batch rather than waited on forever.
+fn match_batch(patterns: &[Pattern], lines: Vec<Line>) -> Option<MatchedBatch> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        lines
+            .into_iter()
+            .map(|line| {
+                let m = match_line(patterns, &line.text);
+                (line, m)
+            })
+            .collect()
+    }))
+    .ok()
+}
+
+/// Write out the lines matched by a worker. Returns whether to go on with the next lines of the
+/// input.
+fn process_matched(
+    batch: MatchedBatch,
+    expressions: &[Expression],
+    emitter: &mut Emitter,
+    output: &mut dyn Write,
+) -> Result<bool, GropError> {
+    for (line, m) in batch {
+        emitter.start_line(&line);
+        if emitter.resumed() {
+            continue;
+        }
+        match emitter.position(&line) {
+            Position::Before => continue,
+            Position::After => return Ok(false),
+            Position::Within => (),
+        }
+        // The expressions tried, as they would have been one after the other.
+        let tried = m.as_ref().map_or(expressions.len(), |(index, _)| index + 1);
+        for (index, expression) in expressions[..tried].iter().enumerate() {
+            emitter
+                .stats
+                .hit(expression.id, index + 1 == tried && m.is_some());
+        }
+        if !process_line(&line, m, expressions, emitter, output)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// A batch of lines of the input file of the index, matched or failing to be read, or `None` once
+/// the file is done.
+type FileBatch = (usize, Option<io::Result<MatchedBatch>>);
+
+/// Read the lines of the input file of the index and match them on a thread of its own, sending
+/// them in batches.
+fn spawn_file_worker(
+    index: usize,
+    input: Source,
+    patterns: Vec<Pattern>,
+    max_line_length: Option<usize>,
+    batch_lines: usize,
+    tx: SyncSender<FileBatch>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut lines = match read_lines(input, None, None, max_line_length) {
+            Ok(lines) => lines,
+            Err(err) => {
+                let _ = tx.send((index, Some(Err(err))));
+                return;
+            }
+        };
+        loop {
+            let mut batch = Vec::with_capacity(batch_lines);
+            let mut read_error = None;
+            for line in lines.by_ref() {
+                match line {
+                    Ok(line) => batch.push(line),
+                    Err(err) => {
+                        read_error = Some(err);
+                        break;
+                    }
+                }
+                if batch.len() == batch_lines {
+                    break;
+                }
+            }
+            let done = batch.len() < batch_lines || read_error.is_some();
+            if !batch.is_empty() {
+                let matched = match_batch(&patterns, batch)
+                    .ok_or_else(|| io::Error::other("a worker failed matching the lines"));
+                if tx.send((index, Some(matched))).is_err() {
+                    return;
+                }
+            }
+            if let Some(err) = read_error {
+                let _ = tx.send((index, Some(Err(err))));
+                return;
+            }
+            if done {
+                let _ = tx.send((index, None));
+                return;
+            }
+        }
+    })
+}
+
+/// Like processing the input files one after the other, with up to a number of jobs of them read
+/// and matched at the same time on threads of their own. The records of each file are written out
+/// once it's done when grouping them by file, or else as they come with the file they come from as
+/// the `__file` field, which leads the output format if it's not in it.
+fn process_files(
+    files: &[&PathBuf],
+    output: &mut dyn Write,
+    config: &Config,
+    jobs: usize,
+    emitter: &mut Emitter,
+    grok: &mut PatternCache,
+) -> Result<(), GropError> {
+    let expressions = compile_expressions(&config.expressions(), grok, &mut emitter.stats)?;
+    let batch_lines = config.pipeline()?.batch_lines;
+    // The records written as they come are told apart by their file, like with -H.
+    if !config.group_by_file {
+        emitter.with_filename = true;
+        if let Some(format) = &emitter.output_format {
+            if format != JSON_FORMAT && !format.split(',').any(|field| field == FILE_FIELD) {
+                emitter.output_format = Some(format!("{},{}", FILE_FIELD, format));
+            }
+        }
+        if let Some(fields) = &mut emitter.fields {
+            if !fields.iter().any(|field| field == FILE_FIELD) {
+                fields.insert(0, String::from(FILE_FIELD));
+            }
+        }
+    }
+    let (shared_tx, shared_rx) = mpsc::sync_channel(2 * jobs);
+    // The channel of each file being read, when grouping the records by file.
+    let mut receivers = HashMap::new();
+    let mut base_offsets = Vec::new();
+    // The files whose remaining lines are dropped, being past the range to process.
+    let mut skipped = vec![false; files.len()];
+    let mut workers = Vec::new();
+    let mut done = 0;
+    let result = loop {
+        while workers.len() < files.len() && workers.len() - done < jobs {
+            let index = workers.len();
+            let (source, base_offset) = open_source(config, Some(files[index]))?;
+            base_offsets.push(base_offset);
+            let tx = match config.group_by_file {
+                true => {
+                    let (tx, rx) = mpsc::sync_channel(2);
+                    receivers.insert(index, rx);
+                    tx
+                }
+                false => shared_tx.clone(),
+            };
+            let patterns = worker_patterns(&expressions, grok)?;
+            let worker = spawn_file_worker(
+                index,
+                source,
+                patterns,
+                emitter.max_line_length,
+                batch_lines,
+                tx,
+            );
+            workers.push(worker);
+        }
+        if done == files.len() {
+            break Ok(());
+        }
+        let received = match config.group_by_file {
+            true => receivers[&done].recv(),
+            false => shared_rx.recv(),
+        };
+        let (index, batch) = match received {
+            Ok((index, Some(Ok(batch)))) => (index, batch),
+            Ok((index, None)) => {
+                receivers.remove(&index);
+                done += 1;
+                continue;
+            }
+            Ok((_, Some(Err(err)))) => break Err(err.into()),
+            Err(_) => break Err(worker_failed()),
+        };
+        if skipped[index] {
+            continue;
+        }
+        emitter.file = Some(files[index].display().to_string());
+        emitter.base_offset = base_offsets[index];
+        let go_on = process_matched(batch, &expressions, emitter, output)?;
+        if emitter.stopped() {
+            break Ok(());
+        }
+        skipped[index] = !go_on;
+    };
+    // The workers still reading files give up on sending their lines.
+    drop(receivers);
+    drop(shared_rx);
+    for worker in workers {
+        worker.join().map_err(|_| worker_failed())?;
+    }
+    result
+}
+
+fn worker_failed() -> GropError {
+    GropError::Io(io::Error::other("a worker failed matching the lines"))
+}
+
+/// A merge config, compiled.
+struct Merge<'a> {
+    config: &'a MergeConfig,
+    fields: &'a [String],
+    bounds: Bounds,
+    // Field whose value the lines are grouped by, each value having its own merged section.
+    key_field: Option<&'a String>,
+    // How long the section of a key is kept open without a line merged into it.
+    key_ttl: Option<Duration>,
+}
+
+/// How the merged sections are told apart, along with the ids of the expressions in the pattern
+/// stats.
+enum Bounds {
+    /// From a line matching the start expression to one matching the end expression.
+    StartEnd {
+        start: Rc<Pattern>,
+        end: Rc<Pattern>,
+        id_start: usize,
+        id_end: usize,
+    },
+    /// A record along with the lines after it matching the continuation expression.
+    Continuation {
+        continuation: Rc<Pattern>,
+        id: usize,
+    },
+}
+
+/// A record held for the continuation lines after it, which are appended to its fields.
+struct Continued<'a> {
+    fields: &'a [String],
+    continuation: Rc<Pattern>,
+    id: usize,
+    merged: usize,
+    buf: HashMap<String, String>,
+}
+
+/// A merged record being built.
+struct Scope {
+    // Rank of the scope among the ones opened, to write out the records left at the end of the
+    // input in order.
+    order: usize,
+    merged: usize,
+    buf: HashMap<String, String>,
+    // When a line was last merged into the record.
+    seen: Instant,
+}
+
+impl<'a> Merge<'a> {
+    fn compile(
+        config: &'a MergeConfig,
+        grok: &mut PatternCache,
+        stats: &mut PatternStats,
+    ) -> Result<Merge<'a>, GropError> {
+        let bounds = match config {
+            MergeConfig {
+                merge_fields: Some(_),
+                merge_exp_start: Some(start),
+                merge_exp_end: Some(end),
+                merge_continuation: None,
+                ..
+            } => Bounds::StartEnd {
+                start: grok.compile(start, false)?,
+                end: grok.compile(end, false)?,
+                id_start: stats.register("merge start", start),
+                id_end: stats.register("merge end", end),
+            },
+            MergeConfig {
+                merge_fields: Some(_),
+                merge_exp_start: None,
+                merge_exp_end: None,
+                merge_key_field: None,
+                merge_continuation: Some(continuation),
+                ..
+            } => Bounds::Continuation {
+                continuation: grok.compile(continuation, false)?,
+                id: stats.register("merge continuation", continuation),
+            },
+            _ => {
+                return Err(GropError::InvalidArg(String::from(
+                    "invalid merge option combinations",
+                )))
+            }
+        };
+        let key_ttl = match &config.merge_key_ttl {
+            Some(ttl) => Some(Duration::from_millis(
+                transform::parse_millis(ttl).ok_or_else(|| {
+                    GropError::InvalidArg(format!(
+                        "invalid merge key ttl {} (should be a duration like 30s or 5m)",
+                        ttl
+                    ))
+                })? as u64,
+            )),
+            None => None,
+        };
+        Ok(Merge {
+            config,
+            fields: config.merge_fields.as_deref().unwrap_or_default(),
+            bounds,
+            key_field: config.merge_key_field.as_ref(),
+            key_ttl,
+        })
+    }
+}
+
+/// Match the lines against the expressions, merging them by the merge config of the expression
+/// matching the line which opens the merge scope. Until the scope ends, the following lines are
+/// merged by the same config whichever expression they match. The lines of a merge config with a
+/// key field are rather merged by the value of the field, each in their own scope, the scopes left
+/// open at the end of the input (or idle for longer than the key TTL) being written out. A record
+/// of a merge config with a continuation expression rather takes the lines after it matching the
+/// expression, whether they match one of the expressions or not.
+fn process_merge(
+    input: Source,
+    output: &mut dyn Write,
+    expressions: &[String],
+    merge_configs: &[Option<&MergeConfig>],
+    emitter: &mut Emitter,
+    grok: &mut PatternCache,
+) -> Result<(), GropError> {
+    let expressions = compile_expressions(expressions, grok, &mut emitter.stats)?;
+    // The merge configs shared by several expressions (the common one) are compiled once.
+    let mut merges: Vec<Merge> = Vec::new();
+    let mut selection = Vec::new();
+    for merge_config in merge_configs.iter() {
+        selection.push(match merge_config {
+            None => None,
+            Some(merge_config) => Some(
+                match merges
+                    .iter()
+                    .position(|merge| std::ptr::eq(merge.config, *merge_config))
+                {
+                    Some(k) => k,
+                    None => {
+                        merges.push(Merge::compile(merge_config, grok, &mut emitter.stats)?);
+                        merges.len() - 1
+                    }
+                },
+            ),
+        });
+    }
+    // The open merge scopes, by merge and key (none for the merges without a key field). The scope
+    // of a merge without a key field takes all the lines until it ends.
+    let mut scopes = HashMap::<(usize, Option<String>), Scope>::new();
+    let mut unkeyed: Option<usize> = None;
+    let mut opened = 0;
+    let mut continued: Option<Continued> = None;
+    // The idle keyed scopes are looked for at most once per the shortest key TTL.
+    let sweep_interval = merges.iter().filter_map(|merge| merge.key_ttl).min();
+    let mut last_sweep = Instant::now();
+    // Waiting for the next line of an input gone quiet is given up on for a while to sweep the
+    // idle scopes, as well as to write the heartbeats.
+    let idle = match (emitter.heartbeat, sweep_interval) {
+        (Some(heartbeat), Some(interval)) => Some(heartbeat.min(interval)),
+        (heartbeat, interval) => heartbeat.or(interval),
+    };
+    let lines = read_lines(input, emitter.deadline, idle, emitter.max_line_length)?;
+    for line in lines {
+        emitter.beat(output)?;
+        if sweep_interval.is_some_and(|interval| last_sweep.elapsed() >= interval) {
+            close_idle_scopes(&mut scopes, &merges, emitter, output)?;
+            last_sweep = Instant::now();
+        }
+        let line = match line {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            line => line?,
+        };
+        emitter.start_line(&line);
+        if emitter.resumed() {
+            continue;
+        }
+        match emitter.position(&line) {
+            Position::Before => continue,
+            Position::After => break,
+            Position::Within => (),
+        }
+        if let Some(held) = &mut continued {
+            let matched = held.continuation.match_against(&line.text).is_some();
+            emitter.stats.hit(held.id, matched);
+            if matched {
+                log::info!("process merge: continuation line: {}", line.text);
+                emitter.check_line(&line, true)?;
+                append_line_to_buf(held.fields, &line.text, &mut held.buf);
+                held.merged += 1;
+                if emitter
+                    .max_merge_lines
+                    .is_some_and(|max| held.merged >= max)
+                {
+                    log::warn!(
+                        "process merge: leaving continued record after {} lines: {}",
+                        held.merged,
+                        line.text
+                    );
+                    emitter.emit_merged(std::mem::take(&mut held.buf), false, output)?;
+                    continued = None;
+                }
+                if emitter.stopped() {
+                    break;
+                }
+                continue;
+            }
+            emitter.emit_merged(std::mem::take(&mut held.buf), false, output)?;
+            continued = None;
+        }
+        let m = match_first(&expressions, &line.text, &mut emitter.stats);
+        emitter.check_line(&line, m.is_some())?;
+        if let Some((index, m)) = m {
+            // The lines lacking the key field of their merge aren't merged.
+            let id = unkeyed
+                .or(selection[index])
+                .and_then(|k| match merges[k].key_field {
+                    None => Some((k, None)),
+                    Some(field) => m.get(field).map(|key| (k, Some(String::from(key)))),
+                });
+            match id {
+                None => {
+                    log::info!("process merge: regular line: {}", line.text);
+                    let record = emitter.record(m, &line, &expressions, index);
+                    emitter.emit(record, output)?;
+                }
+                Some(id) => {
+                    let merge = &merges[id.0];
+                    let (start, end, id_start, id_end) = match &merge.bounds {
+                        Bounds::StartEnd {
+                            start,
+                            end,
+                            id_start,
+                            id_end,
+                        } => (start, end, *id_start, *id_end),
+                        Bounds::Continuation { continuation, id } => {
+                            log::info!("process merge: holding for continuation: {}", line.text);
+                            continued = Some(Continued {
+                                fields: merge.fields,
+                                continuation: continuation.clone(),
+                                id: *id,
+                                merged: 1,
+                                buf: emitter.record(m, &line, &expressions, index),
+                            });
+                            if emitter.stopped() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    let match_start = start.match_against(&line.text);
+                    let match_end = end.match_against(&line.text);
+                    emitter.stats.hit(id_start, match_start.is_some());
+                    emitter.stats.hit(id_end, match_end.is_some());
+                    match (scopes.get_mut(&id), match_start, match_end) {
+                        (None, None, _) => {
+                            log::info!("process merge: regular line: {}", line.text);
+                            let record = emitter.record(m, &line, &expressions, index);
+                            emitter.emit(record, output)?;
+                        }
+                        (None, Some(_), _) => {
+                            log::info!("process merge: entering merge scope: {}", line.text);
+                            if id.1.is_none() {
+                                unkeyed = Some(id.0);
+                            }
+                            let scope = Scope {
+                                order: opened,
+                                merged: 1,
+                                buf: emitter.record(m, &line, &expressions, index),
+                                seen: Instant::now(),
+                            };
+                            scopes.insert(id, scope);
+                            opened += 1;
+                        }
+                        (Some(scope), _, None) => {
+                            log::info!("process merge: in scope: {}", line.text);
+                            merge_match_to_buf(merge.fields, &m, &line.text, &mut scope.buf)?;
+                            scope.merged += 1;
+                            scope.seen = Instant::now();
+                            if emitter
+                                .max_merge_lines
+                                .is_some_and(|max| scope.merged >= max)
+                            {
+                                log::warn!(
+                                    "process merge: leaving merge scope after {} lines: {}",
+                                    scope.merged,
+                                    line.text
+                                );
+                                emitter.emit_merged(
+                                    std::mem::take(&mut scope.buf),
+                                    false,
+                                    output,
+                                )?;
+                                close_scope(&mut scopes, &mut unkeyed, &id);
+                            }
+                        }
+                        (Some(scope), match_start, Some(_)) => {
+                            if merge.config.merge_scope_exclusive {
+                                log::info!(
+                                    "process merge: leaving merge scope (exclusive): {}",
+                                    line.text
+                                );
+                                emitter.emit_merged(
+                                    std::mem::take(&mut scope.buf),
+                                    false,
+                                    output,
+                                )?;
+
+                                // In case the end expression is exclusive, we need further check
+                                // if it match the start expression. If so, we will launch a new
+                                // merge section right away.
+                                if match_start.is_some() {
+                                    scope.buf = emitter.record(m, &line, &expressions, index);
+                                    scope.merged = 1;
+                                    scope.seen = Instant::now();
+                                    log::info!("process merge: still in merge scope as ending line match start pattern");
+                                } else {
+                                    // Not match start expression, just output current line and
+                                    // clear buffer and state.
+                                    let record = emitter.record(m, &line, &expressions, index);
+                                    emitter.emit(record, output)?;
+                                    close_scope(&mut scopes, &mut unkeyed, &id);
+                                }
+                            } else {
+                                log::info!(
+                                    "process merge: leaving merge scope (inclusive): {}",
+                                    line.text
+                                );
+                                merge_match_to_buf(merge.fields, &m, &line.text, &mut scope.buf)?;
+                                emitter.emit_merged(
+                                    std::mem::take(&mut scope.buf),
+                                    true,
+                                    output,
+                                )?;
+                                close_scope(&mut scopes, &mut unkeyed, &id);
+                            }
+                        }
+                    }
+                }
+            }
+        } else if emitter.invert_match {
+            emitter.pass_through(&line.text, output)?;
+        } else {
+            emitter.unmatched(&line, output)?;
+        }
+        // A merged record still being built would be lost when resuming after this line.
+        if scopes.is_empty() && continued.is_none() {
+            emitter.save_checkpoint(output)?;
+        }
+        if emitter.stopped() {
+            break;
+        }
+    }
+    if let Some(held) = continued {
+        emitter.emit_merged(held.buf, false, output)?;
+    }
+    // The records whose end never came are written out in the order they were opened: the keyed
+    // ones, and the others if their merge config says so.
+    let mut left = Vec::new();
+    for ((k, key), scope) in scopes {
+        if key.is_some() || merges[k].config.merge_flush_eof {
+            left.push(scope);
+        } else {
+            log::warn!(
+                "process merge: dropping the record left open at the end of the input ({} lines)",
+                scope.merged
+            );
+        }
+    }
+    left.sort_by_key(|scope| scope.order);
+    for scope in left {
+        log::info!("process merge: leaving merge scope at the end of the input");
+        emitter.emit_merged(scope.buf, false, output)?;
+    }
+    Ok(())
+}
+
+/// Write out the records of the keyed scopes idle for longer than the key TTL of their merge, in
+/// the order they were opened, and close the scopes.
+fn close_idle_scopes(
+    scopes: &mut HashMap<(usize, Option<String>), Scope>,
+    merges: &[Merge],
+    emitter: &mut Emitter,
+    output: &mut dyn Write,
+) -> Result<(), GropError> {
+    let mut idle = scopes
+        .iter()
+        .filter(|((k, key), scope)| {
+            key.is_some()
+                && merges[*k]
+                    .key_ttl
+                    .is_some_and(|ttl| scope.seen.elapsed() > ttl)
+        })
+        .map(|(id, scope)| (scope.order, id.clone()))
+        .collect::<Vec<(usize, (usize, Option<String>))>>();
+    idle.sort();
+    for (_, id) in idle {
+        if let Some(scope) = scopes.remove(&id) {
+            log::info!(
+                "process merge: leaving the merge scope of idle key {}",
+                id.1.unwrap_or_default()
+            );
+            emitter.emit_merged(scope.buf, false, output)?;
+        }
+    }
+    Ok(())
+}
+
+/// Close the merge scope, once its record is written out.
+fn close_scope(
+    scopes: &mut HashMap<(usize, Option<String>), Scope>,
+    unkeyed: &mut Option<usize>,
+    id: &(usize, Option<String>),
+) {
+    scopes.remove(id);
+    if id.1.is_none() {
+        *unkeyed = None;
+    }
+}
+
+/// Merge the fields of the line into the record being built, the raw line (when kept) included.
+fn merge_match_to_buf(
+    merge_field: &[String],
+    m: &Matches,
+    text: &str,
+    buf: &mut HashMap<String, String>,
+) -> Result<(), GropError> {
+    if let Some(line) = buf.get_mut(LINE_FIELD) {
+        line.push('\n');
+        line.push_str(text);
+    }
+    for field in merge_field.iter() {
+        let payload = m.get(field).ok_or_else(|| {
+            GropError::InvalidArg(format!("merge_field {} not exists in pattern", field))
+        })?;
+        let merged = match buf.get(field) {
+            Some(o) => format!("{}\n{}", o, payload),
+            None => String::from(payload),
+        };
+        buf.insert(String::from(field), merged);
+    }
+    Ok(())
+}
+
+/// Append the raw line to the fields of the record being built, as well as to the raw line (when
+/// kept).
+fn append_line_to_buf(fields: &[String], text: &str, buf: &mut HashMap<String, String>) {
+    for field in fields.iter().map(String::as_str).chain([LINE_FIELD]) {
+        match buf.get_mut(field) {
+            Some(payload) => {
+                payload.push('\n');
+                payload.push_str(text);
+            }
+            None if field != LINE_FIELD => {
+                buf.insert(String::from(field), String::from(text));
+            }
+            None => (),
+        }
+    }
+}
+
+/// Split a `pattern_name pattern` definition.
+fn split_pattern_definition(p: &str) -> Result<(&str, &str), GropError> {
+    let pt = p
+        .splitn(2, char::is_whitespace)
+        .map(str::trim)
+        .collect::<Vec<&str>>();
+    if pt.len() != 2 {
+        return Err(GropError::InvalidArg(String::from(
+            r#"Invalid pattern (should be "pattern_name pattern")"#,
+        )));
+    }
+    Ok((pt[0], pt[1]))
+}
+
+fn add_pattern(grok: &mut Grok, m: &mut HashMap<String, String>, p: &str) -> Result<(), GropError> {
+    let (name, pattern) = split_pattern_definition(p)?;
+    m.insert(String::from(name), String::from(pattern));
+    grok.insert_definition(String::from(name), String::from(pattern));
+    Ok(())
+}
+
+/// Split a `field_name pattern` pair, as used by filters and conditions.
+fn split_field_pattern(s: &str) -> Result<(&str, &str), GropError> {
+    let ft = s
+        .splitn(2, char::is_whitespace)
+        .map(str::trim)
+        .collect::<Vec<&str>>();
+    if ft.len() != 2 {
+        return Err(GropError::InvalidArg(String::from(
+            r#"Invalid pattern (should be "field_name pattern")"#,
+        )));
+    }
+    Ok((ft[0], ft[1]))
+}
+
+/// Fold a multi-line expression into a single line: `#` comments (at the start of a line or after
+/// a whitespace, `\#` being a literal `#`) are removed, as well as the whitespaces around each
+/// line. Single-line expressions are left as they are.
+fn unfold_expression(expression: &str) -> String {
+    if !expression.contains('\n') {
+        return String::from(expression);
+    }
+    let mut unfolded = String::new();
+    for line in expression.lines() {
+        let mut end = line.len();
+        let mut prev = ' ';
+        for (i, c) in line.char_indices() {
+            if c == '#' && prev.is_whitespace() {
+                end = i;
+                break;
+            }
+            prev = c;
+        }
+        unfolded.push_str(line[..end].trim());
+    }
+    unfolded
+}
+
+/// Position of a line relative to the range of the lines of the input to process.
+enum Position {
+    Before,
+    Within,
+    After,
+}
+
+/// Parse a `N:SIZE` page (N starting at 1) into the offset and limit of its records.
+fn parse_page(page: &str) -> Result<(u64, u64), GropError> {
+    let invalid = || {
+        GropError::InvalidArg(format!(
+            r#"Invalid page {} (should be "N:SIZE", N starting at 1)"#,
+            page
+        ))
+    };
+    let (n, size) = page.split_once(':').ok_or_else(invalid)?;
+    let n = n.trim().parse::<u64>().map_err(|_| invalid())?;
+    let size = size.trim().parse::<u64>().map_err(|_| invalid())?;
+    if n == 0 || size == 0 {
+        return Err(invalid());
+    }
+    Ok(((n - 1) * size, size))
+}
+
+fn parse_tag(t: &str, condition: Option<Condition>) -> Result<Tag, GropError> {
+    let kv = t.splitn(2, '=').collect::<Vec<&str>>();
+    if kv.len() != 2 || kv[0].is_empty() {
+        return Err(GropError::InvalidArg(format!(
+            r#"Invalid tag {} (should be "name=value")"#,
+            t
+        )));
+    }
+    Ok(Tag {
+        name: String::from(kv[0]),
+        value: String::from(kv[1]),
+        condition,
+    })
+}
+
+/// Expand the `%{NAME}` (and `%{NAME:field}`) references of the pattern into the regex they stand
+/// for, recursively. The stack holds the patterns being expanded, to catch the cycles.
+fn expand_pattern(
+    pattern_map: &HashMap<String, String>,
+    pattern: &str,
+    stack: &mut Vec<String>,
+) -> Result<String, GropError> {
+    let mut expanded = String::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find("%{") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        let mut reference = rest[start + 2..end].splitn(3, ':');
+        let name = reference.next().unwrap_or_default();
+        let field = reference.next();
+        if stack.iter().any(|n| n == name) {
+            return Err(GropError::InvalidArg(format!(
+                "Recursive pattern {} ({} -> {})",
+                name,
+                stack.join(" -> "),
+                name
+            )));
+        }
+        let definition = pattern_map
+            .get(name)
+            .ok_or_else(|| GropError::InvalidArg(format!("Unknown pattern {}", name)))?;
+        stack.push(String::from(name));
+        let inner = expand_pattern(pattern_map, definition, stack)?;
+        stack.pop();
+        match field {
+            Some(field) => expanded.push_str(&format!("(?<{}>{})", field, inner)),
+            None => expanded.push_str(&format!("(?:{})", inner)),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Whether the name matches the glob, where `*` stands for any (possibly empty) text.
+fn matches_glob(name: &str, glob: &str) -> bool {
+    let mut parts = glob.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<&str>>();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// List the pattern names, or show the definition of the target pattern along with the regex it
+/// expands to. A target which is not a pattern name is a glob (with `*`) or a case insensitive
+/// substring, listing the matching patterns with their definitions.
+fn list_pattern(
+    pattern_map: &HashMap<String, String>,
+    target_pattern: Option<String>,
+) -> Result<String, GropError> {
+    let mut spatterns = pattern_map
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<Vec<(&str, &str)>>();
+    spatterns.sort_by(|a, b| a.0.cmp(b.0));
+    let target = match target_pattern {
+        Some(target) => target,
+        None => {
+            return Ok(spatterns
+                .iter()
+                .map(|(k, _)| String::from(*k))
+                .collect::<Vec<String>>()
+                .join("\n"))
+        }
+    };
+    if let Some(definition) = pattern_map.get(&target) {
+        let regex = expand_pattern(pattern_map, definition, &mut vec![target.clone()])?;
+        return Ok(format!("definition: {}\nregex: {}", definition, regex));
+    }
+    let matched = spatterns
+        .iter()
+        .filter(|(k, _)| {
+            if target.contains('*') {
+                matches_glob(k, &target)
+            } else {
+                k.to_lowercase().contains(&target.to_lowercase())
+            }
+        })
+        .map(|(k, v)| format!("{} {}", k, v))
+        .collect::<Vec<String>>();
+    if matched.is_empty() {
+        return Err(GropError::InvalidArg(format!(
+            "Unknown target pattern {}",
+            &target
+        )));
+    }
+    Ok(matched.join("\n"))
+}
+
+/// Compile the expression, and describe the regex it expands to along with the fields it captures.
+fn check_expression(
+    grok: &mut Grok,
+    pattern_map: &HashMap<String, String>,
+    expression: &str,
+) -> Result<String, GropError> {
+    let expression = untyped_groups(expression);
+    grok.compile(&expression, true)?;
+    let regex = expand_pattern(pattern_map, &expression, &mut Vec::new())?;
+    let mut fields: Vec<&str> = Vec::new();
+    let mut rest = regex.as_str();
+    while let Some(start) = rest.find("(?<") {
+        rest = &rest[start + 3..];
+        // The lookbehinds, `(?<=` and `(?<!`, don't capture.
+        if let Some(end) = rest.find('>') {
+            let name = &rest[..end];
+            if !name.starts_with(['=', '!']) && !fields.contains(&name) {
+                fields.push(name);
+            }
+        }
+    }
+    Ok(format!("regex: {}\nfields: {}", regex, fields.join(",")))
+}
+
+fn format_output(
+    m: &HashMap<String, String>,
+    format: &Option<String>,
+    filters: &[FieldFilter],
+    types: &HashMap<String, FieldType>,
+    locale: NumberLocale,
+) -> Result<Option<String>, GropError> {
+    if !FieldFilter::keep(filters, m)? {
+        return Ok(None);
+    }
+    render(m, format, false, types, locale).map(Some)
+}
+
+/// Render the record in the output format, with each field in its own color if asked to. The
+/// fields given a type are written as numbers in JSON, read in the locale.
+fn render(
+    m: &HashMap<String, String>,
+    format: &Option<String>,
+    color: bool,
+    types: &HashMap<String, FieldType>,
+    locale: NumberLocale,
+) -> Result<String, GropError> {
+    let values = match format {
+        Some(format) if format == JSON_FORMAT => {
+            // Sorted, so that the records read the same from one run to the other.
+            let record = m
+                .iter()
+                .map(|(k, v)| match types.get(k) {
+                    Some(field_type) => (k, field_type.json(v, locale)),
+                    None => (k, serde_json::Value::from(v.as_str())),
+                })
+                .collect::<BTreeMap<&String, serde_json::Value>>();
+            return serde_json::to_string(&record)
+                .map_err(|err| GropError::InvalidArg(err.to_string()));
+        }
+        Some(format) => format
+            .split(',')
+            .map(|k| {
+                m.get(k).ok_or_else(|| {
+                    GropError::InvalidArg(format!("unknown field in format string: {}", k))
+                })
+            })
+            .collect::<Result<Vec<&String>, GropError>>()?,
+        None => m.values().collect(),
+    };
+    Ok(values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| if color { paint(v, i) } else { v.to_string() })
+        .collect::<Vec<String>>()
+        .join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_add_valid_pattern() {
+        let mut grok = PatternCache::default();
+        let mut pattern_map = HashMap::<String, String>::new();
+        add_pattern(&mut grok, &mut pattern_map, "FOO foo").expect("failed to add pattern");
+        assert_eq!(pattern_map.get("FOO").unwrap(), "foo");
+        let p = grok
+            .compile("%{FOO:foo}", true)
+            .expect("failed to compile pattern");
+        let m = p.match_against("foo").expect("failed to match pattern");
+        assert_eq!(m.get("foo").unwrap(), "foo");
+    }
+
+    #[test]
+    fn test_add_invalid_pattern() {
+        let mut grok = PatternCache::default();
+        let mut pattern_map = HashMap::<String, String>::new();
+        assert!(add_pattern(&mut grok, &mut pattern_map, "FOO,foo").is_err());
+    }
+
+    #[test]
+    fn test_load_patterns() {
+        let dir = std::env::temp_dir().join(format!("grop-load-library-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good"), "FOO foo\n").unwrap();
+        std::fs::write(dir.join("bad"), "BAR\n").unwrap();
+        // The broken library file is left out.
+        let (_, pattern_map) = load_patterns(&None, Some(&dir)).unwrap();
+        assert_eq!(pattern_map.get("FOO").unwrap(), "foo");
+        assert!(!pattern_map.contains_key("BAR"));
+        // The library is only read when given.
+        let (_, pattern_map) = load_patterns(&None, None).unwrap();
+        assert!(!pattern_map.contains_key("FOO"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_pattern() {
+        let mut pattern_map = HashMap::<String, String>::new();
+        pattern_map.insert(String::from("FOO"), String::from("foo"));
+        pattern_map.insert(String::from("BAR"), String::from("bar"));
+        assert_eq!(list_pattern(&pattern_map, None).unwrap(), "BAR\nFOO");
+        assert_eq!(
+            list_pattern(&pattern_map, Some(String::from("FOO"))).unwrap(),
+            "definition: foo\nregex: foo"
+        );
+
+        pattern_map.insert(String::from("FOOBAR"), String::from("%{FOO:f}-%{BAR}"));
+        pattern_map.insert(String::from("LOOP"), String::from("a%{LOOP2}"));
+        pattern_map.insert(String::from("LOOP2"), String::from("%{LOOP}"));
+        assert_eq!(
+            list_pattern(&pattern_map, Some(String::from("FOOBAR"))).unwrap(),
+            "definition: %{FOO:f}-%{BAR}\nregex: (?<f>foo)-(?:bar)"
+        );
+        assert!(list_pattern(&pattern_map, Some(String::from("LOOP"))).is_err());
+        assert_eq!(
+            list_pattern(&pattern_map, Some(String::from("*BAR"))).unwrap(),
+            "BAR bar\nFOOBAR %{FOO:f}-%{BAR}"
+        );
+        assert_eq!(
+            list_pattern(&pattern_map, Some(String::from("oob"))).unwrap(),
+            "FOOBAR %{FOO:f}-%{BAR}"
+        );
+        assert!(list_pattern(&pattern_map, Some(String::from("B*Z"))).is_err());
+        assert!(matches_glob("HTTPDATE", "*HTTP*"));
+        assert!(matches_glob("HTTP", "*HTTP*"));
+        assert!(!matches_glob("HTTPDATE", "HTTP"));
+        assert!(!matches_glob("AB", "A*B*C"));
+    }
+
+    #[test]
+    fn test_check_expression() {
+        let mut grok = PatternCache::default();
+        let mut pattern_map = HashMap::<String, String>::new();
+        add_pattern(&mut grok, &mut pattern_map, "NUM [0-9]+").unwrap();
+        add_pattern(&mut grok, &mut pattern_map, "PAIR %{NUM:a}/%{NUM:b}").unwrap();
+        assert_eq!(
+            check_expression(
+                &mut grok,
+                &pattern_map,
+                "(?<!x)%{PAIR:pair} (?<raw>.) %{NUM:a}"
+            )
+            .unwrap(),
+            "regex: (?<!x)(?<pair>(?<a>[0-9]+)/(?<b>[0-9]+)) (?<raw>.) (?<a>[0-9]+)\n\
+             fields: pair,a,b,raw"
+        );
+        assert!(check_expression(&mut grok, &pattern_map, "%{NUM:n}(").is_err());
+        assert!(check_expression(&mut grok, &pattern_map, "%{UNKNOWN:n}").is_err());
+    }
+
+    #[test]
+    fn test_load_expressions() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '''
+                # Client and level
+                ^%{IP:client}\s+%{LOGLEVEL:lvl}\s+
+                %{GREEDYDATA:msg}  # the rest
+            '''
+            filters = ["lvl %{WORD} ", """msg %{WORD}
+                \\#%{INT}"""]
+            "#,
+        )
+        .unwrap();
+        let config = config.load_expressions().unwrap();
+        assert_eq!(
+            config.match_expression.unwrap(),
+            r"^%{IP:client}\s+%{LOGLEVEL:lvl}\s+%{GREEDYDATA:msg}"
+        );
+        assert_eq!(
+            config.filters.unwrap(),
+            vec!["lvl %{WORD} ", r"msg %{WORD}\#%{INT}"]
+        );
+
+        let path = std::env::temp_dir().join(format!("grop-filters-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Errors only\nlvl ERROR\n\n  -msg %{DATA}health%{DATA}\n",
+        )
+        .unwrap();
+        let config = Config {
+            filter_file: Some(path.clone()),
+            ..toml::from_str("filters = ['host web1']").unwrap()
+        };
+        assert_eq!(
+            config.load_expressions().unwrap().filters.unwrap(),
+            vec!["lvl ERROR", "-msg %{DATA}health%{DATA}", "host web1"]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_format_output() {
+        let mut grok = PatternCache::default();
+        let mut pattern_map = HashMap::<String, String>::new();
+        add_pattern(&mut grok, &mut pattern_map, "FOO foo").expect("failed to add pattern");
+        add_pattern(&mut grok, &mut pattern_map, "BAR bar").expect("failed to add pattern");
+        let p = grok
+            .compile("%{FOO:foo} %{BAR:bar}", true)
+            .expect("failed to compile pattern");
+        let m = p.match_against("foo bar").expect("failed to match pattern");
+        assert_eq!(
+            format_output(
+                &MatchWrapper::from(m).into(),
+                &Some(String::from("bar,foo")),
+                &[],
+                &HashMap::new(),
+                NumberLocale::C,
+            )
+            .unwrap()
+            .unwrap(),
+            "bar foo"
+        );
+    }
+
+    #[test]
+    fn test_format_output_filter() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let m = p.match_against("bar").expect("failed to match pattern");
+        assert_eq!(
+            format_output(
+                &MatchWrapper::from(m).into(),
+                &Some(String::from("data")),
+                &[
+                    FieldFilter::parse("-data ^b", &mut grok).unwrap(),
+                    FieldFilter::parse("data bar", &mut grok).unwrap(),
+                ],
+                &HashMap::new(),
+                NumberLocale::C,
+            )
+            .unwrap()
+            .unwrap(),
+            "bar",
+        );
+    }
+
+    #[test]
+    fn test_emit_tags() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{WORD:lvl} %{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let mut emitter = Emitter::new(Some(String::from("env,severity,data")), Vec::new());
+        emitter.tags.push(parse_tag("env=prod", None).unwrap());
+        emitter.tags.push(parse_tag("severity=low", None).unwrap());
+        emitter.tags.push(
+            parse_tag(
+                "severity=high",
+                Some(Condition::parse(r#"lvl == "ERROR""#, &mut grok).unwrap()),
+            )
+            .unwrap(),
+        );
+        emitter.tags.push(
+            parse_tag(
+                "severity=medium",
+                Some(Condition::parse("data =~ %{INT}ms", &mut grok).unwrap()),
+            )
+            .unwrap(),
+        );
+        let mut output = Cursor::new(Vec::new());
+        for line in &["ERROR boom", "INFO fine", "INFO took 900ms"] {
+            let m = p.match_against(line).expect("failed to match pattern");
+            emitter
+                .emit(MatchWrapper::from(m).into(), &mut output)
+                .expect("failed to emit");
+        }
+        assert_eq!(
+            &output.get_ref()[..],
+            "prod high boom\nprod low fine\nprod medium took 900ms\n".as_bytes()
+        );
+        assert!(parse_tag("novalue", None).is_err());
+    }
+
+    #[test]
+    fn test_emit_fields() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{WORD:lvl} %{WORD:host} %{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let mut emitter = Emitter::new(Some(String::from("data,lvl")), Vec::new());
+        emitter.fields = Some(vec![String::from("data"), String::from("lvl")]);
+        let mut output = Cursor::new(Vec::new());
+        let m = p
+            .match_against("ERROR web01 boom")
+            .expect("failed to match pattern");
+        emitter
+            .emit(MatchWrapper::from(m).into(), &mut output)
+            .expect("failed to emit");
+        assert_eq!(&output.get_ref()[..], "boom ERROR\n".as_bytes());
+
+        // The dropped fields are not available to the output path either.
+        emitter.partitions = Some(
+            PartitionedWriter::new("{host}.log", output::OutputMode::Truncate, false).unwrap(),
+        );
+        let m = p
+            .match_against("ERROR web01 boom")
+            .expect("failed to match pattern");
+        assert!(emitter
+            .emit(MatchWrapper::from(m).into(), &mut output)
+            .is_err());
+    }
+
+    #[test]
+    fn test_emit_tee() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{WORD:lvl} %{WORD:host} %{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let path = std::env::temp_dir().join(format!("grop-tee-{}.ndjson", std::process::id()));
+        let mut emitter = Emitter::new(Some(String::from("data,lvl")), Vec::new());
+        emitter.fields = Some(vec![String::from("data"), String::from("lvl")]);
+        emitter.summary_only = true;
+        emitter.tees.push((
+            PartitionedWriter::new(path.to_str().unwrap(), output::OutputMode::Truncate, false)
+                .unwrap(),
+            Some(String::from(JSON_FORMAT)),
+        ));
+        let mut output = Cursor::new(Vec::new());
+        for line in ["ERROR web01 boom", "INFO web02 fine"].iter() {
+            let m = p.match_against(line).expect("failed to match pattern");
+            emitter
+                .emit(MatchWrapper::from(m).into(), &mut output)
+                .expect("failed to emit");
+        }
+        emitter.finish(&mut output).expect("failed to finish");
+        assert_eq!(&output.get_ref()[..], "2 records\n".as_bytes());
+        // Written in full, whatever the main output.
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"data\":\"boom\",\"host\":\"web01\",\"lvl\":\"ERROR\"}\n\
+             {\"data\":\"fine\",\"host\":\"web02\",\"lvl\":\"INFO\"}\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_emit_error_line() {
+        let mut emitter = Emitter::new(Some(String::from("nope")), Vec::new());
+        emitter.line_number = 42;
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("msg"), String::from("hello"));
+        let err = emitter.emit(m, &mut Cursor::new(Vec::new())).unwrap_err();
+        assert_eq!(err.code(), "invalid-argument");
+        assert_eq!(
+            err.to_string(),
+            "line 42: Invalid argument unknown field in format string: nope"
+        );
+    }
+
+    #[test]
+    fn test_emit_summary_only() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{WORD:lvl} %{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let mut emitter = Emitter::new(
+            None,
+            vec![FieldFilter::parse("-lvl INFO", &mut grok).unwrap()],
+        );
+        emitter.summary_only = true;
+        let mut output = Cursor::new(Vec::new());
+        for line in ["ERROR boom", "INFO fine", "ERROR bang"].iter() {
+            let m = p.match_against(line).expect("failed to match pattern");
+            emitter
+                .emit(MatchWrapper::from(m).into(), &mut output)
+                .expect("failed to emit");
+        }
+        emitter.finish(&mut output).expect("failed to finish");
+        assert_eq!(&output.get_ref()[..], "2 records\n".as_bytes());
+    }
+
+    #[test]
+    fn test_emit_dedup() {
+        let path = std::env::temp_dir().join(format!("grop-dedup-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut grok = PatternCache::default();
+        let exp = [String::from("%{WORD:w}")];
+        let run =
+            |offset: u64, summary_only: bool, input: &'static str, grok: &mut PatternCache| {
+                let mut emitter = Emitter::new(None, Vec::new());
+                emitter.dedup = Some(Deduplicator::load(path.clone()).unwrap());
+                emitter.offset = offset;
+                emitter.summary_only = summary_only;
+                let mut output = Cursor::new(Vec::new());
+                process(
+                    Source::Reader(Box::new(Cursor::new(input.as_bytes()))),
+                    &mut output,
+                    &exp,
+                    &mut emitter,
+                    grok,
+                )
+                .expect("failed to process");
+                emitter.finish(&mut output).expect("failed to finish");
+                String::from_utf8(output.into_inner()).unwrap()
+            };
+        // The records skipped by the offset are not remembered as seen.
+        assert_eq!(run(1, false, "a\nb\nb\n", &mut grok), "b\n");
+        assert_eq!(run(0, false, "a\nb\n", &mut grok), "a\n");
+        // The records counted in summary mode are remembered as well.
+        assert_eq!(run(0, true, "c\nc\n", &mut grok), "1 records\n");
+        assert_eq!(run(0, false, "a\nb\nc\nd\n", &mut grok), "d\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_emit_count() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{WORD:lvl} %{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let mut emitter = Emitter::new(
+            None,
+            vec![FieldFilter::parse("-lvl INFO", &mut grok).unwrap()],
+        );
+        emitter.count = true;
+        let mut output = Cursor::new(Vec::new());
+        for line in ["ERROR boom", "INFO fine", "ERROR bang"].iter() {
+            let m = p.match_against(line).expect("failed to match pattern");
+            emitter
+                .emit(MatchWrapper::from(m).into(), &mut output)
+                .expect("failed to emit");
+        }
+        emitter.finish(&mut output).expect("failed to finish");
+        assert_eq!(&output.get_ref()[..], "2\n".as_bytes());
+    }
+
+    #[test]
+    fn test_emit_squash_repeats() {
+        let mut grok = PatternCache::default();
+        let p = grok
+            .compile("%{WORD:lvl} %{GREEDYDATA:data}", true)
+            .expect("failed to compile pattern");
+        let mut emitter = Emitter::new(Some(String::from("data,_repeat_count")), Vec::new());
+        emitter.squasher = Some(Squasher::new(None));
+        let mut output = Cursor::new(Vec::new());
+        for line in ["ERROR boom", "ERROR boom", "INFO boom", "INFO fine"].iter() {
+            let m = p.match_against(line).expect("failed to match pattern");
+            emitter
+                .emit(MatchWrapper::from(m).into(), &mut output)
+                .expect("failed to emit");
+        }
+        emitter.finish(&mut output).expect("failed to finish");
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "boom 2\nboom 1\nfine 1\n"
+        );
+    }
+
+    #[test]
+    fn test_process() {
+        let mut grok = PatternCache::default();
+        let mut pattern_map = HashMap::<String, String>::new();
+        add_pattern(&mut grok, &mut pattern_map, "FOO foo").expect("failed to add pattern");
+        add_pattern(&mut grok, &mut pattern_map, "BAR bar").expect("failed to add pattern");
+        let exp = String::from("%{FOO:foo} %{BAR:bar}");
+
+        let input = Cursor::new(
+            r#"
+foo bar
+foo
+bar
+            "#
+            .as_bytes(),
+        );
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &[exp],
+            &mut Emitter::new(Some(String::from("foo,bar")), Vec::new()),
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(&output.get_ref()[..], "foo bar\n".as_bytes())
+    }
+
+    #[test]
+    fn test_process_exit_on() {
+        let mut grok = PatternCache::default();
+        let exp = String::from("%{WORD:lvl} %{GREEDYDATA:msg}");
+        let input = Cursor::new("INFO starting\nINFO server started\nINFO serving\n".as_bytes());
+        let mut emitter = Emitter::new(Some(String::from("msg")), Vec::new());
+        emitter.exit_on = Some((
+            String::from("msg"),
+            grok.compile("started", false).unwrap(),
+            3,
+        ));
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &[exp],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(
+            &output.get_ref()[..],
+            "starting\nserver started\n".as_bytes()
+        );
+        assert_eq!(emitter.exit_code, Some(3));
+    }
+
+    #[test]
+    fn test_render_color() {
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("lvl"), String::from("INFO"));
+        m.insert(String::from("msg"), String::from("hi"));
+        assert_eq!(
+            render(
+                &m,
+                &Some(String::from("lvl,msg")),
+                true,
+                &HashMap::new(),
+                NumberLocale::C
+            )
+            .unwrap(),
+            "\x1b[32mINFO\x1b[0m \x1b[33mhi\x1b[0m"
+        );
+        assert_eq!(
+            render(
+                &m,
+                &Some(String::from("lvl,msg")),
+                false,
+                &HashMap::new(),
+                NumberLocale::C
+            )
+            .unwrap(),
+            "INFO hi"
+        );
+    }
+
+    #[test]
+    fn test_typed_json() {
+        let mut grok = PatternCache::default();
+        let exp = String::from(
+            "%{WORD:method} %{INT:status:int} %{NOTSPACE:bytes:int} %{NOTSPACE:took:float}",
+        );
+        let mut emitter = Emitter::new(Some(String::from(JSON_FORMAT)), Vec::new());
+        emitter.types = field_types(std::slice::from_ref(&exp)).unwrap();
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(Cursor::new("GET 200 1.5 2\nPUT 500 - 0.25\n"))),
+            &mut output,
+            &[exp],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "{\"bytes\":1,\"method\":\"GET\",\"status\":200,\"took\":2.0}\n\
+             {\"bytes\":\"-\",\"method\":\"PUT\",\"status\":500,\"took\":0.25}\n"
+        );
+        assert!(field_types(&[String::from("%{INT:n:long}")]).is_err());
+        assert!(field_types(&[String::from("%{INT:n} %{FOO:x=a:b}")])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_inline_groups() {
+        let exp = String::from(r"%{WORD:user} (?<n:int>\d+) x(?<=x)(?<ms:float>[\d.]+)ms");
+        assert_eq!(
+            untyped_groups(&exp),
+            r"%{WORD:user} (?<n>\d+) x(?<=x)(?<ms>[\d.]+)ms"
+        );
+        assert!(field_types(&[String::from(r"(?<n:long>\d+)")]).is_err());
+        let mut grok = PatternCache::default();
+        let mut emitter = Emitter::new(Some(String::from(JSON_FORMAT)), Vec::new());
+        emitter.types = field_types(std::slice::from_ref(&exp)).unwrap();
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(Cursor::new("bob 42 x1234.5ms\nbob 42 y1ms\n"))),
+            &mut output,
+            &[exp],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "{\"ms\":1234.5,\"n\":42,\"user\":\"bob\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_format_output_json() {
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("msg"), String::from("say \"hi\"\n"));
+        m.insert(String::from("lvl"), String::from("INFO"));
+        assert_eq!(
+            format_output(
+                &m,
+                &Some(String::from("json")),
+                &[],
+                &HashMap::new(),
+                NumberLocale::C
+            )
+            .unwrap(),
+            Some(String::from(r#"{"lvl":"INFO","msg":"say \"hi\"\n"}"#))
+        );
+    }
+
+    #[test]
+    fn test_process_warnings() {
+        let mut grok = PatternCache::default();
+        let exp = String::from("^%{INT:n}$");
+        let input = Cursor::new(b"1\nfoo\n2\xff\n3\n".to_vec());
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter
+            .transforms
+            .push(Transform::parse("bytes size", &mut grok).unwrap());
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &[exp],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(
+            emitter.warnings,
+            Warnings {
+                unmatched: 2,
+                invalid_utf8: 1,
+                missing_fields: 2,
+                ..Warnings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_process_min_match_rate() {
+        let mut grok = PatternCache::default();
+        let run = |emitter: &mut Emitter, grok: &mut PatternCache| {
+            let input = Cursor::new("1\nx\ny\n2\nz\n".as_bytes());
+            process(
+                Source::Reader(Box::new(input)),
+                &mut Cursor::new(Vec::new()),
+                &[String::from("^%{INT:n}$")],
+                emitter,
+                grok,
+            )
+        };
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.min_match_rate = Some((0.5, 3, false));
+        assert_eq!(
+            run(&mut emitter, &mut grok).unwrap_err().to_string(),
+            "Match rate too low: 33.3% of the first 3 lines matched the expression, expected at least 50.0%"
+        );
+
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.min_match_rate = Some((0.5, 3, true));
+        assert!(run(&mut emitter, &mut grok).is_ok());
+
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.min_match_rate = Some((0.3, 3, false));
+        assert!(run(&mut emitter, &mut grok).is_ok());
+    }
+
+    #[test]
+    fn test_process_heartbeat() {
+        // Reads a line, then nothing for a while before the next one.
+        struct Pausing(Vec<&'static str>);
+        impl Read for Pausing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.pop() {
+                    Some("") => {
+                        std::thread::sleep(Duration::from_millis(300));
+                        self.read(buf)
+                    }
+                    Some(line) => {
+                        buf[..line.len()].copy_from_slice(line.as_bytes());
+                        Ok(line.len())
+                    }
+                    None => Ok(0),
+                }
+            }
+        }
+        let mut grok = PatternCache::default();
+        let mut emitter = Emitter::new(Some(String::from("w")), Vec::new());
+        emitter.heartbeat = Some(Duration::from_millis(100));
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(Pausing(vec!["b\n", "", "a\n"]))),
+            &mut output,
+            &[String::from("%{WORD:w}")],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        let lines = output.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.first(), Some(&"a"));
+        assert_eq!(lines.last(), Some(&"b"));
+        assert!(lines.len() > 2);
+        for line in &lines[1..lines.len() - 1] {
+            assert!(line.starts_with("heartbeat ") && line.ends_with(": 1 records, 1 lines"));
+        }
+    }
+
+    #[test]
+    fn test_process_window() {
+        let mut grok = PatternCache::default();
+        let exp = String::from("%{INT:n}");
+        let input = Cursor::new("1\n2\n3\n4\n5\n6\n7\n".as_bytes());
+        let mut emitter = Emitter::new(None, vec![FieldFilter::parse("-n 2", &mut grok).unwrap()]);
+        let (offset, limit) = parse_page("2:2").unwrap();
+        emitter.offset = offset;
+        emitter.limit = Some(limit);
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &[exp],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        // The filtered out records are not counted.
+        assert_eq!(&output.get_ref()[..], "4\n5\n".as_bytes());
+
+        // Nothing is read past the record reaching the limit.
+        struct Unreadable;
+        impl Read for Unreadable {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("read past the limit"))
+            }
+        }
+        let input = Cursor::new("1\n2\n".as_bytes()).chain(Unreadable);
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.limit = Some(2);
+        process(
+            Source::Reader(Box::new(input)),
+            &mut Cursor::new(Vec::new()),
+            &[String::from("%{INT:n}")],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert!(parse_page("0:10").is_err());
+        assert!(parse_page("3").is_err());
     }
 
     #[test]
-    fn test_add_invalid_pattern() {
-        let mut grok = Grok::default();
-        let mut pattern_map = HashMap::<String, String>::new();
-        assert!(add_pattern(&mut grok, &mut pattern_map, "FOO,foo").is_err());
+    fn test_process_range() {
+        let mut grok = PatternCache::default();
+        let run = |emitter: &mut Emitter, grok: &mut PatternCache| {
+            let input = Cursor::new("1\n2\n3\n4\n5\n6\n7\n".as_bytes());
+            let mut output = Cursor::new(Vec::new());
+            process(
+                Source::Reader(Box::new(input)),
+                &mut output,
+                &[String::from("%{INT:n}")],
+                emitter,
+                grok,
+            )
+            .expect("failed to process");
+            String::from_utf8(output.into_inner()).unwrap()
+        };
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.skip = 2;
+        emitter.take = Some(3);
+        assert_eq!(run(&mut emitter, &mut grok), "3\n4\n5\n");
+        // The lines starting from the first byte offset, up to the second one.
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.from_byte = 3;
+        emitter.to_byte = Some(10);
+        assert_eq!(run(&mut emitter, &mut grok), "3\n4\n5\n");
+        // The offsets of the lines of an input opened at a byte offset start from there.
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.base_offset = 4;
+        emitter.from_byte = 4;
+        emitter.to_byte = Some(10);
+        assert_eq!(run(&mut emitter, &mut grok), "1\n2\n3\n");
     }
 
     #[test]
-    fn test_list_pattern() {
-        let mut pattern_map = HashMap::<String, String>::new();
-        pattern_map.insert(String::from("FOO"), String::from("foo"));
-        pattern_map.insert(String::from("BAR"), String::from("bar"));
-        assert_eq!(list_pattern(&pattern_map, None).unwrap(), "BAR\nFOO");
+    fn test_process_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("grop-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("progress");
+        // Killed after the first 2 lines, which emitted 2 records.
+        std::fs::write(&path, "line = 2\nrecords = 2\n").unwrap();
+
+        let mut grok = PatternCache::default();
+        let input = Cursor::new("1\n2\n3\n4\n5\n".as_bytes());
+        let mut emitter = Emitter::new(None, Vec::new());
+        let checkpoint = Checkpoint::load(path.clone(), Duration::from_secs(0)).unwrap();
+        emitter.emitted = checkpoint.resumed_records();
+        emitter.checkpoint = Some(checkpoint);
+        emitter.limit = Some(4);
+        let mut output = Cursor::new(Vec::new());
+        process(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &[String::from("%{INT:n}")],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        // The limit counts the records of the resumed run as well.
+        assert_eq!(&output.get_ref()[..], "3\n4\n".as_bytes());
         assert_eq!(
-            list_pattern(&pattern_map, Some(String::from("FOO"))).unwrap(),
-            "foo"
+            std::fs::read_to_string(&path).unwrap(),
+            "line = 4\nrecords = 4\n"
         );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_format_output() {
-        let mut grok = Grok::default();
-        let mut pattern_map = HashMap::<String, String>::new();
-        add_pattern(&mut grok, &mut pattern_map, "FOO foo").expect("failed to add pattern");
-        add_pattern(&mut grok, &mut pattern_map, "BAR bar").expect("failed to add pattern");
-        let p = grok
-            .compile("%{FOO:foo} %{BAR:bar}", true)
-            .expect("failed to compile pattern");
-        let m = p.match_against("foo bar").expect("failed to match pattern");
+    fn test_process_checkpoint_continuation() {
+        let dir = std::env::temp_dir().join(format!("grop-resume-cont-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("progress");
+        let config: Config =
+            toml::from_str("[merge_config]\nmerge_fields = ['w']\nmerge_continuation = '^\\s'")
+                .unwrap();
+
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.checkpoint = Some(Checkpoint::load(path.clone(), Duration::from_secs(0)).unwrap());
+        process_merge(
+            Source::Reader(Box::new(Cursor::new(
+                "1 a\n  x\nzzz\n2 b\n  y\n".as_bytes(),
+            ))),
+            &mut Cursor::new(Vec::new()),
+            &[String::from("%{INT:n} %{WORD:w}")],
+            &config.expression_merge_configs().unwrap(),
+            &mut emitter,
+            &mut PatternCache::default(),
+        )
+        .expect("failed to process");
+        // No progress is saved while a record is held for its continuation lines.
         assert_eq!(
-            format_output(
-                &MatchWrapper::from(m).into(),
-                &Some(String::from("bar,foo")),
-                &None,
-                &mut grok,
-            )
-            .unwrap()
-            .unwrap(),
-            "bar foo"
+            std::fs::read_to_string(&path).unwrap(),
+            "line = 3\nrecords = 1\n"
         );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_format_output_filter() {
-        let mut grok = Grok::default();
-        let p = grok
-            .compile("%{GREEDYDATA:data}", true)
-            .expect("failed to compile pattern");
-        let m = p.match_against("bar").expect("failed to match pattern");
-        assert_eq!(
-            format_output(
-                &MatchWrapper::from(m).into(),
-                &Some(String::from("data")),
-                &Some(vec![String::from("-data ^b"), String::from("data bar")]),
-                &mut grok,
-            )
+    fn test_open_source_mmap() {
+        let path = std::env::temp_dir().join(format!("grop-mmap-{}", std::process::id()));
+        std::fs::write(&path, "a\n").unwrap();
+        for (settings, mapped) in [
+            ("", false),
+            ("mmap = true", true),
+            ("mmap = true\nhardened = true", false),
+        ] {
+            let config: Config = toml::from_str(settings).unwrap();
+            let (source, _) = open_source(&config, Some(&path)).unwrap();
+            assert_eq!(matches!(source, Source::Mapped(_)), mapped, "{}", settings);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_process_inputs() {
+        let dir = std::env::temp_dir().join(format!("grop-inputs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.log"), dir.join("b.log"));
+        std::fs::write(&a, "1\nx\n2\n").unwrap();
+        std::fs::write(&b, "3\n").unwrap();
+        let mut config: Config = toml::from_str("match_expression = '%{INT:n}'").unwrap();
+        config.input = vec![a.clone(), b.clone()];
+
+        let mut grok = PatternCache::default();
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.with_filename = true;
+        emitter.with_line_number = true;
+        emitter.records = Some(Vec::new());
+        process_inputs(
+            &mut Cursor::new(Vec::new()),
+            &config,
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        let records = emitter
+            .records
             .unwrap()
-            .unwrap(),
-            "bar",
+            .into_iter()
+            .map(|r| {
+                (
+                    r["n"].clone(),
+                    r[FILE_FIELD].clone(),
+                    r[LINE_NUMBER_FIELD].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let (a, b) = (a.display().to_string(), b.display().to_string());
+        assert_eq!(
+            records,
+            vec![
+                (String::from("1"), a.clone(), String::from("1")),
+                (String::from("2"), a, String::from("3")),
+                (String::from("3"), b, String::from("1")),
+            ]
         );
+        assert_eq!(emitter.lines, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_process() {
-        let mut grok = Grok::default();
-        let mut pattern_map = HashMap::<String, String>::new();
-        add_pattern(&mut grok, &mut pattern_map, "FOO foo").expect("failed to add pattern");
-        add_pattern(&mut grok, &mut pattern_map, "BAR bar").expect("failed to add pattern");
-        let exp = String::from("%{FOO:foo} %{BAR:bar}");
+    fn test_process_files() {
+        let dir = std::env::temp_dir().join(format!("grop-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(format!("{}.log", name));
+                let lines = (0..3000).map(|i| format!("{} {}", name, i));
+                std::fs::write(&path, lines.collect::<Vec<String>>().join("\n")).unwrap();
+                path
+            })
+            .collect::<Vec<PathBuf>>();
+        let run = |jobs: usize, group_by_file: bool, with_filename: bool| {
+            let format = match with_filename {
+                true => "__file,f,n,__line",
+                false => "f,n,__line",
+            };
+            let mut config: Config = toml::from_str(&format!(
+                r#"
+                match_expression = '%{{WORD:f}} %{{INT:n}}'
+                output_format = '{}'
+                line_number = true
+                jobs = {}
+                group_by_file = {}
+                with_filename = {}
+                "#,
+                format, jobs, group_by_file, with_filename
+            ))
+            .unwrap();
+            config.input = files.clone();
+            let mut grok = PatternCache::default();
+            let mut emitter = build_emitter(&config, &mut grok).unwrap();
+            let mut output = Vec::new();
+            process_inputs(&mut output, &config, &mut emitter, &mut grok).unwrap();
+            assert_eq!(emitter.lines, 9000);
+            String::from_utf8(output).unwrap()
+        };
+        let sequential = run(1, false, true);
+        assert_eq!(run(2, true, true), sequential);
 
-        let input = Cursor::new(
-            r#"
-foo bar
-foo
-bar
-            "#
-            .as_bytes(),
+        // Each file keeps its order among the records of the others, which come with their file
+        // ahead of them whether asked for or not.
+        let interleaved = run(2, false, false);
+        for (name, path) in ["a", "b", "c"].iter().zip(files.iter()) {
+            let prefix = format!("{} ", path.display());
+            let records = interleaved
+                .lines()
+                .filter_map(|line| line.strip_prefix(&prefix))
+                .collect::<Vec<&str>>();
+            let expected = (0..3000)
+                .map(|i| format!("{} {} {}", name, i, i + 1))
+                .collect::<Vec<String>>();
+            assert_eq!(records, expected);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_process_provenance() {
+        let mut grok = PatternCache::default();
+        let input = Cursor::new("a\nbb\nc\n".as_bytes());
+        let mut emitter = Emitter::new(None, vec![FieldFilter::parse("-w bb", &mut grok).unwrap()]);
+        emitter.file = Some(String::from("app.log"));
+        emitter.provenance = true;
+        emitter.records = Some(Vec::new());
+        process(
+            Source::Reader(Box::new(input)),
+            &mut Cursor::new(Vec::new()),
+            &[String::from("%{WORD:w}")],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        let records = emitter.records.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["w"], "c");
+        assert_eq!(records[1][SOURCE_FIELD], "app.log");
+        assert_eq!(records[1][OFFSET_FIELD], "5");
+        assert_eq!(records[1][EXPRESSION_FIELD], "%{WORD:w}");
+        assert!(records[1][INGESTED_AT_FIELD].ends_with('Z'));
+    }
+
+    #[test]
+    fn test_emit_lag() {
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.lag = true;
+        emitter.records = Some(Vec::new());
+        let minute_ago = format_timestamp(&(Utc::now() - chrono::Duration::minutes(1)));
+        for timestamp in [minute_ago.as_str(), "never"].iter() {
+            let mut m = HashMap::new();
+            m.insert(String::from("timestamp"), String::from(*timestamp));
+            emitter.emit(m, &mut Cursor::new(Vec::new())).unwrap();
+        }
+        let records = emitter.records.unwrap();
+        let lag = records[0][LAG_FIELD].parse::<i64>().unwrap();
+        assert!((60_000..70_000).contains(&lag), "{}", lag);
+        // Unless the record has no timestamp.
+        assert!(!records[1].contains_key(LAG_FIELD));
+    }
+
+    #[test]
+    fn test_process_expressions() {
+        let mut grok = PatternCache::default();
+        let input = Cursor::new("GET /a 200\nerror: disk full\n???\nPUT /b 201\n".as_bytes());
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.provenance = true;
+        emitter.stats = PatternStats::new(true);
+        emitter.records = Some(Vec::new());
+        let expressions = [
+            String::from("^%{WORD:method} %{NOTSPACE:path} %{INT:status}$"),
+            String::from("^error: %{GREEDYDATA:msg}$"),
+        ];
+        process(
+            Source::Reader(Box::new(input)),
+            &mut Cursor::new(Vec::new()),
+            &expressions,
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        let records = emitter.records.unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0][PATTERN_FIELD], "1");
+        assert_eq!(records[1][PATTERN_FIELD], "2");
+        assert_eq!(records[1]["msg"], "disk full");
+        assert_eq!(records[1][EXPRESSION_FIELD], expressions[1]);
+        assert_eq!(records[2]["path"], "/b");
+        assert_eq!(emitter.warnings.unmatched, 1);
+
+        // The expressions are rather named by their expression names, if given.
+        let mut named = Emitter::new(None, Vec::new());
+        named.expression_names = vec![String::from("access"), String::from("error")];
+        named.records = Some(Vec::new());
+        process(
+            Source::Reader(Box::new(Cursor::new("error: x\n".as_bytes()))),
+            &mut Cursor::new(Vec::new()),
+            &expressions,
+            &mut named,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(named.records.unwrap()[0][PATTERN_FIELD], "error");
+
+        // The later expressions are only tried on the lines the earlier ones didn't match.
+        let mut report = Vec::new();
+        emitter.stats.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let counts = report
+            .lines()
+            .skip(1)
+            .map(|l| l.split_whitespace().skip(1).take(2).collect::<Vec<&str>>())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![vec!["2", "2"], vec!["1", "1"]]);
+
+        let config: Config = toml::from_str("match_expressions = ['a', 'b']").unwrap();
+        assert_eq!(config.expressions(), vec!["a", "b"]);
+        let config = config.merge(toml::from_str("match_expression = 'c'").unwrap());
+        assert_eq!(config.expressions(), vec!["c"]);
+    }
+
+    #[test]
+    fn test_merge_output_format() {
+        // The output format given on the command line wins over the fields of the config file.
+        let config: Config = toml::from_str("fields = ['a']").unwrap();
+        let config = config.merge(toml::from_str("output_format = 'json'").unwrap());
+        assert_eq!(config.fields, None);
+        assert_eq!(config.output_format.as_deref(), Some(JSON_FORMAT));
+        let config = config.merge(toml::from_str("fields = ['b']").unwrap());
+        assert_eq!(config.fields, Some(vec![String::from("b")]));
+        assert_eq!(config.output_format, None);
+    }
+
+    #[test]
+    fn test_process_invert_match() {
+        let mut grok = PatternCache::default();
+        let input = "INFO up\nnoise\nERROR down\nINFO again\n";
+        let exp = String::from("^%{WORD:lvl} %{GREEDYDATA:msg}$");
+        let mut emitter = Emitter::new(
+            None,
+            vec![FieldFilter::parse("-lvl INFO", &mut grok).unwrap()],
         );
+        emitter.invert_match = true;
         let mut output = Cursor::new(Vec::new());
         process(
-            Box::new(input),
+            Source::Reader(Box::new(Cursor::new(input.as_bytes()))),
             &mut output,
-            &Some(exp),
-            &Some(String::from("foo,bar")),
-            &None,
+            std::slice::from_ref(&exp),
+            &mut emitter,
             &mut grok,
         )
         .expect("failed to process");
-        assert_eq!(&output.get_ref()[..], "foo bar\n".as_bytes())
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "INFO up\nnoise\nINFO again\n"
+        );
+
+        // A merged record filtered out is written out as all its lines.
+        let mut emitter = Emitter::new(
+            None,
+            vec![FieldFilter::parse("-lvl INFO", &mut grok).unwrap()],
+        );
+        emitter.invert_match = true;
+        let input = "INFO up\nnoise\nERROR START a\nINFO b\nERROR END c\nINFO START d\nWARN e\nINFO END f\n";
+        let mut output = Cursor::new(Vec::new());
+        process_merge(
+            Source::Reader(Box::new(Cursor::new(input.as_bytes()))),
+            &mut output,
+            &[exp],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("msg")]),
+                merge_exp_start: Some(String::from("START")),
+                merge_exp_end: Some(String::from("END")),
+                merge_scope_exclusive: false,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "INFO up\nnoise\nINFO START d\nWARN e\nINFO END f\n"
+        );
+    }
+
+    #[test]
+    fn test_process_on_unmatched() {
+        let mut grok = PatternCache::default();
+        let input = "INFO up\nnoise\nERROR down\n";
+        let exp = String::from("^%{WORD:lvl} %{GREEDYDATA:msg}$");
+        let cases = [
+            (Unmatched::Print, Some("lvl"), "INFO\nnoise\nERROR\n"),
+            (Unmatched::Drop, Some("lvl"), "INFO\nERROR\n"),
+            (Unmatched::Tag, Some("json"), "{\"lvl\":\"INFO\",\"msg\":\"up\"}\n{\"__unmatched\":\"noise\"}\n{\"lvl\":\"ERROR\",\"msg\":\"down\"}\n"),
+        ];
+        for (on_unmatched, format, expected) in cases.iter() {
+            let mut emitter = Emitter::new(format.map(String::from), Vec::new());
+            emitter.on_unmatched = *on_unmatched;
+            let mut output = Cursor::new(Vec::new());
+            process(
+                Source::Reader(Box::new(Cursor::new(input.as_bytes()))),
+                &mut output,
+                std::slice::from_ref(&exp),
+                &mut emitter,
+                &mut grok,
+            )
+            .expect("failed to process");
+            assert_eq!(String::from_utf8(output.into_inner()).unwrap(), *expected);
+        }
+
+        let mut emitter = Emitter::new(None, Vec::new());
+        emitter.on_unmatched = Unmatched::Fail;
+        let result = process(
+            Source::Reader(Box::new(Cursor::new(input.as_bytes()))),
+            &mut Cursor::new(Vec::new()),
+            &[exp],
+            &mut emitter,
+            &mut grok,
+        );
+        match result {
+            Err(GropError::Input { line, err, .. }) => {
+                assert_eq!(line, 2);
+                assert!(matches!(*err, GropError::Unmatched(_)));
+            }
+            _ => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
     }
 
     #[test]
     fn test_process_merge_inclusive() {
-        let mut grok = Grok::default();
+        let mut grok = PatternCache::default();
         let mut pattern_map = HashMap::<String, String>::new();
         add_pattern(&mut grok, &mut pattern_map, "PREFIX =").expect("failed to add pattern");
         let exp = String::from("%{PREFIX:prefix} %{GREEDYDATA:greedydata}");
@@ -611,15 +4954,20 @@ bar
         );
         let mut output = Cursor::new(Vec::new());
         process_merge(
-            Box::new(input),
+            Source::Reader(Box::new(input)),
             &mut output,
-            &Some(exp),
-            &Some(String::from("prefix,greedydata")),
-            &vec![String::from("greedydata")],
-            "%{PREFIX} START",
-            "%{PREFIX} END",
-            false,
-            &None,
+            &[exp],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("greedydata")]),
+                merge_exp_start: Some(String::from("%{PREFIX} START")),
+                merge_exp_end: Some(String::from("%{PREFIX} END")),
+                merge_scope_exclusive: false,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut Emitter::new(Some(String::from("prefix,greedydata")), Vec::new()),
             &mut grok,
         )
         .expect("failed to process");
@@ -634,11 +4982,40 @@ END 4
 "#
             .as_bytes()
         );
+
+        // The start and end lines of the merged record stand out in color.
+        let input = Cursor::new("= START 2\n= 3\n= END 4\n= 5\n".as_bytes());
+        let mut output = Cursor::new(Vec::new());
+        let mut emitter = Emitter::new(Some(String::from("greedydata")), Vec::new());
+        emitter.color = true;
+        process_merge(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &[String::from("%{PREFIX:prefix} %{GREEDYDATA:greedydata}")],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("greedydata")]),
+                merge_exp_start: Some(String::from("%{PREFIX} START")),
+                merge_exp_end: Some(String::from("%{PREFIX} END")),
+                merge_scope_exclusive: false,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut emitter,
+            &mut grok,
+        )
+        .expect("failed to process");
+        assert_eq!(
+            std::str::from_utf8(output.get_ref()).unwrap(),
+            "\x1b[1m\x1b[32mSTART 2\x1b[22m\n\x1b[2m3\x1b[22m\n\x1b[1mEND 4\x1b[0m\x1b[1m\x1b[22m\n\
+             \x1b[32m5\x1b[0m\n"
+        );
     }
 
     #[test]
     fn test_process_merge_exclusive() {
-        let mut grok = Grok::default();
+        let mut grok = PatternCache::default();
         let mut pattern_map = HashMap::<String, String>::new();
         add_pattern(&mut grok, &mut pattern_map, "PREFIX =").expect("failed to add pattern");
         let exp = String::from("%{PREFIX:prefix} %{GREEDYDATA:greedydata}");
@@ -655,15 +5032,20 @@ END 4
         );
         let mut output = Cursor::new(Vec::new());
         process_merge(
-            Box::new(input),
+            Source::Reader(Box::new(input)),
             &mut output,
-            &Some(exp),
-            &Some(String::from("prefix,greedydata")),
-            &vec![String::from("greedydata")],
-            "%{PREFIX} REQUEST",
-            "%{PREFIX} RESPONSE",
-            true,
-            &None,
+            &[exp],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("greedydata")]),
+                merge_exp_start: Some(String::from("%{PREFIX} REQUEST")),
+                merge_exp_end: Some(String::from("%{PREFIX} RESPONSE")),
+                merge_scope_exclusive: true,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut Emitter::new(Some(String::from("prefix,greedydata")), Vec::new()),
             &mut grok,
         )
         .expect("failed to process");
@@ -682,7 +5064,7 @@ END 4
 
     #[test]
     fn test_process_merge_exclusive_mono_pattern() {
-        let mut grok = Grok::default();
+        let mut grok = PatternCache::default();
         let exp = String::from("%{GREEDYDATA:greedydata}");
 
         let input = Cursor::new(
@@ -699,17 +5081,23 @@ END 4
 "#
             .as_bytes(),
         );
+        let filters = vec![FieldFilter::parse("-greedydata REQUEST", &mut grok).unwrap()];
         let mut output = Cursor::new(Vec::new());
         process_merge(
-            Box::new(input),
+            Source::Reader(Box::new(input)),
             &mut output,
-            &Some(exp),
-            &Some(String::from("greedydata")),
-            &vec![String::from("greedydata")],
-            "= REQUEST|RESPONSE",
-            "= ",
-            true,
-            &Some(vec![String::from("-greedydata REQUEST")]),
+            &[exp],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("greedydata")]),
+                merge_exp_start: Some(String::from("= REQUEST|RESPONSE")),
+                merge_exp_end: Some(String::from("= ")),
+                merge_scope_exclusive: true,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut Emitter::new(Some(String::from("greedydata")), filters),
             &mut grok,
         )
         .expect("failed to process");
@@ -730,7 +5118,7 @@ END 4
 
     #[test]
     fn test_process_merge_exclusive_continue() {
-        let mut grok = Grok::default();
+        let mut grok = PatternCache::default();
         let mut pattern_map = HashMap::<String, String>::new();
         add_pattern(&mut grok, &mut pattern_map, "PREFIX =").expect("failed to add pattern");
         let exp = String::from("%{PREFIX:prefix} %{GREEDYDATA:greedydata}");
@@ -748,15 +5136,20 @@ END 4
         );
         let mut output = Cursor::new(Vec::new());
         process_merge(
-            Box::new(input),
+            Source::Reader(Box::new(input)),
             &mut output,
-            &Some(exp),
-            &Some(String::from("prefix,greedydata")),
-            &vec![String::from("greedydata")],
-            "%{PREFIX} DEBUG REQUEST|RESPONSE",
-            "%{PREFIX} DEBUG",
-            true,
-            &None,
+            &[exp],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("greedydata")]),
+                merge_exp_start: Some(String::from("%{PREFIX} DEBUG REQUEST|RESPONSE")),
+                merge_exp_end: Some(String::from("%{PREFIX} DEBUG")),
+                merge_scope_exclusive: true,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut Emitter::new(Some(String::from("prefix,greedydata")), Vec::new()),
             &mut grok,
         )
         .expect("failed to process");
@@ -776,7 +5169,7 @@ END 4
 
     #[test]
     fn test_process_merge_exclusive_continue_filter() {
-        let mut grok = Grok::default();
+        let mut grok = PatternCache::default();
         let mut pattern_map = HashMap::<String, String>::new();
         add_pattern(&mut grok, &mut pattern_map, "PREFIX =").expect("failed to add pattern");
         let exp = String::from("%{PREFIX:prefix} %{GREEDYDATA:greedydata}");
@@ -796,20 +5189,26 @@ END 4
             "#
             .as_bytes(),
         );
+        let filters = vec![
+            FieldFilter::parse("-greedydata n", &mut grok).unwrap(),
+            FieldFilter::parse("greedydata y", &mut grok).unwrap(),
+        ];
         let mut output = Cursor::new(Vec::new());
         process_merge(
-            Box::new(input),
+            Source::Reader(Box::new(input)),
             &mut output,
-            &Some(exp),
-            &Some(String::from("prefix,greedydata")),
-            &vec![String::from("greedydata")],
-            "%{PREFIX} DEBUG REQUEST|RESPONSE",
-            "%{PREFIX} DEBUG",
-            true,
-            &Some(vec![
-                String::from("-greedydata n"),
-                String::from("greedydata y"),
-            ]),
+            &[exp],
+            &[Some(&MergeConfig {
+                merge_fields: Some(vec![String::from("greedydata")]),
+                merge_exp_start: Some(String::from("%{PREFIX} DEBUG REQUEST|RESPONSE")),
+                merge_exp_end: Some(String::from("%{PREFIX} DEBUG")),
+                merge_scope_exclusive: true,
+                merge_key_field: None,
+                merge_key_ttl: None,
+                merge_continuation: None,
+                merge_flush_eof: false,
+            })],
+            &mut Emitter::new(Some(String::from("prefix,greedydata")), filters),
             &mut grok,
         )
         .expect("failed to process");
@@ -826,4 +5225,410 @@ y
             .as_bytes()
         );
     }
+
+    #[test]
+    fn test_process_merge_per_expression() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expressions = ['= %{GREEDYDATA:msg}', 'java %{GREEDYDATA:msg}']
+            expression_names = ['app', 'java']
+            [merge_configs.app]
+            merge_fields = ['msg']
+            merge_exp_start = '= START'
+            merge_exp_end = '= END'
+            merge_scope_exclusive = false
+            [merge_configs.java]
+            merge_fields = ['msg']
+            merge_exp_start = 'java Exception'
+            merge_exp_end = 'java done'
+            merge_scope_exclusive = true
+            "#,
+        )
+        .unwrap();
+        let input = Cursor::new(
+            "= 1\njava Exception\njava at a\n= at b\njava done\n= START 2\njava 3\n= END 4\njava START 5\n"
+                .as_bytes(),
+        );
+        let mut output = Cursor::new(Vec::new());
+        process_merge(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &config.expressions(),
+            &config.expression_merge_configs().unwrap(),
+            &mut Emitter::new(Some(String::from("msg")), Vec::new()),
+            &mut PatternCache::default(),
+        )
+        .expect("failed to process");
+        assert_eq!(
+            std::str::from_utf8(output.get_ref()).unwrap(),
+            "1\nException\nat a\nat b\ndone\nSTART 2\n3\nEND 4\nSTART 5\n"
+        );
+
+        let config: Config = toml::from_str(
+            "match_expression = 'x'\n[merge_configs.2]\nmerge_scope_exclusive = false",
+        )
+        .unwrap();
+        assert!(config.expression_merge_configs().is_err());
+    }
+
+    #[test]
+    fn test_process_merge_by_key() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expressions = ['%{WORD:tid} %{GREEDYDATA:msg}', '%{GREEDYDATA:msg}']
+            [merge_config]
+            merge_fields = ['msg']
+            merge_exp_start = 'begin'
+            merge_exp_end = 'end'
+            merge_scope_exclusive = false
+            merge_key_field = 'tid'
+            "#,
+        )
+        .unwrap();
+        let input = Cursor::new(
+            "t1 begin a\nt2 begin b\nt4 alone\nt1 step a\n-- marker\nt2 end b\nt1 x\nt3 begin c\n\
+             t1 end a\nt3 step c\n"
+                .as_bytes(),
+        );
+        let mut output = Cursor::new(Vec::new());
+        process_merge(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &config.expressions(),
+            &config.expression_merge_configs().unwrap(),
+            &mut Emitter::new(Some(String::from("msg")), Vec::new()),
+            &mut PatternCache::default(),
+        )
+        .expect("failed to process");
+        // The lines without the key aren't merged, and the sections left open are written out at
+        // the end of the input.
+        assert_eq!(
+            std::str::from_utf8(output.get_ref()).unwrap(),
+            "alone\n-- marker\nbegin b\nend b\nbegin a\nstep a\nx\nend a\nbegin c\nstep c\n"
+        );
+
+        // The sections idle for longer than the key TTL are written out without waiting for their
+        // end, the lines of their key coming after that not being merged into them.
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '%{WORD:tid} %{GREEDYDATA:msg}'
+            [merge_config]
+            merge_fields = ['msg']
+            merge_exp_start = 'begin'
+            merge_exp_end = 'end'
+            merge_key_field = 'tid'
+            merge_key_ttl = '0ms'
+            "#,
+        )
+        .unwrap();
+        let mut emitter = Emitter::new(Some(String::from("msg")), Vec::new());
+        process_merge(
+            Source::Reader(Box::new(Cursor::new(
+                "t1 begin a\nt1 step a\nt1 end a\n".as_bytes(),
+            ))),
+            &mut Cursor::new(Vec::new()),
+            &config.expressions(),
+            &config.expression_merge_configs().unwrap(),
+            &mut emitter,
+            &mut PatternCache::default(),
+        )
+        .expect("failed to process");
+        assert_eq!(emitter.emitted, 3);
+    }
+
+    #[test]
+    fn test_process_merge_idle_key() {
+        // Holds the next line back until a record is written out, for up to a while.
+        struct Waiting {
+            lines: Vec<&'static str>,
+            output: Arc<Mutex<Vec<u8>>>,
+            flushed: Arc<AtomicBool>,
+        }
+        impl Read for Waiting {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.lines.pop() {
+                    Some("") => {
+                        let started = Instant::now();
+                        while started.elapsed() < Duration::from_secs(5) {
+                            if !self.output.lock().unwrap().is_empty() {
+                                self.flushed.store(true, Ordering::SeqCst);
+                                break;
+                            }
+                            std::thread::sleep(Duration::from_millis(10));
+                        }
+                        self.read(buf)
+                    }
+                    Some(line) => {
+                        buf[..line.len()].copy_from_slice(line.as_bytes());
+                        Ok(line.len())
+                    }
+                    None => Ok(0),
+                }
+            }
+        }
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '%{WORD:tid} %{GREEDYDATA:msg}'
+            [merge_config]
+            merge_fields = ['msg']
+            merge_exp_start = 'begin'
+            merge_exp_end = 'end'
+            merge_key_field = 'tid'
+            merge_key_ttl = '50ms'
+            "#,
+        )
+        .unwrap();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let flushed = Arc::new(AtomicBool::new(false));
+        let input = Waiting {
+            lines: vec!["t2 alone\n", "", "t1 step a\n", "t1 begin a\n"],
+            output: Arc::clone(&written),
+            flushed: Arc::clone(&flushed),
+        };
+        process_merge(
+            Source::Reader(Box::new(input)),
+            &mut Shared(Arc::clone(&written)),
+            &config.expressions(),
+            &config.expression_merge_configs().unwrap(),
+            &mut Emitter::new(Some(String::from("msg")), Vec::new()),
+            &mut PatternCache::default(),
+        )
+        .expect("failed to process");
+        // The scope is written out while the input is quiet, before the next line comes.
+        assert!(flushed.load(Ordering::SeqCst));
+        assert_eq!(
+            std::str::from_utf8(&written.lock().unwrap()).unwrap(),
+            "begin a\nstep a\nalone\n"
+        );
+    }
+
+    #[test]
+    fn test_process_merge_context() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '%{YEAR} %{LOGLEVEL:lvl} %{GREEDYDATA:msg}'
+            output_format = 'lvl,msg'
+            where = 'lvl == "ERROR"'
+            before_context = 2
+            after_context = 1
+            [merge_config]
+            merge_fields = ['msg']
+            merge_continuation = '^\s+'
+            "#,
+        )
+        .unwrap();
+        let input = "2020 INFO a\nnoise\n2020 INFO b\n2020 ERROR boom\n\tat x\n2020 INFO c\n\
+                     2020 INFO d\n2020 INFO e\n2020 INFO f\n2020 ERROR bad\n";
+        let mut grok = PatternCache::default();
+        let mut emitter = build_emitter(&config, &mut grok).unwrap();
+        let mut output = Vec::new();
+        process_input(
+            Source::Reader(Box::new(Cursor::new(input.as_bytes()))),
+            &mut output,
+            &config,
+            &mut emitter,
+            &mut grok,
+        )
+        .unwrap();
+        // The context is made of the raw lines, those not matching the expression included.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "noise\n2020 INFO b\nERROR boom\n\tat x\n2020 INFO c\n--\n2020 INFO e\n2020 INFO f\n\
+             ERROR bad\n"
+        );
+    }
+
+    #[test]
+    fn test_process_merge_continuation() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '%{YEAR} %{LOGLEVEL:lvl} %{GREEDYDATA:msg}'
+            [merge_config]
+            merge_fields = ['msg']
+            merge_continuation = '^\s+|^Caused by'
+            "#,
+        )
+        .unwrap();
+        let input = Cursor::new(
+            "2020 ERROR boom\n\tat a.b(C.java:1)\nCaused by: y\n\tat c.d\n2020 INFO fine\n\
+             2020 ERROR again\n\tat e\n"
+                .as_bytes(),
+        );
+        let mut output = Cursor::new(Vec::new());
+        process_merge(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &config.expressions(),
+            &config.expression_merge_configs().unwrap(),
+            &mut Emitter::new(Some(String::from("msg")), Vec::new()),
+            &mut PatternCache::default(),
+        )
+        .expect("failed to process");
+        assert_eq!(
+            std::str::from_utf8(output.get_ref()).unwrap(),
+            "boom\n\tat a.b(C.java:1)\nCaused by: y\n\tat c.d\nfine\nagain\n\tat e\n"
+        );
+
+        let config: Config = toml::from_str(
+            "[merge_config]\nmerge_fields = ['msg']\nmerge_continuation = 'x'\nmerge_exp_start = 'y'",
+        )
+        .unwrap();
+        let mut stats = PatternStats::default();
+        assert!(Merge::compile(
+            config.merge_config.as_ref().unwrap(),
+            &mut PatternCache::default(),
+            &mut stats
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_process_merge_flush_eof() {
+        let run = |flush_eof: bool| {
+            let config: Config = toml::from_str(&format!(
+                "match_expression = '%{{GREEDYDATA:msg}}'\n[merge_config]\nmerge_fields = ['msg']\n\
+                 merge_exp_start = 'START'\nmerge_exp_end = 'END'\nmerge_flush_eof = {}",
+                flush_eof
+            ))
+            .unwrap();
+            let mut output = Cursor::new(Vec::new());
+            process_merge(
+                Source::Reader(Box::new(Cursor::new("1\nSTART 2\n3\n".as_bytes()))),
+                &mut output,
+                &config.expressions(),
+                &config.expression_merge_configs().unwrap(),
+                &mut Emitter::new(Some(String::from("msg")), Vec::new()),
+                &mut PatternCache::default(),
+            )
+            .expect("failed to process");
+            String::from_utf8(output.into_inner()).unwrap()
+        };
+        assert_eq!(run(false), "1\n");
+        assert_eq!(run(true), "1\nSTART 2\n3\n");
+    }
+
+    #[test]
+    fn test_process_hardened() {
+        let config: Config = toml::from_str(
+            r#"
+            match_expression = '%{GREEDYDATA:msg}'
+            output_format = 'msg'
+            hardened = true
+            max_line_length = 4
+            max_merge_lines = 2
+            [merge_config]
+            merge_fields = ['msg']
+            merge_exp_start = '^S'
+            merge_exp_end = '^E'
+            merge_scope_exclusive = false
+            "#,
+        )
+        .unwrap();
+        let mut grok = PatternCache::default();
+        let mut emitter = build_emitter(&config, &mut grok).unwrap();
+        let mut output = Vec::new();
+        let input = Cursor::new("S\na\nb\nE\nlonger\n".as_bytes());
+        process_input(
+            Source::Reader(Box::new(input)),
+            &mut output,
+            &config,
+            &mut emitter,
+            &mut grok,
+        )
+        .unwrap();
+        // The merged section ends after its second line, and the last line is cut.
+        assert_eq!(String::from_utf8(output).unwrap(), "S\na\nb\nE\nlong\n");
+        assert_eq!(emitter.emitted, 4);
+        assert_eq!(emitter.warnings.long_lines, 1);
+
+        // The limits have defaults in hardened mode.
+        let config: Config = toml::from_str("hardened = true").unwrap();
+        let emitter = build_emitter(&config, &mut grok).unwrap();
+        assert_eq!(emitter.max_line_length, Some(HARDENED_MAX_LINE_LENGTH));
+        assert_eq!(emitter.max_merge_lines, Some(HARDENED_MAX_MERGE_LINES));
+    }
+
+    #[test]
+    fn test_process_parallel() {
+        let input = (0..3000)
+            .map(|i| match i % 3 {
+                0 => format!("n={}", i),
+                1 => format!("w={}", i),
+                _ => format!("{}", i),
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let run = |tuning: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                match_expressions = ['n=%{{INT:n}}', 'w=%{{WORD:n}}']
+                output_format = '__line,__pattern,n'
+                line_number = true
+                pattern_stats = true
+                limit = 1900
+                {}
+                "#,
+                tuning
+            ))
+            .unwrap();
+            let mut grok = PatternCache::default();
+            let mut emitter = build_emitter(&config, &mut grok).unwrap();
+            let mut output = Vec::new();
+            process_input(
+                Source::Reader(Box::new(Cursor::new(input.clone().into_bytes()))),
+                &mut output,
+                &config,
+                &mut emitter,
+                &mut grok,
+            )
+            .unwrap();
+            emitter.stats.report(&mut output).unwrap();
+            (
+                String::from_utf8(output).unwrap(),
+                emitter.warnings.unmatched,
+            )
+        };
+        let sequential = run("jobs = 1");
+        assert!(sequential.0.starts_with("1 1 0\n2 2 1\n"));
+        assert_eq!(run("jobs = 4"), sequential);
+        assert_eq!(run("jobs = 3\nbatch_lines = 7"), sequential);
+        assert_eq!(run("jobs = 2\nauto_tune = true"), sequential);
+
+        for invalid in ["jobs = 0", "jobs = 2\nbatch_lines = 0"].iter() {
+            let config: Config = toml::from_str(invalid).unwrap();
+            let mut grok = PatternCache::default();
+            let mut emitter = build_emitter(&config, &mut grok).unwrap();
+            let input = Source::Reader(Box::new(Cursor::new(Vec::new())));
+            assert!(
+                process_input(input, &mut Vec::new(), &config, &mut emitter, &mut grok).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_tune_pipeline() {
+        let mut grok = PatternCache::default();
+        let mut stats = PatternStats::default();
+        let expressions =
+            compile_expressions(&[String::from("n=%{INT:n}")], &mut grok, &mut stats).unwrap();
+        let sample = (0..2000)
+            .map(|i| Line {
+                text: format!("n={}", i),
+                ..Line::default()
+            })
+            .collect::<Vec<Line>>();
+        let (jobs, batch_lines) = tune_pipeline(&sample, &expressions, 3, &mut grok).unwrap();
+        assert!([1, 2, 3].contains(&jobs));
+        assert!(TUNED_BATCH_LINES.contains(&batch_lines));
+        assert_eq!(tune_pipeline(&[], &expressions, 1, &mut grok).unwrap().0, 1);
+    }
 }
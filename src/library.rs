@@ -0,0 +1,387 @@
+use crate::{split_pattern_definition, GropError};
+use fgrok::Grok;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+/// Upstream grok pattern collection fetched by `patterns update`, pinned to a release.
+pub const UPSTREAM_URL: &str = "https://raw.githubusercontent.com/logstash-plugins/logstash-patterns-core/v4.3.4/patterns/legacy/grok-patterns";
+
+/// SHA-256 checksum (hex) of the pinned upstream collection, updated along with its URL.
+pub const UPSTREAM_SHA256: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Library file holding the upstream patterns. It sorts before the user's files, so that those
+/// can override the upstream definitions.
+const UPSTREAM_FILE: &str = "00-upstream";
+
+/// Whether the line of a pattern file holds a definition, rather than being blank or a comment.
+fn is_definition(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// Check that each definition is well formed and compiles, along with the given known ones.
+fn validate(known: &[String], definitions: &[String]) -> Result<(), GropError> {
+    let mut grok = Grok::default();
+    for p in known.iter().chain(definitions.iter()) {
+        let (name, pattern) = split_pattern_definition(p)?;
+        grok.insert_definition(String::from(name), String::from(pattern));
+    }
+    for p in definitions.iter() {
+        let (name, _) = split_pattern_definition(p)?;
+        grok.compile(&format!("%{{{}}}", name), false)?;
+    }
+    Ok(())
+}
+
+/// The definitions of a Logstash-style pattern file, or of the files of a pattern directory.
+pub(crate) fn read_definitions(path: &Path) -> Result<Vec<String>, GropError> {
+    let invalid = |err: io::Error| {
+        GropError::InvalidArg(format!(
+            "failed to read patterns from {}: {}",
+            path.display(),
+            err
+        ))
+    };
+    if fs::metadata(path).map_err(invalid)?.is_dir() {
+        return PatternLibrary::open(path.to_path_buf()).definitions();
+    }
+    Ok(fs::read_to_string(path)
+        .map_err(invalid)?
+        .lines()
+        .filter(|l| is_definition(l))
+        .map(|l| String::from(l.trim()))
+        .collect())
+}
+
+/// The user's library of pattern files, each holding one `pattern_name pattern` definition per
+/// line. The files are loaded in name order, so that later files may override earlier ones.
+pub struct PatternLibrary {
+    dir: PathBuf,
+}
+
+impl PatternLibrary {
+    /// The default library directory: `$XDG_CONFIG_HOME/grop/patterns`, falling back to
+    /// `~/.config/grop/patterns`.
+    pub fn default_dir() -> Option<PathBuf> {
+        match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+        }
+        .map(|dir| dir.join("grop").join("patterns"))
+    }
+
+    pub fn open(dir: PathBuf) -> PatternLibrary {
+        PatternLibrary { dir }
+    }
+
+    /// The paths of the pattern files, in name order.
+    fn paths(&self) -> Result<Vec<PathBuf>, GropError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .map(|n| n.to_string_lossy().starts_with('.'))
+                .unwrap_or(true);
+            if path.is_file() && !hidden {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// The pattern files along with their lines, in name order.
+    fn files(&self) -> Result<Vec<(PathBuf, Vec<String>)>, GropError> {
+        let mut files = Vec::new();
+        for path in self.paths()? {
+            let lines = fs::read_to_string(&path)?
+                .lines()
+                .map(String::from)
+                .collect();
+            files.push((path, lines));
+        }
+        Ok(files)
+    }
+
+    fn write_file(path: &Path, lines: &[String]) -> Result<(), GropError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut content = lines.join("\n");
+        content.push('\n');
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Whether the upstream pattern collection was installed into the library.
+    pub(crate) fn upstream_installed(&self) -> bool {
+        self.dir.join(UPSTREAM_FILE).is_file()
+    }
+
+    /// All the definitions of the library.
+    pub fn definitions(&self) -> Result<Vec<String>, GropError> {
+        Ok(self
+            .files()?
+            .into_iter()
+            .flat_map(|(_, lines)| lines.into_iter())
+            .filter(|l| is_definition(l))
+            .map(|l| String::from(l.trim()))
+            .collect())
+    }
+
+    /// The definitions of each pattern file, in name order, or why they can't be read from it (or
+    /// from the directory).
+    pub(crate) fn file_definitions(&self) -> Vec<(PathBuf, Result<Vec<String>, GropError>)> {
+        let paths = match self.paths() {
+            Ok(paths) => paths,
+            Err(err) => return vec![(self.dir.clone(), Err(err))],
+        };
+        paths
+            .into_iter()
+            .map(|path| {
+                let definitions = read_definitions(&path).and_then(|definitions| {
+                    for p in definitions.iter() {
+                        split_pattern_definition(p)?;
+                    }
+                    Ok(definitions)
+                });
+                (path, definitions)
+            })
+            .collect()
+    }
+
+    /// Add a definition to `file`, or replace the existing definition of the same name in place.
+    pub fn add(&self, definition: &str, file: &str) -> Result<(), GropError> {
+        let (name, _) = split_pattern_definition(definition)?;
+        let definition = String::from(definition.trim());
+        validate(&self.definitions()?, std::slice::from_ref(&definition))?;
+
+        for (path, mut lines) in self.files()? {
+            let mut replaced = false;
+            for line in lines.iter_mut() {
+                if is_definition(line) && split_pattern_definition(line)?.0 == name {
+                    *line = definition.clone();
+                    replaced = true;
+                }
+            }
+            if replaced {
+                log::info!("patterns: replace {} in {}", name, path.display());
+                return PatternLibrary::write_file(&path, &lines);
+            }
+        }
+
+        let path = self.dir.join(file);
+        let mut lines = match fs::read_to_string(&path) {
+            Ok(content) => content.lines().map(String::from).collect(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        lines.push(definition);
+        log::info!("patterns: add {} to {}", name, path.display());
+        PatternLibrary::write_file(&path, &lines)
+    }
+
+    /// Remove the definitions of the pattern from all the files.
+    pub fn remove(&self, name: &str) -> Result<(), GropError> {
+        let mut found = false;
+        for (path, lines) in self.files()? {
+            let mut kept = Vec::new();
+            let mut removed = false;
+            for line in lines.into_iter() {
+                if is_definition(&line) && split_pattern_definition(&line)?.0 == name {
+                    removed = true;
+                } else {
+                    kept.push(line);
+                }
+            }
+            if removed {
+                log::info!("patterns: remove {} from {}", name, path.display());
+                PatternLibrary::write_file(&path, &kept)?;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(GropError::InvalidArg(format!(
+                "pattern {} is not in the library",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write all the definitions as a single pattern file, suitable for `import`.
+    pub fn export(&self, output: &mut dyn Write) -> Result<(), GropError> {
+        for definition in self.definitions()?.iter() {
+            writeln!(output, "{}", definition)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a pattern file into the library, keeping its file name.
+    pub fn import(&self, path: &Path, force: bool) -> Result<(), GropError> {
+        let content = fs::read_to_string(path)?;
+        let definitions = content
+            .lines()
+            .filter(|l| is_definition(l))
+            .map(|l| String::from(l.trim()))
+            .collect::<Vec<String>>();
+        let file = path.file_name().ok_or_else(|| {
+            GropError::InvalidArg(format!("invalid pattern file {}", path.display()))
+        })?;
+        let target = self.dir.join(file);
+        if target.exists() && !force {
+            return Err(GropError::InvalidArg(format!(
+                "pattern file {} already exists",
+                target.display()
+            )));
+        }
+        validate(&self.definitions()?, &definitions)?;
+        log::info!(
+            "patterns: import {} definitions into {}",
+            definitions.len(),
+            target.display()
+        );
+        fs::create_dir_all(&self.dir)?;
+        fs::write(&target, content)?;
+        Ok(())
+    }
+
+    /// Verify the checksum of an upstream pattern collection and install it into the library.
+    pub fn install_upstream(&self, content: &[u8], sha256: &str) -> Result<(), GropError> {
+        let digest = Sha256::digest(content)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        if digest != sha256.trim().to_lowercase() {
+            return Err(GropError::InvalidArg(format!(
+                "checksum mismatch of the upstream patterns (expected {}, got {})",
+                sha256, digest
+            )));
+        }
+        let content = String::from_utf8_lossy(content);
+        let definitions = content
+            .lines()
+            .filter(|l| is_definition(l))
+            .map(|l| String::from(l.trim()))
+            .collect::<Vec<String>>();
+        validate(&[], &definitions)?;
+        let target = self.dir.join(UPSTREAM_FILE);
+        log::info!(
+            "patterns: install {} upstream definitions into {}",
+            definitions.len(),
+            target.display()
+        );
+        fs::create_dir_all(&self.dir)?;
+        fs::write(&target, content.as_bytes())?;
+        Ok(())
+    }
+
+    /// Download the upstream pattern collection and install it into the library.
+    pub fn update(&self, url: &str, sha256: &str) -> Result<(), GropError> {
+        log::info!("patterns: download {}", url);
+        let response = ureq::get(url)
+            .call()
+            .map_err(|err| io::Error::other(format!("failed to download patterns: {}", err)))?;
+        let mut content = Vec::new();
+        response.into_reader().read_to_end(&mut content)?;
+        self.install_upstream(&content, sha256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_pattern_library() {
+        let dir = env::temp_dir().join(format!("grop-library-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let lib = PatternLibrary::open(dir.clone());
+        assert!(lib.definitions().unwrap().is_empty());
+
+        lib.add("REQID req-%{INT}", "custom").unwrap();
+        lib.add("COLOR (red|green)", "custom").unwrap();
+        lib.add("REQID rq-%{INT}", "custom").unwrap();
+        assert!(lib.add("BROKEN (", "custom").is_err());
+        assert!(lib.add("BROKEN", "custom").is_err());
+        assert_eq!(
+            lib.definitions().unwrap(),
+            vec![
+                String::from("REQID rq-%{INT}"),
+                String::from("COLOR (red|green)")
+            ]
+        );
+
+        lib.remove("COLOR").unwrap();
+        assert!(lib.remove("COLOR").is_err());
+
+        let mut exported = Vec::new();
+        lib.export(&mut exported).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), "REQID rq-%{INT}\n");
+
+        let shared = env::temp_dir().join(format!("grop-shared-{}", std::process::id()));
+        fs::write(&shared, "# shared\nSESSION sess-%{REQID}\n").unwrap();
+        lib.import(&shared, false).unwrap();
+        assert!(lib.import(&shared, false).is_err());
+        lib.import(&shared, true).unwrap();
+        assert_eq!(lib.definitions().unwrap().len(), 2);
+
+        fs::remove_file(&shared).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_definitions() {
+        let dir = env::temp_dir().join(format!("grop-pattern-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(read_definitions(&dir).is_err());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b"), "# b\nREQID rq-%{INT}\n").unwrap();
+        fs::write(dir.join("a"), "\n  REQID req-%{INT}  \n").unwrap();
+        assert_eq!(
+            read_definitions(&dir).unwrap(),
+            vec![
+                String::from("REQID req-%{INT}"),
+                String::from("REQID rq-%{INT}")
+            ]
+        );
+        assert_eq!(
+            read_definitions(&dir.join("b")).unwrap(),
+            vec![String::from("REQID rq-%{INT}")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_upstream() {
+        let dir = env::temp_dir().join(format!("grop-upstream-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let lib = PatternLibrary::open(dir.clone());
+        let content = b"# upstream\nREQID req-%{INT}\n";
+        let sha256 = "0000000000000000000000000000000000000000000000000000000000000000";
+        assert!(lib.install_upstream(content, sha256).is_err());
+        assert!(lib.definitions().unwrap().is_empty());
+
+        let sha256 = Sha256::digest(content)
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        lib.install_upstream(content, &sha256).unwrap();
+        assert_eq!(
+            lib.definitions().unwrap(),
+            vec![String::from("REQID req-%{INT}")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
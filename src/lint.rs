@@ -0,0 +1,321 @@
+use crate::{
+    capture_field, load_patterns, split_field_pattern, split_pattern_definition, untyped_groups,
+    Config, GropError,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// How the lint findings are reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LintFormat {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<LintFormat, GropError> {
+        match s {
+            "text" => Ok(LintFormat::Text),
+            "json" => Ok(LintFormat::Json),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown lint format {} (should be one of text, json)",
+                s
+            ))),
+        }
+    }
+}
+
+/// The rules checking the custom patterns and the filters, which are shared by the expressions.
+const SHARED_RULES: &[&str] = &["unused-pattern", "unknown-filter-field"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(rule: &'static str, message: String) -> Finding {
+        Finding { rule, message }
+    }
+}
+
+/// A `%{PATTERN}` or `%{PATTERN:field}` reference in an expression.
+#[derive(Debug, PartialEq)]
+struct Reference {
+    pattern: String,
+    field: Option<String>,
+    // Byte offsets of the reference.
+    start: usize,
+    end: usize,
+}
+
+fn references(expression: &str) -> Vec<Reference> {
+    let mut refs = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = expression[offset..].find("%{") {
+        let start = offset + start;
+        let end = match expression[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let mut parts = expression[start + 2..end].splitn(3, ':');
+        refs.push(Reference {
+            pattern: String::from(parts.next().unwrap_or_default()),
+            field: parts.next().map(String::from),
+            start,
+            end: end + 1,
+        });
+        offset = end + 1;
+    }
+    refs
+}
+
+/// Names of the `(?<name>...)` groups in an expression.
+fn named_groups(expression: &str) -> Vec<String> {
+    expression
+        .split("(?<")
+        .skip(1)
+        .filter_map(|s| s.find('>').map(|end| String::from(&s[..end])))
+        .filter(|name| !name.starts_with('=') && !name.starts_with('!'))
+        .map(|name| String::from(capture_field(&name)))
+        .collect()
+}
+
+/// Check the expression for common mistakes. Only the custom patterns are checked for being
+/// unused, the patterns of the library are shared by all expressions.
+pub fn lint(
+    expression: &str,
+    custom_patterns: &[String],
+    filters: &[String],
+) -> Result<Vec<Finding>, GropError> {
+    let mut findings = Vec::new();
+    let refs = references(expression);
+
+    // Captures shadowing each other. Without an explicit field name, a pattern is captured by
+    // its own name.
+    let mut captures = Vec::new();
+    for r in refs.iter() {
+        captures.push(r.field.clone().unwrap_or_else(|| r.pattern.clone()));
+    }
+    captures.extend(named_groups(expression));
+    let mut seen = HashSet::new();
+    let mut reported = HashSet::new();
+    for c in captures.iter() {
+        if !seen.insert(c) && reported.insert(c) {
+            findings.push(Finding::new(
+                "shadowed-capture",
+                format!(
+                    "field {} is captured more than once, only one value is kept",
+                    c
+                ),
+            ));
+        }
+    }
+
+    let terminal = expression.trim_end().trim_end_matches('$').trim_end().len();
+    for r in refs.iter() {
+        if r.pattern == "GREEDYDATA" && r.end < terminal {
+            findings.push(Finding::new(
+                "greedy-non-terminal",
+                format!(
+                    "{} is not at the end of the expression, consider DATA instead",
+                    &expression[r.start..r.end]
+                ),
+            ));
+        }
+    }
+
+    if !expression.starts_with('^') {
+        findings.push(Finding::new(
+            "unanchored",
+            String::from("expression is not anchored with ^, it may match in the middle of lines"),
+        ));
+    }
+
+    // Custom patterns used by the expression, directly or through other custom patterns.
+    let mut definitions = HashMap::new();
+    for p in custom_patterns.iter() {
+        let (name, pattern) = split_pattern_definition(p)?;
+        definitions.insert(name, pattern);
+    }
+    let mut used = HashSet::new();
+    let mut pending = refs.iter().map(|r| r.pattern.clone()).collect::<Vec<_>>();
+    while let Some(name) = pending.pop() {
+        if let Some(pattern) = definitions.get(name.as_str()) {
+            if used.insert(name) {
+                pending.extend(references(pattern).into_iter().map(|r| r.pattern));
+            }
+        }
+    }
+    let mut unused = definitions
+        .keys()
+        .filter(|name| !used.contains(**name))
+        .collect::<Vec<_>>();
+    unused.sort();
+    for name in unused {
+        findings.push(Finding::new(
+            "unused-pattern",
+            format!("custom pattern {} is not used by the expression", name),
+        ));
+    }
+
+    for filter in filters.iter() {
+        let (field, _) = split_field_pattern(filter.strip_prefix('-').unwrap_or(filter))?;
+        if !captures.iter().any(|c| c == field) {
+            findings.push(Finding::new(
+                "unknown-filter-field",
+                format!("filter references field {} which is not captured", field),
+            ));
+        }
+    }
+
+    Ok(findings)
+}
+
+/// Lint the match expressions of the config (those of its preset included) and report the findings
+/// to stdout, prefixed with the name of their expression when there are several. Returns the
+/// number of findings.
+pub fn run_lint(config: Config, format: LintFormat) -> Result<usize, GropError> {
+    let config = match config.preset {
+        Some(preset) => preset.apply(config),
+        None => config,
+    };
+    if config.match_expression.is_none() && config.match_expressions.is_none() {
+        return Err(GropError::InvalidArg(String::from(
+            "no match expression to lint",
+        )));
+    }
+    let expressions = config.expressions();
+    let names = config.expression_names()?;
+    let custom_patterns = config.custom_patterns.clone().unwrap_or_default();
+    let filters = config.filters.clone().unwrap_or_default();
+    let (mut grok, _) = load_patterns(
+        &Some(custom_patterns.clone()),
+        config.pattern_library.as_deref(),
+    )?;
+
+    let mut findings = Vec::new();
+    // The findings on the patterns and filters shared by the expressions, with the number of
+    // expressions they're reported for.
+    let mut shared: Vec<(Finding, usize)> = Vec::new();
+    for (expression, name) in expressions.iter().zip(names.iter()) {
+        let mut expression_findings = lint(expression, &custom_patterns, &filters)?;
+        expression_findings.retain(|finding| {
+            if !SHARED_RULES.contains(&finding.rule) {
+                return true;
+            }
+            match shared.iter_mut().find(|(f, _)| f == finding) {
+                Some((_, count)) => *count += 1,
+                None => shared.push((finding.clone(), 1)),
+            }
+            false
+        });
+        if let Err(err) = grok.compile(&untyped_groups(expression), false) {
+            expression_findings.insert(0, Finding::new("compile", err.to_string()));
+        }
+        if expressions.len() > 1 {
+            for finding in expression_findings.iter_mut() {
+                finding.message = format!("expression {}: {}", name, finding.message);
+            }
+        }
+        findings.extend(expression_findings);
+    }
+    // A pattern or a filter field is only unknown or unused when it is so for every expression.
+    findings.extend(
+        shared
+            .into_iter()
+            .filter(|(_, count)| *count == expressions.len())
+            .map(|(finding, _)| finding),
+    );
+
+    match format {
+        LintFormat::Text => {
+            for f in findings.iter() {
+                println!("warning[{}]: {}", f.rule, f.message);
+            }
+        }
+        LintFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&findings)
+                .map_err(|err| GropError::InvalidArg(err.to_string()))?
+        ),
+    }
+    Ok(findings.len())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn rules(findings: Vec<Finding>) -> Vec<&'static str> {
+        findings.into_iter().map(|f| f.rule).collect()
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(
+            references("^%{INT:a} (?<b>x) %{WORD}$"),
+            vec![
+                Reference {
+                    pattern: String::from("INT"),
+                    field: Some(String::from("a")),
+                    start: 1,
+                    end: 9,
+                },
+                Reference {
+                    pattern: String::from("WORD"),
+                    field: None,
+                    start: 18,
+                    end: 25,
+                },
+            ]
+        );
+        assert_eq!(named_groups("(?<b>x)(?<=y)(?<c>z)"), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_lint() {
+        let no_patterns: Vec<String> = vec![];
+        let no_filters: Vec<String> = vec![];
+        assert!(
+            lint("^%{INT:a} %{GREEDYDATA:b}$", &no_patterns, &no_filters)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            rules(lint("%{INT:a} %{INT:a} %{WORD}", &no_patterns, &no_filters).unwrap()),
+            vec!["shadowed-capture", "unanchored"]
+        );
+        assert_eq!(
+            rules(lint("^%{GREEDYDATA:a} %{INT:b}$", &no_patterns, &no_filters).unwrap()),
+            vec!["greedy-non-terminal"]
+        );
+        assert_eq!(
+            lint(
+                "^%{REQ:r}$",
+                &[
+                    String::from("REQ req-%{ID}"),
+                    String::from("ID %{INT}"),
+                    String::from("SESSION s-%{INT}"),
+                ],
+                &[String::from("-r foo"), String::from("lvl ERROR")],
+            )
+            .unwrap(),
+            vec![
+                Finding::new(
+                    "unused-pattern",
+                    String::from("custom pattern SESSION is not used by the expression")
+                ),
+                Finding::new(
+                    "unknown-filter-field",
+                    String::from("filter references field lvl which is not captured")
+                ),
+            ]
+        );
+    }
+}
@@ -1,35 +1,286 @@
-use grop::{Config, MergeConfig};
-use log;
+use grop::{
+    ColorMode, Compression, ConditionalTag, Config, GropError, History, HistoryEntry, LintFormat,
+    MergeConfig, NumberLocale, OutputMode, PatternLibrary, Preset, Tee, Unmatched, SOURCES,
+    UPSTREAM_SHA256, UPSTREAM_URL,
+};
 use serde::Deserialize;
+use serde_json::json;
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use structopt::StructOpt;
 
 extern crate stderrlog;
 
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Interactively build a match expression from sample lines of the input (or pasted lines)
+    Wizard {
+        /// Sample file, the sample lines are read from stdin if not present
+        #[structopt(parse(from_os_str))]
+        input: Option<PathBuf>,
+    },
+
+    /// List the recently run queries, the most recent one first
+    History,
+
+    /// List the input sources, and whether this build of grop supports them on this platform
+    Sources,
+
+    /// Manage the user pattern library (`~/.config/grop/patterns/`), which is loaded on every run
+    Patterns(PatternsCommand),
+
+    /// Check a match expression for common mistakes. Exits with 1 if there is any finding
+    Lint {
+        /// Grok match expression
+        #[structopt(short, long)]
+        expression: Option<String>,
+
+        /// Custom Grok pattern (format: `<pattern_name> <regexp>`)
+        #[structopt(short, long)]
+        pattern: Option<Vec<String>>,
+
+        /// Filter (`field_name pattern` or `-field_name pattern`) to be used with the expression
+        #[structopt(long)]
+        filter: Option<Vec<String>>,
+
+        /// Report format
+        #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+        format: LintFormat,
+    },
+
+    /// Check the environment: the config, the pattern library and files, that the state files can
+    /// be written and the SSH hosts logged in to. Exits with 1 if any check fails
+    Doctor,
+
+    /// Check the records extracted by the config against test cases. Exits with 1 if any case
+    /// fails
+    TestConfig {
+        /// Case file, or directory of `.toml` case files. Each file holds `[[cases]]` tables with
+        /// the `input` lines and the `expected` records (only the listed fields are compared)
+        #[structopt(long, parse(from_os_str))]
+        cases: PathBuf,
+    },
+
+    /// Generate synthetic lines matching the match expression, e.g. to benchmark a pipeline or to
+    /// write tests without shipping real logs. The well-known patterns and fields (timestamps, IP
+    /// addresses, status codes, log levels...) get realistic values
+    Generate {
+        /// Built-in match expression of a common log format to generate the lines of
+        #[structopt(long, possible_values = &["nginx", "apache_combined", "syslog", "java"])]
+        preset: Option<Preset>,
+
+        /// Grok match expression. When repeated, each line is generated from one of them picked
+        /// at random
+        #[structopt(short, long, number_of_values = 1)]
+        expression: Option<Vec<String>>,
+
+        /// Custom Grok pattern (format: `<pattern_name> <regexp>`)
+        #[structopt(short, long)]
+        pattern: Option<Vec<String>>,
+
+        /// Number of lines to generate
+        #[structopt(long, default_value = "1000")]
+        lines: u64,
+
+        /// Seed of the random values, to generate the same lines again
+        #[structopt(long)]
+        seed: Option<u64>,
+
+        /// Time of the first line (e.g. "2024-01-01T00:00" or "1d" back from now), the following
+        /// ones coming up to 2 seconds apart [default: a second per line back from now]
+        #[structopt(long)]
+        start: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum PatternsCommand {
+    /// Add a pattern, or replace the existing pattern of the same name
+    Add {
+        name: String,
+        pattern: String,
+
+        /// Library file to add the pattern to
+        #[structopt(long, default_value = "custom")]
+        file: String,
+    },
+
+    /// Remove a pattern
+    Rm { name: String },
+
+    /// Print all the patterns of the library as a single pattern file
+    Export,
+
+    /// Copy a pattern file (one `<pattern_name> <regexp>` per line) into the library
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Overwrite the library file of the same name
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Download the upstream grok pattern collection into the library, verifying its checksum
+    Update {
+        /// URL of the pattern collection [default: the pinned logstash-patterns-core release]
+        #[structopt(long, requires = "sha256")]
+        url: Option<String>,
+
+        /// Expected SHA-256 checksum (hex) of the pattern collection at --url [default: the one of
+        /// the pinned release]
+        #[structopt(long, requires = "url")]
+        sha256: Option<String>,
+    },
+}
+
 #[derive(Debug, StructOpt, Deserialize)]
-#[structopt(name = "grop", about = "A grok powered grep-like utility")]
+#[structopt(
+    name = "grop",
+    about = "A grok powered grep-like utility",
+    after_help = EXIT_STATUS
+)]
 pub struct Opt {
-    /// Input file, stdin if not present
+    /// Input files, processed one after the other, stdin if not present. A stdin starting with a
+    /// UTF-16 byte order mark (e.g. piped from PowerShell) is transcoded to UTF-8
     #[structopt(parse(from_os_str))]
-    input: Option<PathBuf>,
+    #[serde(default)]
+    input: Vec<PathBuf>,
+
+    /// Wait for the input file to show up if it's missing, and reopen it once its writer goes away
+    /// if it's a pipe or a device (e.g. `grop --retry-open /tmp/app.fifo`)
+    #[structopt(long, requires = "input")]
+    retry_open: bool,
+
+    /// Map the plain input files in memory rather than reading them line by line, which is faster.
+    /// Only for files which aren't truncated while being read (e.g. rotated by copytruncate): the
+    /// mapping reader crashes on them. Ignored in --hardened mode
+    #[structopt(long, requires = "input")]
+    mmap: bool,
+
+    /// Keep reading the input file as it grows once its end is reached, reopening it when it's
+    /// rotated and reading it from its start again when it's truncated (like `tail -F`). For a
+    /// directory, follow each of its files along with the ones created in it later on
+    #[structopt(short, long, requires = "input")]
+    follow: bool,
+
+    /// Follow the input from its last lines (10 if not given, e.g. `--tail=50`) rather than from
+    /// its start, like `tail -F`. Implies --follow
+    #[structopt(long, value_name = "lines", require_equals = true, requires = "input")]
+    tail: Option<Option<usize>>,
+
+    /// Skip the first lines of each input
+    #[structopt(long, value_name = "lines")]
+    skip: Option<u64>,
+
+    /// Only process this many lines of each input (after the skipped ones)
+    #[structopt(long, value_name = "lines")]
+    take: Option<u64>,
+
+    /// Process each input from its Nth line (starting at 1), same as --skip N-1
+    #[structopt(long, value_name = "line", conflicts_with = "skip")]
+    from_line: Option<u64>,
+
+    /// Process each input from the first line starting at or after the byte offset. Plain files
+    /// are read from there, without reading what comes before
+    #[structopt(long, value_name = "offset")]
+    from_byte: Option<u64>,
+
+    /// Stop processing each input at the first line starting at or after the byte offset
+    #[structopt(long, value_name = "offset")]
+    to_byte: Option<u64>,
+
+    /// Compression of the input: auto (judging by the .gz, .zst or .bz2 extension of the input
+    /// file), none, gzip, zstd or bzip2, e.g. to read compressed stdin [default: auto]
+    #[structopt(long, possible_values = &["auto", "none", "gzip", "zstd", "bzip2"])]
+    compression: Option<Compression>,
+
+    /// Process the input file from its last line to its first one, e.g. with --limit to get the
+    /// most recent records without reading the whole file
+    #[structopt(long, requires = "input", conflicts_with_all = &["retry-open", "merge-field"])]
+    reverse: bool,
+
+    /// Add the input file of each record as the `__file` field ("-" for stdin), e.g. with
+    /// `-o __file,msg`
+    #[structopt(short = "H", long)]
+    with_filename: bool,
+
+    /// Add the number of the line of each record in its input file as the `__line` field
+    #[structopt(short = "n", long)]
+    line_number: bool,
+
+    /// Read stdin even if it's a terminal
+    #[structopt(long)]
+    force_stdin: bool,
+
+    /// Tail --remote-path on each of the hosts over SSH instead of reading the input, adding the
+    /// host of each line as the `_host` field
+    #[structopt(
+        long,
+        use_delimiter = true,
+        requires = "remote-path",
+        conflicts_with = "input"
+    )]
+    ssh: Option<Vec<String>>,
+
+    /// Path of the file to tail on the --ssh hosts
+    #[structopt(long, requires = "ssh")]
+    remote_path: Option<String>,
 
     /// Custom Grok pattern (format: `<pattern_name> <regexp>`)
     #[structopt(short, long)]
     pattern: Option<Vec<String>>,
 
-    /// List available patterns
+    /// Logstash-style pattern file, with one `<pattern_name> <regexp>` definition per line and `#`
+    /// comments
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    pattern_file: Option<Vec<PathBuf>>,
+
+    /// Directory of pattern files, loaded in name order (hidden files are skipped)
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    pattern_dir: Option<Vec<PathBuf>>,
+
+    /// List available patterns. With a pattern name, show its definition and the regex it expands
+    /// to. Otherwise list the patterns whose name matches the glob (e.g. `*HTTP*`) or contains
+    /// the text, along with their definitions
     #[structopt(short, long)]
     list_pattern: Option<Option<String>>,
 
-    /// Grok match expression
-    #[structopt(short, long)]
-    expression: Option<String>,
+    /// Compile the match expression, print the regex it expands to along with the fields it
+    /// captures, and exit
+    #[structopt(long)]
+    check_expression: bool,
+
+    /// Grok match expression. When repeated, each line is matched against the expressions in
+    /// order and the first one matching it is used, its name (--expression-name, else its number
+    /// starting at 1) being added as the `__pattern` field. A field may be given a type, int or
+    /// float (e.g. `%{NUMBER:bytes:int}`), to be written as a number in JSON. A one-off capture may
+    /// be given inline as a regex named group, typed in the same way (e.g. `(?<took:int>\d+)ms`)
+    #[structopt(short, long, number_of_values = 1)]
+    expression: Option<Vec<String>>,
+
+    /// Name of the expression, for each of the expressions in order, written out as the
+    /// `__pattern` field and which --classify reports the lines by [default: their number]
+    #[structopt(long, number_of_values = 1)]
+    expression_name: Option<Vec<String>>,
+
+    /// Built-in match expression, custom patterns and output format for a common log format,
+    /// which the other options override
+    #[structopt(long, possible_values = &["nginx", "apache_combined", "syslog", "java"])]
+    preset: Option<Preset>,
+
+    /// File holding the grok match expression, which may span several lines (the whitespaces
+    /// around each line are dropped) with `#` comments
+    #[structopt(long, parse(from_os_str), conflicts_with = "expression")]
+    expression_file: Option<PathBuf>,
 
     /// Field(s) to be merged among lines.
     /// The unspecified fields will be skipped and only keep the ones in first line.
-    #[structopt(short, long, requires_all=&["merge-exp-start", "merge-exp-end"])]
+    /// Requires either --merge-exp-start and --merge-exp-end, or --merge-continuation
+    #[structopt(short, long)]
     merge_field: Option<Vec<String>>,
 
     /// Grok match expression indicating the start of the merged section
@@ -44,14 +295,379 @@ pub struct Opt {
     #[structopt(long)]
     merge_scope_exclusive: bool,
 
+    /// Field whose value groups the merged lines, e.g. a request or thread id for interleaved
+    /// logs: each value has its own merged section, and the sections still open at the end of the
+    /// input are written out
+    #[structopt(long, requires = "merge-field")]
+    merge_key_field: Option<String>,
+
+    /// Write out the merged section of a key once no line was merged into it for the duration
+    /// (e.g. `5m`), rather than keeping it open until its end comes, which may never happen when
+    /// following the input
+    #[structopt(long, requires = "merge-key-field")]
+    merge_key_ttl: Option<String>,
+
+    /// Grok match expression of the continuation lines (e.g. `^\s+|^Caused by` for stack traces),
+    /// which are appended as they are to the merged fields of the record before them, in place of
+    /// merged sections from a start to an end expression
+    #[structopt(
+        long,
+        requires = "merge-field",
+        conflicts_with_all = &["merge-exp-start", "merge-exp-end", "merge-key-field"]
+    )]
+    merge_continuation: Option<String>,
+
+    /// Write out the record of a merged section still open at the end of the input (e.g. a
+    /// truncated log), instead of dropping it. The sections grouped by --merge-key-field are always
+    /// written out
+    #[structopt(long, requires = "merge-field")]
+    merge_flush_eof: bool,
+
     /// Filter to include (`field_name pattern`) or exclude (`-field_name pattern`) some pattern
     #[structopt(long)]
     filter: Option<Vec<String>>,
 
-    /// Output format (fields of grok expression, separated by comma)
+    /// File holding one filter per line (blank lines and `#` comments are skipped), applied along
+    /// with the --filter ones
+    #[structopt(long, parse(from_os_str))]
+    filter_file: Option<PathBuf>,
+
+    /// Write out at most N records per period for each value of the field, e.g. `--throttle-by
+    /// client 10/min`, the period being a duration (e.g. 30s) or s, min or h. The number of the
+    /// records suppressed for a value is reported once its period ends
+    #[structopt(long, number_of_values = 2, value_names = &["field", "N/period"])]
+    throttle_by: Option<Vec<String>>,
+
+    /// What becomes of the lines which don't match the expression: `print` them as they are,
+    /// `drop` them, `tag` them as records holding the line in the `__unmatched` field, write them
+    /// to `stderr`, or `fail` on the first one [default: drop]
+    #[structopt(long, possible_values = &["print", "drop", "tag", "stderr", "fail"])]
+    on_unmatched: Option<Unmatched>,
+
+    /// Only keep the records for which the expression holds: conditions in format
+    /// `field_name (==|!=|=~|!~|<|<=|>|>=) value` combined with `&&`, `||`, `!` and parentheses,
+    /// e.g. '(level =~ "ERROR|WARN") && !(msg =~ "healthcheck")' or 'level >= warn'. The values
+    /// are ordered as numbers, or else as severities. Applied along with the filters
+    #[structopt(long = "where", value_name = "expression")]
+    where_expression: Option<String>,
+
+    /// Print this many records filtered out (raw lines when merging) before each record written
+    /// out, like `grep -B`
+    #[structopt(short = "B", long, value_name = "records")]
+    before_context: Option<usize>,
+
+    /// Print this many records filtered out (raw lines when merging) after each record written
+    /// out, like `grep -A`
+    #[structopt(short = "A", long, value_name = "records")]
+    after_context: Option<usize>,
+
+    /// Print this many records filtered out (raw lines when merging) around each record written
+    /// out, like `grep -C`
+    #[structopt(short = "C", long, value_name = "records")]
+    context: Option<usize>,
+
+    /// Print the lines which don't match the expression, or whose record is excluded by the
+    /// filters, as they are instead of the records, like `grep -v` (-v being --verbose)
+    #[structopt(long)]
+    invert_match: bool,
+
+    /// Output format (fields of grok expression, separated by comma), or `json`
     #[structopt(short, long)]
     output_format: Option<String>,
 
+    /// Output mode, `json` writes each record as a JSON object per line (same as `-o json`)
+    #[structopt(long, possible_values = &["json"], conflicts_with_all = &["output-format", "fields"])]
+    output: Option<String>,
+
+    /// Shorthand for `--output json`
+    #[structopt(long, conflicts_with_all = &["output", "output-format", "fields"])]
+    json: bool,
+
+    /// Only keep the records whose `level` field is an error or worse (ERROR, ERR, CRITICAL,
+    /// FATAL...), like `--where 'level >= error'` along with the where expression. The records
+    /// without the field are dropped
+    #[structopt(long)]
+    errors: bool,
+
+    /// Time zone (IANA name like `Europe/Stockholm`, or `local`) to display the timestamp fields in.
+    /// Filters and transforms still see the original timestamps
+    #[structopt(long)]
+    display_tz: Option<String>,
+
+    /// Field holding the timestamp of the records [default: the first of timestamp, @timestamp,
+    /// time, ts and date]
+    #[structopt(long)]
+    time_field: Option<String>,
+
+    /// Format of the timestamps of the time field (like `%d/%b/%Y:%H:%M:%S`, taken as UTC without
+    /// `%z`), tried before the common ones
+    #[structopt(long)]
+    time_format: Option<String>,
+
+    /// Skip the records whose timestamp is before the time, a timestamp like 2024-01-01T00:00
+    /// (taken as UTC without an offset) or an age like 2h back from now
+    #[structopt(long, value_name = "time")]
+    since: Option<String>,
+
+    /// Skip the records whose timestamp is at or after the time, in the format of --since
+    #[structopt(long, value_name = "time")]
+    until: Option<String>,
+
+    /// Drop the records whose timestamp is older than the age (e.g. 15m or 2h) at the time they
+    /// are read, e.g. to skip the stale backlog of a followed file
+    #[structopt(long, value_name = "age")]
+    fresher_than: Option<String>,
+
+    /// Only keep these fields (separated by comma) and output them in order. Shorthand for an output
+    /// format which also drops all the other fields from the record
+    #[structopt(long, use_delimiter = true, conflicts_with = "output-format")]
+    fields: Option<Vec<String>>,
+
+    /// Write records into files instead of stdout. The path may contain `{field_name}` placeholders
+    /// (and `{_date}` for the date of the record) to partition records into several files
+    #[structopt(long = "out")]
+    output_path: Option<String>,
+
+    /// Also write the records into the file, in format `path[=format]`, e.g.
+    /// `--tee archive.ndjson=json`. The format is the output format unless given, the path may
+    /// contain placeholders like --out
+    #[structopt(long, number_of_values = 1)]
+    tee: Option<Vec<String>>,
+
+    /// Color each output field: always, never, or auto (only when writing to a terminal, and
+    /// NO_COLOR is not set). The start and end lines of the merged records stand out
+    /// [default: auto]
+    #[structopt(long, possible_values = &["auto", "always", "never"])]
+    color: Option<ColorMode>,
+
+    /// Color whole records by the value of the field, e.g. `--color-by level`. The colors of the
+    /// values are set by `colors` in the config, the usual log level colors by default
+    #[structopt(long)]
+    color_by: Option<String>,
+
+    /// How to treat existing output files [default: truncate]
+    #[structopt(long, requires = "output-path", possible_values = &["append", "truncate", "create-new"])]
+    out_mode: Option<OutputMode>,
+
+    /// Write output files to a temporary file first, which is renamed once all input is processed
+    #[structopt(long, requires = "output-path")]
+    out_atomic: bool,
+
+    /// State file remembering the emitted records, so that they are not emitted again by this or
+    /// later runs using the same file. It keeps growing by a line per distinct record, remove it to
+    /// start over
+    #[structopt(long, parse(from_os_str))]
+    dedup_state: Option<PathBuf>,
+
+    /// Collapse the runs of consecutive identical records (or with the same value of the field)
+    /// into their first record, with the number of records in the run as the `_repeat_count`
+    /// field, like syslog's "last message repeated N times"
+    #[structopt(long, value_name = "field", require_equals = true)]
+    squash_repeats: Option<Option<String>>,
+
+    /// File the progress is periodically saved to, once the output is flushed. A run killed
+    /// partway through resumes from there, appending to its output (redirect stdout with `>>`)
+    #[structopt(long, parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+
+    /// How often the progress is saved to the checkpoint file [default: 10s]
+    #[structopt(long, requires = "checkpoint")]
+    checkpoint_interval: Option<String>,
+
+    /// Compare the output with the content of this file instead of printing it, failing with a
+    /// unified diff if they differ
+    #[structopt(long, parse(from_os_str))]
+    assert_output: Option<PathBuf>,
+
+    /// Constant field (`name=value`) injected into every record
+    #[structopt(long, number_of_values = 1)]
+    tag: Option<Vec<String>>,
+
+    /// Constant field (`name=value`) injected into records satisfying a condition
+    /// (`field_name (==|!=|=~|!~|<|<=|>|>=) value`), e.g. `--tag-if 'lvl == "ERROR"' severity=high`
+    #[structopt(long, number_of_values = 2, value_names = &["condition", "tag"])]
+    tag_if: Option<Vec<String>>,
+
+    /// Reuse the expression, filters and output format of a recent query (1 for the last one, see
+    /// `grop history`). Options given explicitly take precedence
+    #[structopt(long, value_name = "index")]
+    last: Option<Option<usize>>,
+
+    /// Set a field (`name=value`) of every record. The value may contain `{field_name}`
+    /// placeholders, which are expanded from the record
+    #[structopt(long, number_of_values = 1)]
+    set: Option<Vec<String>>,
+
+    /// Remove a field from every record
+    #[structopt(long, number_of_values = 1)]
+    unset: Option<Vec<String>>,
+
+    /// Record transform in format `[if <condition> then] <action>`, where the condition is
+    /// `field_name (==|!=|=~|!~|<|<=|>|>=) value` (`=~` matches a grok pattern) and the action one
+    /// of `set name=value`, `unset name`, or a conversion `<conversion> field [as name]`: `bytes`
+    /// (sizes like `1.5KB` into bytes), `millis` (durations like `3m10s` into milliseconds),
+    /// `epoch`/`epoch-ms` (timestamps into epoch seconds/milliseconds),
+    /// `timestamp`/`timestamp-ms` (epoch seconds/milliseconds into RFC 3339 timestamps),
+    /// `hex`/`base64`/`url` (decode the text) or `crc32`/`md5`/`sha256` (hex digest of the text,
+    /// `_line` being the raw line). The `severity field [as name]` action normalizes level
+    /// spellings (`WARN`, `Warning`, `W`, `30`...) into one of trace, debug, info, warn, error or
+    /// fatal, along with its rank in `<name>_rank`. The `grok field expression` action matches a
+    /// field against a grok expression and adds the captures to the record, e.g.
+    /// `--transform 'if level == "ERROR" then set alert=true'`
+    #[structopt(long, number_of_values = 1)]
+    transform: Option<Vec<String>>,
+
+    /// Carry the last non-empty value of the field forward into the next records without one, until
+    /// it changes, e.g. a session banner only logged once at its start
+    #[structopt(long, number_of_values = 1)]
+    carry_forward: Option<Vec<String>>,
+
+    /// Add the difference of the numeric value of the field from the previous record as the
+    /// `_delta.<field>` field, in format "field [by key]" to take the previous record with the
+    /// same value of the key field, e.g. `--delta 'requests by host'` to turn cumulative counters
+    /// into per-interval ones. Empty for the first record and for the values which are not numbers
+    #[structopt(long, number_of_values = 1)]
+    delta: Option<Vec<String>>,
+
+    /// Add the mean of the numeric value of the field over the last records as the
+    /// `_moving_avg.<field>` field, in format "field[:window=N]" for the number of records (10 if
+    /// not given), e.g. `--moving-avg latency:window=100`
+    #[structopt(long, number_of_values = 1)]
+    moving_avg: Option<Vec<String>>,
+
+    /// Locale the numbers of the typed fields, the deltas and the moving averages are written in:
+    /// c (1234.5, the default), en (1,234.5), de (1.234,5), fr (1 234,5) or ch (1'234.5)
+    #[structopt(long)]
+    number_locale: Option<NumberLocale>,
+
+    /// Base64 encode the field values which are not text (with control characters or invalid
+    /// UTF-8, which is otherwise replaced by U+FFFD)
+    #[structopt(long)]
+    base64_binary: bool,
+
+    /// Add where each record was read from and how it was parsed, to trace it back: the
+    /// `_source` input, the byte `_offset` of its line, `_ingested_at` and the `_expression`
+    #[structopt(long)]
+    provenance: bool,
+
+    /// Fail if there are more warnings (unmatched lines, lines with invalid UTF-8, fields missing
+    /// for the transforms) than the threshold, 0 if not given
+    #[structopt(long, value_name = "threshold")]
+    fail_on_warn: Option<Option<u64>>,
+
+    /// Fail if the rate of lines matching the expression (between 0 and 1) is lower after
+    /// --match-rate-lines lines, e.g. when running the wrong expression against the input
+    #[structopt(long)]
+    min_match_rate: Option<f64>,
+
+    /// Number of lines the match rate is checked after [default: 100]
+    #[structopt(long, requires = "min-match-rate")]
+    match_rate_lines: Option<u64>,
+
+    /// Only warn when the match rate is too low, instead of failing
+    #[structopt(long, requires = "min-match-rate")]
+    match_rate_warn: bool,
+
+    /// Skip the first records written out (counted after the filters)
+    #[structopt(long)]
+    offset: Option<u64>,
+
+    /// Stop reading the input once this many records are written out, like `grep --max-count`
+    /// (-m being --merge-field)
+    #[structopt(long, visible_alias = "max-count")]
+    limit: Option<u64>,
+
+    /// Write out only the Nth page of SIZE records, in format "N:SIZE"
+    #[structopt(long, conflicts_with_all = &["offset", "limit"])]
+    page: Option<String>,
+
+    /// Report how many lines each expression matched and failed to stderr at the end
+    #[structopt(long)]
+    pattern_stats: bool,
+
+    /// Report a uniform sample of the number of lines of each class (the expression matching them
+    /// or `unmatched`) along with the other reports, to stderr at the end, e.g. with --classify to
+    /// see what the unmatched lines look like
+    #[structopt(long, value_name = "lines")]
+    examples: Option<usize>,
+
+    /// Print each line prefixed with the class it falls in, i.e. the name of the expression matching
+    /// it or `unmatched` (separated by a tab), instead of the records, followed by the number of
+    /// the lines of each class to stderr, e.g. to audit the coverage of the expressions
+    #[structopt(long)]
+    classify: bool,
+
+    /// Don't print the records, only the number of them and the reports enabled for the run (e.g.
+    /// --pattern-stats)
+    #[structopt(long)]
+    summary_only: bool,
+
+    /// Only print the number of records (counted after the filters), like `grep -c`
+    #[structopt(short, long, conflicts_with = "summary-only")]
+    count: bool,
+
+    /// Stop as soon as a record matches the filter, in format "field_name pattern", e.g. to wait
+    /// for a service to log that it started
+    #[structopt(long)]
+    exit_on: Option<String>,
+
+    /// Exit code to stop with when a record matches --exit-on [default: 0]
+    #[structopt(long, requires = "exit-on")]
+    exit_code: Option<i32>,
+
+    /// Give up waiting for a record matching --exit-on after the duration (e.g. 60s or 1m30s),
+    /// exiting with code 124
+    #[structopt(long, requires = "exit-on")]
+    timeout: Option<String>,
+
+    /// While following the input, write a heartbeat (`heartbeat <time>: <n> records, <n> lines`, or
+    /// a JSON object with the `_heartbeat` field) once no record was written for the duration
+    /// (e.g. 30s), telling the monitors downstream that no record came rather than grop died
+    #[structopt(long)]
+    heartbeat: Option<String>,
+
+    /// Add how late each record is read as the `_lag` field: the milliseconds from its timestamp
+    /// (see --time-field) to now, e.g. to see the ingestion delay while following the input
+    #[structopt(long)]
+    lag: bool,
+
+    /// Enforce the limits for running on untrusted input, with safe defaults unless given: the
+    /// lines are cut to --max-line-length (64 KiB), the merged records are written out once they
+    /// hold --max-merge-lines (1000), and a panic processing a record only loses that record
+    #[structopt(long)]
+    hardened: bool,
+
+    /// Cut the lines longer than the number of bytes before matching them, counting them as
+    /// warnings. The rest of such a line is not held in memory
+    #[structopt(long)]
+    max_line_length: Option<usize>,
+
+    /// Write a merged record out once it holds the number of lines, ending its merged section
+    #[structopt(long)]
+    max_merge_lines: Option<usize>,
+
+    /// Match the lines on the number of worker threads, writing the records in input order. The
+    /// lines are matched one after the other when merging them, following the input or tailing it
+    /// over SSH, or waiting for a record to exit on. Several input files are read at the same time
+    /// instead, unless resuming from a checkpoint, the records written as they come with their file
+    /// as the `__file` field, like with -H, which leads the output format if it's not in it
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+
+    /// Hand the lines to the worker threads in batches of the number of lines [default: 1024]
+    #[structopt(long)]
+    batch_lines: Option<usize>,
+
+    /// Pick the number of worker threads (up to --jobs, or the number of CPUs) and of lines a
+    /// batch by benchmarking a few of them on the first lines of the input
+    #[structopt(long)]
+    auto_tune: bool,
+
+    /// Write the records of the input files read at the same time file after file, in input order,
+    /// rather than as they come
+    #[structopt(long, requires = "jobs")]
+    group_by_file: bool,
+
     /// Silence all output
     #[structopt(short, long)]
     pub quiet: bool,
@@ -61,57 +677,561 @@ pub struct Opt {
     pub verbose: usize,
 
     /// Config file in toml format. A sample file could be found at "doc/sample.toml".
-    #[structopt(long = "config", parse(from_os_str))]
+    #[structopt(long = "config", global = true, parse(from_os_str))]
     config_file: Option<PathBuf>,
+
+    /// Format of the errors and warnings written to stderr: `text`, or `json` for one JSON object
+    /// per line with the level, code, message, and the input file and line when known
+    #[structopt(long, global = true, default_value = "text", possible_values = &["text", "json"])]
+    error_format: String,
+
+    /// Fail on the keys of the config file grop doesn't know about, instead of ignoring them
+    #[structopt(long, global = true)]
+    strict_config: bool,
+
+    #[structopt(subcommand)]
+    #[serde(skip)]
+    cmd: Option<Command>,
 }
 
-impl Into<Config> for Opt {
-    fn into(self) -> Config {
+impl From<Opt> for Config {
+    fn from(opt: Opt) -> Config {
+        // A single expression is the match expression, several ones are tried in order.
+        let (match_expression, match_expressions) = match opt.expression {
+            Some(mut expressions) if expressions.len() == 1 => (expressions.pop(), None),
+            expressions => (None, expressions),
+        };
         Config {
-            input: self.input,
-            custom_patterns: self.pattern,
-            list_pattern: self.list_pattern,
-            match_expression: self.expression,
-            merge_config: match (
-                &self.merge_field,
-                &self.merge_exp_start,
-                &self.merge_exp_end,
-            ) {
+            input: opt.input,
+            preset: opt.preset,
+            ssh: opt.ssh,
+            remote_path: opt.remote_path,
+            retry_open: opt.retry_open,
+            mmap: opt.mmap,
+            reverse: opt.reverse,
+            with_filename: opt.with_filename,
+            line_number: opt.line_number,
+            follow: opt.follow || opt.tail.is_some(),
+            tail: opt.tail.map(|t| t.unwrap_or(DEFAULT_TAIL)),
+            skip: match (opt.skip, opt.from_line) {
+                (Some(skip), _) => Some(skip),
+                (None, from_line) => from_line.map(|n| n.saturating_sub(1)),
+            },
+            take: opt.take,
+            from_byte: opt.from_byte,
+            to_byte: opt.to_byte,
+            compression: opt.compression,
+            custom_patterns: opt.pattern,
+            pattern_files: opt.pattern_file,
+            pattern_dirs: opt.pattern_dir,
+            pattern_library: PatternLibrary::default_dir(),
+            list_pattern: opt.list_pattern,
+            check_expression: opt.check_expression,
+            match_expression,
+            match_expressions,
+            expression_names: opt.expression_name,
+            expression_file: opt.expression_file,
+            merge_config: match (&opt.merge_field, &opt.merge_exp_start, &opt.merge_exp_end) {
                 (None, None, None) => None,
                 _ => Some(MergeConfig {
-                    merge_fields: self.merge_field,
-                    merge_exp_start: self.merge_exp_start,
-                    merge_exp_end: self.merge_exp_end,
-                    merge_scope_exclusive: self.merge_scope_exclusive,
+                    merge_fields: opt.merge_field,
+                    merge_exp_start: opt.merge_exp_start,
+                    merge_exp_end: opt.merge_exp_end,
+                    merge_scope_exclusive: opt.merge_scope_exclusive,
+                    merge_key_field: opt.merge_key_field,
+                    merge_key_ttl: opt.merge_key_ttl,
+                    merge_continuation: opt.merge_continuation,
+                    merge_flush_eof: opt.merge_flush_eof,
                 }),
             },
-            filters: self.filter,
-            output_format: self.output_format,
+            filters: opt.filter,
+            filter_file: opt.filter_file,
+            where_expression: match (opt.errors, opt.where_expression) {
+                (true, Some(expression)) => {
+                    Some(format!("{} && ({})", ERROR_CONDITION, expression))
+                }
+                (true, None) => Some(String::from(ERROR_CONDITION)),
+                (false, expression) => expression,
+            },
+            invert_match: opt.invert_match,
+            on_unmatched: opt.on_unmatched,
+            throttle: opt.throttle_by.map(|t| t.join(" ")),
+            before_context: opt.before_context.or(opt.context),
+            after_context: opt.after_context.or(opt.context),
+            output_format: match opt.json {
+                true => Some(String::from("json")),
+                false => opt.output.or(opt.output_format),
+            },
+            display_tz: opt.display_tz,
+            time_field: opt.time_field,
+            time_format: opt.time_format,
+            fresher_than: opt.fresher_than,
+            since: opt.since,
+            until: opt.until,
+            fields: opt.fields,
+            output_path: opt.output_path,
+            tee: opt.tee.map(|tees| {
+                tees.iter()
+                    .map(|tee| match tee.rsplit_once('=') {
+                        Some((path, format)) => Tee {
+                            path: String::from(path),
+                            format: Some(String::from(format)),
+                        },
+                        None => Tee {
+                            path: tee.clone(),
+                            format: None,
+                        },
+                    })
+                    .collect()
+            }),
+            output_mode: opt.out_mode,
+            color: opt.color,
+            color_by: opt.color_by,
+            colors: None,
+            aliases: None,
+            merge_configs: None,
+            actions: None,
+            output_atomic: opt.out_atomic,
+            dedup_state: opt.dedup_state,
+            squash_repeats: opt.squash_repeats.is_some(),
+            squash_key: opt.squash_repeats.flatten(),
+            checkpoint: opt.checkpoint,
+            checkpoint_interval: opt.checkpoint_interval,
+            assert_output: opt.assert_output,
+            tags: opt.tag,
+            pattern_stats: opt.pattern_stats,
+            examples: opt.examples,
+            summary_only: opt.summary_only,
+            classify: opt.classify,
+            count: opt.count,
+            exit_on: opt.exit_on,
+            exit_code: opt.exit_code,
+            timeout: opt.timeout,
+            heartbeat: opt.heartbeat,
+            lag: opt.lag,
+            hardened: opt.hardened,
+            max_line_length: opt.max_line_length,
+            max_merge_lines: opt.max_merge_lines,
+            jobs: opt.jobs,
+            batch_lines: opt.batch_lines,
+            auto_tune: opt.auto_tune,
+            group_by_file: opt.group_by_file,
+            base64_binary: opt.base64_binary,
+            provenance: opt.provenance,
+            offset: opt.offset,
+            limit: opt.limit,
+            page: opt.page,
+            fail_on_warn: opt.fail_on_warn.map(|t| t.unwrap_or(0)),
+            min_match_rate: opt.min_match_rate,
+            match_rate_lines: opt.match_rate_lines,
+            match_rate_warn: opt.match_rate_warn,
+            set: opt.set,
+            unset: opt.unset,
+            transforms: opt.transform,
+            carry_forward: opt.carry_forward,
+            deltas: opt.delta,
+            moving_averages: opt.moving_avg,
+            number_locale: opt.number_locale,
+            conditional_tags: opt.tag_if.map(|v| {
+                v.chunks(2)
+                    .map(|c| ConditionalTag {
+                        condition: c[0].clone(),
+                        tag: c[1].clone(),
+                    })
+                    .collect()
+            }),
         }
     }
 }
 
-fn main() {
-    let opt = Opt::from_args();
+fn history() -> Result<History, GropError> {
+    let path = History::default_path().ok_or_else(|| {
+        GropError::InvalidArg(String::from(
+            "can not locate the history file, HOME is not set",
+        ))
+    })?;
+    History::load(path)
+}
 
-    stderrlog::new()
-        .verbosity(opt.verbose)
-        .quiet(opt.quiet)
-        .init()
-        .unwrap();
+fn list_history() -> Result<(), GropError> {
+    for (i, entry) in history()?.entries() {
+        println!("{:>4}  {}", i, entry);
+    }
+    Ok(())
+}
 
-    let config: Config;
+fn list_sources() -> Result<(), GropError> {
+    for (name, supported, description) in SOURCES {
+        let support = if *supported { "yes" } else { "no" };
+        println!("{:<10} {:<4} {}", name, support, description);
+    }
+    Ok(())
+}
 
-    if let Some(config_file) = &opt.config_file {
-        let content = fs::read_to_string(config_file).expect("failed to read config file");
-        let cfg: Config = toml::from_str(&content).expect("failed to parse config file");
-        config = cfg.merge(opt.into());
-    } else {
-        config = opt.into();
+fn manage_patterns(cmd: PatternsCommand) -> Result<(), GropError> {
+    let dir = PatternLibrary::default_dir().ok_or_else(|| {
+        GropError::InvalidArg(String::from(
+            "can not locate the pattern library, HOME is not set",
+        ))
+    })?;
+    let library = PatternLibrary::open(dir);
+    match cmd {
+        PatternsCommand::Add {
+            name,
+            pattern,
+            file,
+        } => library.add(&format!("{} {}", name, pattern), &file),
+        PatternsCommand::Rm { name } => library.remove(&name),
+        PatternsCommand::Export => library.export(&mut std::io::stdout()),
+        PatternsCommand::Import { file, force } => library.import(&file, force),
+        PatternsCommand::Update { url, sha256 } => match (url, sha256) {
+            (Some(url), Some(sha256)) => library.update(&url, &sha256),
+            _ => library.update(UPSTREAM_URL, UPSTREAM_SHA256),
+        },
+    }
+}
+
+/// Number of the last lines --tail follows the input from, if not given.
+const DEFAULT_TAIL: usize = 10;
+
+/// Condition of --errors, the levels being ordered by their severity.
+const ERROR_CONDITION: &str = "level >= error";
+
+/// Exit codes of a query, the same as grep ones.
+const EXIT_STATUS: &str = "EXIT STATUS:
+    0 if any record was written out (or counted), 1 if none was, 2 on error. Stopping on a record
+    matching --exit-on exits with --exit-code instead, and timing out waiting for it with 124.";
+
+/// Printed instead of waiting for lines typed into the terminal, which is rarely what was meant.
+const STDIN_HINT: &str = "grop reads the lines to match from stdin, which is a terminal.
+
+Examples:
+    grop -e '%{IP:client} %{WORD:method}' access.log
+    journalctl -f | grop -e '%{LOGLEVEL:lvl} %{GREEDYDATA:msg}' --filter '-lvl DEBUG'
+    grop --config doc/sample.toml app.log
+
+Pass --force-stdin to type the lines in, or --help for all the options.";
+
+fn run_query(
+    mut config: Config,
+    last: Option<Option<usize>>,
+    force_stdin: bool,
+) -> Result<i32, GropError> {
+    if let Some(index) = last {
+        config = history()?.get(index.unwrap_or(1))?.clone().apply(config);
+    }
+    let reads_stdin = config.input.is_empty()
+        && config.ssh.is_none()
+        && config.list_pattern.is_none()
+        && !config.check_expression;
+    if reads_stdin && !force_stdin && std::io::stdin().is_terminal() {
+        eprintln!("{}", STDIN_HINT);
+        return Ok(2);
     }
+    let entry = match (&config.list_pattern, &config.match_expression) {
+        (None, Some(_)) if !config.check_expression => Some(HistoryEntry::from(&config)),
+        _ => None,
+    };
+    let code = grop::run(config)?;
+    if let Some(entry) = entry {
+        // Failing to remember the query shouldn't fail the query itself.
+        if let Err(err) = history().and_then(|mut h| h.record(entry)) {
+            log::warn!("failed to record the query history: {}", err);
+        }
+    }
+    Ok(code)
+}
+
+/// Expand the user-defined aliases of the arguments (the `[aliases]` table of the config file),
+/// e.g. `--web` for `--preset nginx --filter "-status ^2"`. The expansions are not expanded again.
+fn expand_aliases(args: Vec<OsString>) -> Vec<OsString> {
+    let config_file = args
+        .windows(2)
+        .find(|w| w[0] == "--config")
+        .map(|w| PathBuf::from(&w[1]))
+        .or_else(|| {
+            args.iter().find_map(|a| {
+                a.to_str()
+                    .and_then(|a| a.strip_prefix("--config="))
+                    .map(PathBuf::from)
+            })
+        });
+    // A config failing to load is reported once the arguments are parsed.
+    let aliases = match config_file
+        .and_then(|file| fs::read_to_string(file).ok())
+        .and_then(|content| toml::from_str::<Config>(&content).ok())
+        .and_then(|config| config.aliases)
+    {
+        Some(aliases) => aliases,
+        None => return args,
+    };
+    let mut expanded = Vec::new();
+    let mut options = true;
+    for arg in args {
+        let alias = arg
+            .to_str()
+            .and_then(|a| a.strip_prefix("--"))
+            .and_then(|name| aliases.get(name));
+        match alias {
+            Some(expansion) if options => expanded.extend(expansion.iter().map(OsString::from)),
+            _ => {
+                // The arguments after `--` are inputs, not options.
+                options = options && arg != "--";
+                expanded.push(arg);
+            }
+        }
+    }
+    expanded
+}
 
-    if let Err(err) = grop::run(config) {
+/// Logger writing each message as a JSON object per line to stderr.
+struct JsonLogger;
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!(
+            "{}",
+            json!({
+                "level": record.level().as_str().to_lowercase(),
+                "message": record.args().to_string(),
+            })
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+static JSON_LOGGER: JsonLogger = JsonLogger;
+
+/// Report the error which stopped grop, along with the file and the line it's about, if known.
+fn report_error(err: &GropError, json: bool, file: Option<&Path>) {
+    if !json {
         log::error!("{}", err);
-        exit(1);
+        return;
+    }
+    let mut report = json!({
+        "level": "error",
+        "code": err.code(),
+    });
+    if let Some(file) = file {
+        report["file"] = json!(file.display().to_string());
+    }
+    match err {
+        // The line goes to its own key rather than into the message.
+        GropError::Input { file, line, err } => {
+            if let Some(file) = file {
+                report["file"] = json!(file);
+            }
+            report["line"] = json!(line);
+            report["message"] = json!(err.to_string());
+        }
+        _ => report["message"] = json!(err.to_string()),
+    }
+    eprintln!("{}", report);
+}
+
+fn main() {
+    let mut opt = match Opt::from_iter_safe(expand_aliases(std::env::args_os().collect())) {
+        Ok(opt) => opt,
+        // Usage errors exit with 2 like the other errors, --help and --version with 0.
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            exit(2);
+        }
+        Err(err) => err.exit(),
+    };
+
+    let json_errors = opt.error_format == "json";
+    if json_errors {
+        // Same levels as the text logs: errors only by default, one more level per -v.
+        let level = match (opt.quiet, opt.verbose) {
+            (true, _) => log::LevelFilter::Off,
+            (false, 0) => log::LevelFilter::Error,
+            (false, 1) => log::LevelFilter::Warn,
+            (false, 2) => log::LevelFilter::Info,
+            (false, 3) => log::LevelFilter::Debug,
+            (false, _) => log::LevelFilter::Trace,
+        };
+        log::set_logger(&JSON_LOGGER).unwrap();
+        log::set_max_level(level);
+    } else {
+        stderrlog::new()
+            .verbosity(opt.verbose)
+            .quiet(opt.quiet)
+            .init()
+            .unwrap();
+    }
+
+    // The doctor reports a broken config rather than failing on it.
+    if let Some(Command::Doctor) = opt.cmd {
+        match grop::run_doctor(opt.config_file.as_deref()) {
+            Ok(0) => exit(0),
+            Ok(_) => exit(1),
+            Err(err) => {
+                report_error(&err, json_errors, None);
+                exit(2);
+            }
+        }
+    }
+    let cmd = opt.cmd.take();
+    let last = opt.last.take();
+    let force_stdin = opt.force_stdin;
+    let config: Config = if let Some(config_file) = &opt.config_file {
+        let content = match fs::read_to_string(config_file) {
+            Ok(content) => content,
+            Err(err) => {
+                report_error(&GropError::Io(err), json_errors, Some(config_file));
+                exit(2);
+            }
+        };
+        if opt.strict_config {
+            if let Err(err) = grop::check_config_keys(&content) {
+                report_error(&err, json_errors, Some(config_file));
+                exit(2);
+            }
+        }
+        let cfg: Config = match toml::from_str(&content) {
+            Ok(cfg) => cfg,
+            Err(err) => {
+                let err = GropError::InvalidArg(format!("failed to parse config file: {}", err));
+                report_error(&err, json_errors, Some(config_file));
+                exit(2);
+            }
+        };
+        cfg.merge(opt.into())
+    } else {
+        opt.into()
+    };
+    let config = match config
+        .load_expressions()
+        .and_then(Config::load_pattern_files)
+    {
+        Ok(config) => config,
+        Err(err) => {
+            report_error(&err, json_errors, None);
+            exit(2);
+        }
+    };
+
+    let input = config.input.clone();
+    let result = match cmd {
+        Some(Command::Wizard { input }) => grop::run_wizard(Config {
+            input: input.map(|i| vec![i]).unwrap_or(config.input),
+            ..config
+        }),
+        Some(Command::History) => list_history(),
+        Some(Command::Sources) => list_sources(),
+        Some(Command::Patterns(cmd)) => manage_patterns(cmd),
+        Some(Command::Lint {
+            expression,
+            pattern,
+            filter,
+            format,
+        }) => {
+            // The expression given to lint replaces the ones of the config.
+            let (match_expressions, expression_names) = match expression {
+                Some(_) => (None, None),
+                None => (config.match_expressions, config.expression_names),
+            };
+            let config = Config {
+                match_expression: expression.or(config.match_expression),
+                match_expressions,
+                expression_names,
+                custom_patterns: pattern.or(config.custom_patterns),
+                filters: filter.or(config.filters),
+                ..config
+            };
+            match grop::run_lint(config, format) {
+                Ok(0) => Ok(()),
+                // Fail on findings, so that the lint can gate CI.
+                Ok(_) => exit(1),
+                Err(err) => Err(err),
+            }
+        }
+        Some(Command::Doctor) => unreachable!("the doctor runs before the config is loaded"),
+        Some(Command::Generate {
+            preset,
+            expression,
+            pattern,
+            lines,
+            seed,
+            start,
+        }) => {
+            let config = Config {
+                preset: preset.or(config.preset),
+                match_expressions: expression.or(config.match_expressions),
+                custom_patterns: pattern.or(config.custom_patterns),
+                ..config
+            };
+            grop::run_generate(config, lines, seed, start.as_deref())
+        }
+        Some(Command::TestConfig { cases }) => match grop::run_test_config(config, &cases) {
+            Ok(0) => Ok(()),
+            Ok(_) => exit(1),
+            Err(err) => Err(err),
+        },
+        None => match run_query(config, last, force_stdin) {
+            Ok(0) => Ok(()),
+            Ok(code) => exit(code),
+            Err(err) => Err(err),
+        },
+    };
+    if let Err(err) = result {
+        // Errors of the lines tell their file, only a single input is reported for the others.
+        let file = match input.as_slice() {
+            [file] => Some(file.as_path()),
+            _ => None,
+        };
+        report_error(&err, json_errors, file);
+        match err {
+            // Same as timeout(1), so that scripts can tell it from the other failures.
+            GropError::Timeout(_) => exit(124),
+            _ => exit(2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// The repeated options take a single value each, leaving the input files after them.
+    #[test]
+    fn test_repeated_options_before_input() {
+        let parse = |args: &[&str]| -> Config {
+            Opt::from_iter_safe(["grop", "-e", "%{INT:n}"].iter().chain(args.iter()))
+                .unwrap()
+                .into()
+        };
+        let config = parse(&["--delta", "n", "l.log"]);
+        assert_eq!(config.input, vec![PathBuf::from("l.log")]);
+        assert_eq!(config.deltas, Some(vec![String::from("n")]));
+
+        let config = parse(&["--moving-avg", "n", "l.log"]);
+        assert_eq!(config.input, vec![PathBuf::from("l.log")]);
+        assert_eq!(config.moving_averages, Some(vec![String::from("n")]));
+
+        let config = parse(&["--transform", "set x=1", "l.log"]);
+        assert_eq!(config.input, vec![PathBuf::from("l.log")]);
+        assert_eq!(config.transforms, Some(vec![String::from("set x=1")]));
+
+        let config = parse(&["--set", "x=1", "--unset", "y", "l.log"]);
+        assert_eq!(config.input, vec![PathBuf::from("l.log")]);
+        assert_eq!(config.set, Some(vec![String::from("x=1")]));
+        assert_eq!(config.unset, Some(vec![String::from("y")]));
+
+        let config = parse(&["--tag", "env=prod", "l.log"]);
+        assert_eq!(config.input, vec![PathBuf::from("l.log")]);
+        assert_eq!(config.tags, Some(vec![String::from("env=prod")]));
+
+        let config = parse(&["--pattern-file", "p", "--pattern-dir", "d", "l.log"]);
+        assert_eq!(config.input, vec![PathBuf::from("l.log")]);
+        assert_eq!(config.pattern_files, Some(vec![PathBuf::from("p")]));
+        assert_eq!(config.pattern_dirs, Some(vec![PathBuf::from("d")]));
     }
 }
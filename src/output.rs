@@ -0,0 +1,891 @@
+use crate::timestamp::record_time;
+use crate::GropError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::Value;
+use similar::TextDiff;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufWriter};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// How output files that already exist are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    Append,
+    Truncate,
+    CreateNew,
+}
+
+impl FromStr for OutputMode {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<OutputMode, GropError> {
+        match s {
+            "append" => Ok(OutputMode::Append),
+            "truncate" => Ok(OutputMode::Truncate),
+            "create-new" => Ok(OutputMode::CreateNew),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown output mode {} (should be one of append, truncate, create-new)",
+                s
+            ))),
+        }
+    }
+}
+
+/// What becomes of the lines which don't match the expression.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unmatched {
+    /// Written out as they are, along with the records.
+    Print,
+    Drop,
+    /// Turned into records holding the line in the `__unmatched` field.
+    Tag,
+    /// Written to stderr, for inspection.
+    Stderr,
+    /// Fail on the first one.
+    Fail,
+}
+
+impl FromStr for Unmatched {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<Unmatched, GropError> {
+        match s {
+            "print" => Ok(Unmatched::Print),
+            "drop" => Ok(Unmatched::Drop),
+            "tag" => Ok(Unmatched::Tag),
+            "stderr" => Ok(Unmatched::Stderr),
+            "fail" => Ok(Unmatched::Fail),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown unmatched line policy {} (should be one of print, drop, tag, stderr, fail)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Whether the output fields are colored.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /// Only when writing to a terminal, and NO_COLOR is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<ColorMode, GropError> {
+        match s {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown color mode {} (should be one of auto, always, never)",
+                s
+            ))),
+        }
+    }
+}
+
+/// ANSI colors the output fields cycle through: green, yellow, blue, magenta, cyan, red.
+const COLORS: &[u8] = &[32, 33, 34, 35, 36, 31];
+
+/// Wrap the value in the color of the `i`th field.
+pub(crate) fn paint(v: &str, i: usize) -> String {
+    tint(v, COLORS[i % COLORS.len()])
+}
+
+/// Wrap the text in the ANSI color.
+fn tint(v: &str, color: u8) -> String {
+    format!("\x1b[{}m{}\x1b[0m", color, v)
+}
+
+/// ANSI styles of the lines of a merged record: its start (and end) lines stand out, the lines
+/// merged in between are dimmed.
+const BOLD: u8 = 1;
+const FAINT: u8 = 2;
+/// Turns off the style, and only it, so a color spanning the lines of the record is kept.
+const NORMAL: u8 = 22;
+
+/// Highlight the start line of the merged record, and its end line if it `ended` on one.
+pub(crate) fn highlight_block(record: &str, ended: bool) -> String {
+    let last = record.lines().count().saturating_sub(1);
+    record
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let style = match i == 0 || (ended && i == last) {
+                true => BOLD,
+                false => FAINT,
+            };
+            // The style is reset along with the colors of the fields.
+            let line = line.replace("\x1b[0m", &format!("\x1b[0m\x1b[{}m", style));
+            format!("\x1b[{}m{}\x1b[{}m", style, line, NORMAL)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Names of the colors the records can be tinted with, along with their ANSI code.
+const COLOR_NAMES: &[(&str, u8)] = &[
+    ("black", 30),
+    ("red", 31),
+    ("green", 32),
+    ("yellow", 33),
+    ("blue", 34),
+    ("magenta", 35),
+    ("cyan", 36),
+    ("white", 37),
+];
+
+/// Colors of the log levels, used when no mapping is configured.
+const LEVEL_COLORS: &[(&str, &str)] = &[
+    ("fatal", "red"),
+    ("critical", "red"),
+    ("error", "red"),
+    ("warn", "yellow"),
+    ("warning", "yellow"),
+    ("info", "green"),
+    ("debug", "blue"),
+    ("trace", "magenta"),
+];
+
+/// Mapping of field values (case insensitive) to the color to tint their whole record with.
+pub(crate) struct ColorMap {
+    colors: HashMap<String, u8>,
+}
+
+impl ColorMap {
+    /// Build the mapping from value to color name, defaulting to the usual log level colors.
+    pub(crate) fn new(mapping: Option<&HashMap<String, String>>) -> Result<ColorMap, GropError> {
+        let mapping: Vec<(&str, &str)> = match mapping {
+            Some(mapping) => mapping
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            None => LEVEL_COLORS.to_vec(),
+        };
+        let mut colors = HashMap::new();
+        for (value, name) in mapping {
+            let code = COLOR_NAMES
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, code)| *code)
+                .ok_or_else(|| {
+                    GropError::InvalidArg(format!(
+                        "unknown color {} (should be one of black, red, green, yellow, blue, magenta, cyan, white)",
+                        name
+                    ))
+                })?;
+            colors.insert(value.to_lowercase(), code);
+        }
+        Ok(ColorMap { colors })
+    }
+
+    /// Tint the record line with the color of the value, if it has one.
+    pub(crate) fn tint(&self, value: &str, line: String) -> String {
+        match self.colors.get(&value.to_lowercase()) {
+            Some(color) => tint(&line, *color),
+            None => line,
+        }
+    }
+}
+
+/// Type given to a field in the expression, e.g. `%{NUMBER:bytes:int}`, which the JSON output
+/// writes its values as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FieldType {
+    Int,
+    Float,
+}
+
+impl FieldType {
+    pub(crate) fn parse(s: &str) -> Option<FieldType> {
+        match s {
+            "int" => Some(FieldType::Int),
+            "float" => Some(FieldType::Float),
+            _ => None,
+        }
+    }
+
+    /// The value as a JSON number (the integers truncating the decimals), or as a string if it's
+    /// not a number in the locale.
+    pub(crate) fn json(self, v: &str, locale: NumberLocale) -> Value {
+        let n = locale.normalize(v).unwrap_or_default();
+        let float = n.parse::<f64>().ok().filter(|n| n.is_finite());
+        match (self, n.parse::<i64>(), float) {
+            (FieldType::Int, Ok(n), _) => Value::from(n),
+            (FieldType::Int, _, Some(n)) => Value::from(n.trunc() as i64),
+            (FieldType::Float, _, Some(n)) => Value::from(n),
+            _ => Value::from(v),
+        }
+    }
+}
+
+/// Locale the numbers of the fields are written in, e.g. by European logs, for the typed fields,
+/// the deltas and the moving averages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NumberLocale {
+    /// Decimal point, without grouping, e.g. `1234.5`.
+    #[default]
+    C,
+    /// Decimal point, thousands grouped by commas, e.g. `1,234.5`.
+    En,
+    /// Decimal comma, thousands grouped by dots, e.g. `1.234,5`.
+    De,
+    /// Decimal comma, thousands grouped by (no-break) spaces, e.g. `1 234,5`.
+    Fr,
+    /// Decimal point, thousands grouped by apostrophes, e.g. `1'234.5`.
+    Ch,
+}
+
+impl FromStr for NumberLocale {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<NumberLocale, GropError> {
+        match s {
+            "c" => Ok(NumberLocale::C),
+            "en" => Ok(NumberLocale::En),
+            "de" => Ok(NumberLocale::De),
+            "fr" => Ok(NumberLocale::Fr),
+            "ch" => Ok(NumberLocale::Ch),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown number locale {} (should be one of c, en, de, fr, ch)",
+                s
+            ))),
+        }
+    }
+}
+
+impl NumberLocale {
+    /// The decimal separator and the thousands ones.
+    fn separators(self) -> (char, &'static [char]) {
+        match self {
+            NumberLocale::C => ('.', &[]),
+            NumberLocale::En => ('.', &[',']),
+            NumberLocale::De => (',', &['.']),
+            NumberLocale::Fr => (',', &[' ', '\u{a0}', '\u{202f}']),
+            NumberLocale::Ch => ('.', &['\'']),
+        }
+    }
+
+    /// The number in the notation Rust parses, without the grouping and with a decimal point, or
+    /// `None` if the groups of its thousands are not of 3 digits. The value is not checked to be a
+    /// number otherwise.
+    pub(crate) fn normalize(self, v: &str) -> Option<Cow<'_, str>> {
+        let v = v.trim();
+        let (decimal, grouping) = self.separators();
+        if self == NumberLocale::C {
+            return Some(Cow::Borrowed(v));
+        }
+        let (integer, fraction) = match v.split_once(decimal) {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (v, None),
+        };
+        let mut groups = integer.split(grouping);
+        let mut n = String::from(groups.next().unwrap_or_default());
+        let digits = n.trim_start_matches(['-', '+']).len();
+        for group in groups {
+            if !(1..=3).contains(&digits)
+                || group.len() != 3
+                || !group.bytes().all(|b| b.is_ascii_digit())
+            {
+                return None;
+            }
+            n.push_str(group);
+        }
+        if let Some(fraction) = fraction {
+            n.push('.');
+            n.push_str(fraction);
+        }
+        Some(Cow::Owned(n))
+    }
+
+    /// The value as a number, if it's one in the locale.
+    pub(crate) fn parse(self, v: &str) -> Option<f64> {
+        self.normalize(v)?.parse::<f64>().ok()
+    }
+}
+
+/// Expand every `{field}` placeholder in `template` with the corresponding value in `m`.
+///
+/// Besides the record fields, `{_date}` expands to the local date (`YYYY-MM-DD`) of the record
+/// time, or of the current time if the record has none.
+pub(crate) fn expand_template(
+    template: &str,
+    m: &HashMap<String, String>,
+) -> Result<String, GropError> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            GropError::InvalidArg(format!("unclosed placeholder in template: {}", template))
+        })? + start;
+        let name = &rest[start + 1..end];
+        match m.get(name) {
+            Some(v) => expanded.push_str(v),
+            None if name == "_date" => {
+                let date = match record_time(m, None, None) {
+                    Some(time) => time.with_timezone(&chrono::Local).date_naive(),
+                    None => chrono::Local::now().date_naive(),
+                };
+                expanded.push_str(&date.format("%Y-%m-%d").to_string())
+            }
+            None => {
+                return Err(GropError::InvalidArg(format!(
+                    "unknown field in template: {}",
+                    name
+                )))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Whether the value is text, i.e. it has neither control characters (but tabs) nor invalid UTF-8
+/// sequences (decoded as U+FFFD).
+fn is_text(v: &str) -> bool {
+    !v.chars()
+        .any(|c| c == char::REPLACEMENT_CHARACTER || (c.is_control() && c != '\t'))
+}
+
+/// Base64 encode the values of the record which are not text, so that they can't break the
+/// output documents.
+pub(crate) fn encode_binary(m: &mut HashMap<String, String>) {
+    for v in m.values_mut() {
+        if !is_text(v) {
+            *v = STANDARD.encode(v.as_bytes());
+        }
+    }
+}
+
+/// Compare the output with the content of the expected file, printing a unified diff to stdout if
+/// they differ.
+pub(crate) fn assert_output(expected: &Path, actual: &[u8]) -> Result<(), GropError> {
+    let expected_content = fs::read_to_string(expected)?;
+    let actual = String::from_utf8_lossy(actual);
+    if expected_content == actual {
+        return Ok(());
+    }
+    let diff = TextDiff::from_lines(expected_content.as_str(), actual.as_ref());
+    print!(
+        "{}",
+        diff.unified_diff()
+            .header(&expected.display().to_string(), "actual")
+    );
+    Err(GropError::Assertion(format!(
+        "output does not match {}",
+        expected.display()
+    )))
+}
+
+/// Maximum number of the output files kept open at once, the least recently written one is closed
+/// to open another one.
+const MAX_OPEN_FILES: usize = 64;
+
+/// An output file, along with its writer unless it was closed to keep the number of the open files
+/// down. In atomic mode the records go to `temp` first, which is renamed to the final path once
+/// the run finished successfully.
+struct OutputFile {
+    writer: Option<BufWriter<File>>,
+    temp: Option<PathBuf>,
+    // When the file was last written to, in number of records.
+    used: u64,
+}
+
+/// The value, made safe to be a path component: the path separators and NUL are replaced, and
+/// neither `.`, `..` nor an empty value are kept as such.
+fn sanitize_component(v: &str) -> String {
+    match v {
+        "" | "." | ".." => String::from("_"),
+        v => v.replace(['/', '\\', '\0'], "_"),
+    }
+}
+
+/// Writes each record into the file whose path is expanded from a template, e.g.
+/// `logs/{_date}/{level}.log`.
+pub(crate) struct PartitionedWriter {
+    template: String,
+    mode: OutputMode,
+    atomic: bool,
+    files: HashMap<PathBuf, OutputFile>,
+    // Number of the records written.
+    written: u64,
+}
+
+impl PartitionedWriter {
+    pub(crate) fn new(
+        template: &str,
+        mode: OutputMode,
+        atomic: bool,
+    ) -> Result<PartitionedWriter, GropError> {
+        if atomic && mode == OutputMode::Append {
+            return Err(GropError::InvalidArg(String::from(
+                "atomic output can not be used with the append output mode",
+            )));
+        }
+        Ok(PartitionedWriter {
+            template: String::from(template),
+            mode,
+            atomic,
+            files: HashMap::new(),
+            written: 0,
+        })
+    }
+
+    /// The directory of the part of the template which comes before its first placeholder, which
+    /// the output files must be in.
+    fn fixed_dir(&self) -> &Path {
+        let fixed = &self.template[..self.template.find('{').unwrap_or(self.template.len())];
+        Path::new(&fixed[..fixed.rfind('/').map_or(0, |i| i + 1)])
+    }
+
+    fn open(&self, path: &Path) -> Result<OutputFile, GropError> {
+        log::info!("output: opening partition {}", path.display());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if self.mode == OutputMode::CreateNew && path.exists() {
+            return Err(GropError::InvalidArg(format!(
+                "output file {} already exists",
+                path.display()
+            )));
+        }
+        let temp = if self.atomic {
+            let name = path
+                .file_name()
+                .ok_or_else(|| {
+                    GropError::InvalidArg(format!("invalid output path {}", path.display()))
+                })?
+                .to_string_lossy();
+            Some(path.with_file_name(format!(".{}.grop-tmp", name)))
+        } else {
+            None
+        };
+        let mut options = OpenOptions::new();
+        match self.mode {
+            OutputMode::Append => options.append(true).create(true),
+            OutputMode::Truncate => options.write(true).truncate(true).create(true),
+            OutputMode::CreateNew if temp.is_some() => {
+                options.write(true).truncate(true).create(true)
+            }
+            OutputMode::CreateNew => options.write(true).create_new(true),
+        };
+        let file = options.open(temp.as_deref().unwrap_or(path))?;
+        Ok(OutputFile {
+            writer: Some(BufWriter::new(file)),
+            temp,
+            used: 0,
+        })
+    }
+
+    /// Close the least recently written file if there are too many open ones.
+    fn close_unused(&mut self) -> Result<(), GropError> {
+        let open = self.files.values().filter(|f| f.writer.is_some()).count();
+        if open < MAX_OPEN_FILES {
+            return Ok(());
+        }
+        let unused = self
+            .files
+            .values_mut()
+            .filter(|f| f.writer.is_some())
+            .min_by_key(|f| f.used);
+        if let Some(writer) = unused.and_then(|f| f.writer.take()) {
+            writer.into_inner().map_err(|err| err.into_error())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        m: &HashMap<String, String>,
+        line: &str,
+    ) -> Result<(), GropError> {
+        // Field values must not be able to escape the partition directory layout.
+        let sanitized = m
+            .iter()
+            .map(|(k, v)| (k.clone(), sanitize_component(v)))
+            .collect::<HashMap<String, String>>();
+        let path = PathBuf::from(expand_template(&self.template, &sanitized)?);
+        let contained = path.strip_prefix(self.fixed_dir()).is_ok_and(|rest| {
+            rest.components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        });
+        if !contained {
+            return Err(GropError::InvalidArg(format!(
+                "output file {} is out of {}",
+                path.display(),
+                self.fixed_dir().display()
+            )));
+        }
+        match self.files.get(&path) {
+            Some(file) if file.writer.is_some() => (),
+            Some(_) => {
+                // Reopened to go on with the records written before it was closed.
+                self.close_unused()?;
+                let target = self.files[&path]
+                    .temp
+                    .clone()
+                    .unwrap_or_else(|| path.clone());
+                log::info!("output: reopening partition {}", path.display());
+                let writer = OpenOptions::new().append(true).open(target)?;
+                self.files.get_mut(&path).unwrap().writer = Some(BufWriter::new(writer));
+            }
+            None => {
+                self.close_unused()?;
+                let file = self.open(&path)?;
+                self.files.insert(path.clone(), file);
+            }
+        }
+        self.written += 1;
+        let file = self.files.get_mut(&path).unwrap();
+        file.used = self.written;
+        // Written at once, so that the buffer is never flushed in the middle of a record.
+        let writer = file.writer.as_mut().unwrap();
+        writer.write_all(format!("{}\n", line).as_bytes())?;
+        Ok(())
+    }
+
+    /// Flush the records written so far to the output files.
+    pub(crate) fn flush(&mut self) -> Result<(), GropError> {
+        for writer in self.files.values_mut().filter_map(|f| f.writer.as_mut()) {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the output files, and make sure that none of the files to create from a temporary file
+    /// was created meanwhile.
+    fn ready(&mut self) -> Result<(), GropError> {
+        self.flush()?;
+        if self.mode != OutputMode::CreateNew {
+            return Ok(());
+        }
+        let mut created = self
+            .files
+            .iter()
+            .filter(|(path, file)| file.temp.is_some() && path.exists());
+        match created.next() {
+            Some((path, _)) => Err(GropError::InvalidArg(format!(
+                "output file {} already exists",
+                path.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Publish the output files. In atomic mode, none is published unless every one of them can
+    /// be, and no temporary file is left behind either way.
+    pub(crate) fn finish(&mut self) -> Result<(), GropError> {
+        let ready = self.ready();
+        let mut result = Ok(());
+        for (path, file) in self.files.drain() {
+            let temp = match file.temp {
+                Some(temp) => temp,
+                None => continue,
+            };
+            drop(file.writer);
+            if ready.is_err() {
+                let _ = fs::remove_file(&temp);
+                continue;
+            }
+            if let Err(err) = fs::rename(&temp, &path) {
+                let _ = fs::remove_file(&temp);
+                if result.is_ok() {
+                    result = Err(err.into());
+                }
+            }
+        }
+        ready.and(result)
+    }
+}
+
+impl Drop for PartitionedWriter {
+    // Files left at this point belong to an unfinished run, don't leave half written temporary
+    // files behind.
+    fn drop(&mut self) {
+        for file in self.files.values() {
+            if let Some(temp) = &file.temp {
+                let _ = fs::remove_file(temp);
+            }
+        }
+    }
+}
+
+/// Records shown around the records written out, like `grep -B/-A`: the records filtered out
+/// right before (held back until then) and after them. The groups of records which are not
+/// contiguous are separated by `--`, unless the output has no room for it.
+pub(crate) struct Context {
+    before: usize,
+    after: usize,
+    separator: bool,
+    // The last records filtered out, along with their number.
+    held: VecDeque<(u64, String)>,
+    // Number of the record being emitted, and of the last one written out.
+    number: u64,
+    last: Option<u64>,
+    // Number of the records still to write out after the last record written out.
+    remaining: usize,
+}
+
+impl Context {
+    pub(crate) fn new(before: usize, after: usize, separator: bool) -> Context {
+        Context {
+            before,
+            after,
+            separator,
+            held: VecDeque::with_capacity(before),
+            number: 0,
+            last: None,
+            remaining: 0,
+        }
+    }
+
+    /// Count the record being emitted.
+    pub(crate) fn next(&mut self) {
+        self.number += 1;
+    }
+
+    fn write(&mut self, number: u64, line: &str, output: &mut dyn Write) -> io::Result<()> {
+        if self.separator && self.last.is_some_and(|last| number > last + 1) {
+            writeln!(output, "--")?;
+        }
+        self.last = Some(number);
+        writeln!(output, "{}", line)
+    }
+
+    /// Write out the record filtered out if it comes right after a record written out, or else
+    /// hold it back in case one comes right after it.
+    pub(crate) fn filtered(&mut self, line: String, output: &mut dyn Write) -> io::Result<()> {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return self.write(self.number, &line, output);
+        }
+        if self.before > 0 {
+            if self.held.len() == self.before {
+                self.held.pop_front();
+            }
+            self.held.push_back((self.number, line));
+        }
+        Ok(())
+    }
+
+    /// Write out the records held back before the record, and the record itself.
+    pub(crate) fn matched(&mut self, line: &str, output: &mut dyn Write) -> io::Result<()> {
+        while let Some((number, held)) = self.held.pop_front() {
+            self.write(number, &held, output)?;
+        }
+        self.remaining = self.after;
+        self.write(self.number, line, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let mut context = Context::new(1, 1, true);
+        let mut output = Vec::new();
+        for (i, matched) in [false, false, true, false, false, false, true, false, true]
+            .iter()
+            .enumerate()
+        {
+            context.next();
+            let line = i.to_string();
+            if *matched {
+                context.matched(&line, &mut output).unwrap();
+            } else {
+                context.filtered(line, &mut output).unwrap();
+            }
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\n2\n3\n--\n5\n6\n7\n8\n"
+        );
+    }
+
+    #[test]
+    fn test_color_map() {
+        let levels = ColorMap::new(None).unwrap();
+        assert_eq!(
+            levels.tint("ERROR", String::from("boom")),
+            "\x1b[31mboom\x1b[0m"
+        );
+        assert_eq!(levels.tint("NOTICE", String::from("hi")), "hi");
+
+        let mut mapping = HashMap::new();
+        mapping.insert(String::from("500"), String::from("Red"));
+        let status = ColorMap::new(Some(&mapping)).unwrap();
+        assert_eq!(status.tint("500", String::from("x")), "\x1b[31mx\x1b[0m");
+        assert_eq!(status.tint("ERROR", String::from("x")), "x");
+
+        mapping.insert(String::from("404"), String::from("orange"));
+        assert!(ColorMap::new(Some(&mapping)).is_err());
+    }
+
+    #[test]
+    fn test_highlight_block() {
+        assert_eq!(
+            highlight_block("BEGIN\n  a\nEND", true),
+            "\x1b[1mBEGIN\x1b[22m\n\x1b[2m  a\x1b[22m\n\x1b[1mEND\x1b[22m"
+        );
+        assert_eq!(
+            highlight_block("start\n\x1b[31mx\x1b[0m y", false),
+            "\x1b[1mstart\x1b[22m\n\x1b[2m\x1b[31mx\x1b[0m\x1b[2m y\x1b[22m"
+        );
+        assert_eq!(highlight_block("one", false), "\x1b[1mone\x1b[22m");
+    }
+
+    #[test]
+    fn test_encode_binary() {
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("msg"), String::from("tab\tseparated"));
+        m.insert(String::from("payload"), String::from("\u{1}\u{fffd}"));
+        encode_binary(&mut m);
+        assert_eq!(m.get("msg").unwrap(), "tab\tseparated");
+        assert_eq!(m.get("payload").unwrap(), "Ae+/vQ==");
+    }
+
+    #[test]
+    fn test_number_locale() {
+        assert_eq!(NumberLocale::De.parse("1.234.567,5"), Some(1234567.5));
+        assert_eq!(NumberLocale::De.parse("-12,25"), Some(-12.25));
+        assert_eq!(NumberLocale::Fr.parse("1\u{202f}234,5"), Some(1234.5));
+        assert_eq!(NumberLocale::En.parse("1,234.5"), Some(1234.5));
+        assert_eq!(NumberLocale::Ch.parse("1'234"), Some(1234.0));
+        assert_eq!(NumberLocale::C.parse(" 1234.5 "), Some(1234.5));
+        // The thousands are grouped by 3 digits.
+        for invalid in ["1.5", "1.2345", "1234.567", ".123", "1,2.3"] {
+            assert_eq!(NumberLocale::De.parse(invalid), None, "{}", invalid);
+        }
+        assert_eq!(NumberLocale::C.parse("1,234"), None);
+
+        assert_eq!(FieldType::Int.json("1.234", NumberLocale::De), 1234);
+        assert_eq!(FieldType::Float.json("2,5", NumberLocale::De), 2.5);
+        assert_eq!(FieldType::Float.json("2.5", NumberLocale::De), "2.5");
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("level"), String::from("ERROR"));
+        assert_eq!(
+            expand_template("logs/{level}.log", &m).unwrap(),
+            "logs/ERROR.log"
+        );
+        assert_eq!(
+            expand_template("logs/{_date}", &m).unwrap().len(),
+            "logs/YYYY-MM-DD".len()
+        );
+        m.insert(
+            String::from("timestamp"),
+            String::from("2024-03-01T12:00:00Z"),
+        );
+        // The local date of the record, which may be a day off in the far time zones.
+        let date = expand_template("{_date}", &m).unwrap();
+        assert!(["2024-02-29", "2024-03-01", "2024-03-02"].contains(&date.as_str()));
+        assert!(expand_template("logs/{host}.log", &m).is_err());
+        assert!(expand_template("logs/{level.log", &m).is_err());
+    }
+
+    #[test]
+    fn test_partitioned_writer_modes() {
+        let dir = std::env::temp_dir().join(format!("grop-output-{}", std::process::id()));
+        let template = format!("{}/{{level}}.log", dir.display());
+        let path = dir.join("ERROR.log");
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("level"), String::from("ERROR"));
+
+        let mut w = PartitionedWriter::new(&template, OutputMode::Truncate, true).unwrap();
+        w.write(&m, "first").unwrap();
+        assert!(!path.exists());
+        w.finish().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\n");
+
+        let mut w = PartitionedWriter::new(&template, OutputMode::Append, false).unwrap();
+        w.write(&m, "second").unwrap();
+        w.finish().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+
+        let mut w = PartitionedWriter::new(&template, OutputMode::CreateNew, false).unwrap();
+        assert!(w.write(&m, "third").is_err());
+        assert!(PartitionedWriter::new(&template, OutputMode::Append, true).is_err());
+
+        // Nothing is published if one of the files to create was created meanwhile.
+        let mut w = PartitionedWriter::new(&template, OutputMode::CreateNew, true).unwrap();
+        for level in ["INFO", "WARN"].iter() {
+            m.insert(String::from("level"), String::from(*level));
+            w.write(&m, "fourth").unwrap();
+        }
+        fs::write(dir.join("WARN.log"), "").unwrap();
+        assert!(w.finish().is_err());
+        assert!(!dir.join("INFO.log").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_partitioned_writer_paths() {
+        let dir = std::env::temp_dir().join(format!("grop-partitions-{}", std::process::id()));
+        let template = format!("{}/{{host}}/{{n}}.log", dir.display());
+        let mut w = PartitionedWriter::new(&template, OutputMode::Truncate, false).unwrap();
+        let record = |host: &str, n: usize| {
+            let mut m = HashMap::<String, String>::new();
+            m.insert(String::from("host"), String::from(host));
+            m.insert(String::from("n"), n.to_string());
+            m
+        };
+        for host in ["..", ".", "", "a/../..", "a\\b", "a\0b"].iter() {
+            w.write(&record(host, 0), host).unwrap();
+        }
+        // More partitions than the files kept open, written to again once closed.
+        for n in 0..=MAX_OPEN_FILES {
+            w.write(&record("web", n), "first").unwrap();
+        }
+        w.write(&record("web", 0), "second").unwrap();
+        w.finish().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("_/0.log")).unwrap(),
+            "..\n.\n\n"
+        );
+        assert!(dir.join("a_.._../0.log").exists());
+        assert!(dir.join("a_b/0.log").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("web/0.log")).unwrap(),
+            "first\nsecond\n"
+        );
+
+        // The template itself can't lead out of its fixed directory either.
+        let template = format!("{}/{{host}}/../../x.log", dir.display());
+        let mut w = PartitionedWriter::new(&template, OutputMode::Truncate, false).unwrap();
+        assert!(w.write(&record("web", 0), "x").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_assert_output() {
+        let expected = std::env::temp_dir().join(format!("grop-expected-{}", std::process::id()));
+        fs::write(&expected, "a\nb\n").unwrap();
+        assert!(assert_output(&expected, b"a\nb\n").is_ok());
+        assert!(assert_output(&expected, b"a\nc\n").is_err());
+        fs::remove_file(&expected).unwrap();
+    }
+}
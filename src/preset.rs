@@ -0,0 +1,139 @@
+use crate::{Config, GropError};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Nginx access log, in the default `combined` format.
+const NGINX: &str = r#"
+custom_patterns = ['NGUSER [a-zA-Z\.\@\-\+_%]+']
+match_expression = '^%{IPORHOST:client} - %{NGUSER:user} \[%{HTTPDATE:time}\] "(?:%{WORD:method} %{NOTSPACE:path}(?: HTTP/%{NUMBER:http_version})?|%{DATA:request})" %{NUMBER:status} (?:%{NUMBER:bytes}|-) "%{DATA:referrer}" "%{DATA:agent}"'
+output_format = 'time,client,status,method,path,bytes'
+"#;
+
+/// Apache access log, in the `combined` format.
+const APACHE_COMBINED: &str = r#"
+match_expression = '^%{IPORHOST:client} %{HTTPDUSER:ident} %{HTTPDUSER:user} \[%{HTTPDATE:time}\] "(?:%{WORD:method} %{NOTSPACE:path}(?: HTTP/%{NUMBER:http_version})?|%{DATA:request})" %{NUMBER:status} (?:%{NUMBER:bytes}|-) %{QS:referrer} %{QS:agent}'
+output_format = 'time,client,status,method,path,bytes'
+"#;
+
+/// BSD syslog (RFC 3164) lines, as written by rsyslog or syslog-ng.
+const SYSLOG: &str = r#"
+match_expression = '^%{SYSLOGTIMESTAMP:time} %{SYSLOGHOST:host} %{PROG:program}(?:\[%{POSINT:pid}\])?: %{GREEDYDATA:msg}'
+output_format = 'time,host,program,msg'
+"#;
+
+/// Java application log, in the usual Logback/Log4j layout
+/// (`%d [%thread] %-5level %logger - %msg`).
+const JAVA: &str = r#"
+custom_patterns = [
+    'JAVATHREADNAME [^\]]+',
+    'JAVALOGGER (?:[a-zA-Z$_][a-zA-Z$_0-9]*\.)*[a-zA-Z$_][a-zA-Z$_0-9]*',
+]
+match_expression = '^%{TIMESTAMP_ISO8601:time}\s+\[%{JAVATHREADNAME:thread}\]\s+%{LOGLEVEL:level}\s+%{JAVALOGGER:logger}\s+-\s+%{GREEDYDATA:msg}'
+output_format = 'time,level,logger,msg'
+"#;
+
+/// Built-in configs for common log formats.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    Nginx,
+    ApacheCombined,
+    Syslog,
+    Java,
+}
+
+impl FromStr for Preset {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<Preset, GropError> {
+        match s {
+            "nginx" => Ok(Preset::Nginx),
+            "apache_combined" => Ok(Preset::ApacheCombined),
+            "syslog" => Ok(Preset::Syslog),
+            "java" => Ok(Preset::Java),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown preset {} (should be one of nginx, apache_combined, syslog, java)",
+                s
+            ))),
+        }
+    }
+}
+
+impl Preset {
+    fn config(self) -> Config {
+        let definition = match self {
+            Preset::Nginx => NGINX,
+            Preset::ApacheCombined => APACHE_COMBINED,
+            Preset::Syslog => SYSLOG,
+            Preset::Java => JAVA,
+        };
+        toml::from_str(definition).expect("invalid preset definition")
+    }
+
+    /// Use the preset as the base of the config, which overrides it. The custom patterns of both
+    /// are kept, the config ones coming last so that they may redefine the preset ones.
+    pub fn apply(self, config: Config) -> Config {
+        let mut preset = self.config();
+        let mut patterns = preset.custom_patterns.take().unwrap_or_default();
+        let mut config = preset.merge(config);
+        patterns.extend(config.custom_patterns.take().unwrap_or_default());
+        if !patterns.is_empty() {
+            config.custom_patterns = Some(patterns);
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::load_patterns;
+
+    #[test]
+    fn test_presets() {
+        let samples = [
+            (
+                Preset::Nginx,
+                r#"10.0.0.1 - - [10/Oct/2024:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 612 "-" "curl/8.0""#,
+                "path",
+                "/index.html",
+            ),
+            (
+                Preset::ApacheCombined,
+                r#"10.0.0.1 - frank [10/Oct/2024:13:55:36 -0700] "POST /login HTTP/1.0" 302 - "http://example.com/" "Mozilla/5.0""#,
+                "user",
+                "frank",
+            ),
+            (
+                Preset::Syslog,
+                "Oct 10 13:55:36 web1 sshd[4242]: Accepted publickey for root",
+                "program",
+                "sshd",
+            ),
+            (
+                Preset::Java,
+                "2024-10-10 13:55:36,123 [http-nio-8080-exec-1] WARN  com.example.Api - slow request",
+                "logger",
+                "com.example.Api",
+            ),
+        ];
+        for (preset, line, field, value) in samples.iter() {
+            let config = preset.apply(toml::from_str("custom_patterns = ['X x']").unwrap());
+            let (mut grok, _) = load_patterns(&config.custom_patterns, None).unwrap();
+            let pattern = grok
+                .compile(config.match_expression.as_ref().unwrap(), true)
+                .unwrap();
+            let matches = pattern.match_against(line).expect("preset didn't match");
+            assert_eq!(matches.get(field), Some(*value));
+            assert!(config.output_format.is_some());
+            assert_eq!(config.custom_patterns.unwrap().last().unwrap(), "X x");
+        }
+
+        let config: Config = toml::from_str("output_format = 'msg'").unwrap();
+        let config = Preset::Syslog.apply(config);
+        assert_eq!(config.output_format.unwrap(), "msg");
+        assert!(config.custom_patterns.is_none());
+        assert!("nginx-combined".parse::<Preset>().is_err());
+    }
+}
@@ -0,0 +1,1385 @@
+use crate::GropError;
+#[cfg(feature = "bzip2")]
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use memchr::memchr;
+use memmap2::Mmap;
+use notify::{RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait before trying to open a missing input again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a followed file is checked for new content.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Size of the chunks a file is read backwards by.
+const REVERSE_CHUNK: u64 = 64 * 1024;
+
+/// Size of the chunks decompressed ahead of the matcher, and how many of them are buffered.
+const DECODE_CHUNK: usize = 64 * 1024;
+const DECODE_AHEAD: usize = 16;
+
+/// The input sources, along with whether this build of grop supports them on this platform and
+/// what they are.
+pub const SOURCES: &[(&str, bool, &str)] = &[
+    (
+        "stdin",
+        true,
+        "the standard input, when no input file is given",
+    ),
+    (
+        "file",
+        true,
+        "the input files, mapped in memory when read in full",
+    ),
+    (
+        "directory",
+        true,
+        "the files of a followed directory, along with the new ones",
+    ),
+    (
+        "ssh",
+        true,
+        "a file tailed on remote hosts by the ssh command",
+    ),
+    ("gzip", true, "gzip compressed input (.gz)"),
+    (
+        "zstd",
+        cfg!(feature = "zstd"),
+        "zstd compressed input (.zst)",
+    ),
+    (
+        "bzip2",
+        cfg!(feature = "bzip2"),
+        "bzip2 compressed input (.bz2)",
+    ),
+    (
+        "fifo",
+        cfg!(unix),
+        "a named pipe or device, reopened when its writer goes away",
+    ),
+    (
+        "rotation",
+        cfg!(unix),
+        "a followed file, reopened once it's rotated",
+    ),
+];
+
+/// Compression of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Judging by the extension of the input file: .gz, .zst or .bz2.
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl FromStr for Compression {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<Compression, GropError> {
+        match s {
+            "auto" => Ok(Compression::Auto),
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "bzip2" => Ok(Compression::Bzip2),
+            _ => Err(GropError::InvalidArg(format!(
+                "unknown compression {} (should be one of auto, none, gzip, zstd, bzip2)",
+                s
+            ))),
+        }
+    }
+}
+
+impl Compression {
+    /// The compression of the input file, resolving auto by the extension of its path.
+    pub(crate) fn of(self, path: Option<&Path>) -> Compression {
+        if self != Compression::Auto {
+            return self;
+        }
+        match path.and_then(Path::extension).and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Where the input lines are read from.
+pub(crate) enum Source {
+    Reader(Box<dyn Read + Send>),
+    /// The file tailed on each of the hosts over SSH.
+    Ssh {
+        hosts: Vec<String>,
+        path: String,
+    },
+    /// The files of the directory followed, along with the ones created in it later on. The files
+    /// already there are followed from their last lines, if given.
+    Directory(PathBuf, Option<usize>),
+    /// A regular file, mapped in memory.
+    Mapped(Mmap),
+}
+
+/// An input line, along with the host it was read from when tailing over SSH.
+#[derive(Default)]
+pub(crate) struct Line {
+    pub(crate) text: String,
+    pub(crate) host: Option<String>,
+    // Whether invalid UTF-8 sequences were replaced in the text.
+    pub(crate) invalid_utf8: bool,
+    // Whether the line was cut to the maximal line length.
+    pub(crate) truncated: bool,
+    // Byte offset and number of the line in the (decompressed) input it was read from.
+    pub(crate) offset: u64,
+    pub(crate) number: usize,
+    // File the line was read from, when following a directory.
+    pub(crate) file: Option<String>,
+}
+
+/// Whether the file is a FIFO or a character device, whose end of input only means the writer went
+/// away.
+#[cfg(unix)]
+fn is_stream(file: &File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file.metadata()
+        .map(|m| m.file_type().is_fifo() || m.file_type().is_char_device())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_stream(_: &File) -> bool {
+    false
+}
+
+/// Open the file, waiting for it to show up if it's missing.
+fn open_retrying(path: &Path) -> io::Result<File> {
+    loop {
+        match File::open(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::info!("source: {} is missing, retrying", path.display());
+                thread::sleep(RETRY_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Reader of a pipe or device, reopened whenever its writer goes away.
+struct Reopening {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Read for Reopening {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => self.file.insert(open_retrying(&self.path)?),
+            };
+            match file.read(buf)? {
+                0 => {
+                    log::info!("source: {} was closed, reopening", self.path.display());
+                    self.file = None;
+                }
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+/// Identity of the file behind the metadata, telling whether a path was rotated to a new file.
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// A file which keeps being read as it grows, reopened once it's rotated and read from its start
+/// again once it's truncated (like `tail -F`).
+struct Following {
+    path: PathBuf,
+    file: File,
+    pos: u64,
+}
+
+impl Following {
+    fn rotated(&self, meta: &fs::Metadata) -> io::Result<bool> {
+        Ok(file_id(meta) != file_id(&self.file.metadata()?))
+    }
+}
+
+impl Read for Following {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            match fs::metadata(&self.path) {
+                Ok(meta) if self.rotated(&meta)? => {
+                    // Drain what was written to the rotated file since the last read.
+                    let n = self.file.read(buf)?;
+                    if n > 0 {
+                        self.pos += n as u64;
+                        return Ok(n);
+                    }
+                    log::info!("source: {} was rotated, reopening", self.path.display());
+                    self.file = open_retrying(&self.path)?;
+                    self.pos = 0;
+                }
+                Ok(meta) if meta.len() < self.pos => {
+                    log::info!("source: {} was truncated", self.path.display());
+                    self.file.seek(SeekFrom::Start(0))?;
+                    self.pos = 0;
+                }
+                // Wait for new content, also while the rotated file isn't recreated yet.
+                _ => thread::sleep(FOLLOW_INTERVAL),
+            }
+        }
+    }
+}
+
+/// Reader of the lines of a file from the last one to the first one, reading the file backwards
+/// by chunks.
+pub(crate) struct Reverse {
+    file: File,
+    // End of the part of the file not read yet.
+    pos: u64,
+    // Beginning of the earliest line read, whose start is not read yet.
+    carry: Vec<u8>,
+    // Lines ready to be read.
+    out: Vec<u8>,
+    out_pos: usize,
+    done: bool,
+}
+
+impl Reverse {
+    pub(crate) fn open(path: &Path) -> io::Result<Reverse> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut pos = len;
+        // The final newline doesn't start another (empty) line.
+        if pos > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::Start(pos - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] == b'\n' {
+                pos -= 1;
+            }
+        }
+        Ok(Reverse {
+            done: len == 0,
+            file,
+            pos,
+            carry: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+        })
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.out.clear();
+        self.out_pos = 0;
+        if self.pos == 0 {
+            // What is left is the first line of the file.
+            self.out.append(&mut self.carry);
+            self.out.push(b'\n');
+            self.done = true;
+            return Ok(());
+        }
+        let start = self.pos.saturating_sub(REVERSE_CHUNK);
+        let mut chunk = vec![0u8; (self.pos - start) as usize];
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut chunk)?;
+        chunk.append(&mut self.carry);
+        self.pos = start;
+        match chunk.iter().position(|b| *b == b'\n') {
+            Some(first) => {
+                for line in chunk[first + 1..].split(|b| *b == b'\n').rev() {
+                    self.out.extend_from_slice(line);
+                    self.out.push(b'\n');
+                }
+                chunk.truncate(first);
+                self.carry = chunk;
+            }
+            None => self.carry = chunk,
+        }
+        Ok(())
+    }
+}
+
+impl Read for Reverse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.out_pos);
+        buf[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        Ok(n)
+    }
+}
+
+/// Reader of the output of a decoder running on its own thread, so that the decompression of the
+/// input overlaps with the matching of the lines.
+struct Decoded {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Decoded {
+    fn spawn<R: Read + Send + 'static>(mut decoder: R) -> Decoded {
+        let (tx, rx) = mpsc::sync_channel(DECODE_AHEAD);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; DECODE_CHUNK];
+            match decoder.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    if tx.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                    break;
+                }
+            }
+        });
+        Decoded {
+            chunks: rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for Decoded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The decoder reached the end of the input.
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decompress the input, if it's compressed (with auto resolved already).
+pub(crate) fn decompress(
+    reader: Box<dyn Read + Send>,
+    compression: Compression,
+) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match compression {
+        Compression::Auto | Compression::None => reader,
+        Compression::Gzip => Box::new(Decoded::spawn(MultiGzDecoder::new(reader))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(Decoded::spawn(zstd::Decoder::new(reader)?)),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Box::new(Decoded::spawn(MultiBzDecoder::new(reader))),
+        #[allow(unreachable_patterns)]
+        compression => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{:?} input isn't supported by this build of grop (see grop sources)",
+                    compression
+                )
+                .to_lowercase(),
+            ))
+        }
+    })
+}
+
+/// Reader transcoding UTF-16 input (past its byte order mark) to UTF-8, the invalid code units
+/// being replaced with U+FFFD.
+struct Utf16 {
+    reader: Box<dyn Read + Send>,
+    big_endian: bool,
+    // Bytes read but not decoded yet: an odd byte, or a high surrogate waiting for its pair.
+    carry: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+    done: bool,
+}
+
+impl Utf16 {
+    fn fill(&mut self) -> io::Result<()> {
+        self.out.clear();
+        self.out_pos = 0;
+        let mut chunk = std::mem::take(&mut self.carry);
+        let start = chunk.len();
+        chunk.resize(start + DECODE_CHUNK, 0);
+        let n = loop {
+            match self.reader.read(&mut chunk[start..]) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        chunk.truncate(start + n);
+        self.done = n == 0;
+        let big_endian = self.big_endian;
+        let unit = |b: &[u8]| match big_endian {
+            true => u16::from_be_bytes([b[0], b[1]]),
+            false => u16::from_le_bytes([b[0], b[1]]),
+        };
+        let mut end = chunk.len() & !1;
+        if !self.done && end > 0 && (0xd800..0xdc00).contains(&unit(&chunk[end - 2..end])) {
+            end -= 2;
+        }
+        let mut utf8 = [0; 4];
+        for c in char::decode_utf16(chunk[..end].chunks_exact(2).map(unit)) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            self.out
+                .extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+        }
+        if !self.done {
+            self.carry = chunk.split_off(end);
+        } else if end < chunk.len() {
+            // The input ends in the middle of a code unit.
+            self.out.extend_from_slice(
+                char::REPLACEMENT_CHARACTER
+                    .encode_utf8(&mut utf8)
+                    .as_bytes(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Read for Utf16 {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.out_pos);
+        buf[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        Ok(n)
+    }
+}
+
+/// Transcode the input to UTF-8 if it starts with a UTF-16 byte order mark, as piped from some
+/// Windows tools (e.g. PowerShell), the other inputs being left as they are.
+pub(crate) fn transcode_utf16(
+    mut reader: Box<dyn Read + Send>,
+) -> io::Result<Box<dyn Read + Send>> {
+    let mut bom = Vec::with_capacity(2);
+    (&mut reader).take(2).read_to_end(&mut bom)?;
+    let big_endian = match bom.as_slice() {
+        [0xff, 0xfe] => false,
+        [0xfe, 0xff] => true,
+        _ => return Ok(Box::new(io::Cursor::new(bom).chain(reader))),
+    };
+    Ok(Box::new(Utf16 {
+        reader,
+        big_endian,
+        carry: Vec::new(),
+        out: Vec::new(),
+        out_pos: 0,
+        done: false,
+    }))
+}
+
+/// Open the input file, transparently decompressing it if it's compressed. A followed file is read
+/// from its last `tail` lines, if given.
+pub(crate) fn open_input(
+    path: &Path,
+    retry: bool,
+    follow: bool,
+    tail: Option<usize>,
+    compression: Compression,
+) -> io::Result<Box<dyn Read + Send>> {
+    decompress(
+        open_plain(path, retry, follow, tail)?,
+        compression.of(Some(path)),
+    )
+}
+
+/// Offset of the start of the last lines of the file.
+fn tail_offset(file: &mut File, lines: usize) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    let mut wanted = lines;
+    // The final newline ends the last line rather than starting another (empty) one.
+    if len > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] == b'\n' {
+            wanted += 1;
+        }
+    }
+    if wanted == 0 {
+        return Ok(len);
+    }
+    let mut pos = len;
+    while pos > 0 {
+        let start = pos.saturating_sub(REVERSE_CHUNK);
+        let mut chunk = vec![0u8; (pos - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        for (i, b) in chunk.iter().enumerate().rev() {
+            if *b == b'\n' {
+                wanted -= 1;
+                if wanted == 0 {
+                    return Ok(start + i as u64 + 1);
+                }
+            }
+        }
+        pos = start;
+    }
+    Ok(0)
+}
+
+/// Open the input file. With `retry`, a missing file is waited for and pipes or devices are
+/// reopened once their writer goes away, so that the input never ends.
+fn open_plain(
+    path: &Path,
+    retry: bool,
+    follow: bool,
+    tail: Option<usize>,
+) -> io::Result<Box<dyn Read + Send>> {
+    let mut file = if retry {
+        open_retrying(path)?
+    } else {
+        File::open(path)?
+    };
+    if retry && is_stream(&file) {
+        return Ok(Box::new(Reopening {
+            path: path.to_path_buf(),
+            file: Some(file),
+        }));
+    }
+    if follow {
+        let pos = match tail {
+            Some(lines) => tail_offset(&mut file, lines)?,
+            None => 0,
+        };
+        file.seek(SeekFrom::Start(pos))?;
+        return Ok(Box::new(Following {
+            path: path.to_path_buf(),
+            file,
+            pos,
+        }));
+    }
+    Ok(Box::new(file))
+}
+
+/// Open the (uncompressed) input file at the first line starting at or after the byte offset,
+/// without reading what comes before it. Returns the offset of that line along with the reader.
+pub(crate) fn open_at(path: &Path, offset: u64) -> io::Result<(Box<dyn Read + Send>, u64)> {
+    let mut file = File::open(path)?;
+    if offset == 0 {
+        return Ok((Box::new(file), 0));
+    }
+    // The line is whole if the byte before the offset ends the previous one.
+    file.seek(SeekFrom::Start(offset - 1))?;
+    let mut reader = BufReader::new(file);
+    let n = reader.read_until(b'\n', &mut Vec::new())?;
+    Ok((Box::new(reader), (offset - 1 + n as u64).max(offset)))
+}
+
+/// Read the line into the buffer like `read_until`, only keeping its first `max` bytes (cut at a
+/// character boundary) so that a huge line doesn't take up as much memory. Returns the number of
+/// bytes read along with whether the line was cut.
+fn read_line_within<R: BufRead>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max: usize,
+) -> io::Result<(usize, bool)> {
+    let (mut read, mut cut, mut end) = (0, false, false);
+    while !end {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            break;
+        }
+        let used = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                end = true;
+                i + 1
+            }
+            None => available.len(),
+        };
+        let mut content = &available[..used];
+        if end {
+            content = content.strip_suffix(b"\n").unwrap_or(content);
+            content = content.strip_suffix(b"\r").unwrap_or(content);
+        }
+        let room = max.saturating_sub(buf.len());
+        cut |= content.len() > room;
+        buf.extend_from_slice(&content[..content.len().min(room)]);
+        reader.consume(used);
+        read += used;
+    }
+    if cut {
+        let len = whole_chars(buf);
+        buf.truncate(len);
+    }
+    Ok((read, cut))
+}
+
+/// Length of the bytes without the character they were cut in the middle of, if any.
+fn whole_chars(bytes: &[u8]) -> usize {
+    let mut start = bytes.len();
+    while start > 0 && bytes.len() - start < 3 && bytes[start - 1] & 0xc0 == 0x80 {
+        start -= 1;
+    }
+    if start > 0 {
+        let width = match bytes[start - 1] {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        if bytes.len() - (start - 1) < width {
+            return start - 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Map the file in memory, unless it's not a regular file or it's empty (which can't be mapped).
+pub(crate) fn map_file(path: &Path) -> io::Result<Option<Mmap>> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return Ok(None);
+    }
+    // Safety: the file is only read, though truncating it meanwhile (e.g. when rotated by copying
+    // and truncating it) faults on the bytes gone, which is why mapping is opt-in (`--mmap`).
+    unsafe { Mmap::map(&file) }.map(Some)
+}
+
+/// Lines of a file mapped in memory, split over the mapped bytes rather than each read into a
+/// buffer of its own. Like the lines of a reader, the invalid UTF-8 sequences are replaced by
+/// U+FFFD, and the lines are cut to the maximal length if given.
+pub(crate) struct MappedLines {
+    map: Mmap,
+    // Offset of the next line in the file, and number of the last one.
+    position: usize,
+    number: usize,
+    max_line_length: Option<usize>,
+}
+
+impl MappedLines {
+    pub(crate) fn new(map: Mmap, max_line_length: Option<usize>) -> MappedLines {
+        MappedLines {
+            map,
+            position: 0,
+            number: 0,
+            max_line_length,
+        }
+    }
+
+    /// Read the next line into the line, reusing its text buffer. Returns false once all the lines
+    /// are read.
+    pub(crate) fn next_into(&mut self, line: &mut Line) -> bool {
+        let rest = &self.map[self.position..];
+        if rest.is_empty() {
+            return false;
+        }
+        let (mut content, len) = match memchr(b'\n', rest) {
+            Some(i) => {
+                let content = &rest[..i];
+                (content.strip_suffix(b"\r").unwrap_or(content), i + 1)
+            }
+            None => (rest, rest.len()),
+        };
+        line.truncated = false;
+        if let Some(max) = self.max_line_length.filter(|max| content.len() > *max) {
+            content = &content[..whole_chars(&content[..max])];
+            line.truncated = true;
+        }
+        line.text.clear();
+        match std::str::from_utf8(content) {
+            Ok(text) => {
+                line.text.push_str(text);
+                line.invalid_utf8 = false;
+            }
+            Err(_) => {
+                line.text.push_str(&String::from_utf8_lossy(content));
+                line.invalid_utf8 = true;
+            }
+        }
+        self.number += 1;
+        line.number = self.number;
+        line.offset = self.position as u64;
+        line.host = None;
+        line.file = None;
+        self.position += len;
+        true
+    }
+}
+
+impl Iterator for MappedLines {
+    type Item = io::Result<Line>;
+
+    fn next(&mut self) -> Option<io::Result<Line>> {
+        let mut line = Line::default();
+        match self.next_into(&mut line) {
+            true => Some(Ok(line)),
+            false => None,
+        }
+    }
+}
+
+/// Lines of the reader, with the invalid UTF-8 sequences replaced by U+FFFD instead of failing,
+/// cut to the maximal length if given.
+fn lossy_lines<R: BufRead>(
+    mut reader: R,
+    max_line_length: Option<usize>,
+) -> impl Iterator<Item = io::Result<Line>> {
+    let mut offset = 0;
+    let mut number = 0;
+    std::iter::from_fn(move || {
+        let mut buf = Vec::new();
+        let read = match max_line_length {
+            Some(max) => read_line_within(&mut reader, &mut buf, max),
+            None => reader.read_until(b'\n', &mut buf).map(|n| (n, false)),
+        };
+        match read {
+            Ok((0, _)) => None,
+            Ok((n, truncated)) => {
+                let line_offset = offset;
+                offset += n as u64;
+                number += 1;
+                if buf.ends_with(b"\n") {
+                    buf.pop();
+                    if buf.ends_with(b"\r") {
+                        buf.pop();
+                    }
+                }
+                let text = String::from_utf8_lossy(&buf);
+                Some(Ok(Line {
+                    invalid_utf8: matches!(text, Cow::Owned(_)),
+                    truncated,
+                    text: text.into_owned(),
+                    host: None,
+                    offset: line_offset,
+                    number,
+                    file: None,
+                }))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    })
+}
+
+/// Quote the argument for the remote shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Readers along with the host they read from.
+type Readers = Vec<(Option<String>, Box<dyn Read + Send>)>;
+
+/// Read the lines of the reader from its own thread, tagging them with where they come from.
+fn spawn_reader<F>(
+    tx: Sender<io::Result<Line>>,
+    reader: Box<dyn Read + Send>,
+    max_line_length: Option<usize>,
+    tag: F,
+) where
+    F: Fn(Line) -> Line + Send + 'static,
+{
+    thread::spawn(move || {
+        for line in lossy_lines(BufReader::new(reader), max_line_length) {
+            if tx.send(line.map(&tag)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Read the lines of each of the readers from its own thread, interleaved as they come.
+fn merge(readers: Readers, max_line_length: Option<usize>) -> Receiver<io::Result<Line>> {
+    let (tx, rx) = mpsc::channel();
+    for (host, reader) in readers {
+        spawn_reader(tx.clone(), reader, max_line_length, move |l| Line {
+            host: host.clone(),
+            ..l
+        });
+    }
+    rx
+}
+
+/// Follow the file of a watched directory from its own thread, unless it's already followed.
+/// Hidden files and compressed ones (i.e. rotated archives) are skipped.
+fn follow_file(
+    tx: &Sender<io::Result<Line>>,
+    followed: &mut HashSet<PathBuf>,
+    path: PathBuf,
+    tail: Option<usize>,
+    max_line_length: Option<usize>,
+) {
+    let hidden = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    if hidden
+        || !path.is_file()
+        || Compression::Auto.of(Some(&path)) != Compression::None
+        || followed.contains(&path)
+    {
+        return;
+    }
+    match open_plain(&path, false, true, tail) {
+        Ok(reader) => {
+            log::info!("source: following {}", path.display());
+            let file = path.display().to_string();
+            spawn_reader(tx.clone(), reader, max_line_length, move |l| Line {
+                file: Some(file.clone()),
+                ..l
+            });
+            followed.insert(path);
+        }
+        // The file may be gone already.
+        Err(err) => log::warn!("source: failed to follow {} ({})", path.display(), err),
+    }
+}
+
+/// Follow the files of the directory, along with the ones created in it later on, interleaving
+/// their lines as they come. The files already there are followed from their last `tail` lines,
+/// if given, while the new ones are followed from their start.
+fn watch_dir(
+    dir: &Path,
+    tail: Option<usize>,
+    max_line_length: Option<usize>,
+) -> io::Result<Receiver<io::Result<Line>>> {
+    let watch_error = |err: notify::Error| {
+        io::Error::other(format!("failed to watch {}: {}", dir.display(), err))
+    };
+    let (events_tx, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events_tx).map_err(watch_error)?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(watch_error)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut followed = HashSet::new();
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    paths.sort();
+    for path in paths {
+        follow_file(&tx, &mut followed, path, tail, max_line_length);
+    }
+    thread::spawn(move || {
+        // The directory is only watched as long as the watcher lives.
+        let _watcher = watcher;
+        for event in events {
+            match event {
+                Ok(event) if event.kind.is_create() => {
+                    for path in event.paths {
+                        follow_file(&tx, &mut followed, path, None, max_line_length);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("source: watching the directory failed ({})", err),
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Start `tail -F` of the path on each of the hosts, returning the output of each of them.
+fn tail_ssh(hosts: &[String], path: &str) -> io::Result<Readers> {
+    let mut readers: Readers = Vec::new();
+    for host in hosts.iter() {
+        log::info!("source: tailing {} on {}", path, host);
+        let mut child = Command::new("ssh")
+            .args(["-o", "BatchMode=yes", host, "tail", "-F"])
+            .arg(shell_quote(path))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| io::Error::new(err.kind(), format!("failed to run ssh: {}", err)))?;
+        if let Some(stdout) = child.stdout.take() {
+            readers.push((Some(host.clone()), Box::new(stdout)));
+        }
+        // The other hosts are still tailed when one of them fails.
+        let host = host.clone();
+        thread::spawn(move || match child.wait() {
+            Ok(status) if !status.success() => {
+                log::warn!("source: tailing on {} stopped ({})", host, status)
+            }
+            Err(err) => log::warn!("source: tailing on {} stopped ({})", host, err),
+            _ => {}
+        });
+    }
+    Ok(readers)
+}
+
+/// Lines of the source. With a deadline, waiting for the next line times out, which ends the lines
+/// with a `TimedOut` error. With an idle interval, waiting for the next line for longer than it
+/// rather yields a `WouldBlock` error, the lines going on after it.
+pub(crate) fn read_lines(
+    source: Source,
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+    max_line_length: Option<usize>,
+) -> io::Result<Box<dyn Iterator<Item = io::Result<Line>>>> {
+    let rx = match source {
+        Source::Reader(input) if deadline.is_none() && idle.is_none() => {
+            return Ok(Box::new(lossy_lines(
+                BufReader::new(input),
+                max_line_length,
+            )))
+        }
+        Source::Mapped(map) => return Ok(Box::new(MappedLines::new(map, max_line_length))),
+        Source::Reader(input) => merge(vec![(None, input)], max_line_length),
+        Source::Ssh { hosts, path } => merge(tail_ssh(&hosts, &path)?, max_line_length),
+        Source::Directory(dir, tail) => watch_dir(&dir, tail, max_line_length)?,
+    };
+    Ok(Box::new(std::iter::from_fn(move || {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let received = match (left, idle) {
+            (Some(left), Some(idle)) => rx.recv_timeout(left.min(idle)),
+            (Some(wait), None) | (None, Some(wait)) => rx.recv_timeout(wait),
+            (None, None) => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        match received {
+            Ok(line) => Some(line),
+            Err(RecvTimeoutError::Timeout) if !timed_out => Some(Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "no line read for a while",
+            ))),
+            Err(RecvTimeoutError::Timeout) => Some(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out reading the input",
+            ))),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_read_lines_timeout() {
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_secs(1));
+                Ok(0)
+            }
+        }
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut lines = read_lines(
+            Source::Reader(Box::new(Stalled)),
+            Some(deadline),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            lines.next().unwrap().err().unwrap().kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let source = Source::Reader(Box::new(Cursor::new("a\nb\n".as_bytes())));
+        let lines = read_lines(source, Some(deadline), None, None).unwrap();
+        assert_eq!(
+            lines.map(|l| l.unwrap().text).collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_input_reopen() {
+        let dir = std::env::temp_dir().join(format!("grop-fifo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fifo = dir.join("fifo");
+        let _ = std::fs::remove_file(&fifo);
+        assert!(Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+
+        // Two writers coming one after the other.
+        let path = fifo.clone();
+        let writer = thread::spawn(move || {
+            for line in ["first\n", "second\n"].iter() {
+                let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+                f.write_all(line.as_bytes()).unwrap();
+                drop(f);
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut lines =
+            BufReader::new(open_input(&fifo, true, false, None, Compression::Auto).unwrap())
+                .lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "first");
+        assert_eq!(lines.next().unwrap().unwrap(), "second");
+        writer.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reverse() {
+        let dir = std::env::temp_dir().join(format!("grop-reverse-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("input");
+        let reversed = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            BufReader::new(Reverse::open(&path).unwrap())
+                .lines()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(reversed("a\nbb\n\nccc\n"), vec!["ccc", "", "bb", "a"]);
+        assert_eq!(reversed("a\r\nb"), vec!["b", "a"]);
+        assert!(reversed("").is_empty());
+
+        // Lines spanning several chunks.
+        let lines = (0..20000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mut expected = lines.clone();
+        expected.reverse();
+        assert_eq!(reversed(&lines.join("\n")), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch_dir() {
+        let dir = std::env::temp_dir().join(format!("grop-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).display().to_string();
+        std::fs::write(dir.join("a.log"), "a1\n").unwrap();
+        std::fs::write(dir.join(".hidden"), "hidden\n").unwrap();
+        let mut lines = read_lines(Source::Directory(dir.clone(), None), None, None, None).unwrap();
+        let mut next = || {
+            let line = lines.next().unwrap().unwrap();
+            (line.file.unwrap(), line.number, line.text)
+        };
+        assert_eq!(next(), (file("a.log"), 1, String::from("a1")));
+
+        // Rotated archives are not followed.
+        std::fs::write(dir.join("a.log.1.gz"), "archived\n").unwrap();
+        std::fs::write(dir.join("b.log"), "b1\n").unwrap();
+        assert_eq!(next(), (file("b.log"), 1, String::from("b1")));
+        let mut a = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("a.log"))
+            .unwrap();
+        a.write_all(b"a2\n").unwrap();
+        assert_eq!(next(), (file("a.log"), 2, String::from("a2")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_input_follow() {
+        let dir = std::env::temp_dir().join(format!("grop-follow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        std::fs::write(&path, "first\n").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let reader = open_input(&path, false, true, None, Compression::Auto).unwrap();
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                if tx.send(line.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(next(), "first");
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"appended\n").unwrap();
+        assert_eq!(next(), "appended");
+
+        std::fs::write(&path, "new\n").unwrap();
+        assert_eq!(next(), "new");
+
+        file.write_all(b"last\n").unwrap();
+        std::fs::rename(&path, dir.join("app.log.1")).unwrap();
+        std::fs::write(&path, "rotated\n").unwrap();
+        assert_eq!(next(), "last");
+        assert_eq!(next(), "rotated");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_at() {
+        let path = std::env::temp_dir().join(format!("grop-at-{}", std::process::id()));
+        fs::write(&path, "ab\ncd\nef\n").unwrap();
+        let read_at = |offset| {
+            let (mut reader, start) = open_at(&path, offset).unwrap();
+            let mut text = String::new();
+            reader.read_to_string(&mut text).unwrap();
+            (start, text)
+        };
+        assert_eq!(read_at(0), (0, String::from("ab\ncd\nef\n")));
+        assert_eq!(read_at(3), (3, String::from("cd\nef\n")));
+        assert_eq!(read_at(4), (6, String::from("ef\n")));
+        assert_eq!(read_at(20), (20, String::new()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tail_offset() {
+        let path = std::env::temp_dir().join(format!("grop-tail-{}", std::process::id()));
+        let offset = |lines| tail_offset(&mut File::open(&path).unwrap(), lines).unwrap();
+        std::fs::write(&path, "a\nbb\nccc\n").unwrap();
+        assert_eq!(offset(0), 9);
+        assert_eq!(offset(1), 5);
+        assert_eq!(offset(2), 2);
+        assert_eq!(offset(3), 0);
+        assert_eq!(offset(10), 0);
+        std::fs::write(&path, "a\nbb\nccc").unwrap();
+        assert_eq!(offset(1), 5);
+        assert_eq!(offset(2), 2);
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(offset(10), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_transcode_utf16() {
+        let transcode = |bytes: Vec<u8>, split: usize| {
+            let (head, tail) = bytes.split_at(split);
+            let reader = Cursor::new(head.to_vec()).chain(Cursor::new(tail.to_vec()));
+            let mut text = String::new();
+            transcode_utf16(Box::new(reader))
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+        let text = "a\né\n😀x\n";
+        let le = [0xffu8, 0xfe]
+            .iter()
+            .copied()
+            .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect::<Vec<u8>>();
+        // Whichever way the reads split the code units and the surrogate pairs.
+        for split in 0..le.len() {
+            assert_eq!(transcode(le.clone(), split), text);
+        }
+        let be = [0xfeu8, 0xff]
+            .iter()
+            .copied()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect::<Vec<u8>>();
+        assert_eq!(transcode(be, 3), text);
+
+        // A lone surrogate, or a truncated code unit, is replaced.
+        assert_eq!(
+            transcode(vec![0xff, 0xfe, 0x3d, 0xd8, b'a', 0], 4),
+            "\u{fffd}a"
+        );
+        assert_eq!(transcode(vec![0xff, 0xfe, b'a', 0, b'b'], 2), "a\u{fffd}");
+        // The other inputs are left as they are.
+        assert_eq!(transcode(Vec::from("é\n"), 1), "é\n");
+    }
+
+    #[test]
+    fn test_open_input_compressed() {
+        let dir = std::env::temp_dir().join(format!("grop-compressed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lines = (0..50000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let text = lines.join("\n") + "\n";
+        let read = |path: &Path, compression| {
+            BufReader::new(open_input(path, false, false, None, compression).unwrap())
+                .lines()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+
+        // Rotated logs may be concatenated gzip members.
+        let mut gzip = Vec::new();
+        for half in lines.chunks(25000) {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(half.join("\n").as_bytes()).unwrap();
+            encoder.write_all(b"\n").unwrap();
+            gzip.extend(encoder.finish().unwrap());
+        }
+        let path = dir.join("input.log.gz");
+        std::fs::write(&path, &gzip).unwrap();
+        assert_eq!(read(&path, Compression::Auto), lines);
+
+        #[cfg(feature = "zstd")]
+        {
+            let path = dir.join("input.log.zst");
+            std::fs::write(&path, zstd::encode_all(text.as_bytes(), 0).unwrap()).unwrap();
+            assert_eq!(read(&path, Compression::Auto), lines);
+        }
+
+        #[cfg(feature = "bzip2")]
+        {
+            let mut encoder =
+                bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+            encoder.write_all(text.as_bytes()).unwrap();
+            let path = dir.join("input.log.bz2");
+            std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+            assert_eq!(read(&path, Compression::Auto), lines);
+        }
+
+        // The decoders left out of the build fail to open the input.
+        #[cfg(not(feature = "zstd"))]
+        assert!(open_input(&path, false, false, None, Compression::Zstd).is_err());
+
+        // The compression can be given explicitly, or turned off.
+        let path = dir.join("archive");
+        std::fs::write(&path, &gzip).unwrap();
+        assert_eq!(read(&path, Compression::Gzip), lines);
+        let path = dir.join("plain.gz");
+        std::fs::write(&path, &text).unwrap();
+        assert_eq!(read(&path, Compression::None), lines);
+
+        let mut content = String::new();
+        assert!(open_input(&path, false, false, None, Compression::Auto)
+            .unwrap()
+            .read_to_string(&mut content)
+            .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lossy_lines() {
+        let input = Cursor::new(b"ok\r\nbad \xff\xfe bytes\nlast".to_vec());
+        let lines = lossy_lines(input, None)
+            .map(|l| {
+                let l = l.unwrap();
+                (l.text, l.invalid_utf8)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                (String::from("ok"), false),
+                (String::from("bad \u{fffd}\u{fffd} bytes"), true),
+                (String::from("last"), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lossy_lines_within() {
+        let input = Cursor::new("abcdef\r\nxé\nab\r\nlast".as_bytes());
+        let lines = lossy_lines(input, Some(2))
+            .map(|l| {
+                let l = l.unwrap();
+                (l.text, l.truncated, l.offset)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                (String::from("ab"), true, 0),
+                (String::from("x"), true, 8),
+                (String::from("ab"), false, 12),
+                (String::from("la"), true, 16)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mapped_lines() {
+        let path = std::env::temp_dir().join(format!("grop-mapped-{}", std::process::id()));
+        let bytes = b"abcdef\r\nx\xc3\xa9\nab\r\n\n\xff\r\rlast";
+        std::fs::write(&path, bytes).unwrap();
+        for max_line_length in [None, Some(2)] {
+            let mapped = MappedLines::new(map_file(&path).unwrap().unwrap(), max_line_length)
+                .map(|l| {
+                    let l = l.unwrap();
+                    (l.text, l.invalid_utf8, l.truncated, l.offset, l.number)
+                })
+                .collect::<Vec<_>>();
+            let read = lossy_lines(Cursor::new(bytes), max_line_length)
+                .map(|l| {
+                    let l = l.unwrap();
+                    (l.text, l.invalid_utf8, l.truncated, l.offset, l.number)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(mapped, read);
+        }
+
+        // The empty files are not mapped.
+        std::fs::write(&path, "").unwrap();
+        assert!(map_file(&path).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let rx = merge(
+            vec![
+                (
+                    Some(String::from("web1")),
+                    Box::new(Cursor::new("a\nb\n".as_bytes())),
+                ),
+                (
+                    Some(String::from("web2")),
+                    Box::new(Cursor::new("c\n".as_bytes())),
+                ),
+            ],
+            None,
+        );
+        let mut lines = rx
+            .iter()
+            .map(|l| {
+                let l = l.unwrap();
+                format!("{} {}", l.host.unwrap(), l.text)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(lines, vec!["web1 a", "web1 b", "web2 c"]);
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,302 @@
+use crate::generate::Rng;
+use crate::GropError;
+use std::fmt;
+use std::io::prelude::*;
+
+struct Counter {
+    role: &'static str,
+    expression: String,
+    matched: u64,
+    failed: u64,
+}
+
+/// Counts how many lines each expression matched and failed, reported at the end of the run
+/// when enabled.
+#[derive(Default)]
+pub(crate) struct PatternStats {
+    enabled: bool,
+    counters: Vec<Counter>,
+}
+
+impl PatternStats {
+    pub(crate) fn new(enabled: bool) -> PatternStats {
+        PatternStats {
+            enabled,
+            counters: Vec::new(),
+        }
+    }
+
+    /// Register an expression, returning the id to count its matches with.
+    pub(crate) fn register(&mut self, role: &'static str, expression: &str) -> usize {
+        self.counters.push(Counter {
+            role,
+            expression: String::from(expression),
+            matched: 0,
+            failed: 0,
+        });
+        self.counters.len() - 1
+    }
+
+    pub(crate) fn hit(&mut self, id: usize, matched: bool) {
+        if !self.enabled {
+            return;
+        }
+        let counter = &mut self.counters[id];
+        if matched {
+            counter.matched += 1;
+        } else {
+            counter.failed += 1;
+        }
+    }
+
+    pub(crate) fn report(&self, output: &mut dyn Write) -> Result<(), GropError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        writeln!(
+            output,
+            "{:<12} {:>10} {:>10}  EXPRESSION",
+            "ROLE", "MATCHED", "FAILED"
+        )?;
+        for c in self.counters.iter() {
+            writeln!(
+                output,
+                "{:<12} {:>10} {:>10}  {}",
+                c.role, c.matched, c.failed, c.expression
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Class of the lines not matching any expression.
+pub(crate) const UNMATCHED_CLASS: &str = "unmatched";
+
+/// Counts the lines of each class, i.e. by the expression matching them, to audit the coverage of
+/// the expressions.
+pub(crate) struct Classes {
+    names: Vec<String>,
+    counts: Vec<u64>,
+    unmatched: u64,
+}
+
+impl Classes {
+    pub(crate) fn new(names: Vec<String>) -> Classes {
+        Classes {
+            counts: vec![0; names.len()],
+            names,
+            unmatched: 0,
+        }
+    }
+
+    /// Count the line matched by the expression (at the index), returning its class.
+    pub(crate) fn classify(&mut self, index: Option<usize>) -> &str {
+        match index {
+            Some(index) => {
+                self.counts[index] += 1;
+                &self.names[index]
+            }
+            None => {
+                self.unmatched += 1;
+                UNMATCHED_CLASS
+            }
+        }
+    }
+
+    pub(crate) fn report(&self, output: &mut dyn Write) -> Result<(), GropError> {
+        let total = self.counts.iter().sum::<u64>() + self.unmatched;
+        writeln!(output, "{:<12} {:>10} {:>8}", "CLASS", "LINES", "PERCENT")?;
+        let classes = self
+            .names
+            .iter()
+            .map(String::as_str)
+            .zip(self.counts.iter())
+            .chain(std::iter::once((UNMATCHED_CLASS, &self.unmatched)));
+        for (name, count) in classes {
+            let percent = match total {
+                0 => 0.0,
+                _ => *count as f64 * 100.0 / total as f64,
+            };
+            writeln!(output, "{:<12} {:>10} {:>7.1}%", name, count, percent)?;
+        }
+        Ok(())
+    }
+}
+
+/// A uniform sample of the lines of each class (by reservoir sampling), reported along with the
+/// counts so that they come with concrete lines.
+pub(crate) struct Examples {
+    size: usize,
+    names: Vec<String>,
+    // The number of the lines of each class, the unmatched ones last, along with the lines kept
+    // and their rank among them.
+    samples: Vec<(u64, Vec<(u64, String)>)>,
+    rng: Rng,
+}
+
+impl Examples {
+    pub(crate) fn new(names: Vec<String>, size: usize) -> Examples {
+        Examples {
+            size,
+            samples: (0..=names.len()).map(|_| (0, Vec::new())).collect(),
+            names,
+            rng: Rng::new(0),
+        }
+    }
+
+    /// Offer the line matched by the expression (at the index) to the sample of its class.
+    pub(crate) fn offer(&mut self, index: Option<usize>, line: &str) {
+        let (seen, kept) = &mut self.samples[index.unwrap_or(self.names.len())];
+        *seen += 1;
+        if kept.len() < self.size {
+            kept.push((*seen, String::from(line)));
+        } else {
+            // Each of the lines seen so far is kept with the same chance.
+            let slot = self.rng.below(*seen) as usize;
+            if slot < self.size {
+                kept[slot] = (*seen, String::from(line));
+            }
+        }
+    }
+
+    /// Write the lines kept of each class, in input order.
+    pub(crate) fn report(&self, output: &mut dyn Write) -> Result<(), GropError> {
+        writeln!(output, "{:<12} EXAMPLE", "CLASS")?;
+        let names = self.names.iter().map(String::as_str);
+        for (name, (_, kept)) in names
+            .chain(std::iter::once(UNMATCHED_CLASS))
+            .zip(self.samples.iter())
+        {
+            let mut kept = kept.iter().collect::<Vec<&(u64, String)>>();
+            kept.sort();
+            for (_, line) in kept {
+                writeln!(output, "{:<12} {}", name, line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Signs of degraded parsing, which don't stop the run.
+#[derive(Default, Debug, PartialEq)]
+pub(crate) struct Warnings {
+    pub(crate) unmatched: u64,
+    pub(crate) invalid_utf8: u64,
+    pub(crate) missing_fields: u64,
+    // Lines cut to the maximal line length, and records lost to a panic in hardened mode.
+    pub(crate) long_lines: u64,
+    pub(crate) failed_records: u64,
+}
+
+impl Warnings {
+    pub(crate) fn total(&self) -> u64 {
+        self.unmatched
+            + self.invalid_utf8
+            + self.missing_fields
+            + self.long_lines
+            + self.failed_records
+    }
+}
+
+impl fmt::Display for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} unmatched lines, {} lines with invalid UTF-8, {} missing fields",
+            self.unmatched, self.invalid_utf8, self.missing_fields
+        )?;
+        // Only possible with the limits of untrusted input.
+        if self.long_lines > 0 {
+            write!(f, ", {} truncated lines", self.long_lines)?;
+        }
+        if self.failed_records > 0 {
+            write!(f, ", {} failed records", self.failed_records)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_pattern_stats() {
+        let mut stats = PatternStats::new(true);
+        let id = stats.register("expression", "%{INT:a}");
+        stats.hit(id, true);
+        stats.hit(id, true);
+        stats.hit(id, false);
+        let mut out = Vec::new();
+        stats.report(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().nth(1).unwrap(),
+            "expression            2          1  %{INT:a}"
+        );
+
+        let mut stats = PatternStats::new(false);
+        let id = stats.register("expression", "%{INT:a}");
+        stats.hit(id, true);
+        let mut out = Vec::new();
+        stats.report(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_examples() {
+        let mut examples = Examples::new(vec![String::from("access")], 2);
+        examples.offer(None, "junk");
+        for i in 0..1000 {
+            examples.offer(Some(0), &format!("GET /{}", i));
+        }
+        let mut out = Vec::new();
+        examples.report(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "CLASS        EXAMPLE");
+        let kept = lines[1..3]
+            .iter()
+            .map(|l| {
+                l.strip_prefix("access       GET /")
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect::<Vec<u32>>();
+        assert!(kept[0] < kept[1]);
+        assert_eq!(lines[3], "unmatched    junk");
+
+        // Each line has the same chance to be kept.
+        let mut kept = [0u64; 10];
+        let mut examples = Examples::new(Vec::new(), 1);
+        for _ in 0..1000 {
+            examples.samples[0] = (0, Vec::new());
+            for i in 0..10 {
+                examples.offer(None, &i.to_string());
+            }
+            let (_, sample) = &examples.samples[0];
+            kept[sample[0].1.parse::<usize>().unwrap()] += 1;
+        }
+        assert!(kept.iter().all(|n| (50..150).contains(n)), "{:?}", kept);
+    }
+
+    #[test]
+    fn test_classes() {
+        let mut classes = Classes::new(vec![String::from("access"), String::from("error")]);
+        assert_eq!(classes.classify(Some(1)), "error");
+        assert_eq!(classes.classify(None), "unmatched");
+        assert_eq!(classes.classify(Some(1)), "error");
+        assert_eq!(classes.classify(Some(1)), "error");
+        let mut out = Vec::new();
+        classes.report(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "CLASS             LINES  PERCENT\n\
+             access                0     0.0%\n\
+             error                 3    75.0%\n\
+             unmatched             1    25.0%\n"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{Actions, CompleteAction, ConditionalTag, Config, GropError, MergeConfig, Tee};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use toml::Value;
+
+/// Deserializer capturing the field names a struct asks for, without deserializing anything.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("field names captured"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + usize::from(ca != *cb)).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Line (starting at 1) of the first `key =` assignment in the content.
+fn key_line(content: &str, key: &str) -> Option<usize> {
+    content
+        .lines()
+        .position(|l| {
+            l.trim_start()
+                .strip_prefix(key)
+                .map(|rest| rest.trim_start().starts_with('='))
+                .unwrap_or(false)
+        })
+        .map(|i| i + 1)
+}
+
+fn check_table(
+    content: &str,
+    table: &toml::value::Table,
+    known: &[&str],
+    section: &str,
+) -> Result<(), GropError> {
+    let mut keys = table.keys().collect::<Vec<&String>>();
+    keys.sort();
+    for key in keys {
+        if known.contains(&key.as_str()) {
+            continue;
+        }
+        let mut message = format!("unknown config key {}{}", section, key);
+        if let Some(line) = key_line(content, key) {
+            message.push_str(&format!(" at line {}", line));
+        }
+        if let Some(similar) = known.iter().min_by_key(|k| distance(key, k)) {
+            if distance(key, similar) <= 2 {
+                message.push_str(&format!(" (did you mean {}?)", similar));
+            }
+        }
+        return Err(GropError::InvalidArg(message));
+    }
+    Ok(())
+}
+
+/// Check that the config has no key grop doesn't know about, so that the typos don't go unnoticed.
+pub fn check_config_keys(content: &str) -> Result<(), GropError> {
+    let value = content
+        .parse::<Value>()
+        .map_err(|err| GropError::InvalidArg(format!("invalid config: {}", err)))?;
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => return Ok(()),
+    };
+    check_table(content, table, field_names::<Config>(), "")?;
+    if let Some(Value::Table(merge_config)) = table.get("merge_config") {
+        check_table(
+            content,
+            merge_config,
+            field_names::<MergeConfig>(),
+            "merge_config.",
+        )?;
+    }
+    if let Some(Value::Table(merge_configs)) = table.get("merge_configs") {
+        for merge_config in merge_configs.values().filter_map(Value::as_table) {
+            check_table(
+                content,
+                merge_config,
+                field_names::<MergeConfig>(),
+                "merge_configs.",
+            )?;
+        }
+    }
+    if let Some(Value::Table(actions)) = table.get("actions") {
+        check_table(content, actions, field_names::<Actions>(), "actions.")?;
+        if let Some(Value::Array(on_complete)) = actions.get("on_complete") {
+            for action in on_complete.iter().filter_map(Value::as_table) {
+                check_table(
+                    content,
+                    action,
+                    field_names::<CompleteAction>(),
+                    "actions.on_complete.",
+                )?;
+            }
+        }
+    }
+    if let Some(Value::Array(tees)) = table.get("tee") {
+        for tee in tees.iter().filter_map(Value::as_table) {
+            check_table(content, tee, field_names::<Tee>(), "tee.")?;
+        }
+    }
+    if let Some(Value::Array(tags)) = table.get("conditional_tags") {
+        for tag in tags.iter().filter_map(Value::as_table) {
+            check_table(
+                content,
+                tag,
+                field_names::<ConditionalTag>(),
+                "conditional_tags.",
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_check_config_keys() {
+        assert!(
+            check_config_keys("output_format = 'a'\n[merge_config]\nmerge_fields = []").is_ok()
+        );
+        assert_eq!(
+            check_config_keys("filters = []\nouput_format = 'a'")
+                .unwrap_err()
+                .to_string(),
+            "Invalid argument unknown config key ouput_format at line 2 (did you mean output_format?)"
+        );
+        assert_eq!(
+            check_config_keys("[[conditional_tags]]\ncondition = 'a b'\ntags = 'c=d'")
+                .unwrap_err()
+                .to_string(),
+            "Invalid argument unknown config key conditional_tags.tags at line 3 (did you mean tag?)"
+        );
+        assert!(check_config_keys("colour = true").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::transform::parse_millis;
+use crate::GropError;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Formats of timestamps carrying an offset, tried after RFC 3339 and RFC 2822.
+const OFFSET_FORMATS: &[&str] = &["%d/%b/%Y:%H:%M:%S %z", "%Y-%m-%d %H:%M:%S%.f %z"];
+
+/// Formats of timestamps without offset, which are taken as UTC.
+const NAIVE_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y/%m/%d %H:%M:%S%.f",
+];
+
+/// Parse a timestamp in one of the common log formats.
+pub(crate) fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc2822(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    for format in OFFSET_FORMATS.iter() {
+        if let Ok(t) = DateTime::parse_from_str(s, format) {
+            return Some(t.with_timezone(&Utc));
+        }
+    }
+    for format in NAIVE_FORMATS.iter() {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Some(Utc.from_utc_datetime(&t));
+        }
+    }
+    None
+}
+
+/// Parse a timestamp in the format if given (taken as UTC without an offset), or else in one of
+/// the common log formats.
+fn parse_timestamp_as(s: &str, format: Option<&str>) -> Option<DateTime<Utc>> {
+    if let Some(format) = format {
+        if let Ok(t) = DateTime::parse_from_str(s.trim(), format) {
+            return Some(t.with_timezone(&Utc));
+        }
+        if let Ok(t) = NaiveDateTime::parse_from_str(s.trim(), format) {
+            return Some(Utc.from_utc_datetime(&t));
+        }
+    }
+    parse_timestamp(s)
+}
+
+/// Formats of the bounds of a time range, besides the ones of the timestamps, which are taken as
+/// UTC.
+const BOUND_FORMATS: &[&str] = &["%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"];
+
+/// Parse the bound of a time range: a timestamp, possibly down to the minute or the day, or an
+/// age like 2h back from now.
+pub(crate) fn parse_time_bound(s: &str) -> Result<DateTime<Utc>, GropError> {
+    let s = s.trim();
+    if let Some(t) = parse_timestamp(s) {
+        return Ok(t);
+    }
+    for format in BOUND_FORMATS.iter() {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(Utc.from_utc_datetime(&t));
+        }
+    }
+    if let Some(t) = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+    {
+        return Ok(Utc.from_utc_datetime(&t));
+    }
+    match parse_millis(s) {
+        Some(millis) => Ok(Utc::now() - chrono::Duration::milliseconds(millis as i64)),
+        None => Err(GropError::InvalidArg(format!(
+            "invalid time {} (should be a timestamp like 2024-01-01T00:00 or an age like 2h)",
+            s
+        ))),
+    }
+}
+
+/// Fields the timestamp of a record is looked for in, in order, unless its field is given.
+const TIME_FIELDS: &[&str] = &["timestamp", "@timestamp", "time", "ts", "date"];
+
+/// The timestamp of the record, in the time field if given, or else in the first of the usual
+/// fields holding one. It's parsed in the time format if given, or else in one of the common ones.
+pub(crate) fn record_time(
+    m: &HashMap<String, String>,
+    time_field: Option<&str>,
+    time_format: Option<&str>,
+) -> Option<DateTime<Utc>> {
+    let value = match time_field {
+        Some(field) => m.get(field),
+        None => TIME_FIELDS.iter().find_map(|field| m.get(*field)),
+    };
+    value.and_then(|v| parse_timestamp_as(v, time_format))
+}
+
+/// Format a timestamp as RFC 3339, with as many sub-second digits as needed.
+pub(crate) fn format_timestamp<Z: TimeZone>(t: &DateTime<Z>) -> String
+where
+    Z::Offset: std::fmt::Display,
+{
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// Time zone the timestamps are displayed in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DisplayZone {
+    Local,
+    Named(Tz),
+}
+
+impl FromStr for DisplayZone {
+    type Err = GropError;
+
+    fn from_str(s: &str) -> Result<DisplayZone, GropError> {
+        match s {
+            "local" => Ok(DisplayZone::Local),
+            _ => s.parse::<Tz>().map(DisplayZone::Named).map_err(|_| {
+                GropError::InvalidArg(format!(
+                    "unknown time zone {} (should be an IANA name like Europe/Stockholm, or local)",
+                    s
+                ))
+            }),
+        }
+    }
+}
+
+impl DisplayZone {
+    fn render(&self, t: &DateTime<Utc>) -> String {
+        match self {
+            DisplayZone::Local => format_timestamp(&t.with_timezone(&Local)),
+            DisplayZone::Named(tz) => format_timestamp(&t.with_timezone(tz)),
+        }
+    }
+
+    /// Copy of the record with the timestamp fields rendered in this zone.
+    pub(crate) fn localize(&self, m: &HashMap<String, String>) -> HashMap<String, String> {
+        m.iter()
+            .map(|(k, v)| match parse_timestamp(v) {
+                Some(t) => (k.clone(), self.render(&t)),
+                None => (k.clone(), v.clone()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = Utc.with_ymd_and_hms(2020, 5, 14, 10, 0, 0).unwrap();
+        for s in [
+            "2020-05-14T10:00:00Z",
+            "2020-05-14T12:00:00+02:00",
+            "Thu, 14 May 2020 10:00:00 +0000",
+            "14/May/2020:12:00:00 +0200",
+            "2020-05-14 10:00:00",
+            "2020/05/14 10:00:00.000",
+        ]
+        .iter()
+        {
+            assert_eq!(parse_timestamp(s), Some(expected), "{}", s);
+        }
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(format_timestamp(&expected), "2020-05-14T10:00:00Z");
+    }
+
+    #[test]
+    fn test_record_time() {
+        let mut m = HashMap::new();
+        m.insert(String::from("time"), String::from("2024-10-10T13:55:36Z"));
+        m.insert(String::from("at"), String::from("2024-10-11 08:00:00"));
+        m.insert(String::from("ts"), String::from("11.10.2024 08h00"));
+        let time = |field, format| record_time(&m, field, format).map(|t| format_timestamp(&t));
+        assert_eq!(time(None, None).unwrap(), "2024-10-10T13:55:36Z");
+        assert_eq!(time(Some("at"), None).unwrap(), "2024-10-11T08:00:00Z");
+        assert!(time(Some("msg"), None).is_none());
+        assert!(time(Some("ts"), None).is_none());
+        let format = Some("%d.%m.%Y %Hh%M");
+        assert_eq!(time(Some("ts"), format).unwrap(), "2024-10-11T08:00:00Z");
+        // The common formats are still tried.
+        assert_eq!(time(Some("at"), format).unwrap(), "2024-10-11T08:00:00Z");
+    }
+
+    #[test]
+    fn test_parse_time_bound() {
+        for (s, expected) in [
+            ("2024-01-01T00:00", "2024-01-01T00:00:00Z"),
+            ("2024-01-01 12:30", "2024-01-01T12:30:00Z"),
+            ("2024-01-01", "2024-01-01T00:00:00Z"),
+            ("2024-01-01T00:00:00+01:00", "2023-12-31T23:00:00Z"),
+        ]
+        .iter()
+        {
+            assert_eq!(format_timestamp(&parse_time_bound(s).unwrap()), *expected);
+        }
+        let ago = Utc::now() - parse_time_bound("2h").unwrap();
+        assert!((ago.num_seconds() - 7200).abs() <= 1);
+        assert!(parse_time_bound("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_display_zone() {
+        let zone = "Europe/Stockholm".parse::<DisplayZone>().unwrap();
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("time"), String::from("2020-05-14T10:00:00Z"));
+        m.insert(String::from("msg"), String::from("hello"));
+        let m = zone.localize(&m);
+        assert_eq!(m.get("time").unwrap(), "2020-05-14T12:00:00+02:00");
+        assert_eq!(m.get("msg").unwrap(), "hello");
+        assert!("Mars/Olympus".parse::<DisplayZone>().is_err());
+    }
+}
@@ -0,0 +1,928 @@
+use crate::cache::PatternCache;
+use crate::output::{expand_template, NumberLocale};
+use crate::timestamp::{format_timestamp, parse_timestamp};
+use crate::GropError;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chrono::DateTime;
+use fgrok::Pattern;
+use md5::Md5;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Operator {
+    Eq,
+    Ne,
+    Match,
+    NotMatch,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    // The operators starting with another one come first, for them to be told apart.
+    pub(crate) const ALL: [&'static str; 8] = ["==", "!=", "=~", "!~", "<=", ">=", "<", ">"];
+
+    pub(crate) fn parse(s: &str) -> Option<Operator> {
+        match s {
+            "==" => Some(Operator::Eq),
+            "!=" => Some(Operator::Ne),
+            "=~" => Some(Operator::Match),
+            "!~" => Some(Operator::NotMatch),
+            "<" => Some(Operator::Lt),
+            "<=" => Some(Operator::Le),
+            ">" => Some(Operator::Gt),
+            ">=" => Some(Operator::Ge),
+            _ => None,
+        }
+    }
+}
+
+/// The order of the value relative to the other one, as numbers, or else as severities if both
+/// values are ones (e.g. `WARN` comes before `error`).
+fn compare(v: &str, other: &str) -> Option<Ordering> {
+    match (v.trim().parse::<f64>(), other.trim().parse::<f64>()) {
+        (Ok(v), Ok(other)) => v.partial_cmp(&other),
+        _ => {
+            let (_, rank) = normalize_severity(v)?;
+            let (_, other_rank) = normalize_severity(other)?;
+            Some(rank.cmp(&other_rank))
+        }
+    }
+}
+
+/// A comparison of a record field, e.g. `level == "ERROR"`, `msg =~ "%{IP}"` or `status >= 500`.
+pub(crate) struct Condition {
+    field: String,
+    operator: Operator,
+    value: String,
+    // Compiled value of the (not) match operators.
+    pattern: Option<Rc<Pattern>>,
+}
+
+impl Condition {
+    /// Parse a condition in format `field_name operator value`, the value possibly quoted.
+    pub(crate) fn parse(s: &str, grok: &mut PatternCache) -> Result<Condition, GropError> {
+        let invalid = || {
+            GropError::InvalidArg(format!(
+                r#"Invalid condition {} (should be "field_name (==|!=|=~|!~|<|<=|>|>=) value")"#,
+                s
+            ))
+        };
+        let mut parts = s.trim().splitn(3, char::is_whitespace);
+        let field = parts.next().filter(|f| !f.is_empty()).ok_or_else(invalid)?;
+        let operator = parts.next().and_then(Operator::parse).ok_or_else(invalid)?;
+        let value = parts.next().map(str::trim).ok_or_else(invalid)?;
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(v) => v,
+            None => value,
+        };
+        Condition::new(field, operator, value, grok)
+    }
+
+    pub(crate) fn new(
+        field: &str,
+        operator: Operator,
+        value: &str,
+        grok: &mut PatternCache,
+    ) -> Result<Condition, GropError> {
+        let pattern = match operator {
+            Operator::Match | Operator::NotMatch => Some(grok.compile(value, false)?),
+            // A value which is neither a number nor a severity would order no record.
+            Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge
+                if value.trim().parse::<f64>().is_err() && normalize_severity(value).is_none() =>
+            {
+                return Err(GropError::InvalidArg(format!(
+                    "{} can't be ordered by {} (should be a number or a severity)",
+                    field, value
+                )))
+            }
+            _ => None,
+        };
+        Ok(Condition {
+            field: String::from(field),
+            operator,
+            value: String::from(value),
+            pattern,
+        })
+    }
+
+    /// A missing field is neither equal to nor matching any value, nor comparable to it.
+    pub(crate) fn eval(&self, m: &HashMap<String, String>) -> bool {
+        let v = m.get(&self.field);
+        let ordering = || v.and_then(|v| compare(v, &self.value));
+        let matched = || match (v, &self.pattern) {
+            (None, _) => false,
+            (Some(v), None) => *v == self.value,
+            (Some(v), Some(pattern)) => pattern.match_against(v).is_some(),
+        };
+        match self.operator {
+            Operator::Eq | Operator::Match => matched(),
+            Operator::Ne | Operator::NotMatch => !matched(),
+            Operator::Lt => ordering() == Some(Ordering::Less),
+            Operator::Le => matches!(ordering(), Some(Ordering::Less | Ordering::Equal)),
+            Operator::Gt => ordering() == Some(Ordering::Greater),
+            Operator::Ge => matches!(ordering(), Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Conversion of a field value into its canonical representation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Conversion {
+    /// Sizes like `1.5KB` or `2GiB`, into bytes.
+    Bytes,
+    /// Durations like `200ms` or `3m10s`, into milliseconds.
+    Millis,
+    /// Formatted timestamps, into epoch seconds.
+    Epoch,
+    /// Formatted timestamps, into epoch milliseconds.
+    EpochMillis,
+    /// Epoch seconds, into RFC 3339 timestamps.
+    Timestamp,
+    /// Epoch milliseconds, into RFC 3339 timestamps.
+    TimestampMillis,
+    /// Hex encoded text.
+    Hex,
+    /// Base64 (standard or URL safe alphabet) encoded text.
+    Base64,
+    /// Percent (URL) encoded text.
+    Url,
+    /// Any text, into its CRC-32 checksum (hex).
+    Crc32,
+    /// Any text, into its MD5 digest (hex).
+    Md5,
+    /// Any text, into its SHA-256 digest (hex).
+    Sha256,
+}
+
+impl Conversion {
+    /// Actions of the transforms, along with their conversion.
+    const ACTIONS: &'static [(&'static str, Conversion)] = &[
+        ("bytes", Conversion::Bytes),
+        ("millis", Conversion::Millis),
+        ("epoch", Conversion::Epoch),
+        ("epoch-ms", Conversion::EpochMillis),
+        ("timestamp", Conversion::Timestamp),
+        ("timestamp-ms", Conversion::TimestampMillis),
+        ("hex", Conversion::Hex),
+        ("base64", Conversion::Base64),
+        ("url", Conversion::Url),
+        ("crc32", Conversion::Crc32),
+        ("md5", Conversion::Md5),
+        ("sha256", Conversion::Sha256),
+    ];
+
+    fn convert(self, v: &str) -> Option<String> {
+        match self {
+            Conversion::Bytes => parse_bytes(v).map(|n| n.to_string()),
+            Conversion::Millis => parse_millis(v).map(|n| n.to_string()),
+            Conversion::Epoch => parse_timestamp(v).map(|t| t.timestamp().to_string()),
+            Conversion::EpochMillis => parse_timestamp(v).map(|t| t.timestamp_millis().to_string()),
+            Conversion::Timestamp => {
+                let secs = v.trim().parse::<f64>().ok()?;
+                DateTime::from_timestamp_millis((secs * 1e3).round() as i64)
+                    .map(|t| format_timestamp(&t))
+            }
+            Conversion::TimestampMillis => {
+                let millis = v.trim().parse::<i64>().ok()?;
+                DateTime::from_timestamp_millis(millis).map(|t| format_timestamp(&t))
+            }
+            Conversion::Hex => hex::decode(v.trim())
+                .ok()
+                .map(|b| String::from_utf8_lossy(&b).into_owned()),
+            Conversion::Base64 => {
+                let v = v.trim();
+                STANDARD
+                    .decode(v)
+                    .or_else(|_| URL_SAFE.decode(v))
+                    .or_else(|_| STANDARD_NO_PAD.decode(v))
+                    .or_else(|_| URL_SAFE_NO_PAD.decode(v))
+                    .ok()
+                    .map(|b| String::from_utf8_lossy(&b).into_owned())
+            }
+            Conversion::Url => Some(
+                percent_decode_str(&v.replace('+', " "))
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            ),
+            Conversion::Crc32 => Some(format!("{:08x}", crc32fast::hash(v.as_bytes()))),
+            Conversion::Md5 => Some(hex::encode(Md5::digest(v.as_bytes()))),
+            Conversion::Sha256 => Some(hex::encode(Sha256::digest(v.as_bytes()))),
+        }
+    }
+}
+
+/// Split a leading decimal number from the rest of the string.
+fn split_number(s: &str) -> Option<(f64, &str)> {
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let number = s[..end].parse::<f64>().ok()?;
+    Some((number, &s[end..]))
+}
+
+fn parse_bytes(s: &str) -> Option<f64> {
+    let (number, unit) = split_number(s.trim())?;
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "m" | "mb" => 1e6,
+        "g" | "gb" => 1e9,
+        "t" | "tb" => 1e12,
+        "ki" | "kib" => 1024.0,
+        "mi" | "mib" => 1024.0 * 1024.0,
+        "gi" | "gib" => 1024.0 * 1024.0 * 1024.0,
+        "ti" | "tib" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((number * multiplier).round())
+}
+
+/// Parse a duration made of one or more `<number><unit>` parts, e.g. `3m10s`.
+pub(crate) fn parse_millis(s: &str) -> Option<f64> {
+    let mut rest = s.trim();
+    let mut total = 0.0;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let (number, tail) = split_number(rest)?;
+        let end = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let multiplier = match &tail[..end] {
+            "ns" => 1e-6,
+            "us" | "µs" => 1e-3,
+            "ms" => 1.0,
+            "s" => 1e3,
+            "m" => 60e3,
+            "h" => 3600e3,
+            "d" => 86400e3,
+            _ => return None,
+        };
+        total += number * multiplier;
+        rest = &tail[end..];
+    }
+    Some(total)
+}
+
+/// Canonical severities along with their rank, following the numeric levels of Python's logging.
+const SEVERITIES: &[(&str, u8)] = &[
+    ("trace", 5),
+    ("debug", 10),
+    ("info", 20),
+    ("warn", 30),
+    ("error", 40),
+    ("fatal", 50),
+];
+
+/// Map a level spelling (e.g. `WARN`, `Warning`, `W`, `30`, or the syslog `4`) onto its canonical
+/// severity and rank.
+pub(crate) fn normalize_severity(s: &str) -> Option<(&'static str, u8)> {
+    let s = s.trim().trim_start_matches('[').trim_end_matches(']');
+    let severity = match s.to_lowercase().as_str() {
+        "trace" | "trc" | "t" | "finest" | "finer" | "verbose" => "trace",
+        "debug" | "dbg" | "d" | "fine" | "config" | "7" | "10" => "debug",
+        "info" | "inf" | "i" | "information" | "informational" | "notice" | "5" | "6" | "20" => {
+            "info"
+        }
+        "warn" | "warning" | "wrn" | "w" | "4" | "30" => "warn",
+        "error" | "err" | "e" | "eror" | "severe" | "3" | "40" => "error",
+        "fatal" | "ftl" | "f" | "critical" | "crit" | "c" | "alert" | "emerg" | "emergency"
+        | "panic" | "0" | "1" | "2" | "50" => "fatal",
+        _ => return None,
+    };
+    SEVERITIES
+        .iter()
+        .find(|(name, _)| *name == severity)
+        .copied()
+}
+
+/// A record rewrite, applied to each record after the tags are injected.
+pub(crate) enum Transform {
+    /// Set the field to the value, whose `{field_name}` placeholders are expanded from the record.
+    Set { field: String, template: String },
+    /// Remove the field from the record.
+    Unset { field: String },
+    /// Convert the value of the field, storing the result in the target field. Values that can't
+    /// be converted are left untouched.
+    Convert {
+        field: String,
+        target: String,
+        conversion: Conversion,
+    },
+    /// Match the value of the field against the expression, adding the captures to the record.
+    Grok { field: String, pattern: Rc<Pattern> },
+    /// Normalize the level in the field into the canonical severity stored in the target field,
+    /// and its rank in the `<target>_rank` field.
+    Severity { field: String, target: String },
+    /// Apply the transform only to the records satisfying the condition.
+    If {
+        condition: Condition,
+        then: Box<Transform>,
+    },
+}
+
+impl Transform {
+    /// Parse a `name=value` set transform.
+    pub(crate) fn parse_set(s: &str) -> Result<Transform, GropError> {
+        let kv = s.splitn(2, '=').collect::<Vec<&str>>();
+        if kv.len() != 2 || kv[0].is_empty() {
+            return Err(GropError::InvalidArg(format!(
+                r#"Invalid set transform {} (should be "name=value")"#,
+                s
+            )));
+        }
+        Ok(Transform::Set {
+            field: String::from(kv[0]),
+            template: String::from(kv[1]),
+        })
+    }
+
+    /// Parse a transform in format `[if <condition> then] (set name=value | unset name)`, e.g.
+    /// `if level == "ERROR" then set alert=true`.
+    pub(crate) fn parse(s: &str, grok: &mut PatternCache) -> Result<Transform, GropError> {
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix("if ") {
+            let then = rest.find(" then ").ok_or_else(|| {
+                GropError::InvalidArg(format!(r#"Invalid transform {} (missing "then")"#, s))
+            })?;
+            return Ok(Transform::If {
+                condition: Condition::parse(&rest[..then], grok)?,
+                then: Box::new(Transform::parse(&rest[then + " then ".len()..], grok)?),
+            });
+        }
+        if let Some(kv) = s.strip_prefix("set ") {
+            return Transform::parse_set(kv.trim());
+        }
+        if let Some(field) = s.strip_prefix("unset ") {
+            return Ok(Transform::Unset {
+                field: String::from(field.trim()),
+            });
+        }
+        if let Some(args) = s.strip_prefix("grok ") {
+            let mut args = args.trim().splitn(2, char::is_whitespace);
+            if let (Some(field), Some(expression)) = (args.next(), args.next()) {
+                let expression = expression.trim();
+                let expression = match expression
+                    .strip_prefix('"')
+                    .and_then(|e| e.strip_suffix('"'))
+                {
+                    Some(e) => e,
+                    None => expression,
+                };
+                return Ok(Transform::Grok {
+                    field: String::from(field),
+                    pattern: grok.compile(expression, false)?,
+                });
+            }
+        }
+        let args = s.split_whitespace().collect::<Vec<&str>>();
+        match args[..] {
+            ["severity", field] => {
+                return Ok(Transform::Severity {
+                    field: String::from(field),
+                    target: String::from("severity"),
+                })
+            }
+            ["severity", field, "as", target] => {
+                return Ok(Transform::Severity {
+                    field: String::from(field),
+                    target: String::from(target),
+                })
+            }
+            _ => {}
+        }
+        for (action, conversion) in Conversion::ACTIONS.iter() {
+            let (field, target) = match args[..] {
+                [a, field] if a == *action => (field, field),
+                [a, field, "as", target] if a == *action => (field, target),
+                _ => continue,
+            };
+            return Ok(Transform::Convert {
+                field: String::from(field),
+                target: String::from(target),
+                conversion: *conversion,
+            });
+        }
+        Err(GropError::InvalidArg(format!(
+            r#"Invalid transform {} (should be "[if <condition> then] <action>", see --help for the actions)"#,
+            s
+        )))
+    }
+
+    /// The field the transform works on, if it's missing from the record.
+    pub(crate) fn missing_field(&self, m: &HashMap<String, String>) -> Option<&str> {
+        let field = match self {
+            Transform::Convert { field, .. }
+            | Transform::Grok { field, .. }
+            | Transform::Severity { field, .. } => field,
+            Transform::If { condition, then } if condition.eval(m) => return then.missing_field(m),
+            _ => return None,
+        };
+        if m.contains_key(field) {
+            None
+        } else {
+            Some(field)
+        }
+    }
+
+    pub(crate) fn apply(&self, m: &mut HashMap<String, String>) -> Result<(), GropError> {
+        match self {
+            Transform::Set { field, template } => {
+                let value = expand_template(template, m)?;
+                m.insert(field.clone(), value);
+            }
+            Transform::Unset { field } => {
+                m.remove(field);
+            }
+            Transform::Convert {
+                field,
+                target,
+                conversion,
+            } => match m.get(field).and_then(|v| conversion.convert(v)) {
+                Some(value) => {
+                    m.insert(target.clone(), value);
+                }
+                None => log::debug!("transform: can't convert field {}", field),
+            },
+            Transform::Grok { field, pattern } => {
+                let captures = match m.get(field).and_then(|v| pattern.match_against(v)) {
+                    Some(matches) => matches
+                        .iter()
+                        .map(|(k, v)| (String::from(k), String::from(v)))
+                        .collect::<Vec<(String, String)>>(),
+                    None => Vec::new(),
+                };
+                m.extend(captures);
+            }
+            Transform::Severity { field, target } => {
+                match m.get(field).and_then(|v| normalize_severity(v)) {
+                    Some((severity, rank)) => {
+                        m.insert(target.clone(), String::from(severity));
+                        m.insert(format!("{}_rank", target), rank.to_string());
+                    }
+                    None => log::debug!("transform: unknown severity in field {}", field),
+                }
+            }
+            Transform::If { condition, then } => {
+                if condition.eval(m) {
+                    then.apply(m)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prefix of the fields holding the deltas, followed by the name of the field.
+const DELTA_PREFIX: &str = "_delta.";
+
+/// Difference of the numeric value of the field from the one of the previous record (of the same
+/// key value, if a key field is given), e.g. turning cumulative counters into per-interval ones.
+pub(crate) struct Delta {
+    field: String,
+    key: Option<String>,
+    locale: NumberLocale,
+    // Last value of the field, by key value.
+    previous: HashMap<String, f64>,
+}
+
+impl Delta {
+    /// Parse a delta in format `field [by key]`, of the numbers written in the locale.
+    pub(crate) fn parse(s: &str, locale: NumberLocale) -> Result<Delta, GropError> {
+        let (field, key) = match s.split_whitespace().collect::<Vec<&str>>()[..] {
+            [field] => (field, None),
+            [field, "by", key] => (field, Some(String::from(key))),
+            _ => {
+                return Err(GropError::InvalidArg(format!(
+                    r#"Invalid delta {} (should be "field [by key]")"#,
+                    s
+                )))
+            }
+        };
+        Ok(Delta {
+            field: String::from(field),
+            key,
+            locale,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Add the `_delta.<field>` field to the record, empty for the first record (of the key) and
+    /// for the values which are not numbers.
+    pub(crate) fn apply(&mut self, m: &mut HashMap<String, String>) {
+        let key = match &self.key {
+            Some(key) => m.get(key).cloned(),
+            None => Some(String::new()),
+        };
+        let value = m.get(&self.field).and_then(|v| self.locale.parse(v));
+        let delta = match (key, value) {
+            (Some(key), Some(value)) => self
+                .previous
+                .insert(key, value)
+                .map(|previous| (value - previous).to_string()),
+            _ => None,
+        };
+        m.insert(
+            format!("{}{}", DELTA_PREFIX, self.field),
+            delta.unwrap_or_default(),
+        );
+    }
+}
+
+/// Field whose last non-empty value is carried forward into the next records, e.g. a session
+/// banner only printed once at its start.
+pub(crate) struct CarryForward {
+    field: String,
+    value: Option<String>,
+}
+
+impl CarryForward {
+    pub(crate) fn new(field: &str) -> CarryForward {
+        CarryForward {
+            field: String::from(field),
+            value: None,
+        }
+    }
+
+    /// Remember the value of the field if the record has one, or else set it to the last one.
+    pub(crate) fn apply(&mut self, m: &mut HashMap<String, String>) {
+        match m.get(&self.field).filter(|v| !v.is_empty()) {
+            Some(value) => self.value = Some(value.clone()),
+            None => {
+                if let Some(value) = &self.value {
+                    m.insert(self.field.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Prefix of the fields holding the moving averages, followed by the name of the field.
+const MOVING_AVG_PREFIX: &str = "_moving_avg.";
+
+/// Number of the last values a moving average is taken over, if not given.
+const DEFAULT_WINDOW: usize = 10;
+
+/// Mean of the numeric values of the field over the last records, e.g. to spot the trend of the
+/// latencies while tailing a log.
+pub(crate) struct MovingAverage {
+    field: String,
+    window: usize,
+    locale: NumberLocale,
+    values: VecDeque<f64>,
+    sum: f64,
+}
+
+impl MovingAverage {
+    /// Parse a moving average in format `field[:window=N]`, of the numbers written in the locale.
+    pub(crate) fn parse(s: &str, locale: NumberLocale) -> Result<MovingAverage, GropError> {
+        let invalid = || {
+            GropError::InvalidArg(format!(
+                r#"Invalid moving average {} (should be "field[:window=N]", N > 0)"#,
+                s
+            ))
+        };
+        let (field, window) = match s.trim().split_once(':') {
+            Some((field, window)) => {
+                let window = window
+                    .trim()
+                    .strip_prefix("window=")
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(invalid)?;
+                (field, window)
+            }
+            None => (s.trim(), DEFAULT_WINDOW),
+        };
+        if field.is_empty() {
+            return Err(invalid());
+        }
+        Ok(MovingAverage {
+            field: String::from(field),
+            window,
+            locale,
+            values: VecDeque::with_capacity(window),
+            sum: 0.0,
+        })
+    }
+
+    /// Add the `_moving_avg.<field>` field to the record, the mean of the value of the field along
+    /// with the ones of the previous records in the window. The values which are not numbers are
+    /// left out, the field is empty until there is one.
+    pub(crate) fn apply(&mut self, m: &mut HashMap<String, String>) {
+        if let Some(value) = m.get(&self.field).and_then(|v| self.locale.parse(v)) {
+            if self.values.len() == self.window {
+                self.sum -= self.values.pop_front().unwrap_or_default();
+            }
+            self.values.push_back(value);
+            self.sum += value;
+        }
+        let mean = match self.values.len() {
+            0 => String::new(),
+            n => (self.sum / n as f64).to_string(),
+        };
+        m.insert(format!("{}{}", MOVING_AVG_PREFIX, self.field), mean);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_transform_apply() {
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("host"), String::from("web1"));
+        m.insert(String::from("port"), String::from("80"));
+
+        Transform::parse_set("addr={host}:{port}")
+            .unwrap()
+            .apply(&mut m)
+            .unwrap();
+        Transform::Unset {
+            field: String::from("port"),
+        }
+        .apply(&mut m)
+        .unwrap();
+        assert_eq!(m.get("addr").unwrap(), "web1:80");
+        assert!(!m.contains_key("port"));
+
+        assert!(Transform::parse_set("=foo").is_err());
+        assert!(Transform::parse_set("a={nope}")
+            .unwrap()
+            .apply(&mut m)
+            .is_err());
+    }
+
+    #[test]
+    fn test_conditional_transform() {
+        let mut grok = PatternCache::default();
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("level"), String::from("ERROR"));
+        m.insert(String::from("msg"), String::from("from 10.0.0.1"));
+
+        for t in [
+            r#"if level == "ERROR" then set alert=true"#,
+            r#"if level != ERROR then set quiet=true"#,
+            r#"if msg =~ "%{IP:ip}" then set remote=yes"#,
+            r#"if nope == "" then set missing=true"#,
+            r#"if level >= warn then set severe=true"#,
+            r#"if level < "error" then set mild=true"#,
+            r#"if level == "ERROR" then if msg !~ "^from" then unset msg"#,
+            r#"unset level"#,
+        ]
+        .iter()
+        {
+            Transform::parse(t, &mut grok)
+                .unwrap()
+                .apply(&mut m)
+                .unwrap();
+        }
+        let mut fields = m.keys().map(String::as_str).collect::<Vec<&str>>();
+        fields.sort_unstable();
+        assert_eq!(fields, vec!["alert", "msg", "remote", "severe"]);
+
+        assert!(Transform::parse(r#"if level = "ERROR" then set a=b"#, &mut grok).is_err());
+        assert!(Transform::parse(r#"if level == "ERROR" set a=b"#, &mut grok).is_err());
+        assert!(Transform::parse("drop level", &mut grok).is_err());
+    }
+
+    #[test]
+    fn test_normalize_units() {
+        assert_eq!(parse_bytes("1.5KB"), Some(1500.0));
+        assert_eq!(parse_bytes("2 KiB"), Some(2048.0));
+        assert_eq!(parse_bytes("42"), Some(42.0));
+        assert_eq!(parse_bytes("3 apples"), None);
+        assert_eq!(parse_millis("200ms"), Some(200.0));
+        assert_eq!(parse_millis("3m10s"), Some(190_000.0));
+        assert_eq!(parse_millis("1h0.5s"), Some(3_600_500.0));
+        assert_eq!(parse_millis("10"), None);
+        assert_eq!(parse_millis(""), None);
+
+        let mut grok = PatternCache::default();
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("size"), String::from("1.5MB"));
+        m.insert(String::from("took"), String::from("1.5us"));
+        Transform::parse("bytes size", &mut grok)
+            .unwrap()
+            .apply(&mut m)
+            .unwrap();
+        Transform::parse("millis took as took_ms", &mut grok)
+            .unwrap()
+            .apply(&mut m)
+            .unwrap();
+        assert_eq!(m.get("size").unwrap(), "1500000");
+        assert_eq!(m.get("took").unwrap(), "1.5us");
+        assert_eq!(m.get("took_ms").unwrap(), "0.0015");
+        assert!(Transform::parse("bytes size into x", &mut grok).is_err());
+    }
+
+    #[test]
+    fn test_epoch_conversions() {
+        assert_eq!(
+            Conversion::Epoch.convert("2020-05-14T10:00:00Z"),
+            Some(String::from("1589450400"))
+        );
+        assert_eq!(
+            Conversion::EpochMillis.convert("2020-05-14T10:00:00.250+00:00"),
+            Some(String::from("1589450400250"))
+        );
+        assert_eq!(
+            Conversion::Timestamp.convert("1589450400.5"),
+            Some(String::from("2020-05-14T10:00:00.500Z"))
+        );
+        assert_eq!(
+            Conversion::TimestampMillis.convert("1589450400000"),
+            Some(String::from("2020-05-14T10:00:00Z"))
+        );
+        assert_eq!(Conversion::Timestamp.convert("soon"), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Conversion::Hex.convert("68656c6c6f"),
+            Some(String::from("hello"))
+        );
+        assert_eq!(Conversion::Hex.convert("xyz"), None);
+        assert_eq!(
+            Conversion::Base64.convert("dXNlcj1ib2I/"),
+            Some(String::from("user=bob?"))
+        );
+        assert_eq!(
+            Conversion::Base64.convert("dXNlcj1ib2I_"),
+            Some(String::from("user=bob?"))
+        );
+        assert_eq!(Conversion::Base64.convert("%%%"), None);
+        assert_eq!(
+            Conversion::Url.convert("a%20b+c%3D"),
+            Some(String::from("a b c="))
+        );
+
+        let mut grok = PatternCache::default();
+        let mut m = HashMap::<String, String>::new();
+        m.insert(
+            String::from("payload"),
+            String::from("dXNlcj1ib2IgaWQ9NDI="),
+        );
+        for t in [
+            "base64 payload as decoded",
+            r#"grok decoded "user=%{WORD:user} id=%{INT:id}""#,
+        ]
+        .iter()
+        {
+            Transform::parse(t, &mut grok)
+                .unwrap()
+                .apply(&mut m)
+                .unwrap();
+        }
+        assert_eq!(m.get("decoded").unwrap(), "user=bob id=42");
+        assert_eq!(m.get("user").unwrap(), "bob");
+        assert_eq!(m.get("id").unwrap(), "42");
+    }
+
+    #[test]
+    fn test_severity() {
+        for (s, expected) in [
+            ("WARN", Some(("warn", 30))),
+            ("Warning", Some(("warn", 30))),
+            ("W", Some(("warn", 30))),
+            ("30", Some(("warn", 30))),
+            ("[ERR]", Some(("error", 40))),
+            ("2", Some(("fatal", 50))),
+            ("loud", None),
+        ]
+        .iter()
+        {
+            assert_eq!(normalize_severity(s), *expected, "{}", s);
+        }
+
+        let mut grok = PatternCache::default();
+        let t = Transform::parse("severity lvl", &mut grok).unwrap();
+        let mut m = HashMap::<String, String>::new();
+        m.insert(String::from("lvl"), String::from("Critical"));
+        t.apply(&mut m).unwrap();
+        assert_eq!(m.get("severity").unwrap(), "fatal");
+        assert_eq!(m.get("severity_rank").unwrap(), "50");
+        assert_eq!(m.get("lvl").unwrap(), "Critical");
+    }
+
+    #[test]
+    fn test_delta() {
+        let record = |host: &str, n: &str| {
+            let mut m = HashMap::new();
+            m.insert(String::from("host"), String::from(host));
+            m.insert(String::from("n"), String::from(n));
+            m
+        };
+        let mut delta = Delta::parse("n", NumberLocale::C).unwrap();
+        let deltas = [("a", "10"), ("a", "25"), ("a", "x"), ("b", "20.5")]
+            .iter()
+            .map(|(host, n)| {
+                let mut m = record(host, n);
+                delta.apply(&mut m);
+                m.remove("_delta.n").unwrap()
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(deltas, vec!["", "15", "", "-4.5"]);
+
+        let mut delta = Delta::parse("n by host", NumberLocale::C).unwrap();
+        let deltas = [("a", "10"), ("b", "20"), ("a", "12"), ("b", "19")]
+            .iter()
+            .map(|(host, n)| {
+                let mut m = record(host, n);
+                delta.apply(&mut m);
+                m.remove("_delta.n").unwrap()
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(deltas, vec!["", "", "2", "-1"]);
+        assert!(Delta::parse("n per host", NumberLocale::C).is_err());
+
+        let mut delta = Delta::parse("n", NumberLocale::De).unwrap();
+        let deltas = ["1.000,5", "1.250", "1.25"]
+            .iter()
+            .map(|n| {
+                let mut m = record("a", n);
+                delta.apply(&mut m);
+                m.remove("_delta.n").unwrap()
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(deltas, vec!["", "249.5", ""]);
+    }
+
+    #[test]
+    fn test_carry_forward() {
+        let mut carry = CarryForward::new("session");
+        let sessions = [None, Some("a"), None, Some(""), Some("b"), None]
+            .iter()
+            .map(|session| {
+                let mut m = HashMap::new();
+                if let Some(session) = session {
+                    m.insert(String::from("session"), String::from(*session));
+                }
+                carry.apply(&mut m);
+                m.remove("session")
+            })
+            .collect::<Vec<Option<String>>>();
+        assert_eq!(
+            sessions,
+            vec![
+                None,
+                Some(String::from("a")),
+                Some(String::from("a")),
+                Some(String::from("a")),
+                Some(String::from("b")),
+                Some(String::from("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let mut avg = MovingAverage::parse("ms:window=3", NumberLocale::C).unwrap();
+        let means = ["x", "10", "20", "30", "-", "40"]
+            .iter()
+            .map(|ms| {
+                let mut m = HashMap::new();
+                m.insert(String::from("ms"), String::from(*ms));
+                avg.apply(&mut m);
+                m.remove("_moving_avg.ms").unwrap()
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(means, vec!["", "10", "15", "20", "20", "30"]);
+        assert_eq!(
+            MovingAverage::parse("ms", NumberLocale::C).unwrap().window,
+            DEFAULT_WINDOW
+        );
+        assert!(MovingAverage::parse("ms:window=0", NumberLocale::C).is_err());
+        assert!(MovingAverage::parse("ms:size=3", NumberLocale::C).is_err());
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(
+            Conversion::Crc32.convert("hello"),
+            Some(String::from("3610a686"))
+        );
+        assert_eq!(
+            Conversion::Md5.convert("hello"),
+            Some(String::from("5d41402abc4b2a76b9719d911017c592"))
+        );
+        assert_eq!(
+            Conversion::Sha256.convert("hello"),
+            Some(String::from(
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            ))
+        );
+    }
+}
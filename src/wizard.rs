@@ -0,0 +1,378 @@
+use crate::{load_patterns, Config, GropError};
+use fgrok::Grok;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader};
+
+/// Number of sample lines the expression is checked against.
+const MAX_SAMPLES: usize = 5;
+
+/// Patterns suggested for a token, in the order they are tried.
+const SUGGESTIONS: &[&str] = &[
+    "TIMESTAMP_ISO8601",
+    "IP",
+    "UUID",
+    "LOGLEVEL",
+    "INT",
+    "NUMBER",
+    "WORD",
+    "NOTSPACE",
+];
+
+const HELP: &str = r#"Commands:
+  <n> PATTERN[:name]     match token <n> with a grok pattern, optionally capturing it as "name"
+  <n>.. PATTERN[:name]   match token <n> and everything after it with a grok pattern
+  <n> =                  match token <n> literally
+  auto                   use the suggested pattern for every literal token
+  show                   show the tokens and the current expression
+  done                   print the resulting config and quit
+  quit                   quit without printing anything"#;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Assignment {
+    Literal,
+    Capture {
+        pattern: String,
+        name: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct WizardResult {
+    match_expression: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_format: Option<String>,
+}
+
+/// The expression being built, derived from the tokens of the first sample line.
+struct Draft {
+    tokens: Vec<String>,
+    assignments: Vec<Assignment>,
+    // Index of the token whose assignment also covers all the following tokens.
+    tail: Option<usize>,
+}
+
+impl Draft {
+    fn new(line: &str) -> Draft {
+        let tokens = line
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<String>>();
+        let assignments = vec![Assignment::Literal; tokens.len()];
+        Draft {
+            tokens,
+            assignments,
+            tail: None,
+        }
+    }
+
+    fn expression(&self) -> String {
+        let end = match self.tail {
+            Some(tail) => tail + 1,
+            None => self.tokens.len(),
+        };
+        let parts = self.tokens[..end]
+            .iter()
+            .zip(self.assignments.iter())
+            .map(|(token, assignment)| match assignment {
+                Assignment::Literal => escape(token),
+                Assignment::Capture {
+                    pattern,
+                    name: Some(name),
+                } => format!("%{{{}:{}}}", pattern, name),
+                Assignment::Capture {
+                    pattern,
+                    name: None,
+                } => format!("%{{{}}}", pattern),
+            })
+            .collect::<Vec<String>>();
+        format!(r"^{}$", parts.join(r"\s+"))
+    }
+
+    fn capture_names(&self) -> Vec<String> {
+        let end = match self.tail {
+            Some(tail) => tail + 1,
+            None => self.tokens.len(),
+        };
+        self.assignments[..end]
+            .iter()
+            .filter_map(|a| match a {
+                Assignment::Capture { name: Some(n), .. } => Some(n.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Escape the regex meta characters of a literal token.
+fn escape(token: &str) -> String {
+    let mut escaped = String::new();
+    for c in token.chars() {
+        if r"\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Return the first suggested pattern matching the whole token.
+fn suggest(grok: &mut Grok, token: &str) -> Option<&'static str> {
+    SUGGESTIONS.iter().copied().find(|p| {
+        grok.compile(&format!("^%{{{}}}$", p), true)
+            .map(|pattern| pattern.match_against(token).is_some())
+            .unwrap_or(false)
+    })
+}
+
+fn show(draft: &Draft, grok: &mut Grok, out: &mut dyn Write) -> Result<(), GropError> {
+    writeln!(out, "Tokens of the first sample line:")?;
+    for (i, token) in draft.tokens.iter().enumerate() {
+        let assignment = match &draft.assignments[i] {
+            _ if draft.tail.map(|t| i > t).unwrap_or(false) => String::from("(covered)"),
+            Assignment::Literal => match suggest(grok, token) {
+                Some(p) => format!("literal (suggest {})", p),
+                None => String::from("literal"),
+            },
+            Assignment::Capture { pattern, name } => match name {
+                Some(name) => format!("{}:{}", pattern, name),
+                None => pattern.clone(),
+            },
+        };
+        writeln!(out, "  [{}] {:<30} {}", i, token, assignment)?;
+    }
+    Ok(())
+}
+
+fn feedback(
+    draft: &Draft,
+    samples: &[String],
+    grok: &mut Grok,
+    out: &mut dyn Write,
+) -> Result<(), GropError> {
+    let expression = draft.expression();
+    writeln!(out, "Expression: {}", expression)?;
+    let pattern = match grok.compile(&expression, true) {
+        Ok(pattern) => pattern,
+        Err(err) => {
+            writeln!(out, "  invalid expression: {}", err)?;
+            return Ok(());
+        }
+    };
+    for sample in samples.iter() {
+        match pattern.match_against(sample) {
+            Some(m) => {
+                let mut fields = m
+                    .iter()
+                    .map(|(k, v)| format!("{}={:?}", k, v))
+                    .collect::<Vec<String>>();
+                fields.sort();
+                writeln!(out, "  match:    {}", sample)?;
+                if !fields.is_empty() {
+                    writeln!(out, "            {}", fields.join(" "))?;
+                }
+            }
+            None => writeln!(out, "  no match: {}", sample)?,
+        }
+    }
+    Ok(())
+}
+
+/// Apply an assignment command (`<n> PATTERN[:name]`, `<n>.. PATTERN[:name]` or `<n> =`).
+fn assign(draft: &mut Draft, grok: &mut Grok, cmd: &str) -> Result<(), String> {
+    let parts = cmd.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() != 2 {
+        return Err(String::from("unknown command, type \"help\" for help"));
+    }
+    let (index, is_tail) = match parts[0].strip_suffix("..") {
+        Some(index) => (index, true),
+        None => (parts[0], false),
+    };
+    let index = index
+        .parse::<usize>()
+        .map_err(|_| String::from("unknown command, type \"help\" for help"))?;
+    if index >= draft.tokens.len() {
+        return Err(format!("there is no token [{}]", index));
+    }
+    let assignment = if parts[1] == "=" {
+        Assignment::Literal
+    } else {
+        let mut pn = parts[1].splitn(2, ':');
+        let pattern = pn.next().unwrap_or_default();
+        if let Err(err) = grok.compile(&format!("%{{{}}}", pattern), true) {
+            return Err(err.to_string());
+        }
+        Assignment::Capture {
+            pattern: String::from(pattern),
+            name: pn.next().map(String::from),
+        }
+    };
+    draft.assignments[index] = assignment;
+    if is_tail {
+        draft.tail = Some(index);
+    } else if draft.tail.map(|t| index >= t).unwrap_or(false) {
+        draft.tail = None;
+    }
+    Ok(())
+}
+
+/// Drive the interactive session, reading commands from `prompt`. Returns the resulting config
+/// snippet, or `None` if the user quit.
+fn wizard(
+    samples: &[String],
+    grok: &mut Grok,
+    prompt: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<Option<String>, GropError> {
+    let first = samples
+        .first()
+        .ok_or_else(|| GropError::InvalidArg(String::from("no sample line to build from")))?;
+    let mut draft = Draft::new(first);
+    writeln!(out, "{}", HELP)?;
+    show(&draft, grok, out)?;
+    feedback(&draft, samples, grok, out)?;
+    loop {
+        write!(out, "> ")?;
+        out.flush()?;
+        let mut cmd = String::new();
+        // The end of input finishes the session just like "done".
+        if prompt.read_line(&mut cmd)? == 0 {
+            writeln!(out)?;
+            break;
+        }
+        match cmd.trim() {
+            "" => continue,
+            "help" => writeln!(out, "{}", HELP)?,
+            "show" => show(&draft, grok, out)?,
+            "done" => break,
+            "quit" => return Ok(None),
+            "auto" => {
+                for i in 0..draft.tokens.len() {
+                    if draft.assignments[i] == Assignment::Literal {
+                        if let Some(p) = suggest(grok, &draft.tokens[i]) {
+                            draft.assignments[i] = Assignment::Capture {
+                                pattern: String::from(p),
+                                name: None,
+                            };
+                        }
+                    }
+                }
+                feedback(&draft, samples, grok, out)?;
+            }
+            cmd => match assign(&mut draft, grok, cmd) {
+                Ok(_) => feedback(&draft, samples, grok, out)?,
+                Err(msg) => writeln!(out, "{}", msg)?,
+            },
+        }
+    }
+    let names = draft.capture_names();
+    let result = WizardResult {
+        match_expression: draft.expression(),
+        output_format: if names.is_empty() {
+            None
+        } else {
+            Some(names.join(","))
+        },
+    };
+    toml::to_string(&result)
+        .map(Some)
+        .map_err(|err| GropError::InvalidArg(err.to_string()))
+}
+
+/// Interactively build a match expression from the sample lines of the input (or stdin) and print
+/// the resulting config.
+pub fn run_wizard(config: Config) -> Result<(), GropError> {
+    let (mut grok, _) = load_patterns(&config.custom_patterns, config.pattern_library.as_deref())?;
+    let stdin = io::stdin();
+    let mut prompt = stdin.lock();
+    let mut out = io::stdout();
+
+    let mut samples = Vec::new();
+    match config.input.first() {
+        Some(file) => {
+            for line in BufReader::new(File::open(file)?).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    samples.push(line);
+                }
+                if samples.len() == MAX_SAMPLES {
+                    break;
+                }
+            }
+        }
+        None => {
+            writeln!(
+                out,
+                "Paste up to {} sample lines, end with an empty line:",
+                MAX_SAMPLES
+            )?;
+            for line in prompt.by_ref().lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    break;
+                }
+                samples.push(line);
+                if samples.len() == MAX_SAMPLES {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(result) = wizard(&samples, &mut grok, &mut prompt, &mut out)? {
+        writeln!(out, "\n# Resulting config\n{}", result)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_suggest() {
+        let mut grok = Grok::default();
+        assert_eq!(suggest(&mut grok, "10.0.0.1"), Some("IP"));
+        assert_eq!(suggest(&mut grok, "ERROR"), Some("LOGLEVEL"));
+        assert_eq!(suggest(&mut grok, "42"), Some("INT"));
+        assert_eq!(suggest(&mut grok, "a.b/c"), Some("NOTSPACE"));
+    }
+
+    #[test]
+    fn test_wizard_session() {
+        let mut grok = Grok::default();
+        let samples = vec![
+            String::from("10.0.0.1 ERROR disk (sda) is full"),
+            String::from("10.0.0.2 INFO all good"),
+        ];
+        let mut prompt =
+            Cursor::new("0 IP:client\n9 WORD\n1 LOGLEVEL:lvl\n2.. GREEDYDATA:msg\ndone\n");
+        let mut out = Cursor::new(Vec::new());
+        let result = wizard(&samples, &mut grok, &mut prompt, &mut out)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            result,
+            r#"match_expression = "^%{IP:client}\\s+%{LOGLEVEL:lvl}\\s+%{GREEDYDATA:msg}$"
+output_format = "client,lvl,msg"
+"#
+        );
+        let out = String::from_utf8(out.into_inner()).unwrap();
+        assert!(out.contains("there is no token [9]"));
+        assert!(out.contains(r#"client="10.0.0.2" lvl="INFO" msg="all good""#));
+
+        let mut prompt = Cursor::new("quit\n");
+        let mut out = Cursor::new(Vec::new());
+        assert!(wizard(&samples, &mut grok, &mut prompt, &mut out)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_escape() {
+        let d = Draft::new("[x] a.b");
+        assert_eq!(d.expression(), r"^\[x\]\s+a\.b$");
+    }
+}